@@ -0,0 +1,205 @@
+use crate::car_state::CarState;
+use crate::game_logic::theta_grid::ThetaGrid;
+use crate::game_logic::{AIControlled, AiDebugCar, AiDebugCars, RacerId, ThetaCheckpointList};
+use crate::networking_plugin::NetworkClient;
+use bevy::prelude::*;
+
+// Grid cost shown at full red on the heat map, the cheapest road is green
+const HEAT_MAP_MAX_COST: f32 = 200.0;
+// Only cells this close to a camera get drawn, the rest are off screen anyway
+const HEAT_MAP_RANGE: f32 = 1200.0;
+// How far above a car its behaviour label sits
+const LABEL_HEIGHT: f32 = 48.0;
+
+const PATH_COLOR: Color = Color::srgb(1.0, 1.0, 0.0);
+const TARGET_COLOR: Color = Color::srgb(1.0, 0.0, 1.0);
+const CHECKPOINT_COLOR: Color = Color::srgb(0.0, 1.0, 1.0);
+const CLEAR_SIGHT_COLOR: Color = Color::srgb(0.0, 1.0, 0.0);
+const BLOCKED_SIGHT_COLOR: Color = Color::srgb(1.0, 0.0, 0.0);
+
+/// Which parts of the AI debug overlay are showing. F3 toggles each CPU's path, target,
+/// checkpoint and behaviour, F4 the pathfinding grid's cost heat map and F5 the line of
+/// sight rays to where each CPU is aiming.
+#[derive(Resource, Default)]
+pub struct AiDebugOverlay {
+    pub paths: bool,
+    pub heat_map: bool,
+    pub sight: bool,
+}
+
+impl AiDebugOverlay {
+    // whether anything's showing that needs to know what the CPUs are doing
+    fn watching_cars(&self) -> bool {
+        self.paths || self.sight
+    }
+}
+
+// Text above a CPU car saying what its behaviour tree has it doing
+#[derive(Component)]
+pub struct AiDebugLabel(u32);
+
+pub fn toggle_ai_debug(
+    input: Res<ButtonInput<KeyCode>>,
+    mut overlay: ResMut<AiDebugOverlay>,
+    mut network_client: ResMut<NetworkClient>,
+) {
+    let was_watching = overlay.watching_cars();
+    if input.just_pressed(KeyCode::F3) {
+        overlay.paths = !overlay.paths;
+    }
+    if input.just_pressed(KeyCode::F4) {
+        overlay.heat_map = !overlay.heat_map;
+    }
+    if input.just_pressed(KeyCode::F5) {
+        overlay.sight = !overlay.sight;
+    }
+
+    // online the server has to be asked to send what its CPUs are doing
+    let watching = overlay.watching_cars();
+    if watching != was_watching
+        && let Some(client) = &mut network_client.client
+        && let Err(e) = client.watch_ai(watching)
+    {
+        println!("Failed to ask for AI debug info: {}", e);
+    }
+}
+
+// Offline the CPUs are right here, take a look at them every frame the overlay needs them
+pub fn collect_ai_debug(
+    overlay: Res<AiDebugOverlay>,
+    grid: Res<ThetaGrid>,
+    mut debug_cars: ResMut<AiDebugCars>,
+    ai_cars: Query<(&RacerId, &Transform, &CarState, &ThetaCheckpointList), With<AIControlled>>,
+) {
+    if !overlay.watching_cars() {
+        return;
+    }
+    debug_cars.cars = ai_cars
+        .iter()
+        .map(|(id, transform, car_state, checkpoints)| {
+            AiDebugCar::new(
+                id.0,
+                transform.translation.truncate(),
+                car_state.behaviour(),
+                checkpoints,
+                &grid,
+            )
+        })
+        .collect();
+}
+
+pub fn draw_ai_debug(
+    mut gizmos: Gizmos,
+    overlay: Res<AiDebugOverlay>,
+    debug_cars: Res<AiDebugCars>,
+    grid: Res<ThetaGrid>,
+    cameras: Query<&Transform, With<Camera>>,
+) {
+    if overlay.heat_map {
+        let in_view = |pos: Vec2| {
+            cameras
+                .iter()
+                .any(|camera| camera.translation.truncate().distance(pos) < HEAT_MAP_RANGE)
+        };
+        for index in 0..grid.node_count() {
+            let node = grid.node_at(index);
+            let pos = Vec2::new(node.world_x, node.world_y);
+            if !in_view(pos) {
+                continue;
+            }
+            let color = if node.passable {
+                // log scale, road costs a few and grass and the like hundreds
+                let cost = node.cost + grid.dynamic_cost(index);
+                let heat = (cost.ln() / HEAT_MAP_MAX_COST.ln()).clamp(0.0, 1.0);
+                Color::srgba(heat, 1.0 - heat, 0.0, 0.4)
+            } else {
+                Color::srgba(0.2, 0.2, 0.2, 0.4)
+            };
+            gizmos.rect_2d(pos, Vec2::splat(grid.tile_size * 0.9), color);
+        }
+    }
+
+    for car in &debug_cars.cars {
+        let pos = Vec2::from(car.pos);
+        if overlay.paths {
+            let path: Vec<Vec2> = car.path.iter().copied().map(Vec2::from).collect();
+            if let Some(&first) = path.first() {
+                gizmos.line_2d(pos, first, PATH_COLOR);
+            }
+            gizmos.linestrip_2d(path.iter().copied(), PATH_COLOR);
+            for &point in &path {
+                gizmos.circle_2d(point, 4.0, PATH_COLOR);
+            }
+            if let Some(target) = car.target {
+                gizmos.circle_2d(Vec2::from(target), 12.0, TARGET_COLOR);
+            }
+            if let Some((a, b)) = car.checkpoint {
+                gizmos.line_2d(Vec2::from(a), Vec2::from(b), CHECKPOINT_COLOR);
+                gizmos.circle_2d(Vec2::from(a), 4.0, CHECKPOINT_COLOR);
+                gizmos.circle_2d(Vec2::from(b), 4.0, CHECKPOINT_COLOR);
+            }
+        }
+        if overlay.sight {
+            for &(to, clear) in &car.sight {
+                let color = if clear {
+                    CLEAR_SIGHT_COLOR
+                } else {
+                    BLOCKED_SIGHT_COLOR
+                };
+                gizmos.line_2d(pos, Vec2::from(to), color);
+                gizmos.circle_2d(Vec2::from(to), 8.0, color);
+            }
+        }
+    }
+}
+
+// Keep a label floating over every CPU while paths are showing
+pub fn update_ai_debug_labels(
+    mut commands: Commands,
+    overlay: Res<AiDebugOverlay>,
+    debug_cars: Res<AiDebugCars>,
+    mut labels: Query<(Entity, &AiDebugLabel, &mut Transform, &mut Text2d)>,
+) {
+    let label_at = |car: &AiDebugCar| Vec3::new(car.pos.0, car.pos.1 + LABEL_HEIGHT, 950.0);
+    let label_text = |car: &AiDebugCar| format!("CPU {}: {:?}", car.id, car.behaviour);
+
+    for (entity, label, mut transform, mut text) in labels.iter_mut() {
+        match debug_cars.cars.iter().find(|car| car.id == label.0) {
+            Some(car) if overlay.paths => {
+                transform.translation = label_at(car);
+                text.0 = label_text(car);
+            }
+            _ => commands.entity(entity).despawn(),
+        }
+    }
+
+    if !overlay.paths {
+        return;
+    }
+    for car in &debug_cars.cars {
+        if labels.iter().any(|(_, label, ..)| label.0 == car.id) {
+            continue;
+        }
+        commands.spawn((
+            AiDebugLabel(car.id),
+            Text2d::new(label_text(car)),
+            TextFont {
+                font_size: 16.0,
+                ..default()
+            },
+            Transform::from_translation(label_at(car)),
+        ));
+    }
+}
+
+// Nothing from the last race should be left on screen for the next
+pub fn clear_ai_debug(
+    mut commands: Commands,
+    mut debug_cars: ResMut<AiDebugCars>,
+    labels: Query<Entity, With<AiDebugLabel>>,
+) {
+    debug_cars.cars.clear();
+    for entity in labels.iter() {
+        commands.entity(entity).despawn();
+    }
+}
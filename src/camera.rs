@@ -1,33 +1,122 @@
 use crate::car::Background;
 use crate::game_logic::GameMap;
-use crate::game_logic::{Car, PlayerControlled};
+use crate::game_logic::{Car, LocalPlayer, LocalPlayers, PlayerControlled};
 use bevy::prelude::*;
+use bevy::render::camera::{ScalingMode, Viewport};
+use bevy::window::PrimaryWindow;
 
 // Camera-related constants
 pub const WIN_W: f32 = 1280.;
 pub const WIN_H: f32 = 720.;
 
-// Camera movement system that follows the player
+// Which local player a camera follows, 0 is the main camera
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlayerCamera(pub u8);
+
+// Camera movement system that follows the player, one camera per local player
 pub fn move_camera(
     game_map: Res<GameMap>,
-    player_car: Single<&Transform, With<PlayerControlled>>,
-    mut camera: Single<&mut Transform, (With<Camera>, Without<PlayerControlled>)>,
+    player_cars: Query<(&LocalPlayer, &Transform), With<PlayerControlled>>,
+    mut cameras: Query<(&Camera, &PlayerCamera, &mut Transform), Without<PlayerControlled>>,
+) {
+    for (camera, player_camera, mut camera_transform) in cameras.iter_mut() {
+        let Some((_, player_car)) = player_cars
+            .iter()
+            .find(|(local_player, _)| local_player.0 == player_camera.0)
+        else {
+            continue;
+        };
+
+        // split-screen halves see less of the map, so they can get closer to the edge
+        let view = camera
+            .logical_viewport_size()
+            .unwrap_or(Vec2::new(WIN_W, WIN_H));
+        let max = Vec3::new(
+            game_map.width / 2. - view.x / 2.,
+            game_map.height / 2. - view.y / 2.,
+            0.,
+        );
+        let min = -max;
+
+        // clamp to map bounds
+        let mut target = player_car.translation.clamp(min, max);
+
+        // round to integers to prevent subpixel gaps
+        target.x = target.x.round();
+        target.y = target.y.round();
+
+        camera_transform.translation = target;
+    }
+}
+
+// Split the window down the middle and give the second player their own camera
+pub fn setup_split_screen(
+    mut commands: Commands,
+    local_players: Res<LocalPlayers>,
+    cameras: Query<&PlayerCamera>,
 ) {
-    let max = Vec3::new(
-        game_map.width / 2. - WIN_W / 2.,
-        game_map.height / 2. - WIN_H / 2.,
-        0.,
-    );
-    let min = -max.clone();
+    if !local_players.split_screen() || cameras.iter().any(|camera| camera.0 == 1) {
+        return;
+    }
 
-    // clamp to map bounds
-    let mut target = player_car.translation.clamp(min, max);
+    // viewports get sized by fit_split_screen, the UI stays on player one's half
 
-    // round to integers to prevent subpixel gaps
-    target.x = target.x.round();
-    target.y = target.y.round();
+    let mut projection = OrthographicProjection::default_2d();
+    projection.scaling_mode = ScalingMode::WindowSize;
+    commands.spawn((
+        Camera2d,
+        Camera {
+            order: 1,
+            ..default()
+        },
+        Projection::Orthographic(projection),
+        PlayerCamera(1),
+    ));
+}
+
+// Keep each half of the window sized to the player it belongs to
+pub fn fit_split_screen(
+    local_players: Res<LocalPlayers>,
+    window: Single<&Window, With<PrimaryWindow>>,
+    mut cameras: Query<(&mut Camera, &PlayerCamera)>,
+) {
+    if !local_players.split_screen() {
+        return;
+    }
+    let size = window.physical_size();
+    let half = UVec2::new(size.x / 2, size.y);
+    if half.x == 0 || half.y == 0 {
+        return;
+    }
 
-    camera.translation = target;
+    for (mut camera, player_camera) in cameras.iter_mut() {
+        let position = UVec2::new(half.x * player_camera.0 as u32, 0);
+        let up_to_date = camera
+            .viewport
+            .as_ref()
+            .is_some_and(|v| v.physical_position == position && v.physical_size == half);
+        if !up_to_date {
+            camera.viewport = Some(Viewport {
+                physical_position: position,
+                physical_size: half,
+                ..default()
+            });
+        }
+    }
+}
+
+// Back to one full-window camera when leaving the race
+pub fn cleanup_split_screen(
+    mut commands: Commands,
+    mut cameras: Query<(Entity, &mut Camera, &PlayerCamera)>,
+) {
+    for (entity, mut camera, player_camera) in cameras.iter_mut() {
+        if player_camera.0 == 0 {
+            camera.viewport = None;
+        } else {
+            commands.entity(entity).despawn();
+        }
+    }
 }
 
 pub fn reset_camera_for_credits(
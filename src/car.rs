@@ -7,12 +7,17 @@ use crate::game_logic::{
     EASY_DRIFT_TURN_MULTIPLIER, FRICTION, LATERAL_FRICTION, PLAYER_SPEED, START_ORIENTATION,
     TURNING_RATE,
 };
-use crate::game_logic::{AIControlled, Car, Orientation, PlayerControlled, Velocity};
 use crate::game_logic::{
-    CpuDifficulty, GameMap, LapCounter, MapLevelData, TILE_SIZE, ThetaCheckpointList, ThetaCommand,
-    theta_star, handle_collision,
+    AIControlled, Car, DriveInput, Hazard, LocalPlayer, LocalPlayers, OBSTACLE_COST, Orientation,
+    PlayerControlled, Velocity,
 };
-use crate::speed::SpeedBoost;
+use crate::game_logic::{
+    AI_ID_START, AiCar, AiPowerups, AiRecovery, AiReflexes, AiTraffic, AiTrack, BehaviourTrees,
+    Blackboard, CpuDifficulty, Finished, GameMap, LapCounter, MapLevelData, PATH_SEARCHES_PER_TICK,
+    Personality, PracticeGrid, RaceSettings, RacerId, RacingLine, TILE_SIZE, ThetaCheckpointList,
+    Team, TrafficCar, drive_ai_car, handle_collision, place_ai_car, plan_paths, rivals,
+};
+use crate::speed::{SpeedBoost, SpeedPowerup};
 use bevy::prelude::*;
 use crate::game_logic::theta_grid::ThetaGrid;
 
@@ -26,277 +31,151 @@ pub struct DriftState {
     pub was_drifting: bool,
 }
 
-// Car movement system
+// Car movement system, runs once for every local player's car
 pub fn move_player_car(
     game_map: Res<GameMap>,
     time: Res<Time>,
     input: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>,
     drift_settings: Res<DriftSettings>,
-    player_car: Single<
+    mut player_cars: Query<
         (
+            Entity,
+            &LocalPlayer,
             &mut Transform,
             &mut Velocity,
             &mut Orientation,
             &mut Sprite,
             &mut DriftState,
             Option<&SpeedBoost>,
+            Has<Finished>,
         ),
         (With<PlayerControlled>, Without<Background>),
     >,
     other_cars: Query<(&Transform, &Velocity), (With<Car>, Without<PlayerControlled>)>,
 ) {
-    let (mut transform, mut velocity, mut orientation, mut sprite, mut drift_state, speed_boost) =
-        player_car.into_inner();
+    // In split-screen the local cars also have to bump into each other
+    let local_cars: Vec<(Entity, Vec2, Vec2)> = player_cars
+        .iter()
+        .map(|(entity, _, t, v, ..)| (entity, t.translation.truncate(), v.velocity))
+        .collect();
 
-    let deltat = time.delta_secs();
-    let accel = ACCEL_RATE * deltat;
-
-    // Space bar to drift
-    let is_drifting = input.pressed(KeyCode::Space);
-    let easy_mode = drift_settings.easy_mode;
-    let turn_scale = if is_drifting && easy_mode {
-        EASY_DRIFT_TURN_MULTIPLIER
-    } else {
-        1.0
-    };
-    let speed_bonus = if is_drifting && easy_mode {
-        EASY_DRIFT_SPEED_BONUS
-    } else {
-        1.0
-    };
-
-    // PLACEHOLDER LOGIC FOR TILE COLLISIONS
-
-    // Get the current tile
-    let pos = transform.translation.truncate();
-    let tile = game_map.get_tile(pos.x, pos.y, TILE_SIZE as f32);
-    // println!("title id: {}",tile.tile_id);
-    // Modifiers from terrain
-    let mut fric_mod = tile.friction_modifier;
-    let mut speed_mod = tile.speed_modifier;
-    let mut turn_mod = tile.turn_modifier;
-    let decel_mod = tile.decel_modifier;
-
-    //LOS DEBUG, ADD 'mut gizmos: Gizmos' to function input
-    /*
-    println!("Car Position: {}, {}", tile.x_coordinate, tile.y_coordinate);
-    let los = game_map.line_of_sight((tile.x_coordinate, tile.y_coordinate), (77.0, 17.0));
-    if(los)
+    for (
+        entity,
+        local_player,
+        mut transform,
+        mut velocity,
+        mut orientation,
+        mut sprite,
+        mut drift_state,
+        speed_boost,
+        finished,
+    ) in player_cars.iter_mut()
     {
-        println!("Line of sight found")
-    } else { println!("Line of sight not found") };
-
-    let world_pos1 = game_map.tile_to_world(tile.x_coordinate, tile.y_coordinate, 64.0);
-    let world_pos2 = game_map.tile_to_world(77.0, 17.0, 64.0);
-
-    // Choose color based on LOS result
-    let color = if los {
-        Color::srgb(0.0, 1.0, 0.0) // Green for clear LOS
-    } else {
-        Color::srgb(1.0, 0.0, 0.0) // Red for blocked
-    };
-
-    // Draw line between points
-    gizmos.line_2d(world_pos1, world_pos2, color);
-
-    // Draw dots at endpoints
-    gizmos.circle_2d(world_pos1, 8.0, color);
-    gizmos.circle_2d(world_pos2, 8.0, color);
-    */
-
-    // Speed boost override
-
-    // if tile.speed_boost {
-    //     **velocity = orientation.forward_vector() * PLAYER_SPEED * 1.5;
-    // }
-
-    if speed_boost.is_some() {
-        fric_mod = 10.0;
-        speed_mod = 3.0;
-        turn_mod = 1.5;
-        // print!("Speed boost on tile at {}, {}\n", x, y);
-        // ADD SPEED BOOST COLOR CHANGE HERE
-        let hue = (time.elapsed_secs() * 180.0) % 360.0; // Speed of 180 degrees/sec
-        sprite.color = Color::hsl(hue, 1.0, 0.7); // Full saturation, 70% lightness
-    } else {
-        sprite.color = Color::WHITE; // Normal color (no tint)
-    }
-
-    // Turning
-    if input.pressed(KeyCode::KeyA) {
-        orientation.angle += TURNING_RATE * deltat * turn_mod * turn_scale;
-    }
-    if input.pressed(KeyCode::KeyD) {
-        orientation.angle -= TURNING_RATE * deltat * turn_mod * turn_scale;
-    }
-
-    // Accelerate forward in the direction of car orientation
-    if input.pressed(KeyCode::KeyW) {
-        let forward = orientation.forward_vector() * accel;
-        **velocity += forward;
-        // println!("{},{}", x, y); commented by dvdzs for lap logic
-        **velocity = velocity.clamp_length_max(PLAYER_SPEED * speed_mod * speed_bonus);
-    }
-
-    // Accelerate in the direction opposite of orientation
-    if input.pressed(KeyCode::KeyS) {
-        let backward = -orientation.forward_vector() * (accel / 2.0);
-        **velocity += backward;
-        **velocity = velocity.clamp_length_max(PLAYER_SPEED * (speed_mod / 2.0) * speed_bonus);
-    }
-
-    // Friction when not accelerating
-    if !input.any_pressed([KeyCode::KeyW, KeyCode::KeyS]) {
-        let decel_rate = decel_mod * fric_mod * deltat;
-        let curr_speed = velocity.length();
-        if curr_speed > 0.0 {
-            let new_speed = (curr_speed - decel_rate).max(0.0);
-            if new_speed > 0.0 {
-                **velocity = velocity.normalize() * new_speed;
-            } else {
-                **velocity = Vec2::ZERO;
-            }
+        if finished {
+            continue;
         }
-    }
+        let controls = DriveInput::read(*local_player, &input, &gamepads);
 
-    // Apply lateral friction when not drifting (or in easy mode drifts) to reduce sliding
-    if (!is_drifting || easy_mode) && velocity.length() > 0.01 {
-        let forward = orientation.forward_vector();
-        let right = Vec2::new(-forward.y, forward.x);
+        let deltat = time.delta_secs();
+        let accel = ACCEL_RATE * deltat;
 
-        let forward_speed = velocity.dot(forward);
-        let lateral_speed = velocity.dot(right);
-
-        let damping_strength = if is_drifting && easy_mode {
-            EASY_DRIFT_LATERAL_FRICTION
+        // Space bar (right shift for player two) to drift
+        let is_drifting = controls.drift;
+        let easy_mode = drift_settings.easy_mode;
+        let turn_scale = if is_drifting && easy_mode {
+            EASY_DRIFT_TURN_MULTIPLIER
         } else {
-            LATERAL_FRICTION
+            1.0
+        };
+        let speed_bonus = if is_drifting && easy_mode {
+            EASY_DRIFT_SPEED_BONUS
+        } else {
+            1.0
         };
-        let damping = (1.0 - damping_strength * deltat).max(0.0);
-        let new_lateral_speed = lateral_speed * damping;
-
-        **velocity = forward * forward_speed + right * new_lateral_speed;
-    }
-
-    // Drift boost
-    if drift_state.was_drifting && !is_drifting {
-        let boost_velocity = orientation.forward_vector() * PLAYER_SPEED * DRIFT_RELEASE_BOOST;
-        **velocity += boost_velocity;
-    }
-
-    drift_state.was_drifting = is_drifting;
 
-    // Updated position
-    let change = **velocity * deltat;
+        // PLACEHOLDER LOGIC FOR TILE COLLISIONS
 
-    let min = Vec3::new(
-        -game_map.width / 2. + (CAR_SIZE as f32) / 2.,
-        -game_map.height / 2. + (CAR_SIZE as f32) / 2.,
-        900.,
-    );
-    let max = Vec3::new(
-        game_map.width / 2. - (CAR_SIZE as f32) / 2.,
-        game_map.height / 2. - (CAR_SIZE as f32) / 2.,
-        900.,
-    );
+        // Get the current tile
+        let pos = transform.translation.truncate();
+        let tile = game_map.get_tile(pos.x, pos.y, TILE_SIZE as f32);
+        // println!("title id: {}",tile.tile_id);
+        // Modifiers from terrain
+        let mut fric_mod = tile.friction_modifier;
+        let mut speed_mod = tile.speed_modifier;
+        let mut turn_mod = tile.turn_modifier;
+        let decel_mod = tile.decel_modifier;
 
-    // Rotate car to match orientation
-    transform.rotation = Quat::from_rotation_z(orientation.angle);
+        //LOS DEBUG, ADD 'mut gizmos: Gizmos' to function input
+        /*
+        println!("Car Position: {}, {}", tile.x_coordinate, tile.y_coordinate);
+        let los = game_map.line_of_sight((tile.x_coordinate, tile.y_coordinate), (77.0, 17.0));
+        if(los)
+        {
+            println!("Line of sight found")
+        } else { println!("Line of sight not found") };
+
+        let world_pos1 = game_map.tile_to_world(tile.x_coordinate, tile.y_coordinate, 64.0);
+        let world_pos2 = game_map.tile_to_world(77.0, 17.0, 64.0);
+
+        // Choose color based on LOS result
+        let color = if los {
+            Color::srgb(0.0, 1.0, 0.0) // Green for clear LOS
+        } else {
+            Color::srgb(1.0, 0.0, 0.0) // Red for blocked
+        };
 
-    // Calculate new position
-    let new_position = (transform.translation + change.extend(0.)).clamp(min, max);
+        // Draw line between points
+        gizmos.line_2d(world_pos1, world_pos2, color);
 
-    // Handle collision detection and response
-    // Convert Query to iterator of (position, velocity) pairs
-    let other_cars_iter = other_cars
-        .iter()
-        .map(|(t, v)| (t.translation.truncate(), v.velocity));
-    let should_update = handle_collision(
-        new_position,
-        transform.translation.truncate(),
-        &mut velocity.velocity,
-        &game_map,
-        other_cars_iter,
-    );
+        // Draw dots at endpoints
+        gizmos.circle_2d(world_pos1, 8.0, color);
+        gizmos.circle_2d(world_pos2, 8.0, color);
+        */
 
-    // Update position only if no collision occurred
-    if should_update {
-        transform.translation = new_position;
-    }
-}
+        // Speed boost override
 
-pub fn move_ai_cars(
-    game_map: Res<GameMap>,
-    theta_grid: Res<ThetaGrid>,
-    time: Res<Time>,
-    mut ai_cars: Query<
-        (
-            &mut Transform,
-            &mut Velocity,
-            &mut Orientation,
-            &mut ThetaCheckpointList,
-        ),
-        (With<AIControlled>, Without<Background>),
-    >,
-    other_cars: Query<(&Transform, &Velocity), (With<Car>, Without<AIControlled>)>,
-) {
-    let deltat = time.delta_secs();
-    let accel = ACCEL_RATE * deltat;
+        // if tile.speed_boost {
+        //     **velocity = orientation.forward_vector() * PLAYER_SPEED * 1.5;
+        // }
 
-    // Turning
-    // Iterate through each AI-controlled car
-    for (mut transform, mut velocity, mut orientation, mut theta_checkpoint_list) in
-        ai_cars.iter_mut()
-    {
-        let pos = transform.translation.truncate();
+        if speed_boost.is_some() {
+            fric_mod = 10.0;
+            speed_mod = 3.0;
+            turn_mod = 1.5;
+            // print!("Speed boost on tile at {}, {}\n", x, y);
+            // ADD SPEED BOOST COLOR CHANGE HERE
+            let hue = (time.elapsed_secs() * 180.0) % 360.0; // Speed of 180 degrees/sec
+            sprite.color = Color::hsl(hue, 1.0, 0.7); // Full saturation, 70% lightness
+        } else {
+            sprite.color = Color::WHITE; // Normal color (no tint)
+        }
 
-        // Get the current tile
-        let tile = game_map.get_tile(pos.x, pos.y, TILE_SIZE as f32);
-        // Modifiers from terrain
-        let fric_mod = tile.friction_modifier;
-        let speed_mod = tile.speed_modifier;
-        let turn_mod = tile.turn_modifier;
-        let decel_mod = tile.decel_modifier;
+        // Turning
+        if controls.left {
+            orientation.angle += TURNING_RATE * deltat * turn_mod * turn_scale;
+        }
+        if controls.right {
+            orientation.angle -= TURNING_RATE * deltat * turn_mod * turn_scale;
+        }
 
-        // Get command from steering helper using Theta* pathfinding
-        let command = theta_star(
-            (pos.x, pos.y),
-            orientation.angle,
-            &mut theta_checkpoint_list,
-            &theta_grid,
-        );
+        // Accelerate forward in the direction of car orientation
+        if controls.forward {
+            let forward = orientation.forward_vector() * accel;
+            **velocity += forward;
+            // println!("{},{}", x, y); commented by dvdzs for lap logic
+            **velocity = velocity.clamp_length_max(PLAYER_SPEED * speed_mod * speed_bonus);
+        }
 
-        // Execute the command
-        match command {
-            ThetaCommand::TurnLeft => {
-                orientation.angle += TURNING_RATE * deltat * turn_mod;
-            }
-            ThetaCommand::TurnRight => {
-                orientation.angle -= TURNING_RATE * deltat * turn_mod;
-            }
-            ThetaCommand::Forward => {
-                let forward = orientation.forward_vector() * accel;
-                **velocity += forward;
-                **velocity = velocity.clamp_length_max(PLAYER_SPEED * speed_mod);
-            }
-            ThetaCommand::Reverse => {
-                let backward = -orientation.forward_vector() * (accel / 4.0);
-                **velocity += backward;
-                **velocity = velocity.clamp_length_max(PLAYER_SPEED * (speed_mod / 4.0));
-            }
-            ThetaCommand::Stop => {
-                if velocity.length() > 0.0 {
-                    let backward = -orientation.forward_vector() * (accel / 2.0);
-                    **velocity += backward;
-                    **velocity = velocity.clamp_length_max(PLAYER_SPEED * (speed_mod / 2.0));
-                } else {
-                    **velocity = Vec2::ZERO;
-                }
-            }
+        // Accelerate in the direction opposite of orientation
+        if controls.backward {
+            let backward = -orientation.forward_vector() * (accel / 2.0);
+            **velocity += backward;
+            **velocity = velocity.clamp_length_max(PLAYER_SPEED * (speed_mod / 2.0) * speed_bonus);
         }
 
-        // Apply friction when not accelerating forward or reversing
-        if !matches!(command, ThetaCommand::Forward | ThetaCommand::Reverse) {
+        // Friction when not accelerating
+        if !controls.accelerating() {
             let decel_rate = decel_mod * fric_mod * deltat;
             let curr_speed = velocity.length();
             if curr_speed > 0.0 {
@@ -309,6 +188,33 @@ pub fn move_ai_cars(
             }
         }
 
+        // Apply lateral friction when not drifting (or in easy mode drifts) to reduce sliding
+        if (!is_drifting || easy_mode) && velocity.length() > 0.01 {
+            let forward = orientation.forward_vector();
+            let right = Vec2::new(-forward.y, forward.x);
+
+            let forward_speed = velocity.dot(forward);
+            let lateral_speed = velocity.dot(right);
+
+            let damping_strength = if is_drifting && easy_mode {
+                EASY_DRIFT_LATERAL_FRICTION
+            } else {
+                LATERAL_FRICTION
+            };
+            let damping = (1.0 - damping_strength * deltat).max(0.0);
+            let new_lateral_speed = lateral_speed * damping;
+
+            **velocity = forward * forward_speed + right * new_lateral_speed;
+        }
+
+        // Drift boost
+        if drift_state.was_drifting && !is_drifting {
+            let boost_velocity = orientation.forward_vector() * PLAYER_SPEED * DRIFT_RELEASE_BOOST;
+            **velocity += boost_velocity;
+        }
+
+        drift_state.was_drifting = is_drifting;
+
         // Updated position
         let change = **velocity * deltat;
 
@@ -333,7 +239,13 @@ pub fn move_ai_cars(
         // Convert Query to iterator of (position, velocity) pairs
         let other_cars_iter = other_cars
             .iter()
-            .map(|(t, v)| (t.translation.truncate(), v.velocity));
+            .map(|(t, v)| (t.translation.truncate(), v.velocity))
+            .chain(
+                local_cars
+                    .iter()
+                    .filter(|(other, ..)| *other != entity)
+                    .map(|(_, pos, vel)| (*pos, *vel)),
+            );
         let should_update = handle_collision(
             new_position,
             transform.translation.truncate(),
@@ -348,6 +260,158 @@ pub fn move_ai_cars(
         }
     }
 }
+
+// Marks every car and hazard on the pathfinding grid, so the AI plans round them
+pub fn mark_dynamic_obstacles(
+    mut theta_grid: ResMut<ThetaGrid>,
+    cars: Query<&Transform, (With<Car>, Without<Background>)>,
+    hazards: Query<(&Transform, &Hazard)>,
+) {
+    theta_grid.clear_dynamic_costs();
+    for transform in &cars {
+        let pos = transform.translation;
+        theta_grid.add_dynamic_cost((pos.x, pos.y), CAR_SIZE as f32 / 2.0, OBSTACLE_COST);
+    }
+    for (transform, hazard) in &hazards {
+        let pos = transform.translation;
+        theta_grid.add_dynamic_cost((pos.x, pos.y), hazard.radius, OBSTACLE_COST);
+    }
+}
+
+// Hands the AI cars' path requests to the async compute pool and picks up the results,
+// runs before the AI drives so a path that's just arrived is followed straight away
+pub fn plan_ai_paths(
+    theta_grid: Res<ThetaGrid>,
+    mut ai_cars: Query<&mut ThetaCheckpointList, With<AIControlled>>,
+) {
+    plan_paths(
+        ai_cars
+            .iter_mut()
+            .map(|checkpoints| (checkpoints.into_inner(), &*theta_grid)),
+        PATH_SEARCHES_PER_TICK,
+    );
+}
+
+// The cars the AI races against that it doesn't drive itself, and which team each is on
+type OtherCar<'a> = (&'a Transform, &'a Velocity, Option<&'a Team>);
+
+pub fn move_ai_cars(
+    game_map: Res<GameMap>,
+    theta_grid: Res<ThetaGrid>,
+    racing_line: Res<RacingLine>,
+    time: Res<Time>,
+    mut ai_cars: Query<
+        (
+            Entity,
+            &mut Transform,
+            &mut Velocity,
+            &mut Orientation,
+            &mut ThetaCheckpointList,
+            &mut AiReflexes,
+            &mut AiRecovery,
+            &mut AiTraffic,
+            &AiPowerups,
+            Has<SpeedBoost>,
+            &CpuDifficulty,
+            &CarState,
+            Option<&Team>,
+        ),
+        (With<AIControlled>, Without<Background>),
+    >,
+    other_cars: Query<OtherCar, (With<Car>, Without<AIControlled>)>,
+    pickups: Query<&Transform, (With<SpeedPowerup>, Without<AIControlled>)>,
+) {
+    let deltat = time.delta_secs();
+
+    // Where every car is and where it's going at the start of the frame, for the AI to react to
+    let ai_positions: Vec<(Entity, Option<Team>, Vec2, Vec2)> = ai_cars
+        .iter()
+        .map(|(entity, transform, velocity, .., team)| {
+            (entity, team.copied(), transform.translation.truncate(), **velocity)
+        })
+        .collect();
+    let pickup_positions: Vec<Vec2> = pickups.iter().map(|t| t.translation.truncate()).collect();
+
+    // Iterate through each AI-controlled car
+    for (
+        entity,
+        mut transform,
+        mut velocity,
+        mut orientation,
+        mut theta_checkpoint_list,
+        mut reflexes,
+        mut recovery,
+        mut ai_traffic,
+        powerups,
+        boosted,
+        difficulty,
+        car_state,
+        team,
+    ) in ai_cars.iter_mut()
+    {
+        let traffic: Vec<TrafficCar> = ai_positions
+            .iter()
+            .filter(|(other, ..)| *other != entity)
+            .map(|&(_, other_team, pos, velocity)| (other_team, pos, velocity))
+            .chain(
+                other_cars
+                    .iter()
+                    .map(|(t, v, other_team)| (other_team.copied(), t.translation.truncate(), **v)),
+            )
+            .map(|(other_team, pos, velocity)| TrafficCar {
+                pos,
+                velocity,
+                rival: rivals(team.copied(), other_team),
+            })
+            .collect();
+        // only drivers that know what to do with a power-up go looking for one, and one at a time
+        let wanted_pickups = if !powerups.held && difficulty.profile().uses_boosts {
+            pickup_positions.as_slice()
+        } else {
+            &[]
+        };
+
+        let pos = transform.translation.truncate();
+        let car = AiCar {
+            velocity: &mut velocity,
+            orientation: &mut orientation,
+            checkpoints: &mut theta_checkpoint_list,
+            reflexes: &mut reflexes,
+            recovery: &mut recovery,
+            ai_traffic: &mut ai_traffic,
+            difficulty: *difficulty,
+            behaviour: car_state.behaviour(),
+            boosted,
+        };
+        let track = AiTrack {
+            game_map: &game_map,
+            theta_grid: &theta_grid,
+            racing_line: &racing_line,
+            traffic: &traffic,
+            pickups: wanted_pickups,
+        };
+        let target = drive_ai_car(car, pos, &track, deltat);
+
+        // Rotate car to match orientation
+        transform.rotation = Quat::from_rotation_z(orientation.angle);
+
+        // Keep it on the map and out of the walls and the players' cars
+        let other_cars_iter = other_cars
+            .iter()
+            .map(|(t, v, _)| (t.translation.truncate(), v.velocity));
+        let placed = place_ai_car(
+            pos,
+            target,
+            &mut velocity,
+            &mut recovery,
+            &game_map,
+            other_cars_iter,
+        );
+        // same height as a player's car once it's moving
+        transform.translation = placed.extend(900.);
+    }
+}
+
 // Car spawning functionality
 pub fn spawn_cars(
     mut commands: Commands,
@@ -356,23 +420,19 @@ pub fn spawn_cars(
     state: Res<State<crate::GameState>>,
     map_data: Res<MapLevelData>,
     skin_selection: Res<CarSkinSelection>,
+    race_settings: Res<RaceSettings>,
+    local_players: Res<LocalPlayers>,
+    practice_grid: Res<PracticeGrid>,
 ) {
     let car_sheet_handle = asset_server.load(skin_selection.current_skin());
     let car_layout = TextureAtlasLayout::from_grid(UVec2::splat(CAR_SIZE), 2, 2, None, None);
     let car_layout_handle = texture_atlases.add(car_layout);
 
-    let start_positions = {
-        let base = map_data.start_position;
-        let spacing = 100.0;
-        [
-            (base.x, base.y),
-            (base.x + spacing, base.y),
-            (base.x, base.y - spacing),
-            (base.x + spacing, base.y - spacing),
-        ]
-    };
-
-    let player_start = start_positions[0];
+    let start_positions = map_data.start_grid;
+    let player_start = start_positions
+        .first()
+        .copied()
+        .unwrap_or((map_data.start_position.x, map_data.start_position.y));
 
     // Spawn player car
     commands.spawn((
@@ -392,109 +452,272 @@ pub fn spawn_cars(
         Orientation::new(START_ORIENTATION),
         Car,
         PlayerControlled,
-        LapCounter::default(),
+        LapCounter::new(race_settings.laps),
+        RacerId(0),
+        LocalPlayer(0),
         PredictionBuffer::new(),
         DriftState::default(),
     ));
 
-    // Spawn AI car IF in demo mode
-    if *state.get() == crate::GameState::PlayingDemo {
-        let ai_start = start_positions.get(1).copied().unwrap_or(player_start);
+    // Second local player for split-screen, takes the next grid slot
+    let mut next_slot = 1;
+    if *state.get() == crate::GameState::PlayingDemo && local_players.split_screen() {
+        let second_start = start_positions.get(next_slot).copied().unwrap_or(player_start);
+        next_slot += 1;
         commands.spawn((
             Sprite::from_atlas_image(
-                asset_server.load(AI_SKIN),
+                asset_server.load(skin_selection.random_other()),
                 TextureAtlas {
                     layout: car_layout_handle.clone(),
                     index: 0,
                 },
             ),
             Transform {
-                translation: Vec3::new(ai_start.0, ai_start.1, 10.),
+                translation: Vec3::new(second_start.0, second_start.1, 10.),
                 rotation: Quat::from_rotation_z(START_ORIENTATION),
                 ..default()
             },
             Velocity::new(),
             Orientation::new(START_ORIENTATION),
             Car,
-            AIControlled,
-            LapCounter::default(),
-            CarState::new(), // carstate for the AI
-            ThetaCheckpointList::new(Vec::new()),
+            PlayerControlled,
+            LapCounter::new(race_settings.laps),
+            RacerId(1),
+            LocalPlayer(1),
+            DriftState::default(),
         ));
     }
+
+    // Fill the rest of the grid with the CPU cars picked on the race setup screen
+    if *state.get() == crate::GameState::PlayingDemo {
+        let humans = next_slot;
+        for i in 0..race_settings.ai_slots(humans) {
+            let driver = practice_grid.driver(i);
+            let ai_start = start_positions
+                .get(humans + i)
+                .copied()
+                .unwrap_or(player_start);
+            commands.spawn((
+                Sprite::from_atlas_image(
+                    asset_server.load(AI_SKIN),
+                    TextureAtlas {
+                        layout: car_layout_handle.clone(),
+                        index: 0,
+                    },
+                ),
+                Transform {
+                    translation: Vec3::new(ai_start.0, ai_start.1, 10.),
+                    rotation: Quat::from_rotation_z(START_ORIENTATION),
+                    ..default()
+                },
+                Velocity::new(),
+                Orientation::new(START_ORIENTATION),
+                Car,
+                AIControlled,
+                LapCounter::new(race_settings.laps),
+                RacerId(AI_ID_START + i as u32),
+                driver.difficulty,
+                driver.personality,
+                CarState::new(), // carstate for the AI
+                ThetaCheckpointList::new(Vec::new()),
+                (
+                    AiReflexes::default(),
+                    AiRecovery::default(),
+                    AiTraffic::default(),
+                    AiPowerups::default(),
+                ),
+            ));
+        }
+    }
 }
 
-// beginnings of the fsm system
+// runs each AI car's behaviour tree, move_ai_cars does what it decides
 pub fn ai_car_fsm(
     mut ai_query: Query<
         (
             Entity,
             &mut CarState,
-            &mut Transform,
-            &mut Velocity,
-            &mut Orientation,
-            &mut ThetaCheckpointList,
+            &Transform,
+            &Velocity,
+            &Orientation,
+            &Personality,
+            &AiRecovery,
+            &AiPowerups,
+            Has<SpeedBoost>,
+            Option<&Team>,
         ),
         With<AIControlled>,
     >,
-    other_cars: Query<&Transform, (With<Car>, Without<AIControlled>)>,
-    mut delta_time: Res<Time>,
-    difficulty: Res<CpuDifficulty>,
-    grid: Res<ThetaGrid>,
+    other_cars: Query<OtherCar, (With<Car>, Without<AIControlled>)>,
+    time: Res<Time>,
+    trees: Res<BehaviourTrees>,
 ) {
-    // define proximity threshold (in game units)
-    const PROXIMITY_THRESHOLD: f32 = 300.0;
-
-    // just an idea, but we COULD determine threshold based on difficulty
-    /*
-    let proximity_threshold = match *difficulty {
-        CpuDifficulty::Easy => 200.0,   // Blind as a bat
-        CpuDifficulty::Medium => 300.0, // Normal
-        CpuDifficulty::Hard => 600.0,   // Eagle eyes
-    };
-    */
+    // where every car is at the start of the frame, for the trees to look at
+    let car_positions: Vec<(Option<Entity>, Option<Team>, Vec2, Vec2)> = ai_query
+        .iter()
+        .map(|(entity, _, transform, velocity, .., team)| {
+            (Some(entity), team.copied(), transform.translation.truncate(), **velocity)
+        })
+        .chain(
+            other_cars
+                .iter()
+                .map(|(t, v, team)| (None, team.copied(), t.translation.truncate(), **v)),
+        )
+        .collect();
 
     for (
         entity,
         mut car_state,
-        mut transform,
-        mut velocity,
-        mut orientation,
-        mut checkpoints,
+        transform,
+        _,
+        orientation,
+        personality,
+        recovery,
+        powerups,
+        boosted,
+        team,
     ) in ai_query.iter_mut()
     {
         // check for nearby cars
         let ai_pos = transform.translation.truncate();
-        let mut closest_car_distance = f32::MAX;
-        let mut closest_car_position = None;
+        let traffic: Vec<TrafficCar> = car_positions
+            .iter()
+            .filter(|(other, ..)| *other != Some(entity))
+            .map(|&(_, other_team, pos, velocity)| TrafficCar {
+                pos,
+                velocity,
+                rival: rivals(team.copied(), other_team),
+            })
+            .collect();
+        let blackboard = Blackboard {
+            stuck: recovery.stuck(),
+            holding_item: powerups.held,
+            boosted,
+            ..Blackboard::sense(ai_pos, orientation.forward_vector(), &traffic)
+        };
 
-        for other_transform in other_cars.iter() {
-            let other_pos = other_transform.translation.truncate();
-            let distance = ai_pos.distance(other_pos);
+        car_state.update(time.delta(), trees.for_personality(*personality), &blackboard);
+    }
+}
 
-            if distance < closest_car_distance {
-                closest_car_distance = distance;
-                closest_car_position = Some(other_pos);
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic::theta_grid::map2_grid;
+    use crate::game_logic::{LapCounter, load_map_from_file};
+    use crate::networking::MapChoice;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use std::time::Duration;
+
+    const STEP: f32 = 1.0 / 60.0;
+    const LAPS: u8 = 2;
+    // give up on a car that still hasn't finished after this long
+    const TIME_LIMIT_SECS: f32 = 600.0;
+
+    // Drive one AI car around the big map with nobody else on track, aiming and making
+    // mistakes as `seed` says. Returns its average lap time in seconds, or None if it
+    // never finished.
+    fn average_lap_time(
+        difficulty: CpuDifficulty,
+        seed: u64,
+        game_map: &GameMap,
+        grid: &ThetaGrid,
+        racing_line: &RacingLine,
+    ) -> Option<f32> {
+        let map_data = MapLevelData::for_map(2);
+        let (checkpoints, finish) = map_data.gates();
+        let start = map_data.start_grid[0];
+
+        let mut pos = Vec2::new(start.0, start.1);
+        let mut velocity = Velocity::new();
+        let mut orientation = Orientation::new(START_ORIENTATION);
+        let mut path = ThetaCheckpointList::new(Vec::new()).load_checkpoint_list(2);
+        path.rng = StdRng::seed_from_u64(seed);
+        let mut reflexes = AiReflexes {
+            rng: StdRng::seed_from_u64(seed),
+            ..default()
+        };
+        let mut recovery = AiRecovery::default();
+        let mut ai_traffic = AiTraffic::default();
+        let track = AiTrack {
+            game_map,
+            theta_grid: grid,
+            racing_line,
+            traffic: &[],
+            pickups: &[],
+        };
+        let mut lap_counter = LapCounter::new(LAPS);
+        lap_counter.advance(pos, &checkpoints, &finish);
+        // nobody else is on track, so its tree only ever has it racing or recovering
+        let trees = BehaviourTrees::default();
+        let mut car_state = CarState::new();
+
+        let mut elapsed = 0.0;
+        while !lap_counter.has_finished && elapsed < TIME_LIMIT_SECS {
+            let blackboard = Blackboard {
+                stuck: recovery.stuck(),
+                ..default()
+            };
+            car_state.update(
+                Duration::from_secs_f32(STEP),
+                trees.for_personality(Personality::Racer),
+                &blackboard,
+            );
+            let car = AiCar {
+                velocity: &mut velocity,
+                orientation: &mut orientation,
+                checkpoints: &mut path,
+                reflexes: &mut reflexes,
+                recovery: &mut recovery,
+                ai_traffic: &mut ai_traffic,
+                difficulty,
+                behaviour: car_state.behaviour(),
+                boosted: false,
+            };
+            let target = drive_ai_car(car, pos, &track, STEP);
+            pos = place_ai_car(
+                pos,
+                target,
+                &mut velocity,
+                &mut recovery,
+                game_map,
+                std::iter::empty(),
+            );
+            lap_counter.advance(pos, &checkpoints, &finish);
+            elapsed += STEP;
         }
 
-        // determine if any car is within proximity threshold
-        let car_nearby = closest_car_distance < PROXIMITY_THRESHOLD;
-
-        // pass all the properties to the update function
-        // maybe roll this into a struct in the future for readability
+        lap_counter.has_finished.then(|| elapsed / LAPS as f32)
+    }
 
-        car_state.update(
-            &mut delta_time,
-            &mut transform,
-            &mut velocity,
-            &mut orientation,
-            car_nearby,
-            closest_car_position,
-            closest_car_distance,
-            &difficulty,
-            &mut checkpoints,
-            &grid,
+    #[test]
+    fn hard_cpu_laps_faster_than_easy() {
+        let game_map = load_map_from_file("assets/map2.txt");
+        let grid = map2_grid();
+        let racing_line = RacingLine::load(MapChoice::Big).unwrap();
+
+        // the AI aims at random points and makes random mistakes, so average a few races,
+        // seeded so every run drives the same ones
+        const SEEDS: [u64; 3] = [1, 2, 3];
+        let average = |difficulty: CpuDifficulty| {
+            SEEDS
+                .iter()
+                .map(|&seed| {
+                    average_lap_time(difficulty, seed, &game_map, &grid, &racing_line)
+                        .unwrap_or_else(|| panic!("{:?} CPU didn't finish race {}", difficulty, seed))
+                })
+                .sum::<f32>()
+                / SEEDS.len() as f32
+        };
+        let easy = average(CpuDifficulty::Easy);
+        let hard = average(CpuDifficulty::Hard);
+
+        assert!(
+            hard < easy,
+            "Hard averaged {:.1}s a lap, Easy {:.1}s",
+            hard,
+            easy
         );
     }
 }
@@ -1,9 +1,9 @@
 use crate::drift_settings::DriftSettings;
 use crate::game_logic::{
-    CAR_SIZE, CLIENT_TIMESTEP, DRIFT_RELEASE_BOOST, Orientation, PhysicsInput, PLAYER_SPEED,
-    PlayerControlled, TILE_SIZE, Velocity, handle_collision,
+    CAR_SIZE, CLIENT_TIMESTEP, DRIFT_RELEASE_BOOST, Eliminated, Finished, Orientation, PhysicsInput, PLAYER_SPEED,
+    PlayerControlled, RaceSettings, TILE_SIZE, Team, Velocity, handle_collision,
 };
-use crate::multiplayer::NetworkPlayer;
+use crate::multiplayer::CollidableNetworkCar;
 use crate::networking::InputData;
 use crate::networking_plugin::NetworkClient;
 use crate::speed::SpeedBoost;
@@ -55,17 +55,25 @@ pub fn send_keyboard_input(
             &mut Orientation,
             &mut PredictionBuffer,
             Option<&SpeedBoost>,
+            Option<&Team>,
         ),
         With<PlayerControlled>,
     >,
-    other_cars: Query<(&Transform, &Velocity), (With<NetworkPlayer>, Without<PlayerControlled>)>,
+    other_cars: Query<(&Transform, &Velocity, Option<&Team>), CollidableNetworkCar>,
     game_map: Res<crate::game_logic::GameMap>,
     drift_settings: Res<DriftSettings>,
+    race_settings: Res<RaceSettings>,
+    finished: Query<(), (With<PlayerControlled>, Or<(With<Finished>, With<Eliminated>)>)>,
 ) {
     let Some(client) = network_client.client.as_mut() else {
         return;
     };
 
+    // Out of the race, the server holds our car still
+    if !finished.is_empty() {
+        return;
+    }
+
     let forward = input.pressed(KeyCode::KeyW);
     let backward = input.pressed(KeyCode::KeyS);
     let left = input.pressed(KeyCode::KeyA);
@@ -79,7 +87,7 @@ pub fn send_keyboard_input(
     let easy_drift = drift_settings.easy_mode;
     let boost_active = player_car
         .get_single()
-        .map(|(_, _, _, _, boost)| boost.is_some())
+        .map(|(_, _, _, _, boost, _)| boost.is_some())
         .unwrap_or(false);
 
     input_buffer.pending_inputs.push(InputData {
@@ -100,7 +108,7 @@ pub fn send_keyboard_input(
     }
 
     // Predict movement locally for instant feedback
-    if let Ok((mut transform, mut velocity, mut orientation, mut buffer, speed_boost)) =
+    if let Ok((mut transform, mut velocity, mut orientation, mut buffer, speed_boost, my_team)) =
         player_car.get_single_mut()
     {
         let physics_input = PhysicsInput {
@@ -155,7 +163,10 @@ pub fn send_keyboard_input(
         let new_position = pos.extend(transform.translation.z);
         let other_cars_iter = other_cars
             .iter()
-            .map(|(t, v)| (t.translation.truncate(), v.velocity));
+            .filter(|(_, _, other_team)| {
+                race_settings.cars_collide(my_team.copied(), other_team.copied())
+            })
+            .map(|(t, v, _)| (t.translation.truncate(), v.velocity));
         let should_update = handle_collision(
             new_position,
             old_pos,
@@ -0,0 +1,578 @@
+// src/game_logic/ai_controller.rs
+use crate::game_logic::{
+    ACCEL_RATE, DRIFT_RELEASE_BOOST, DifficultyProfile, EASY_DRIFT_LATERAL_FRICTION,
+    EASY_DRIFT_SPEED_BONUS, EASY_DRIFT_TURN_MULTIPLIER, LATERAL_FRICTION, Orientation,
+    PLAYER_SPEED, TURNING_RATE, TerrainTile, Velocity,
+};
+use bevy::prelude::*;
+use std::f32::consts::PI;
+
+// Pure pursuit steers at a point this far up the path, plus however far the car covers in LOOKAHEAD_SECS
+pub const MIN_LOOKAHEAD: f32 = 96.0;
+const LOOKAHEAD_SECS: f32 = 0.35;
+// How quickly a full brake slows the car, in pixels per second per second
+pub const BRAKE_RATE: f32 = 400.0;
+// Target further round than this and the car backs up instead (about 153 degrees)
+const REVERSE_ANGLE: f32 = PI * 0.85;
+// Curvature is measured across this many points either side, single points are too noisy
+pub const CURVATURE_SPAN: usize = 2;
+// (proportional, integral, derivative) gains, heading error is in radians and speed error in pixels/s
+const HEADING_GAINS: (f32, f32, f32) = (1.0, 0.0, 0.05);
+const SPEED_GAINS: (f32, f32, f32) = (0.02, 0.005, 0.0);
+// Integral terms stop building past this, so a long stretch against a wall doesn't wind them up
+const MAX_INTEGRAL: f32 = 50.0;
+// Bends tighter than this (1 / radius in pixels) are worth drifting, if one comes up within
+// DRIFT_ENTRY_SECS at the car's speed and it's going at least DRIFT_MIN_SPEED
+const DRIFT_CURVATURE: f32 = 1.0 / 160.0;
+const DRIFT_ENTRY_SECS: f32 = 0.4;
+const DRIFT_MIN_SPEED: f32 = 150.0;
+// It only starts once it's braked to within this much of the speed it wants for the bend
+const DRIFT_ENTRY_SLACK: f32 = 1.1;
+// A drift is held at least DRIFT_MIN_SECS, then let go once the nose is lined up with the
+// way out (to within DRIFT_RELEASE_ANGLE for the best drivers, sloppier ones let go early)
+const DRIFT_MIN_SECS: f32 = 0.3;
+const DRIFT_MAX_SECS: f32 = 2.0;
+const DRIFT_RELEASE_ANGLE: f32 = 0.1;
+
+/// What an AI driver is doing with the wheel and pedals
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AiControls {
+    pub steer: f32,    // -1 (full right) to 1 (full left)
+    pub throttle: f32, // 0-1
+    pub brake: f32,    // 0-1, held at a standstill it reverses
+    pub drift: bool,   // letting go gives the same boost a player's drift does
+}
+
+impl AiControls {
+    // what a sloppy driver does instead: steers the wrong way or lifts off
+    pub fn mistake(self) -> Self {
+        if self.steer.abs() > 0.1 {
+            Self {
+                steer: -self.steer,
+                ..self
+            }
+        } else {
+            Self {
+                throttle: 0.0,
+                ..self
+            }
+        }
+    }
+}
+
+/// A point on the path an AI car is following, with the fastest it should pass it
+#[derive(Clone, Copy, Debug)]
+pub struct PathPoint {
+    pub pos: Vec2,
+    pub max_speed: f32, // pixels per second
+}
+
+/// Where an AI car is and how it handles on the ground under it, what its controller
+/// drives from
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CarMotion {
+    pub pos: Vec2,
+    pub angle: f32,
+    pub velocity: Vec2,
+    pub top_speed: f32, // pixels per second on the ground it's on
+    pub turn_rate: f32, // radians per second on the ground it's on
+}
+
+/// The parts of an AI car its controls move
+pub struct ControlledCar<'a> {
+    pub velocity: &'a mut Velocity,
+    pub orientation: &'a mut Orientation,
+    pub was_drifting: &'a mut bool, // drifting last frame, letting go gives the boost
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Pid {
+    pub gains: (f32, f32, f32),
+    integral: f32,
+    last_error: Option<f32>,
+}
+
+impl Pid {
+    pub fn new(gains: (f32, f32, f32)) -> Self {
+        Self {
+            gains,
+            ..default()
+        }
+    }
+
+    pub fn update(&mut self, error: f32, delta_secs: f32) -> f32 {
+        let (kp, ki, kd) = self.gains;
+        self.integral = (self.integral + error * delta_secs).clamp(-MAX_INTEGRAL, MAX_INTEGRAL);
+        let derivative = match self.last_error {
+            Some(last) if delta_secs > 0.0 => (error - last) / delta_secs,
+            _ => 0.0,
+        };
+        self.last_error = Some(error);
+        kp * error + ki * self.integral + kd * derivative
+    }
+}
+
+/// Turns a path into steering, throttle and brake: pure pursuit with the look-ahead
+/// growing with speed, PID on the heading and speed errors, and brake points picked
+/// from how tight the path gets further on.
+#[derive(Clone, Debug)]
+pub struct AiController {
+    pub heading: Pid,
+    pub speed: Pid,
+    pub drifting_for: Option<f32>, // how long it's been drifting, None when it isn't
+}
+
+impl Default for AiController {
+    fn default() -> Self {
+        Self {
+            heading: Pid::new(HEADING_GAINS),
+            speed: Pid::new(SPEED_GAINS),
+            drifting_for: None,
+        }
+    }
+}
+
+impl AiController {
+    /// `path` runs on from the car. `delta_secs` is the time since the controller was
+    /// last asked.
+    pub fn drive(
+        &mut self,
+        car: &CarMotion,
+        path: &[PathPoint],
+        profile: &DifficultyProfile,
+        delta_secs: f32,
+    ) -> AiControls {
+        let CarMotion {
+            pos,
+            angle,
+            velocity,
+            top_speed,
+            turn_rate,
+        } = *car;
+        let speed = velocity.length();
+        let Some(target) = pursuit_target(pos, path, speed) else {
+            return AiControls::default();
+        };
+
+        let to_target = target - pos;
+        let alpha = wrap_angle(to_target.y.atan2(to_target.x) - angle);
+        if alpha.abs() > REVERSE_ANGLE {
+            return AiControls {
+                brake: 1.0,
+                ..default()
+            };
+        }
+
+        // the arc through the target needs a turn rate of 2 v sin(alpha) / distance,
+        // PID trims whatever the car's slip and reactions leave over
+        let distance = to_target.length().max(1.0);
+        let arc = 2.0 * speed * alpha.sin() / distance / turn_rate.max(f32::EPSILON);
+        // errors inside the driver's tolerance aren't worth correcting, past it only the
+        // part outside is, so the steering doesn't jump as the error crosses it
+        let heading_error = alpha.signum() * (alpha.abs() - profile.steer_tolerance).max(0.0);
+        let steer = (arc + self.heading.update(heading_error, delta_secs)).clamp(-1.0, 1.0);
+
+        // brake for whichever corner ahead needs it soonest, and slow enough to make the
+        // arc to the pursuit point at all, a car pointing well off its path (backing out
+        // of a wall, say) would otherwise swing wide of it
+        let arc_speed = turn_rate * distance / (2.0 * alpha.sin().abs()).max(f32::EPSILON);
+        let target_speed = brake_speed(pos, path, turn_rate).min(top_speed).min(arc_speed);
+        let push = self.speed.update(target_speed - speed, delta_secs);
+        let drift = self.drift(car, alpha, target_speed, path, profile, delta_secs);
+        AiControls {
+            steer,
+            throttle: push.clamp(0.0, 1.0),
+            brake: (-push).clamp(0.0, 1.0),
+            drift,
+        }
+    }
+
+    // Whether to be drifting: into a sharp bend coming up once the car is down to the speed
+    // for it, held through the apex and let go when the nose points the way out (`alpha`
+    // off the pursuit point) for the release boost
+    fn drift(
+        &mut self,
+        car: &CarMotion,
+        alpha: f32,
+        target_speed: f32,
+        path: &[PathPoint],
+        profile: &DifficultyProfile,
+        delta_secs: f32,
+    ) -> bool {
+        if profile.drift_skill <= 0.0 {
+            return false;
+        }
+        let (pos, speed) = (car.pos, car.velocity.length());
+        let Some(held) = self.drifting_for else {
+            let entering = sharpest_bend(pos, path, speed * DRIFT_ENTRY_SECS).is_some_and(
+                |curvature| {
+                    curvature >= DRIFT_CURVATURE
+                        && speed >= DRIFT_MIN_SPEED
+                        && speed <= target_speed * DRIFT_ENTRY_SLACK
+                },
+            );
+            if entering {
+                self.drifting_for = Some(0.0);
+            }
+            return entering;
+        };
+
+        let held = held + delta_secs;
+        let past_apex = sharpest_bend(pos, path, MIN_LOOKAHEAD)
+            .is_none_or(|curvature| curvature < DRIFT_CURVATURE);
+        let lined_up = alpha.abs() < DRIFT_RELEASE_ANGLE / profile.drift_skill;
+        let release = held >= DRIFT_MAX_SECS || (held >= DRIFT_MIN_SECS && past_apex && lined_up);
+        self.drifting_for = (!release).then_some(held);
+        !release
+    }
+}
+
+// Pure pursuit: the point the look-ahead (which grows with speed) along the path, or the
+// end of the path if it's shorter than that. It's slid along between path points rather
+// than hopping from one to the next, which would jerk the steering each time.
+fn pursuit_target(pos: Vec2, path: &[PathPoint], speed: f32) -> Option<Vec2> {
+    let lookahead = MIN_LOOKAHEAD + speed * LOOKAHEAD_SECS;
+    let mut travelled = 0.0;
+    let mut from = pos;
+    for point in path {
+        let step = from.distance(point.pos);
+        if travelled + step >= lookahead {
+            return Some(from.lerp(point.pos, (lookahead - travelled) / step.max(f32::EPSILON)));
+        }
+        travelled += step;
+        from = point.pos;
+    }
+    path.last().map(|point| point.pos)
+}
+
+// Curvature of the tightest bend on the path within `range` of the car, if the path
+// is long enough to measure one
+fn sharpest_bend(pos: Vec2, path: &[PathPoint], range: f32) -> Option<f32> {
+    let mut travelled = 0.0;
+    let mut from = pos;
+    let mut sharpest = None;
+    for i in 0..path.len() {
+        travelled += from.distance(path[i].pos);
+        from = path[i].pos;
+        if travelled > range {
+            break;
+        }
+        if i >= CURVATURE_SPAN && i + CURVATURE_SPAN < path.len() {
+            let curvature = menger_curvature(
+                path[i - CURVATURE_SPAN].pos,
+                path[i].pos,
+                path[i + CURVATURE_SPAN].pos,
+            );
+            sharpest = Some(curvature.max(sharpest.unwrap_or(0.0)));
+        }
+    }
+    sharpest
+}
+
+// Fastest the car can go now and still slow down in time for every point on the path:
+// each point allows its own speed limit and the speed the car can take its corner at,
+// plus whatever braking between here and there takes off
+fn brake_speed(pos: Vec2, path: &[PathPoint], turn_rate: f32) -> f32 {
+    let mut allowed = f32::INFINITY;
+    let mut travelled = 0.0;
+    let mut from = pos;
+    for (i, point) in path.iter().enumerate() {
+        travelled += from.distance(point.pos);
+        from = point.pos;
+
+        let mut corner_speed = point.max_speed;
+        if i >= CURVATURE_SPAN && i + CURVATURE_SPAN < path.len() {
+            let curvature = menger_curvature(
+                path[i - CURVATURE_SPAN].pos,
+                point.pos,
+                path[i + CURVATURE_SPAN].pos,
+            );
+            corner_speed = corner_speed.min(cornering_speed(curvature, turn_rate));
+        }
+        allowed = allowed.min((corner_speed.powi(2) + 2.0 * BRAKE_RATE * travelled).sqrt());
+    }
+    allowed
+}
+
+/// Move a car the way its AI's controls say, with the same handling a player's car has
+/// (an easy-mode drift when drifting). `top_speed` is its top speed before terrain, the
+/// terrain and the driver's difficulty do the rest.
+pub fn apply_ai_controls(
+    controls: &AiControls,
+    car: ControlledCar,
+    tile: &TerrainTile,
+    profile: &DifficultyProfile,
+    top_speed: f32,
+    deltat: f32,
+) {
+    let ControlledCar {
+        velocity,
+        orientation,
+        was_drifting,
+    } = car;
+    let accel = ACCEL_RATE * deltat;
+    let speed_mod = tile.speed_modifier * profile.speed_scale;
+    let (turn_scale, speed_bonus, lateral_friction) = if controls.drift {
+        (
+            EASY_DRIFT_TURN_MULTIPLIER,
+            EASY_DRIFT_SPEED_BONUS,
+            EASY_DRIFT_LATERAL_FRICTION,
+        )
+    } else {
+        (1.0, 1.0, LATERAL_FRICTION)
+    };
+
+    orientation.angle += TURNING_RATE * deltat * tile.turn_modifier * controls.steer * turn_scale;
+    let forward = orientation.forward_vector();
+
+    if controls.throttle > 0.0 {
+        **velocity += forward * accel * controls.throttle;
+        **velocity = velocity.clamp_length_max(top_speed * speed_mod * speed_bonus);
+    }
+
+    // braking slows the car down, once it's stopped (or rolling back) it reverses
+    let reversing = controls.brake > 0.0 && velocity.dot(forward) <= 1.0;
+    if reversing {
+        **velocity -= forward * (accel / 2.0) * controls.brake;
+        **velocity = velocity.clamp_length_max(top_speed * (speed_mod / 2.0) * speed_bonus);
+    } else if controls.brake > 0.0 {
+        let new_speed = (velocity.length() - BRAKE_RATE * controls.brake * deltat).max(0.0);
+        **velocity = velocity.normalize_or_zero() * new_speed;
+    }
+
+    // Friction when not accelerating forward or reversing
+    if controls.throttle <= 0.0 && !reversing {
+        let decel_rate = tile.decel_modifier * tile.friction_modifier * deltat;
+        let new_speed = (velocity.length() - decel_rate).max(0.0);
+        **velocity = velocity.normalize_or_zero() * new_speed;
+    }
+
+    // Lateral friction stops the car sliding, less of it while drifting
+    if velocity.length() > 0.01 {
+        let right = forward.perp();
+        let damping = (1.0 - lateral_friction * deltat).max(0.0);
+        **velocity = forward * velocity.dot(forward) + right * velocity.dot(right) * damping;
+    }
+
+    // Letting go of a drift kicks the car out along its nose like a player's drift boost,
+    // as hard as the driver's good at it
+    if *was_drifting && !controls.drift {
+        **velocity += forward * PLAYER_SPEED * DRIFT_RELEASE_BOOST * profile.drift_skill;
+    }
+    *was_drifting = controls.drift;
+}
+
+fn wrap_angle(angle: f32) -> f32 {
+    (angle + PI).rem_euclid(2.0 * PI) - PI
+}
+
+/// Fastest a car turning at `turn_rate` (radians per second) can take a bend of `curvature`:
+/// turning at a fixed rate, the tightest radius is speed / rate
+pub fn cornering_speed(curvature: f32, turn_rate: f32) -> f32 {
+    turn_rate / curvature.max(1e-6)
+}
+
+// 1 / radius of the circle through three points
+pub fn menger_curvature(a: Vec2, b: Vec2, c: Vec2) -> f32 {
+    let area2 = (b - a).perp_dot(c - a).abs();
+    let sides = a.distance(b) * b.distance(c) * c.distance(a);
+    if sides <= f32::EPSILON {
+        0.0
+    } else {
+        2.0 * area2 / sides
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic::{CpuDifficulty, ROAD, RacingLine, TILES, TURNING_RATE};
+    use crate::networking::MapChoice;
+
+    const STEP: f32 = 1.0 / 60.0;
+
+    // Points every 8 pixels, straight on along +x for `straight` pixels then round a
+    // left-hand bend of `radius`
+    fn path_into_bend(from: Vec2, straight: f32, radius: f32) -> Vec<PathPoint> {
+        let point = |pos| PathPoint {
+            pos,
+            max_speed: 400.0,
+        };
+        let mut path: Vec<PathPoint> = (1..=(straight / 8.0) as usize)
+            .map(|i| point(from + Vec2::new(i as f32 * 8.0, 0.0)))
+            .collect();
+        let corner = from + Vec2::new(straight, radius);
+        path.extend((1..20).map(|i| {
+            let angle = -PI / 2.0 + i as f32 * 8.0 / radius;
+            point(corner + Vec2::from_angle(angle) * radius)
+        }));
+        path
+    }
+
+    #[test]
+    fn drifts_into_tight_bends_and_lets_go_lined_up_on_the_way_out() {
+        let hard = CpuDifficulty::Hard.profile();
+        let bend = path_into_bend(Vec2::ZERO, 40.0, 100.0);
+        let at = |speed| CarMotion {
+            velocity: Vec2::new(speed, 0.0),
+            ..default()
+        };
+
+        // flat out into it is too fast to start a drift, braked down to the corner's speed it's fine
+        let mut controller = AiController::default();
+        assert!(!controller.drift(&at(400.0), 0.0, 200.0, &bend, &hard, STEP));
+        assert!(controller.drift(&at(200.0), 0.0, 200.0, &bend, &hard, STEP));
+
+        // Easy drivers never drift
+        let mut easy = AiController::default();
+        let easy_profile = CpuDifficulty::Easy.profile();
+        assert!(!easy.drift(&at(200.0), 0.0, 200.0, &bend, &easy_profile, STEP));
+
+        // still in the bend it holds on, past it but pointing the wrong way too
+        assert!(controller.drift(&at(200.0), 0.0, 200.0, &bend, &hard, DRIFT_MIN_SECS));
+        let straight = path_into_bend(Vec2::ZERO, 400.0, 100.0);
+        assert!(controller.drift(&at(200.0), 0.5, 200.0, &straight, &hard, STEP));
+
+        // lined up with the way out, it lets go
+        assert!(!controller.drift(&at(200.0), 0.0, 200.0, &straight, &hard, STEP));
+        assert_eq!(controller.drifting_for, None);
+    }
+
+    #[test]
+    fn letting_go_of_a_drift_boosts_like_a_player() {
+        let tile = &TILES[ROAD as usize];
+        let coast = |difficulty: CpuDifficulty, was_drifting: bool| {
+            let mut velocity = Velocity::from(Vec2::new(300.0, 0.0));
+            let mut orientation = Orientation::new(0.0);
+            let mut drifting = was_drifting;
+            let car = ControlledCar {
+                velocity: &mut velocity,
+                orientation: &mut orientation,
+                was_drifting: &mut drifting,
+            };
+            let profile = difficulty.profile();
+            apply_ai_controls(&AiControls::default(), car, tile, &profile, PLAYER_SPEED, STEP);
+            velocity.x
+        };
+
+        // the best drivers get the whole boost, past top speed, weaker ones part of it
+        let gain = |difficulty| coast(difficulty, true) - coast(difficulty, false);
+        assert!((gain(CpuDifficulty::Hard) - PLAYER_SPEED * DRIFT_RELEASE_BOOST).abs() < 1.0);
+        assert!(coast(CpuDifficulty::Hard, true) > PLAYER_SPEED * tile.speed_modifier);
+        assert!((gain(CpuDifficulty::Medium) - gain(CpuDifficulty::Hard) / 2.0).abs() < 1.0);
+        assert_eq!(gain(CpuDifficulty::Easy), 0.0);
+    }
+
+    #[test]
+    fn drifting_car_slides_further_than_one_with_grip() {
+        let tile = &TILES[ROAD as usize];
+        let sideways_after = |drift: bool| {
+            let mut velocity = Velocity::from(Vec2::new(200.0, 200.0));
+            let mut orientation = Orientation::new(0.0);
+            let controls = AiControls {
+                throttle: 1.0,
+                drift,
+                ..default()
+            };
+            let mut was_drifting = drift;
+            for _ in 0..10 {
+                let car = ControlledCar {
+                    velocity: &mut velocity,
+                    orientation: &mut orientation,
+                    was_drifting: &mut was_drifting,
+                };
+                let profile = CpuDifficulty::Hard.profile();
+                apply_ai_controls(&controls, car, tile, &profile, PLAYER_SPEED, STEP);
+            }
+            velocity.y
+        };
+        assert!(sideways_after(true) > sideways_after(false));
+        assert!(sideways_after(false) < 200.0);
+    }
+
+    #[test]
+    fn looks_further_ahead_the_faster_it_goes() {
+        let straight = path_into_bend(Vec2::ZERO, 800.0, 100.0);
+        let reach = |speed| pursuit_target(Vec2::ZERO, &straight[..100], speed).unwrap().x;
+        assert!((reach(0.0) - MIN_LOOKAHEAD).abs() < 0.01);
+        assert!(reach(0.0) < reach(200.0) && reach(200.0) < reach(400.0));
+        // shorter than the look-ahead, it heads for the end
+        assert_eq!(reach(10_000.0), 800.0);
+    }
+
+    #[test]
+    fn brakes_for_the_tightest_bend_on_map2_and_speeds_up_after() {
+        let line = RacingLine::load(MapChoice::Big).unwrap();
+        let n = line.points.len();
+        let at = |i: usize| line.heading_at(i).0;
+        let bend = |i: usize| menger_curvature(at(i + n - 2), at(i), at(i + 2));
+        let apex = (0..n).max_by(|&a, &b| bend(a).total_cmp(&bend(b))).unwrap() + n;
+        let allowed = |i: usize| {
+            let path: Vec<PathPoint> = (line.points_ahead(i + 1, 800.0).into_iter())
+                .map(|pos| PathPoint {
+                    pos,
+                    max_speed: PLAYER_SPEED,
+                })
+                .collect();
+            brake_speed(at(i), &path, TURNING_RATE)
+        };
+
+        // flat out well before it, slowing on the way in, and back up to speed on the way out
+        assert!(allowed(apex - 12) >= PLAYER_SPEED);
+        assert!((apex - 8..apex).any(|i| allowed(i) < PLAYER_SPEED * 0.9));
+        assert!((apex + 1..apex + 8).any(|i| allowed(i) >= PLAYER_SPEED));
+    }
+
+    #[test]
+    fn steering_doesnt_jump_between_frames() {
+        let line = RacingLine::load(MapChoice::Big).unwrap();
+        let tile = &TILES[ROAD as usize];
+        let profile = CpuDifficulty::Hard.profile();
+        let (mut pos, angle) = line.heading_at(0);
+        let mut velocity = Velocity::from(Vec2::ZERO);
+        let mut orientation = Orientation::new(angle);
+        let mut was_drifting = false;
+        let mut controller = AiController::default();
+        let mut closest = 0;
+        let mut last_steer = None;
+
+        // ten seconds round the bends at the start of the track
+        for _ in 0..600 {
+            closest = (closest..closest + 8)
+                .min_by(|&a, &b| {
+                    let (a, b) = (line.heading_at(a).0, line.heading_at(b).0);
+                    a.distance(pos).total_cmp(&b.distance(pos))
+                })
+                .unwrap();
+            assert!(line.heading_at(closest).0.distance(pos) < 48.0, "lost the line");
+            let path: Vec<PathPoint> = (line.points_ahead(closest + 1, 600.0).into_iter())
+                .map(|pos| PathPoint {
+                    pos,
+                    max_speed: PLAYER_SPEED,
+                })
+                .collect();
+            let car = CarMotion {
+                pos,
+                angle: orientation.angle,
+                velocity: *velocity,
+                top_speed: PLAYER_SPEED,
+                turn_rate: TURNING_RATE,
+            };
+            let controls = controller.drive(&car, &path, &profile, STEP);
+            if let Some(last) = last_steer {
+                let change: f32 = controls.steer - last;
+                assert!(change.abs() < 0.15, "steer went {} to {}", last, controls.steer);
+            }
+            last_steer = Some(controls.steer);
+
+            let car = ControlledCar {
+                velocity: &mut velocity,
+                orientation: &mut orientation,
+                was_drifting: &mut was_drifting,
+            };
+            apply_ai_controls(&controls, car, tile, &profile, PLAYER_SPEED, STEP);
+            pos += *velocity * STEP;
+        }
+        assert!(closest > 100, "only got {} points round", closest);
+    }
+}
@@ -0,0 +1,153 @@
+// src/game_logic/ai_debug.rs
+use crate::game_logic::{Behaviour, ThetaCheckpointList, ThetaGrid};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Most path points kept per car, anything further along is too far ahead to matter
+const MAX_DEBUG_PATH: usize = 32;
+
+/// What the AI debug overlay shows of one CPU car. Built the same way offline and on the
+/// server, which sends them to the clients that ask for them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AiDebugCar {
+    pub id: u32,
+    pub pos: (f32, f32),
+    pub behaviour: Behaviour,
+    pub path: Vec<(f32, f32)>, // the racing line or Theta* path it's following, in world coordinates
+    pub target: Option<(f32, f32)>, // where it's pathfinding to in its current checkpoint
+    pub checkpoint: Option<((f32, f32), (f32, f32))>, // ends of the checkpoint it's heading for
+    pub sight: Vec<((f32, f32), bool)>, // points it's aiming at, and whether it can see each one
+}
+
+impl AiDebugCar {
+    pub fn new(
+        id: u32,
+        pos: Vec2,
+        behaviour: Behaviour,
+        checkpoints: &ThetaCheckpointList,
+        grid: &ThetaGrid,
+    ) -> Self {
+        // tracks with a racing line never search for a path, show the stretch it's driving
+        let path = if checkpoints.line_path.is_empty() {
+            checkpoints
+                .cached_path
+                .iter()
+                .skip(checkpoints.path_index)
+                .take(MAX_DEBUG_PATH)
+                .filter_map(|&(x, y)| grid.get_node(x, y))
+                .map(|node| (node.world_x, node.world_y))
+                .collect()
+        } else {
+            checkpoints
+                .line_path
+                .iter()
+                .take(MAX_DEBUG_PATH)
+                .copied()
+                .collect()
+        };
+        let checkpoint = checkpoints
+            .checkpoints
+            .get(checkpoints.current_checkpoint_index)
+            .map(|checkpoint| checkpoint.world_ends(grid));
+
+        // the same check Theta* makes, from the car's cell to the cell of whatever it's aiming at
+        let from = grid.world_to_grid(pos.x, pos.y);
+        let sight = checkpoints
+            .current_waypoint(grid)
+            .into_iter()
+            .chain(checkpoints.target_world_pos)
+            .map(|to| {
+                let to_cell = grid.world_to_grid(to.0, to.1);
+                let clear = grid.line_of_sight(
+                    (from.0 as f32, from.1 as f32),
+                    (to_cell.0 as f32, to_cell.1 as f32),
+                );
+                (to, clear)
+            })
+            .collect();
+
+        Self {
+            id,
+            pos: (pos.x, pos.y),
+            behaviour,
+            path,
+            target: checkpoints.target_world_pos,
+            checkpoint,
+            sight,
+        }
+    }
+}
+
+/// The latest look at every CPU car for the debug overlay, from the local AI offline
+/// or streamed from the server online
+#[derive(Resource, Default)]
+pub struct AiDebugCars {
+    pub cars: Vec<AiDebugCar>,
+}
+
+impl AiDebugCars {
+    /// Take in one of the server's messages, which only has some of the cars in it
+    pub fn merge(&mut self, cars: Vec<AiDebugCar>) {
+        for car in cars {
+            match self.cars.iter_mut().find(|known| known.id == car.id) {
+                Some(known) => *known = car,
+                None => self.cars.push(car),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic::theta_grid::map2_grid;
+    use crate::game_logic::{MapLevelData, TILE_SIZE};
+    use crate::networking::ServerMessage;
+
+    #[test]
+    fn snapshot_survives_the_trip_from_server_to_client() {
+        let grid = map2_grid();
+        let start = MapLevelData::for_map(2).start_grid[0];
+        let pos = Vec2::new(start.0, start.1);
+
+        let mut checkpoints = ThetaCheckpointList::new(Vec::new()).load_checkpoint_list(2);
+        let (x, y) = grid.world_to_grid(pos.x, pos.y);
+        checkpoints.cached_path = (0..4).map(|i| (x + i, y)).collect();
+        checkpoints.path_index = 1;
+        checkpoints.target_world_pos = Some((pos.x + 3.0 * TILE_SIZE as f32, pos.y));
+
+        let car = AiDebugCar::new(7, pos, Behaviour::Ram, &checkpoints, &grid);
+        assert_eq!(car.path.len(), 3, "only the part of the path still ahead");
+        assert!(car.checkpoint.is_some());
+        assert_eq!(car.sight.len(), 2);
+
+        let payload = serde_json::json!({ "type": "ai_debug", "cars": [&car] }).to_string();
+        match serde_json::from_str::<ServerMessage>(&payload) {
+            Ok(ServerMessage::AiDebug { cars }) => assert_eq!(cars, vec![car]),
+            other => panic!("client read {:?}", other),
+        }
+    }
+
+    #[test]
+    fn racing_line_cars_show_the_line_and_merge_back_together() {
+        let grid = map2_grid();
+        let start = MapLevelData::for_map(2).start_grid[0];
+        let pos = Vec2::new(start.0, start.1);
+
+        // on a racing line track it's the line that gets shown, Theta* never ran
+        let mut checkpoints = ThetaCheckpointList::new(Vec::new()).load_checkpoint_list(2);
+        checkpoints.line_path = (0..100).map(|i| (pos.x + i as f32 * 12.345, pos.y)).collect();
+        let cars: Vec<AiDebugCar> = (0..8)
+            .map(|id| AiDebugCar::new(id, pos, Behaviour::Race, &checkpoints, &grid))
+            .collect();
+        assert_eq!(cars[0].path.len(), MAX_DEBUG_PATH);
+
+        // the server sends them a few at a time
+        let mut client = AiDebugCars::default();
+        for batch in cars.chunks(3) {
+            client.merge(batch.to_vec());
+        }
+        client.merge(vec![cars[0].clone()]);
+        assert_eq!(client.cars, cars);
+    }
+}
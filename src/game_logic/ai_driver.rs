@@ -0,0 +1,192 @@
+// src/game_logic/ai_driver.rs
+use crate::game_logic::theta_grid::ThetaGrid;
+use crate::game_logic::{
+    AiControls, AiRecovery, AiReflexes, AiTraffic, BOOST_SPEED_SCALE, Behaviour, CAR_SIZE,
+    CarMotion, ControlledCar, CpuDifficulty, GameMap, Orientation, PLAYER_SPEED, PathPoint,
+    RacingLine, Recovery, TILE_SIZE, TURNING_RATE, ThetaCheckpointList, TrafficCar, Velocity,
+    apply_ai_controls, handle_collision, pickup_detour, respawn_point, theta_star_target,
+};
+use bevy::prelude::*;
+
+/// The track every AI car in a race is driving on, and what's on it this frame
+pub struct AiTrack<'a> {
+    pub game_map: &'a GameMap,
+    pub theta_grid: &'a ThetaGrid,
+    pub racing_line: &'a RacingLine,
+    pub traffic: &'a [TrafficCar], // the other cars, as this one sees them
+    pub pickups: &'a [Vec2],       // power-ups it's willing to go out of its way for
+}
+
+/// One AI car's driving state, borrowed from its components
+pub struct AiCar<'a> {
+    pub velocity: &'a mut Velocity,
+    pub orientation: &'a mut Orientation,
+    pub checkpoints: &'a mut ThetaCheckpointList,
+    pub reflexes: &'a mut AiReflexes,
+    pub recovery: &'a mut AiRecovery,
+    pub ai_traffic: &'a mut AiTraffic,
+    pub difficulty: CpuDifficulty,
+    pub behaviour: Behaviour, // what its behaviour tree last said to do
+    pub boosted: bool,
+}
+
+/// One frame of an AI car following the racing line (or its Theta* path). Steers,
+/// works the throttle and brakes the way its difficulty allows, reacts to the traffic
+/// the way its behaviour tree last said to, and returns where the car wants to move
+/// to, before clamping to the map and checking collisions (see `place_ai_car`). A car
+/// whose tree has it recovering backs out instead, or is put back on the track if that
+/// keeps failing. It goes out of its way a little for pickups, and faster while boosted.
+pub fn drive_ai_car(car: AiCar, pos: Vec2, track: &AiTrack, deltat: f32) -> Vec2 {
+    let AiCar {
+        velocity,
+        orientation,
+        checkpoints,
+        reflexes,
+        recovery,
+        ai_traffic,
+        difficulty,
+        behaviour,
+        boosted,
+    } = car;
+    let profile = difficulty.profile();
+
+    // Get the current tile, top speed on it also depends on the car's difficulty
+    let tile = track.game_map.get_tile(pos.x, pos.y, TILE_SIZE as f32);
+    let speed_mod = tile.speed_modifier * profile.speed_scale;
+
+    // Avoiding, defending and ramming cars aim somewhere off their path. Everyone but the
+    // rammers also keeps clear of the cars round it and gets past slower ones, and a car
+    // that's keeping to its line swings by any pickup barely out of its way.
+    if let Some(waypoint) = checkpoints.current_waypoint(track.theta_grid) {
+        let waypoint = Vec2::from(waypoint);
+        let mut offset =
+            behaviour.target_offset(pos, orientation.forward_vector(), waypoint, track.traffic);
+        if behaviour != Behaviour::Ram {
+            offset += ai_traffic.steer(
+                pos,
+                **velocity,
+                waypoint + offset,
+                track.traffic,
+                track.theta_grid,
+                deltat,
+            );
+        }
+        if offset == Vec2::ZERO {
+            offset = pickup_detour(pos, waypoint, track.pickups).unwrap_or_default();
+        }
+        checkpoints.target_offset = (offset.x, offset.y);
+    }
+
+    // Once its tree says so, back out of anywhere it's got stuck, and if that doesn't work
+    // put it back on the track
+    let target = checkpoints.current_waypoint(track.theta_grid).map(Vec2::from);
+    let recover = behaviour == Behaviour::Recover;
+    match recovery.update(pos, velocity.length(), target, reflexes.controls.steer, recover, deltat) {
+        Recovery::Reversing(controls) => {
+            let car = ControlledCar {
+                velocity,
+                orientation,
+                was_drifting: &mut reflexes.was_drifting,
+            };
+            apply_ai_controls(&controls, car, tile, &profile, PLAYER_SPEED, deltat);
+            return pos + **velocity * deltat;
+        }
+        Recovery::Replan => checkpoints.replan(),
+        Recovery::Respawn => {
+            if let Some((spot, angle)) =
+                respawn_point(checkpoints, track.theta_grid, track.racing_line)
+            {
+                checkpoints.replan();
+                **velocity = Vec2::ZERO;
+                orientation.angle = angle;
+                return spot;
+            }
+        }
+        Recovery::Driving => {}
+    }
+
+    // A boost lifts the top speed and so does another car's tow (rammers don't look for
+    // one), the corners still slow it down as much as ever
+    let mut top_speed = if boosted {
+        PLAYER_SPEED * BOOST_SPEED_SCALE
+    } else {
+        PLAYER_SPEED
+    };
+    if behaviour != Behaviour::Ram {
+        top_speed *= ai_traffic.top_speed_scale();
+    }
+
+    // Follow the track's racing line, or pathfind with Theta* if it doesn't have one.
+    // Slower drivers take longer to react either way.
+    let controls = reflexes.next_controls(&profile, deltat, |controller, since| {
+        let path = if track.racing_line.points.is_empty() {
+            theta_star_target((pos.x, pos.y), checkpoints, track.theta_grid, &profile)
+                .map(|aim| PathPoint {
+                    pos: Vec2::from(aim),
+                    max_speed: top_speed * speed_mod,
+                })
+                .into_iter()
+                .collect()
+        } else {
+            track.racing_line.path_ahead(
+                pos,
+                top_speed * profile.speed_scale,
+                checkpoints,
+                &profile,
+                track.theta_grid,
+            )
+        };
+        // nowhere to head for (just past a checkpoint), keep going straight
+        if path.is_empty() {
+            return AiControls {
+                throttle: 1.0,
+                ..default()
+            };
+        }
+        let car = CarMotion {
+            pos,
+            angle: orientation.angle,
+            velocity: **velocity,
+            top_speed: top_speed * speed_mod,
+            turn_rate: TURNING_RATE * tile.turn_modifier,
+        };
+        controller.drive(&car, &path, &profile, since)
+    });
+
+    let car = ControlledCar {
+        velocity,
+        orientation,
+        was_drifting: &mut reflexes.was_drifting,
+    };
+    apply_ai_controls(&controls, car, tile, &profile, top_speed, deltat);
+
+    pos + **velocity * deltat
+}
+
+/// Where an AI car at `pos` that wants to move to `target` ends up: kept on the map,
+/// and left where it is (bounced off) if it would hit a wall or one of `others`
+pub fn place_ai_car<I>(
+    pos: Vec2,
+    target: Vec2,
+    velocity: &mut Velocity,
+    recovery: &mut AiRecovery,
+    game_map: &GameMap,
+    others: I,
+) -> Vec2
+where
+    I: IntoIterator<Item = (Vec2, Vec2)>,
+{
+    let half_car = Vec2::splat(CAR_SIZE as f32 / 2.0);
+    let corner = Vec2::new(game_map.width, game_map.height) / 2.0 - half_car;
+    let new_position = target.clamp(-corner, corner);
+
+    let moved = handle_collision(
+        new_position.extend(0.0),
+        pos,
+        &mut velocity.velocity,
+        game_map,
+        others,
+    );
+    recovery.note_wall_contact(!moved);
+    if moved { new_position } else { pos }
+}
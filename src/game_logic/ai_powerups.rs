@@ -0,0 +1,366 @@
+// src/game_logic/ai_powerups.rs
+use crate::game_logic::{
+    Behaviour, CpuDifficulty, GameMap, RacingLine, TILE_SIZE, ThetaCheckpointList, ThetaGrid,
+    order_by_progress,
+};
+use crate::speed::SpeedBoost;
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+pub const POWERUP_TREE_PATH: &str = "assets/ai/powerup_tree.json";
+// Top speed while a CPU's boost is running, the same lift a player's boost gives
+pub const BOOST_SPEED_SCALE: f32 = 3.0;
+/// How long a boost lasts once it's fired
+pub const BOOST_SECS: f32 = 5.0;
+/// How many pickups are kept on the track, and how close a car has to get to one to grab it
+pub const PICKUP_COUNT: usize = 10;
+pub const PICKUP_DISTANCE: f32 = 64.0;
+// How often a CPU holding a power-up asks its tree whether to fire it
+const DECISION_SECS: f32 = 0.5;
+// Pickups further ahead than this aren't worth thinking about yet
+const DETOUR_RANGE: f32 = 400.0;
+// Furthest a CPU will move off its line for a pickup, about a car's width either side
+const MAX_DETOUR: f32 = 96.0;
+// The track counts as straight if the line this far ahead stays within STRAIGHT_ANGLE of the nose
+const STRAIGHT_DISTANCE: f32 = 600.0;
+const STRAIGHT_ANGLE: f32 = 0.15;
+// A car inside this cone and range is one a boost would help get past
+const IN_FRONT_DISTANCE: f32 = 400.0;
+const IN_FRONT_ANGLE: f32 = 0.35;
+
+/// What a CPU's power-up decision tree can ask about
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerupCondition {
+    StraightAhead,
+    CarInFront,
+    LastPlace,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerupDecision {
+    Fire,
+    Hold,
+}
+
+/// A node of the tree: either a question with a branch for each answer, or what to do
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum PowerupNode {
+    Branch {
+        #[serde(rename = "if")]
+        condition: PowerupCondition,
+        then: Box<PowerupNode>,
+        #[serde(rename = "else")]
+        otherwise: Box<PowerupNode>,
+    },
+    Leaf(PowerupDecision),
+}
+
+/// Everything the tree gets to know about where a CPU is
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PowerupSituation {
+    pub straight_ahead: bool,
+    pub car_in_front: bool,
+    pub last_place: bool,
+}
+
+impl PowerupSituation {
+    fn check(&self, condition: PowerupCondition) -> bool {
+        match condition {
+            PowerupCondition::StraightAhead => self.straight_ahead,
+            PowerupCondition::CarInFront => self.car_in_front,
+            PowerupCondition::LastPlace => self.last_place,
+        }
+    }
+}
+
+/// When CPUs fire the power-ups they're holding. Read from `assets/ai/powerup_tree.json`
+/// so it can be tuned without a rebuild.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct PowerupTree {
+    pub root: PowerupNode,
+}
+
+impl Default for PowerupTree {
+    // fire on a straight to get past someone, or to catch up from the back
+    fn default() -> Self {
+        use PowerupCondition::*;
+        use PowerupDecision::*;
+        let branch = |condition, then, otherwise| PowerupNode::Branch {
+            condition,
+            then: Box::new(then),
+            otherwise: Box::new(otherwise),
+        };
+        Self {
+            root: branch(
+                StraightAhead,
+                branch(
+                    CarInFront,
+                    PowerupNode::Leaf(Fire),
+                    branch(LastPlace, PowerupNode::Leaf(Fire), PowerupNode::Leaf(Hold)),
+                ),
+                PowerupNode::Leaf(Hold),
+            ),
+        }
+    }
+}
+
+impl PowerupTree {
+    /// Read the tree from its file, falling back to the built-in one if it's missing or broken
+    pub fn load() -> Self {
+        let Ok(text) = fs::read_to_string(POWERUP_TREE_PATH) else {
+            println!("No power-up tree at {}, using the default", POWERUP_TREE_PATH);
+            return Self::default();
+        };
+        match serde_json::from_str(&text) {
+            Ok(root) => Self { root },
+            Err(e) => {
+                println!("Failed to read power-up tree from {}: {}", POWERUP_TREE_PATH, e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Whether CPU `id` fires the power-up it's holding. Drivers that don't know any better,
+    /// or whose behaviour tree says to, fire the moment they're asked. Every decision is
+    /// logged along with what the tree was told, for tuning the tree.
+    pub fn decide_for(
+        &self,
+        id: u32,
+        difficulty: CpuDifficulty,
+        told_to: bool,
+        situation: &PowerupSituation,
+    ) -> PowerupDecision {
+        let decision = if told_to || !difficulty.profile().uses_boosts {
+            PowerupDecision::Fire
+        } else {
+            self.decide(situation)
+        };
+        info!(
+            "CPU {} powerup decision {:?} (straight ahead {}, car in front {}, last place {})",
+            id, decision, situation.straight_ahead, situation.car_in_front, situation.last_place
+        );
+        decision
+    }
+
+    pub fn decide(&self, situation: &PowerupSituation) -> PowerupDecision {
+        let mut node = &self.root;
+        loop {
+            match node {
+                PowerupNode::Branch {
+                    condition,
+                    then,
+                    otherwise,
+                } => {
+                    node = if situation.check(*condition) {
+                        then
+                    } else {
+                        otherwise
+                    };
+                }
+                PowerupNode::Leaf(decision) => return *decision,
+            }
+        }
+    }
+}
+
+/// The power-up a CPU car is holding, if any, and when it next thinks about firing it
+#[derive(Component, Default)]
+pub struct AiPowerups {
+    pub held: bool,
+    decide_in: f32,
+}
+
+impl AiPowerups {
+    /// Whether it's time to ask the tree again. Always true the frame after a pickup.
+    pub fn time_to_decide(&mut self, delta_secs: f32) -> bool {
+        if !self.held {
+            return false;
+        }
+        self.decide_in -= delta_secs;
+        if self.decide_in > 0.0 {
+            return false;
+        }
+        self.decide_in = DECISION_SECS;
+        true
+    }
+
+    pub fn pick_up(&mut self) {
+        self.held = true;
+        self.decide_in = 0.0;
+    }
+}
+
+/// A CPU's power-ups as one frame of `use_ai_powerup` needs them
+pub struct PowerupHolder<'a> {
+    pub id: u32,
+    pub difficulty: CpuDifficulty,
+    pub behaviour: Behaviour, // what its behaviour tree last said to do
+    pub boosted: bool,
+    pub powerups: &'a mut AiPowerups,
+}
+
+/// One frame of a CPU holding a power-up: every so often it asks its power-up tree whether
+/// to fire, and gets a boost if so. `situation` is only worked out when the tree is asked.
+/// Shared by offline CPUs and the server's.
+pub fn use_ai_powerup(
+    commands: &mut Commands,
+    entity: Entity,
+    holder: PowerupHolder,
+    tree: &PowerupTree,
+    delta_secs: f32,
+    situation: impl FnOnce() -> PowerupSituation,
+) {
+    let PowerupHolder {
+        id,
+        difficulty,
+        behaviour,
+        boosted,
+        powerups,
+    } = holder;
+    // one boost at a time, and no waiting when its behaviour tree says to use it
+    if boosted {
+        return;
+    }
+    let told_to = powerups.held && behaviour == Behaviour::UseItem;
+    if !told_to && !powerups.time_to_decide(delta_secs) {
+        return;
+    }
+    if tree.decide_for(id, difficulty, told_to, &situation()) == PowerupDecision::Fire {
+        powerups.held = false;
+        commands.entity(entity).insert(SpeedBoost {
+            timer: Timer::from_seconds(BOOST_SECS, TimerMode::Once),
+        });
+    }
+}
+
+/// Who's running last of the cars still on track, each (id, progress, distance to next
+/// gate) and ordered the same way as the standings. Nobody if it's a race of one.
+pub fn last_place(mut running: Vec<(u32, (u8, usize), f32)>) -> Option<u32> {
+    order_by_progress(&mut running);
+    if running.len() > 1 {
+        running.last().map(|(id, ..)| *id)
+    } else {
+        None
+    }
+}
+
+/// A pickup on the track online. The server keeps every lobby's and tells the players
+/// whenever one is put down or collected, so everyone races for the same ones.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Pickup {
+    pub id: u32,
+    pub pos: (f32, f32),
+}
+
+/// `count` random spots on the road to put pickups, in world coordinates
+pub fn pickup_spots(game_map: &GameMap, count: usize) -> Vec<Vec2> {
+    let mut rng = rand::rng();
+    let road_tiles: Vec<(f32, f32)> = game_map
+        .terrain_layer
+        .iter()
+        .flatten()
+        .filter(|tile| tile.tile_id <= 15)
+        .map(|tile| (tile.x_coordinate, tile.y_coordinate))
+        .collect();
+    if road_tiles.is_empty() {
+        return Vec::new();
+    }
+    (0..count)
+        .map(|_| {
+            let (x, y) = road_tiles[rng.random_range(0..road_tiles.len())];
+            game_map.tile_to_world(x, y, TILE_SIZE as f32)
+        })
+        .collect()
+}
+
+/// How far to move the point a CPU steers at so it drives over a pickup on the way to
+/// `waypoint`, if there's one close enough to its line to be worth it
+pub fn pickup_detour(pos: Vec2, waypoint: Vec2, pickups: &[Vec2]) -> Option<Vec2> {
+    let direction = (waypoint - pos).normalize_or_zero();
+    let side = Vec2::new(-direction.y, direction.x);
+    pickups
+        .iter()
+        .map(|&pickup| {
+            let to_pickup = pickup - pos;
+            (to_pickup.dot(direction), to_pickup.dot(side))
+        })
+        .filter(|&(along, across)| along > 0.0 && along < DETOUR_RANGE && across.abs() < MAX_DETOUR)
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, across)| side * across)
+}
+
+/// Whether the road in front of the car runs straight on from where it's pointing: the
+/// next stretch of racing line if the track has one, otherwise the way to its next waypoint
+pub fn straight_ahead(
+    pos: Vec2,
+    forward: Vec2,
+    checkpoints: &ThetaCheckpointList,
+    grid: &ThetaGrid,
+    racing_line: &RacingLine,
+) -> bool {
+    let along_nose = |from: Vec2, to: Vec2| forward.angle_to(to - from).abs() < STRAIGHT_ANGLE;
+    if racing_line.points.is_empty() {
+        return checkpoints
+            .current_waypoint(grid)
+            .is_some_and(|waypoint| along_nose(pos, Vec2::from(waypoint)));
+    }
+    // measured from the line rather than the car, so wandering off it to one side doesn't count
+    let ahead = racing_line.points_ahead(checkpoints.line_index.unwrap_or(0), STRAIGHT_DISTANCE);
+    ahead.len() > 1 && ahead[1..].iter().all(|&point| along_nose(ahead[0], point))
+}
+
+/// Whether any of the other cars is just ahead of this one, close to where it's pointing
+pub fn car_in_front(pos: Vec2, forward: Vec2, traffic: &[Vec2]) -> bool {
+    traffic.iter().any(|&other| {
+        other.distance(pos) < IN_FRONT_DISTANCE && forward.angle_to(other - pos).abs() < IN_FRONT_ANGLE
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_file_matches_default_and_decides() {
+        let tree = PowerupTree::load();
+        assert_eq!(tree, PowerupTree::default());
+
+        let fire_when = |straight_ahead, car_in_front, last_place| {
+            tree.decide(&PowerupSituation {
+                straight_ahead,
+                car_in_front,
+                last_place,
+            }) == PowerupDecision::Fire
+        };
+        assert!(fire_when(true, true, false));
+        assert!(fire_when(true, false, true));
+        assert!(!fire_when(true, false, false));
+        assert!(!fire_when(false, true, true));
+    }
+
+    #[test]
+    fn last_place_is_furthest_behind_and_nobody_racing_alone() {
+        let running = vec![(1, (1, 3), 50.0), (2, (2, 0), 900.0), (3, (1, 3), 20.0)];
+        assert_eq!(last_place(running), Some(1));
+        assert_eq!(last_place(vec![(4, (0, 0), 0.0)]), None);
+    }
+
+    #[test]
+    fn detours_only_for_pickups_close_to_the_line() {
+        let (pos, waypoint) = (Vec2::ZERO, Vec2::new(1000.0, 0.0));
+        let near = Vec2::new(200.0, 60.0);
+        let far_off_line = Vec2::new(100.0, 300.0);
+        let behind = Vec2::new(-100.0, 0.0);
+
+        assert_eq!(
+            pickup_detour(pos, waypoint, &[far_off_line, near, behind]),
+            Some(Vec2::new(0.0, 60.0))
+        );
+        assert_eq!(pickup_detour(pos, waypoint, &[far_off_line, behind]), None);
+    }
+}
@@ -0,0 +1,355 @@
+// src/game_logic/behaviour_tree.rs
+use crate::game_logic::{Personality, TrafficCar};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+
+pub const BEHAVIOUR_TREES_PATH: &str = "assets/ai/behaviour_trees.json";
+// Another car closer than this (in game units) counts as nearby
+pub const PROXIMITY_THRESHOLD: f32 = 300.0;
+
+/// What a CPU's behaviour tree can ask about its surroundings
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BehaviourCondition {
+    CarNearby,
+    CarAhead,
+    CarBehind,
+    Stuck,
+    HoldingItem,
+    Boosted,
+}
+
+/// What a CPU can be doing, picked by its behaviour tree
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Behaviour {
+    // drives its own line and gets past anyone slower
+    #[default]
+    Race,
+    // goes after the closest car ahead and rams it
+    Ram,
+    // keeps its distance from the cars round it
+    Avoid,
+    // sits in front of whoever is behind
+    Defend,
+    // leaves it to the recovery to back it out of wherever it's stuck
+    Recover,
+    // fires the power-up it's holding
+    UseItem,
+}
+
+/// A node of a behaviour tree. Conditions and actions are the leaves, everything
+/// else decides which of them get looked at.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum BehaviourNode {
+    Selector(Vec<BehaviourNode>), // tries each child in turn until one succeeds
+    Sequence(Vec<BehaviourNode>), // runs each child in turn until one fails
+    Condition(BehaviourCondition),
+    Action(Behaviour),
+    Invert(Box<BehaviourNode>), // succeeds when its child fails, an action under it is never picked
+    Cooldown {
+        // once its child succeeds it fails for this long without asking the child
+        secs: f32,
+        child: Box<BehaviourNode>,
+    },
+}
+
+// What a node comes back with: failed, or succeeded having picked an action (or not yet)
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Status {
+    Failure,
+    Success(Option<Behaviour>),
+}
+
+impl BehaviourNode {
+    // How many nodes are in this one's subtree, itself included
+    fn size(&self) -> usize {
+        1 + match self {
+            BehaviourNode::Selector(children) | BehaviourNode::Sequence(children) => {
+                children.iter().map(BehaviourNode::size).sum()
+            }
+            BehaviourNode::Invert(child) | BehaviourNode::Cooldown { child, .. } => child.size(),
+            BehaviourNode::Condition(_) | BehaviourNode::Action(_) => 0,
+        }
+    }
+
+    // `id` numbers the nodes depth first, so each decorator keeps its own memory between ticks
+    fn tick(&self, id: usize, blackboard: &Blackboard, memory: &mut BehaviourMemory) -> Status {
+        match self {
+            BehaviourNode::Selector(children) => {
+                let mut child_id = id + 1;
+                for child in children {
+                    if let Status::Success(picked) = child.tick(child_id, blackboard, memory) {
+                        return Status::Success(picked);
+                    }
+                    child_id += child.size();
+                }
+                Status::Failure
+            }
+            BehaviourNode::Sequence(children) => {
+                let mut child_id = id + 1;
+                for child in children {
+                    match child.tick(child_id, blackboard, memory) {
+                        Status::Failure => return Status::Failure,
+                        Status::Success(Some(picked)) => return Status::Success(Some(picked)),
+                        Status::Success(None) => child_id += child.size(),
+                    }
+                }
+                Status::Success(None)
+            }
+            BehaviourNode::Condition(condition) => {
+                if blackboard.check(*condition) {
+                    Status::Success(None)
+                } else {
+                    Status::Failure
+                }
+            }
+            BehaviourNode::Action(behaviour) => Status::Success(Some(*behaviour)),
+            BehaviourNode::Invert(child) => match child.tick(id + 1, blackboard, memory) {
+                Status::Failure => Status::Success(None),
+                Status::Success(_) => Status::Failure,
+            },
+            BehaviourNode::Cooldown { secs, child } => {
+                if memory
+                    .ready_at
+                    .get(&id)
+                    .is_some_and(|&at| memory.clock < at)
+                {
+                    return Status::Failure;
+                }
+                let status = child.tick(id + 1, blackboard, memory);
+                if status != Status::Failure {
+                    memory.ready_at.insert(id, memory.clock + secs);
+                }
+                status
+            }
+        }
+    }
+
+    /// What the tree says to do right now, racing if it doesn't pick anything
+    pub fn decide(&self, blackboard: &Blackboard, memory: &mut BehaviourMemory) -> Behaviour {
+        match self.tick(0, blackboard, memory) {
+            Status::Success(Some(behaviour)) => behaviour,
+            _ => Behaviour::Race,
+        }
+    }
+}
+
+/// Everything a behaviour tree gets to know about where a CPU is
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Blackboard {
+    pub closest_car_distance: f32,
+    pub car_nearby: bool,
+    pub car_ahead: bool,  // a nearby car in front of the nose
+    pub car_behind: bool, // a nearby car behind it
+    pub stuck: bool,
+    pub holding_item: bool,
+    pub boosted: bool,
+}
+
+impl Blackboard {
+    /// What a car at `pos`, pointing along `forward`, can see of its rivals in `traffic`,
+    /// teammates are no threat and no target. The rest is filled in by whoever knows about it.
+    pub fn sense(pos: Vec2, forward: Vec2, traffic: &[TrafficCar]) -> Self {
+        let nearby: Vec<Vec2> = traffic
+            .iter()
+            .filter(|car| car.rival)
+            .map(|car| car.pos)
+            .filter(|other| other.distance(pos) < PROXIMITY_THRESHOLD)
+            .collect();
+        let closest_car = nearby
+            .iter()
+            .copied()
+            .min_by(|a, b| a.distance(pos).total_cmp(&b.distance(pos)));
+        Self {
+            closest_car_distance: closest_car.map_or(f32::MAX, |other| other.distance(pos)),
+            car_nearby: closest_car.is_some(),
+            car_ahead: nearby.iter().any(|other| (*other - pos).dot(forward) > 0.0),
+            car_behind: nearby.iter().any(|other| (*other - pos).dot(forward) < 0.0),
+            ..default()
+        }
+    }
+
+    fn check(&self, condition: BehaviourCondition) -> bool {
+        match condition {
+            BehaviourCondition::CarNearby => self.car_nearby,
+            BehaviourCondition::CarAhead => self.car_ahead,
+            BehaviourCondition::CarBehind => self.car_behind,
+            BehaviourCondition::Stuck => self.stuck,
+            BehaviourCondition::HoldingItem => self.holding_item,
+            BehaviourCondition::Boosted => self.boosted,
+        }
+    }
+}
+
+/// What one car's tree remembers between ticks, the cooldowns running on its decorators
+#[derive(Clone, Debug, Default)]
+pub struct BehaviourMemory {
+    clock: f32,
+    ready_at: HashMap<usize, f32>,
+}
+
+impl BehaviourMemory {
+    pub fn advance(&mut self, delta_secs: f32) {
+        self.clock += delta_secs;
+    }
+}
+
+/// The behaviour tree each personality drives by. Read from `assets/ai/behaviour_trees.json`
+/// so personalities can be tuned, or new behaviours strung together, without a rebuild.
+#[derive(Resource, Clone, Debug, PartialEq)]
+pub struct BehaviourTrees {
+    pub trees: HashMap<Personality, BehaviourNode>,
+}
+
+impl Default for BehaviourTrees {
+    fn default() -> Self {
+        use BehaviourCondition::*;
+        use BehaviourNode::*;
+        // everyone backs out of trouble first and races when there's nothing else to do
+        let personality = |branches: Vec<BehaviourNode>| {
+            let mut children = vec![Sequence(vec![Condition(Stuck), Action(Behaviour::Recover)])];
+            children.extend(branches);
+            children.push(Action(Behaviour::Race));
+            Selector(children)
+        };
+        let trees = HashMap::from([
+            (Personality::Racer, personality(vec![])),
+            (
+                Personality::Shy,
+                personality(vec![Sequence(vec![
+                    Condition(CarNearby),
+                    Action(Behaviour::Avoid),
+                ])]),
+            ),
+            (
+                Personality::Aggressive,
+                personality(vec![
+                    // fire whatever it's holding to hit harder
+                    Sequence(vec![
+                        Condition(CarAhead),
+                        Condition(HoldingItem),
+                        Action(Behaviour::UseItem),
+                    ]),
+                    Sequence(vec![Condition(CarNearby), Action(Behaviour::Ram)]),
+                ]),
+            ),
+            (
+                Personality::Blocker,
+                // a block at a time, with a breather in between so it's not impossible to pass
+                personality(vec![Cooldown {
+                    secs: 1.0,
+                    child: Box::new(Sequence(vec![
+                        Condition(CarBehind),
+                        Action(Behaviour::Defend),
+                    ])),
+                }]),
+            ),
+        ]);
+        Self { trees }
+    }
+}
+
+impl BehaviourTrees {
+    /// Read the trees from their file, falling back to the built-in ones for anything
+    /// missing, or for all of them if the file is missing or broken
+    pub fn load() -> Self {
+        let mut loaded = Self::default();
+        let Ok(text) = fs::read_to_string(BEHAVIOUR_TREES_PATH) else {
+            println!(
+                "No behaviour trees at {}, using the defaults",
+                BEHAVIOUR_TREES_PATH
+            );
+            return loaded;
+        };
+        match serde_json::from_str::<HashMap<Personality, BehaviourNode>>(&text) {
+            Ok(trees) => loaded.trees.extend(trees),
+            Err(e) => println!(
+                "Failed to read behaviour trees from {}: {}",
+                BEHAVIOUR_TREES_PATH, e
+            ),
+        }
+        loaded
+    }
+
+    pub fn for_personality(&self, personality: Personality) -> &BehaviourNode {
+        &self.trees[&personality]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_file_matches_defaults_and_picks_behaviours() {
+        let trees = BehaviourTrees::load();
+        assert_eq!(trees, BehaviourTrees::default());
+
+        let decide = |personality, blackboard: &Blackboard| {
+            trees
+                .for_personality(personality)
+                .decide(blackboard, &mut BehaviourMemory::default())
+        };
+        let car_at = |x, rival| TrafficCar {
+            pos: Vec2::new(x, 0.0),
+            velocity: Vec2::ZERO,
+            rival,
+        };
+        let chased = Blackboard::sense(Vec2::ZERO, Vec2::X, &[car_at(-100.0, true)]);
+        let chasing = Blackboard {
+            holding_item: true,
+            ..Blackboard::sense(Vec2::ZERO, Vec2::X, &[car_at(100.0, true)])
+        };
+        let teammate_behind = Blackboard::sense(Vec2::ZERO, Vec2::X, &[car_at(-100.0, false)]);
+        let stuck = Blackboard {
+            stuck: true,
+            ..chased
+        };
+
+        assert_eq!(decide(Personality::Racer, &chased), Behaviour::Race);
+        assert_eq!(decide(Personality::Shy, &chased), Behaviour::Avoid);
+        assert_eq!(decide(Personality::Blocker, &chased), Behaviour::Defend);
+        assert_eq!(decide(Personality::Blocker, &chasing), Behaviour::Race);
+        assert_eq!(decide(Personality::Aggressive, &chased), Behaviour::Ram);
+        assert_eq!(
+            decide(Personality::Aggressive, &chasing),
+            Behaviour::UseItem
+        );
+        assert_eq!(decide(Personality::Aggressive, &stuck), Behaviour::Recover);
+        assert_eq!(decide(Personality::Blocker, &teammate_behind), Behaviour::Race);
+        assert_eq!(
+            decide(Personality::Shy, &Blackboard::default()),
+            Behaviour::Race
+        );
+    }
+
+    #[test]
+    fn cooldown_holds_its_child_off_then_lets_it_go_again() {
+        let tree: BehaviourNode = serde_json::from_str(
+            r#"{"selector": [
+                {"cooldown": {"secs": 1.0, "child": {"action": "ram"}}},
+                {"invert": {"condition": "boosted"}},
+                {"action": "avoid"}
+            ]}"#,
+        )
+        .unwrap();
+        let blackboard = Blackboard::default();
+        let mut memory = BehaviourMemory::default();
+
+        assert_eq!(tree.decide(&blackboard, &mut memory), Behaviour::Ram);
+        // the inverted condition succeeds without picking anything, so nothing's picked at all
+        memory.advance(0.5);
+        assert_eq!(tree.decide(&blackboard, &mut memory), Behaviour::Race);
+        let boosted = Blackboard {
+            boosted: true,
+            ..blackboard
+        };
+        assert_eq!(tree.decide(&boosted, &mut memory), Behaviour::Avoid);
+        memory.advance(0.5);
+        assert_eq!(tree.decide(&blackboard, &mut memory), Behaviour::Ram);
+    }
+}
@@ -9,6 +9,12 @@ pub struct PlayerControlled;
 #[derive(Component)]
 pub struct AIControlled;
 
+// Something left on the track that the AI plans its paths around
+#[derive(Component)]
+pub struct Hazard {
+    pub radius: f32, // pixels either side of its centre
+}
+
 #[derive(Component, Clone)]
 pub struct Orientation {
     pub angle: f32,
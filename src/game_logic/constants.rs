@@ -17,20 +17,29 @@ pub const DRIFT_RELEASE_BOOST: f32 = 1.5;
 pub const CAR_SIZE: u32 = 64;
 pub const TILE_SIZE: u32 = 64;
 
-// Fixed grid of starting positions (world coordinates) for up to 4 racers
-pub const START_POSITIONS: [(f32, f32); 4] = [
+// Fixed grid of starting positions (world coordinates) for up to 8 racers, pole first
+pub const START_POSITIONS: [(f32, f32); 8] = [
     (2752.0, 960.0),
     (2852.0, 960.0),
     (2752.0, 860.0),
     (2852.0, 860.0),
+    (2752.0, 760.0),
+    (2852.0, 760.0),
+    (2752.0, 660.0),
+    (2852.0, 660.0),
 ];
 
-// Fixed grid for map2 starting line (centered at 1300, -1131)
-pub const START_POSITIONS_MAP2: [(f32, f32); 4] = [
+// Fixed grid for map2 starting line (centered at 1300, -1131). The straight ends right
+// behind the line, so the back two rows line up on the exit of the last corner.
+pub const START_POSITIONS_MAP2: [(f32, f32); 8] = [
     (1300.0, -1131.0),
     (1400.0, -1131.0),
     (1300.0, -1231.0),
     (1400.0, -1231.0),
+    (1500.0, -1231.0),
+    (1600.0, -1231.0),
+    (1500.0, -1330.0),
+    (1600.0, -1330.0),
 ];
 
 // Orientation (radians) for spawned cars so they face the track direction
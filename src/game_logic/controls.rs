@@ -0,0 +1,107 @@
+// src/game_logic/controls.rs
+use bevy::prelude::*;
+
+// Stick has to be pushed this far before it counts as steering
+const STICK_DEADZONE: f32 = 0.3;
+
+/// Which person on this machine drives the car: 0 is the main player,
+/// 1 is the second player in split-screen.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalPlayer(pub u8);
+
+// How many people are playing on this machine, 2 splits the screen
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct LocalPlayers {
+    pub count: u8,
+}
+
+impl Default for LocalPlayers {
+    fn default() -> Self {
+        Self { count: 1 }
+    }
+}
+
+impl LocalPlayers {
+    pub fn split_screen(&self) -> bool {
+        self.count > 1
+    }
+}
+
+// Keyboard layout for one local player
+pub struct ControlScheme {
+    pub forward: KeyCode,
+    pub backward: KeyCode,
+    pub left: KeyCode,
+    pub right: KeyCode,
+    pub drift: KeyCode,
+}
+
+pub const PLAYER_ONE_KEYS: ControlScheme = ControlScheme {
+    forward: KeyCode::KeyW,
+    backward: KeyCode::KeyS,
+    left: KeyCode::KeyA,
+    right: KeyCode::KeyD,
+    drift: KeyCode::Space,
+};
+
+pub const PLAYER_TWO_KEYS: ControlScheme = ControlScheme {
+    forward: KeyCode::ArrowUp,
+    backward: KeyCode::ArrowDown,
+    left: KeyCode::ArrowLeft,
+    right: KeyCode::ArrowRight,
+    drift: KeyCode::ShiftRight,
+};
+
+impl LocalPlayer {
+    pub fn keys(&self) -> &'static ControlScheme {
+        match self.0 {
+            0 => &PLAYER_ONE_KEYS,
+            _ => &PLAYER_TWO_KEYS,
+        }
+    }
+}
+
+// What one local player is pressing this frame
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DriveInput {
+    pub forward: bool,
+    pub backward: bool,
+    pub left: bool,
+    pub right: bool,
+    pub drift: bool,
+}
+
+impl DriveInput {
+    /// Read a local player's keys. The second player can also drive with the first gamepad.
+    pub fn read(
+        player: LocalPlayer,
+        keyboard: &ButtonInput<KeyCode>,
+        gamepads: &Query<&Gamepad>,
+    ) -> Self {
+        let keys = player.keys();
+        let mut input = Self {
+            forward: keyboard.pressed(keys.forward),
+            backward: keyboard.pressed(keys.backward),
+            left: keyboard.pressed(keys.left),
+            right: keyboard.pressed(keys.right),
+            drift: keyboard.pressed(keys.drift),
+        };
+
+        // the first gamepad belongs to the second player
+        if let Some(gamepad) = gamepads.iter().next().filter(|_| player.0 == 1) {
+            let stick = gamepad.left_stick();
+            input.forward |= gamepad.pressed(GamepadButton::RightTrigger2)
+                || gamepad.pressed(GamepadButton::South);
+            input.backward |= gamepad.pressed(GamepadButton::LeftTrigger2)
+                || gamepad.pressed(GamepadButton::West);
+            input.left |= stick.x < -STICK_DEADZONE || gamepad.pressed(GamepadButton::DPadLeft);
+            input.right |= stick.x > STICK_DEADZONE || gamepad.pressed(GamepadButton::DPadRight);
+            input.drift |= gamepad.pressed(GamepadButton::RightTrigger);
+        }
+        input
+    }
+
+    pub fn accelerating(&self) -> bool {
+        self.forward || self.backward
+    }
+}
@@ -0,0 +1,131 @@
+// src/game_logic/cup.rs
+use crate::game_logic::{RaceResultEntry, RaceResults};
+use crate::networking::MapChoice;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Cup points for P1-P4, anything further back (or a DNF) scores nothing
+pub const CUP_POINTS: [u32; 4] = [10, 6, 4, 2];
+// Races in a cup, alternating between the tracks starting from the chosen one
+pub const CUP_RACES: usize = 4;
+// Online, how long the standings stay up before the server starts the next race
+pub const CUP_INTERMISSION_SECS: f32 = 10.0;
+
+/// A cup in progress: the playlist, which race we're on and everyone's points so far.
+/// Online the server keeps the real one and sends a copy between races.
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CupStandings {
+    pub tracks: Vec<MapChoice>,
+    pub race: usize, // index into tracks of the race being run, or just finished
+    pub points: HashMap<u32, u32>,
+}
+
+impl CupStandings {
+    pub fn new(first: MapChoice) -> Self {
+        let other = match first {
+            MapChoice::Small => MapChoice::Big,
+            MapChoice::Big => MapChoice::Small,
+        };
+        let tracks = (0..CUP_RACES)
+            .map(|i| if i % 2 == 0 { first } else { other })
+            .collect();
+        Self {
+            tracks,
+            race: 0,
+            points: HashMap::new(),
+        }
+    }
+
+    pub fn is_last_race(&self) -> bool {
+        self.race + 1 >= self.tracks.len()
+    }
+
+    /// Track of the race after this one, if there is one
+    pub fn next_track(&self) -> Option<MapChoice> {
+        self.tracks.get(self.race + 1).copied()
+    }
+
+    /// Move on to the next race, returns its track
+    pub fn advance(&mut self) -> Option<MapChoice> {
+        let next = self.next_track()?;
+        self.race += 1;
+        Some(next)
+    }
+
+    /// Add the points from a finished race. Every car that took part gets a
+    /// row in the table, even if it hasn't scored yet.
+    pub fn award(&mut self, results: &[RaceResultEntry]) {
+        for entry in results {
+            let points = if entry.dnf {
+                0
+            } else {
+                CUP_POINTS
+                    .get((entry.position as usize).saturating_sub(1))
+                    .copied()
+                    .unwrap_or(0)
+            };
+            *self.points.entry(entry.id).or_default() += points;
+        }
+    }
+
+    /// (id, points) from the cup leader down, ties go to the lower id
+    pub fn table(&self) -> Vec<(u32, u32)> {
+        let mut table: Vec<(u32, u32)> = self.points.iter().map(|(id, pts)| (*id, *pts)).collect();
+        table.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        table
+    }
+}
+
+// Offline cups score each race as it ends, online the server does it and sends the table
+pub fn award_cup_points(results: Res<RaceResults>, cup: Option<ResMut<CupStandings>>) {
+    let Some(mut cup) = cup else {
+        return;
+    };
+    cup.award(&results.entries);
+    info!(
+        "Cup after race {} of {}: {:?}",
+        cup.race + 1,
+        cup.tracks.len(),
+        cup.table()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: u32, position: u8, dnf: bool) -> RaceResultEntry {
+        RaceResultEntry {
+            id,
+            position,
+            time: (!dnf).then_some(60.0 + position as f32),
+            dnf,
+            eliminated: false,
+        }
+    }
+
+    #[test]
+    fn points_go_10_6_4_2_and_ties_go_to_the_lower_id() {
+        let mut cup = CupStandings::new(MapChoice::Small);
+        cup.award(&[
+            entry(5, 1, false),
+            entry(1000, 2, false),
+            entry(2, 3, false),
+            entry(1001, 4, false),
+            entry(7, 5, false),
+            entry(3, 6, true),
+        ]);
+        assert_eq!(cup.table(), [(5, 10), (1000, 6), (2, 4), (1001, 2), (3, 0), (7, 0)]);
+
+        // 5 and 1000 end up level on 12 and 5 goes above, a DNF still scores nothing
+        cup.award(&[
+            entry(2, 1, false),
+            entry(1000, 2, false),
+            entry(3, 3, false),
+            entry(5, 4, false),
+            entry(1001, 5, true),
+        ]);
+        assert_eq!(cup.table(), [(2, 14), (5, 12), (1000, 12), (3, 4), (1001, 2), (7, 0)]);
+    }
+}
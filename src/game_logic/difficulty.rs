@@ -1,7 +1,11 @@
 // src/game_logic/difficulty.rs
+use crate::game_logic::{AiControls, AiController};
 use bevy::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
 
-#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Resource, Component, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum CpuDifficulty {
     Easy,
     Medium,
@@ -31,6 +35,42 @@ impl CpuDifficulty {
         }
     }
 
+    /// How an AI car of this difficulty drives
+    pub fn profile(&self) -> DifficultyProfile {
+        match self {
+            CpuDifficulty::Easy => DifficultyProfile {
+                speed_scale: 0.8,
+                reaction_secs: 0.08,
+                steer_tolerance: 0.15,
+                aim_spread: 1.0,
+                line_offset: 96.0,
+                mistake_chance: 0.05,
+                drift_skill: 0.0,
+                uses_boosts: false,
+            },
+            CpuDifficulty::Medium => DifficultyProfile {
+                speed_scale: 0.9,
+                reaction_secs: 0.05,
+                steer_tolerance: 0.12,
+                aim_spread: 0.6,
+                line_offset: 48.0,
+                mistake_chance: 0.02,
+                drift_skill: 0.5,
+                uses_boosts: true,
+            },
+            CpuDifficulty::Hard => DifficultyProfile {
+                speed_scale: 1.0,
+                reaction_secs: 0.02,
+                steer_tolerance: 0.1,
+                aim_spread: 0.25,
+                line_offset: 12.0,
+                mistake_chance: 0.0,
+                drift_skill: 1.0,
+                uses_boosts: true,
+            },
+        }
+    }
+
     pub fn prev(self) -> Self {
         match self {
             CpuDifficulty::Easy => CpuDifficulty::Hard,
@@ -39,3 +79,67 @@ impl CpuDifficulty {
         }
     }
 }
+
+// Everything about the AI that depends on difficulty
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DifficultyProfile {
+    pub speed_scale: f32,     // fraction of the normal top speed
+    pub reaction_secs: f32,   // how long a steering decision is held before the next one
+    pub steer_tolerance: f32, // radians off the target heading before the car corrects for it
+    pub aim_spread: f32,      // how much of a checkpoint's width the target point may wander over, 0-1
+    pub line_offset: f32,     // furthest it wanders off the racing line, in pixels
+    pub mistake_chance: f32,  // chance that a decision is the wrong one
+    pub drift_skill: f32,     // 0 never drifts, up to 1 for perfect entries, releases and boosts
+    pub uses_boosts: bool,
+}
+
+// What an AI car last decided to do, it sticks to it until its reaction time is up
+#[derive(Component)]
+pub struct AiReflexes {
+    pub controls: AiControls,
+    pub controller: AiController,
+    pub was_drifting: bool, // drifting last frame, so letting go now gives the boost
+    pub until_next_decision: f32,
+    pub since_last_decision: f32,
+    pub rng: StdRng, // when it makes its mistakes, seeded from the OS unless a test says otherwise
+}
+
+impl Default for AiReflexes {
+    fn default() -> Self {
+        Self {
+            controls: AiControls::default(),
+            controller: AiController::default(),
+            was_drifting: false,
+            until_next_decision: 0.0,
+            since_last_decision: 0.0,
+            rng: StdRng::from_os_rng(),
+        }
+    }
+}
+
+impl AiReflexes {
+    /// The controls to drive with this frame. `decide` is only asked once the
+    /// last decision has been held for the reaction time, and is given the
+    /// controller and how long it's been since it was last asked. Every so
+    /// often the driver gets it wrong.
+    pub fn next_controls(
+        &mut self,
+        profile: &DifficultyProfile,
+        delta_secs: f32,
+        decide: impl FnOnce(&mut AiController, f32) -> AiControls,
+    ) -> AiControls {
+        self.until_next_decision -= delta_secs;
+        self.since_last_decision += delta_secs;
+        if self.until_next_decision <= 0.0 {
+            self.until_next_decision += profile.reaction_secs;
+            let controls = decide(&mut self.controller, self.since_last_decision);
+            self.since_last_decision = 0.0;
+            self.controls = if self.rng.random::<f32>() < profile.mistake_chance {
+                controls.mistake()
+            } else {
+                controls
+            };
+        }
+        self.controls
+    }
+}
@@ -1,8 +1,13 @@
-use crate::GameState;
-use crate::game_logic::{AIControlled, Car, PlayerControlled};
-use crate::multiplayer::NetworkPlayer;
-use crate::networking_plugin::NetworkClient;
+use crate::game_logic::{
+    Car, LocalPlayer, START_POSITIONS, START_POSITIONS_MAP2, TeamAssignments,
+};
+use crate::networking::MapChoice;
+use crate::race_phase::RacePhase;
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Once the first car finishes, everyone else has this long before they're marked DNF
+pub const DNF_TIMEOUT_SECS: f32 = 30.0;
 
 #[derive(Component)]
 pub struct LapCounter {
@@ -10,6 +15,7 @@ pub struct LapCounter {
     pub total_laps: u8,
     pub has_finished: bool,
     pub next_checkpoint: usize, // index of the next checkpoint to hit
+    pub last_position: Option<Vec2>, // where the car was last frame, for gate crossing
 }
 
 impl Default for LapCounter {
@@ -19,8 +25,249 @@ impl Default for LapCounter {
             total_laps: 2, // two for now
             has_finished: false,
             next_checkpoint: 0,
+            last_position: None,
+        }
+    }
+}
+
+impl LapCounter {
+    pub fn new(total_laps: u8) -> Self {
+        Self {
+            total_laps,
+            ..default()
+        }
+    }
+
+    // how far around the race this car is, for ordering unfinished cars
+    pub fn progress(&self) -> (u8, usize) {
+        (self.current_lap, self.next_checkpoint)
+    }
+
+    // distance left to the next gate, breaks ties between cars with the same progress
+    pub fn distance_to_next(&self, car_pos: Vec2, checkpoints: &[Gate], finish: &Gate) -> f32 {
+        let gate = checkpoints.get(self.next_checkpoint).unwrap_or(finish);
+        car_pos.distance(gate.center)
+    }
+
+    /// Move the car to `car_pos` and check it against the next gate.
+    /// `checkpoints` must be sorted by checkpoint index.
+    pub fn advance(&mut self, car_pos: Vec2, checkpoints: &[Gate], finish: &Gate) -> LapEvent {
+        // first update for this car, nothing to cross yet
+        let Some(prev_pos) = self.last_position.replace(car_pos) else {
+            return LapEvent::None;
+        };
+        if self.has_finished {
+            return LapEvent::None;
+        }
+
+        // check next checkpoint
+        if let Some(gate) = checkpoints.get(self.next_checkpoint) {
+            if gate.crossed_by(prev_pos, car_pos) {
+                self.next_checkpoint += 1;
+                return LapEvent::Checkpoint(self.next_checkpoint - 1);
+            }
+            return LapEvent::None;
+        }
+
+        // all checkpoints done, check finish line
+        if finish.crossed_by(prev_pos, car_pos) {
+            self.current_lap += 1;
+            self.next_checkpoint = 0;
+
+            if self.current_lap >= self.total_laps {
+                self.has_finished = true;
+                return LapEvent::Finished;
+            }
+            return LapEvent::Lap(self.current_lap);
+        }
+
+        LapEvent::None
+    }
+}
+
+// What happened to a car on its latest lap counter update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LapEvent {
+    None,
+    Checkpoint(usize),
+    Lap(u8),
+    Finished,
+}
+
+// Id used for standings: the network player id online, 0 for the local player offline,
+// and 1000+ for AI cars (same as the server's AI ids)
+#[derive(Component, Clone, Copy, Debug)]
+pub struct RacerId(pub u32);
+
+pub const AI_ID_START: u32 = 1000;
+
+pub fn racer_name(id: u32, local_id: Option<u32>) -> String {
+    if Some(id) == local_id {
+        "You".to_string()
+    } else if id >= AI_ID_START {
+        format!("CPU {}", id - AI_ID_START + 1)
+    } else {
+        format!("Player {}", id)
+    }
+}
+
+// Sent by update_laps whenever a car crosses the finish line on a new lap
+#[derive(Event, Clone, Copy, Debug)]
+pub struct LapCompleted {
+    pub id: u32,
+    pub lap: u8,
+    pub race_time: f32, // seconds since GO
+}
+
+// Car has completed all its laps and is out of the race (spectating or auto-driving)
+#[derive(Component)]
+pub struct Finished;
+
+// Car was knocked out of an elimination race, it's held still as a ghost that nothing hits
+#[derive(Component)]
+pub struct Eliminated;
+
+/// Sort racers still on track from leader to last place.
+/// Each racer is (id, progress, distance to next gate).
+pub fn order_by_progress(racers: &mut [(u32, (u8, usize), f32)]) {
+    racers.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.total_cmp(&b.2)));
+}
+
+// One line of the final standings
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RaceResultEntry {
+    pub id: u32,
+    pub position: u8,
+    pub time: Option<f32>, // seconds, None if the car didn't finish
+    pub dnf: bool,
+    #[serde(default)]
+    pub eliminated: bool, // time is when it was knocked out
+}
+
+// Final standings shown on the results screen
+#[derive(Resource, Default, Clone)]
+pub struct RaceResults {
+    pub entries: Vec<RaceResultEntry>,
+}
+
+// Offline race bookkeeping: clock, finishing order and the DNF countdown
+#[derive(Resource, Default)]
+pub struct RaceStandings {
+    pub elapsed: f32,
+    pub finish_order: Vec<RaceResultEntry>,
+    pub dnf_timer: Option<Timer>,
+    pub player_lap_times: Vec<f32>,
+}
+
+impl RaceStandings {
+    // time of the player's lap that just ended
+    pub fn record_player_lap(&mut self) {
+        let lap_start: f32 = self.player_lap_times.iter().sum();
+        self.player_lap_times.push(self.elapsed - lap_start);
+    }
+
+    pub fn record_finish(&mut self, id: u32) -> &RaceResultEntry {
+        let entry = RaceResultEntry {
+            id,
+            position: self.finish_order.len() as u8 + 1,
+            time: Some(self.elapsed),
+            dnf: false,
+            eliminated: false,
+        };
+        self.finish_order.push(entry);
+
+        // first finisher starts the countdown for everyone else
+        if self.dnf_timer.is_none() {
+            self.dnf_timer = Some(Timer::from_seconds(DNF_TIMEOUT_SECS, TimerMode::Once));
+        }
+        self.finish_order.last().unwrap()
+    }
+}
+
+/// Standings once the race is over: finishers in order, then everyone else as DNF
+/// ordered by how far they got.
+pub fn build_race_results(
+    finish_order: &[RaceResultEntry],
+    mut unfinished: Vec<(u32, (u8, usize))>,
+) -> Vec<RaceResultEntry> {
+    let mut entries = finish_order.to_vec();
+    unfinished.sort_by(|a, b| b.1.cmp(&a.1));
+    for (id, _) in unfinished {
+        entries.push(RaceResultEntry {
+            id,
+            position: entries.len() as u8 + 1,
+            time: None,
+            dnf: true,
+            eliminated: false,
+        });
+    }
+    entries
+}
+
+pub fn format_race_time(seconds: f32) -> String {
+    let minutes = (seconds / 60.0).floor() as u32;
+    format!("{}:{:05.2}", minutes, seconds - minutes as f32 * 60.0)
+}
+
+// Width (world units) of checkpoint and finish gates, matches the barrel sprite
+pub const GATE_WIDTH: f32 = 512.0;
+
+/// A line segment the cars have to drive through.
+/// The segment runs along `rotation` and is crossed "forwards" when moving along
+/// its left-hand normal, so a gate with rotation 0 expects cars driving up (+y).
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Gate {
+    pub center: Vec2,
+    pub rotation: f32,
+    pub width: f32,
+}
+
+impl Gate {
+    pub fn new(center: Vec2, rotation: f32, width: f32) -> Self {
+        Self {
+            center,
+            rotation,
+            width,
         }
     }
+
+    pub fn endpoints(&self) -> (Vec2, Vec2) {
+        let half = Vec2::from_angle(self.rotation) * (self.width / 2.0);
+        (self.center - half, self.center + half)
+    }
+
+    // direction a car has to be travelling for the crossing to count
+    pub fn forward(&self) -> Vec2 {
+        Vec2::from_angle(self.rotation).perp()
+    }
+
+    /// True if moving from `from` to `to` crosses the gate in the forward direction
+    pub fn crossed_by(&self, from: Vec2, to: Vec2) -> bool {
+        let movement = to - from;
+        if movement.dot(self.forward()) <= 0.0 {
+            return false;
+        }
+
+        let (a, b) = self.endpoints();
+        segments_intersect(from, to, a, b)
+    }
+}
+
+// Segment p1->p2 against q1->q2. The start of the movement segment is excluded
+// so a car resting on a gate doesn't trigger it twice.
+fn segments_intersect(p1: Vec2, p2: Vec2, q1: Vec2, q2: Vec2) -> bool {
+    let r = p2 - p1;
+    let s = q2 - q1;
+    let denom = r.perp_dot(s);
+    if denom.abs() < f32::EPSILON {
+        return false; // parallel, can't cross
+    }
+
+    let qp = q1 - p1;
+    let t = qp.perp_dot(s) / denom; // along the movement
+    let u = qp.perp_dot(r) / denom; // along the gate
+
+    t > 0.0 && t <= 1.0 && (0.0..=1.0).contains(&u)
 }
 
 #[derive(Component)]
@@ -35,24 +282,97 @@ pub struct Checkpoint {
 #[derive(Resource, Clone, Default)]
 pub struct MapLevelData {
     pub start_position: Vec3, // Where the player spawns
+    pub start_grid: &'static [(f32, f32)], // every grid slot, pole first
     pub finish_line_pos: Vec3,
+    pub finish_line_rotation: f32, // gate rotation, forward is the gate's left-hand normal
     pub checkpoints: Vec<(Vec3, f32)>, // Position, Rotation (radians)
 }
 
+impl MapLevelData {
+    pub fn for_choice(choice: MapChoice) -> Self {
+        match choice {
+            MapChoice::Small => Self::for_map(1),
+            MapChoice::Big => Self::for_map(2),
+        }
+    }
+
+    pub fn for_map(map_num: u8) -> Self {
+        match map_num {
+            1 => MapLevelData {
+                start_position: Vec3::new(2752.0, 960.0, 5.0),
+                start_grid: &START_POSITIONS,
+                finish_line_pos: Vec3::new(2752., 960., 5.),
+                finish_line_rotation: 0.0,
+                // rotations point each gate's forward normal along the racing direction
+                checkpoints: vec![
+                    (Vec3::new(2752., 1500., 10.), 0.0),
+                    (Vec3::new(2700., 2700., 10.), std::f32::consts::PI / 4.0),
+                    (Vec3::new(425., 2725., 10.), std::f32::consts::PI / 2.0),
+                    (Vec3::new(-1600., 400., 10.), std::f32::consts::PI / 2.0),
+                    (Vec3::new(-2044., -1493., 10.), std::f32::consts::PI * 0.75),
+                    (Vec3::new(-1979., -2750., 10.), std::f32::consts::PI / -2.0),
+                    (Vec3::new(1515., -2750., 10.), std::f32::consts::PI / -2.0),
+                    (Vec3::new(2100., -150., 10.), 0.0),
+                ],
+            },
+            2 => MapLevelData {
+                start_position: Vec3::new(1300.0, -1131.0, 5.0),
+                start_grid: &START_POSITIONS_MAP2,
+                finish_line_pos: Vec3::new(1300.0, -1131.0, 5.0),
+                finish_line_rotation: 0.0,
+                checkpoints: vec![
+                    (Vec3::new(1386., 974., 10.), 0.0),
+                    (Vec3::new(3175., 1949., 10.), std::f32::consts::PI / -2.0),
+                    (Vec3::new(-1891., 2167., 10.), std::f32::consts::PI * -0.75),
+                    (Vec3::new(-471., 2146., 10.), std::f32::consts::PI / 2.0),
+                    (Vec3::new(862., 1907., 10.), std::f32::consts::PI),
+                    (Vec3::new(-1834., 30., 10.), std::f32::consts::PI / 2.0),
+                    (Vec3::new(-2841., 2059., 10.), 0.0),
+                    (Vec3::new(-3738., 1465., 10.), std::f32::consts::PI),
+                    (Vec3::new(-91., -2441., 10.), std::f32::consts::PI),
+                    (Vec3::new(3117., -2376., 10.), 0.0),
+                ],
+            },
+            _ => panic!("Invalid map num: {}", map_num),
+        }
+    }
+
+    /// Checkpoint gates in order, plus the finish gate
+    pub fn gates(&self) -> (Vec<Gate>, Gate) {
+        let checkpoints = self
+            .checkpoints
+            .iter()
+            .map(|(pos, rotation)| Gate::new(pos.truncate(), *rotation, GATE_WIDTH))
+            .collect();
+        let finish = Gate::new(
+            self.finish_line_pos.truncate(),
+            self.finish_line_rotation,
+            GATE_WIDTH,
+        );
+        (checkpoints, finish)
+    }
+}
+
 pub fn spawn_lap_triggers(
     mut commands: Commands, 
     asset_server: Res<AssetServer>,
     map_data: Res<MapLevelData>
 ) {
     let finish_line_handle = asset_server.load("finish_line.png");
-    
-    // spawn finish line from resource
+
+    // spawn finish line from resource, stretched so the sprite covers the whole gate
     commands.spawn((
         FinishLine,
+        Gate::new(
+            map_data.finish_line_pos.truncate(),
+            map_data.finish_line_rotation,
+            GATE_WIDTH,
+        ),
         Sprite::from_image(finish_line_handle),
         Transform {
             translation: map_data.finish_line_pos,
-            ..default()
+            rotation: Quat::from_rotation_z(map_data.finish_line_rotation),
+            scale: Vec3::new(GATE_WIDTH / 256.0, 1.0, 1.0), // finish_line.png is 256 wide
         },
     ));
 
@@ -62,6 +382,7 @@ pub fn spawn_lap_triggers(
     for (i, (pos, rotation)) in map_data.checkpoints.iter().enumerate() {
         commands.spawn((
             Checkpoint { index: i },
+            Gate::new(pos.truncate(), *rotation, GATE_WIDTH),
             Sprite::from_image(checkpoint_handle.clone()),
             Transform {
                 translation: *pos,
@@ -74,64 +395,157 @@ pub fn spawn_lap_triggers(
 
 
 pub fn update_laps(
-    mut query_cars: Query<(&Transform, &mut LapCounter, Option<&PlayerControlled>), With<Car>>,
-    query_finish: Query<&Transform, With<FinishLine>>,
-    query_checkpoints: Query<(&Transform, &Checkpoint)>,
-    mut next_state: ResMut<NextState<GameState>>,
+    mut commands: Commands,
+    mut query_cars: Query<
+        (Entity, &Transform, &mut LapCounter, &RacerId, Option<&LocalPlayer>),
+        With<Car>,
+    >,
+    query_finish: Query<&Gate, With<FinishLine>>,
+    query_checkpoints: Query<(&Gate, &Checkpoint)>,
+    mut standings: ResMut<RaceStandings>,
+    mut results: ResMut<RaceResults>,
+    time: Res<Time>,
+    mut next_phase: ResMut<NextState<RacePhase>>,
+    mut lap_events: EventWriter<LapCompleted>,
 ) {
-    let Ok(finish_transform) = query_finish.single() else {
+    let Ok(finish_gate) = query_finish.single() else {
         return;
     };
 
-    // config
-    let checkpoint_half_size = Vec2::new(860.0 / 2.0, 720.0 / 2.0);
-    let finish_half_size = Vec2::new(860.0 / 2.0, 720.0 / 2.0);
-    let padding = 0.0; // allows some buffer for detection
-
-    let mut checkpoint_data: Vec<(Vec3, usize)> = query_checkpoints
+    let mut checkpoint_data: Vec<(Gate, usize)> = query_checkpoints
         .iter()
-        .map(|(t, c)| (t.translation, c.index))
+        .map(|(g, c)| (*g, c.index))
         .collect();
 
     // sort to ensure 0, 1, 2, 3
     checkpoint_data.sort_by_key(|(_, i)| *i);
+    let checkpoint_gates: Vec<Gate> = checkpoint_data.into_iter().map(|(g, _)| g).collect();
 
-    for (car_transform, mut lap_counter, player_flag) in query_cars.iter_mut() {
+    standings.elapsed += time.delta_secs();
+
+    let mut local_finished_now = false;
+    for (entity, car_transform, mut lap_counter, racer_id, local_player) in query_cars.iter_mut() {
+        // lap times on the HUD and in the records belong to the main player
+        let is_player = local_player == Some(&LocalPlayer(0));
         let car_pos = car_transform.translation.truncate();
-        
 
-        // check next checkpoint
-        if let Some((checkpoint_pos, index)) = checkpoint_data
-            .iter()
-            .find(|(_, i)| *i == lap_counter.next_checkpoint)
-        {
-            let delta = car_pos - checkpoint_pos.truncate();
-
-            if delta.x.abs() < checkpoint_half_size.x + padding
-                && delta.y.abs() < checkpoint_half_size.y + padding
-            {
-                info!("Reached checkpoint {}", index);
-                lap_counter.next_checkpoint += 1;
+        match lap_counter.advance(car_pos, &checkpoint_gates, finish_gate) {
+            LapEvent::None => {}
+            LapEvent::Checkpoint(index) => info!("Reached checkpoint {}", index),
+            LapEvent::Lap(lap) => {
+                info!("Lap complete {}", lap);
+                if is_player {
+                    standings.record_player_lap();
+                }
+                lap_events.write(LapCompleted {
+                    id: racer_id.0,
+                    lap,
+                    race_time: standings.elapsed,
+                });
             }
-        }
-        // check finish line
-        if lap_counter.next_checkpoint >= checkpoint_data.len() {
-            let delta_finish = car_pos - finish_transform.translation.truncate();
-
-            if delta_finish.x.abs() < finish_half_size.x + padding
-                && delta_finish.y.abs() < finish_half_size.y + padding
-            {
-                lap_counter.current_lap += 1;
-                lap_counter.next_checkpoint = 0;
-
-                info!("Lap complete {}", lap_counter.current_lap);
-
-                if lap_counter.current_lap >= lap_counter.total_laps {
-                    lap_counter.has_finished = true;
-                    info!("Car finished all laps!");
-                    next_state.set(GameState::Victory);
+            LapEvent::Finished => {
+                if is_player {
+                    standings.record_player_lap();
                 }
+                lap_events.write(LapCompleted {
+                    id: racer_id.0,
+                    lap: lap_counter.current_lap,
+                    race_time: standings.elapsed,
+                });
+                let entry = standings.record_finish(racer_id.0);
+                info!(
+                    "Car {} finished P{} in {}",
+                    racer_id.0,
+                    entry.position,
+                    format_race_time(entry.time.unwrap_or_default())
+                );
+                // finished cars drop out of the race
+                commands.entity(entity).insert(Finished);
+                local_finished_now |= local_player.is_some();
             }
         }
     }
-}
\ No newline at end of file
+
+    // in split-screen the race only counts as done once both players are home
+    let local_all_finished = query_cars
+        .iter()
+        .filter(|(_, _, _, _, local_player)| local_player.is_some())
+        .all(|(_, _, lap_counter, _, _)| lap_counter.has_finished);
+    if local_finished_now && local_all_finished {
+        next_phase.set(RacePhase::Finished);
+    }
+
+    // race is over once everyone is home or the DNF countdown runs out
+    let dnf_expired = match standings.dnf_timer.as_mut() {
+        Some(timer) => timer.tick(time.delta()).finished(),
+        None => false,
+    };
+    let all_finished = query_cars
+        .iter()
+        .all(|(_, _, lap_counter, _, _)| lap_counter.has_finished);
+
+    if all_finished || dnf_expired {
+        let unfinished = query_cars
+            .iter()
+            .filter(|(_, _, lap_counter, _, _)| !lap_counter.has_finished)
+            .map(|(_, _, lap_counter, racer_id, _)| (racer_id.0, lap_counter.progress()))
+            .collect();
+        results.entries = build_race_results(&standings.finish_order, unfinished);
+        next_phase.set(RacePhase::Results);
+    }
+}
+
+// Fresh clock and standings for every offline race
+pub fn reset_race_standings(mut commands: Commands) {
+    commands.insert_resource(RaceStandings::default());
+    commands.insert_resource(RaceResults::default());
+    // offline races are every car for itself
+    commands.insert_resource(TeamAssignments::default());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gate_only_counts_driving_through_it_forwards() {
+        let gate = Gate::new(Vec2::ZERO, 0.0, 100.0);
+        assert!(gate.crossed_by(Vec2::new(0.0, -10.0), Vec2::new(0.0, 10.0)));
+        assert!(!gate.crossed_by(Vec2::new(0.0, 10.0), Vec2::new(0.0, -10.0)), "backwards");
+        assert!(!gate.crossed_by(Vec2::new(60.0, -10.0), Vec2::new(60.0, 10.0)), "round the end");
+        assert!(!gate.crossed_by(Vec2::new(-20.0, -10.0), Vec2::new(20.0, -10.0)), "alongside");
+
+        // ending a frame on the line counts, starting the next one there doesn't again
+        assert!(gate.crossed_by(Vec2::new(0.0, -10.0), Vec2::ZERO));
+        assert!(!gate.crossed_by(Vec2::ZERO, Vec2::new(0.0, 10.0)));
+
+        // turned a quarter left, it's driven through going -x
+        let turned = Gate::new(Vec2::new(500.0, 0.0), std::f32::consts::FRAC_PI_2, 100.0);
+        assert!(turned.crossed_by(Vec2::new(510.0, 0.0), Vec2::new(490.0, 0.0)));
+        assert!(!turned.crossed_by(Vec2::new(490.0, 0.0), Vec2::new(510.0, 0.0)));
+    }
+
+    #[test]
+    fn finishers_come_first_then_dnfs_by_how_far_they_got() {
+        let mut standings = RaceStandings {
+            elapsed: 61.5,
+            ..default()
+        };
+        standings.record_finish(1000);
+        standings.elapsed = 63.0;
+        standings.record_finish(2);
+
+        // one still on its first lap, two on the second a couple of checkpoints apart
+        let unfinished = vec![(1001, (0, 5)), (3, (1, 2)), (1002, (1, 4))];
+        let results = build_race_results(&standings.finish_order, unfinished);
+
+        let order: Vec<(u32, u8, bool)> =
+            results.iter().map(|e| (e.id, e.position, e.dnf)).collect();
+        assert_eq!(
+            order,
+            [(1000, 1, false), (2, 2, false), (1002, 3, true), (3, 4, true), (1001, 5, true)]
+        );
+        assert_eq!(results[1].time, Some(63.0));
+        assert!(results[2..].iter().all(|e| e.time.is_none()));
+    }
+}
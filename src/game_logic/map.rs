@@ -3,6 +3,10 @@ use bevy::prelude::*;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
+// Every tile sprite of the track, so a race can be cleared away before the next one
+#[derive(Component)]
+pub struct MapTile;
+
 #[derive(Resource, Clone)]
 pub struct GameMap {
     pub height: f32,
@@ -145,6 +149,7 @@ pub fn spawn_map(
                     -(y as f32 * tile_size) + game_map.height / 2.0 - tile_size / 2.0,
                     1.0, // terrain on top
                 ),
+                MapTile,
             ));
         }
     }
@@ -170,6 +175,7 @@ pub fn spawn_map(
                         -(y as f32 * tile_size) + game_map.height / 2.0 - tile_size / 2.0,
                         0.1 + layer_index as f32 * 0.1, // important so no z-fighting
                     ),
+                    MapTile,
                 ));
             }
         }
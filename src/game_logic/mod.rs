@@ -1,21 +1,55 @@
+pub mod ai_controller;
+pub mod ai_debug;
+pub mod ai_driver;
+pub mod ai_powerups;
+pub mod behaviour_tree;
 pub mod collisions;
 pub mod components;
 pub mod constants;
+pub mod controls;
+pub mod cup;
 pub mod difficulty;
 pub mod lap_system;
 pub mod map;
+pub mod path_planning;
+pub mod personality;
 pub mod physics;
+pub mod practice_grid;
+pub mod race_settings;
+pub mod racing_line;
+pub mod recovery;
+pub mod stable_hash;
+pub mod teams;
 pub mod terrain;
 pub mod theta;
 pub mod theta_grid;
+pub mod theta_search;
+pub mod traffic;
 
+pub use ai_controller::*;
+pub use ai_debug::*;
+pub use ai_driver::*;
+pub use ai_powerups::*;
+pub use behaviour_tree::*;
 pub use collisions::*;
 pub use components::*;
 pub use constants::*;
+pub use controls::*;
+pub use cup::*;
 pub use difficulty::*;
 pub use lap_system::*;
 pub use map::*;
+pub use path_planning::*;
+pub use personality::*;
 pub use physics::*;
+pub use practice_grid::*;
+pub use race_settings::*;
+pub use racing_line::*;
+pub use recovery::*;
+pub use stable_hash::*;
+pub use teams::*;
 pub use terrain::*;
 pub use theta::*;
 pub use theta_grid::*;
+pub use theta_search::*;
+pub use traffic::*;
@@ -0,0 +1,114 @@
+// src/game_logic/path_planning.rs
+use crate::game_logic::{ThetaCheckpointList, ThetaGrid, theta_star_generator};
+use bevy::tasks::{AsyncComputeTaskPool, Task, TaskPool, futures::check_ready};
+
+// Most Theta* searches started per frame (or server tick) across every AI car, the rest
+// keep steering straight at their checkpoint and get their turn next time
+pub const PATH_SEARCHES_PER_TICK: usize = 4;
+
+/// A Theta* search running on the async compute pool, and the checkpoint target it's for
+pub struct PathSearch {
+    target: (f32, f32),
+    task: Task<Option<Vec<(usize, usize)>>>,
+}
+
+impl ThetaCheckpointList {
+    /// Start a search for the path this car asked for, if it asked for one. The search
+    /// gets its own handle on the grid, which shares its nodes so that's cheap.
+    /// Returns whether a search was started.
+    pub fn start_path_search(&mut self, grid: &ThetaGrid) -> bool {
+        let (Some((start, goal)), Some(target)) = (self.path_request, self.target_world_pos) else {
+            return false;
+        };
+        self.path_request = None;
+
+        let grid = grid.clone();
+        let task = AsyncComputeTaskPool::get_or_init(TaskPool::default)
+            .spawn(async move { theta_star_generator(&grid, start, goal) });
+        self.path_search = Some(PathSearch { target, task });
+        true
+    }
+
+    /// Pick up this car's search if it has finished, storing the path for `theta_star_target`
+    /// to follow. Searches for a target the car has since moved on from are thrown away.
+    pub fn collect_path_search(&mut self) {
+        let Some(search) = self.path_search.as_mut() else {
+            return;
+        };
+        let Some(result) = check_ready(&mut search.task) else {
+            return;
+        };
+        let target = search.target;
+        self.path_search = None;
+        if self.target_world_pos != Some(target) {
+            return;
+        }
+
+        match result {
+            Some(path) => {
+                self.cached_path = path;
+                self.path_index = 0;
+                self.path_failed = false;
+                self.detour_failed_at = None;
+            }
+            None => self.path_failed = true,
+        }
+    }
+}
+
+/// Collect finished searches for every car, then start new ones until `budget` runs out
+pub fn plan_paths<'a>(
+    cars: impl IntoIterator<Item = (&'a mut ThetaCheckpointList, &'a ThetaGrid)>,
+    budget: usize,
+) {
+    let mut started = 0;
+    for (checkpoints, grid) in cars {
+        checkpoints.collect_path_search();
+        if started < budget && checkpoints.start_path_search(grid) {
+            started += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic::theta_grid::map2_grid;
+    use crate::game_logic::{CpuDifficulty, theta_star_target};
+
+    #[test]
+    fn searches_are_budgeted_and_fill_cached_path() {
+        let grid = map2_grid();
+        let profile = CpuDifficulty::Hard.profile();
+        let start = (0.0, 0.0);
+
+        let mut cars: Vec<ThetaCheckpointList> = (0..PATH_SEARCHES_PER_TICK + 2)
+            .map(|_| ThetaCheckpointList::new(Vec::new()).load_checkpoint_list(2))
+            .collect();
+        for car in &mut cars {
+            // no path yet, so it asks for one and heads straight for its checkpoint
+            assert!(theta_star_target(start, car, &grid, &profile).is_some());
+            assert!(car.path_request.is_some());
+        }
+
+        plan_paths(cars.iter_mut().map(|car| (car, &grid)), PATH_SEARCHES_PER_TICK);
+        let searching = cars.iter().filter(|car| car.path_search.is_some()).count();
+        assert_eq!(searching, PATH_SEARCHES_PER_TICK);
+
+        // later ticks pick up the finished searches and start the ones left over
+        for _ in 0..1000 {
+            if cars.iter().all(|car| !car.cached_path.is_empty()) {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            plan_paths(cars.iter_mut().map(|car| (car, &grid)), PATH_SEARCHES_PER_TICK);
+        }
+        for car in &cars {
+            let goal = car.target_world_pos.unwrap();
+            assert_eq!(
+                car.cached_path.last().copied(),
+                Some(grid.world_to_grid(goal.0, goal.1))
+            );
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// src/game_logic/personality.rs
+use crate::game_logic::{Behaviour, TrafficCar};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Cars further away than this don't change how a CPU drives
+pub const TRAFFIC_RADIUS: f32 = 300.0;
+// Furthest a shy car or a blocker moves its aim off the racing line, same as the waypoint leeway
+const MAX_SWERVE: f32 = 128.0;
+
+/// How a CPU car treats the traffic around it, each one drives by its own behaviour tree
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum Personality {
+    // drives its own line and ignores everyone else
+    #[default]
+    Racer,
+    // keeps its distance from other cars
+    Shy,
+    // goes after nearby cars and rams them
+    Aggressive,
+    // sits in front of whoever is behind to defend its position
+    Blocker,
+}
+
+impl Personality {
+    pub const ALL: [Personality; 4] = [
+        Personality::Racer,
+        Personality::Shy,
+        Personality::Aggressive,
+        Personality::Blocker,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Personality::Racer => "Racer",
+            Personality::Shy => "Shy",
+            Personality::Aggressive => "Aggressive",
+            Personality::Blocker => "Blocker",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            Personality::Racer => Personality::Shy,
+            Personality::Shy => Personality::Aggressive,
+            Personality::Aggressive => Personality::Blocker,
+            Personality::Blocker => Personality::Racer,
+        }
+    }
+}
+
+impl Behaviour {
+    /// How far to move the point a CPU steers at off its path, given where it is,
+    /// which way it's facing, the path waypoint it's heading for and the other cars
+    /// in the race. Only rivals are shied away from, rammed or blocked, never teammates.
+    pub fn target_offset(
+        &self,
+        pos: Vec2,
+        forward: Vec2,
+        waypoint: Vec2,
+        traffic: &[TrafficCar],
+    ) -> Vec2 {
+        let nearby = traffic
+            .iter()
+            .filter(|car| car.rival)
+            .map(|car| car.pos)
+            .filter(|other| other.distance(pos) < TRAFFIC_RADIUS);
+        let closest = |a: &Vec2, b: &Vec2| a.distance(pos).total_cmp(&b.distance(pos));
+
+        match self {
+            Behaviour::Race | Behaviour::Recover | Behaviour::UseItem => Vec2::ZERO,
+            // boid-style separation, the closer a car is the harder it pushes
+            Behaviour::Avoid => {
+                let push: Vec2 = nearby
+                    .map(|other| {
+                        let away = pos - other;
+                        away.normalize_or_zero() * (1.0 - away.length() / TRAFFIC_RADIUS)
+                    })
+                    .sum();
+                push.clamp_length_max(1.0) * MAX_SWERVE
+            }
+            // drive straight at the closest car ahead
+            Behaviour::Ram => nearby
+                .filter(|other| (*other - pos).dot(forward) > 0.0)
+                .min_by(closest)
+                .map_or(Vec2::ZERO, |rival| rival - waypoint),
+            // slide across to sit in front of the closest car behind
+            Behaviour::Defend => {
+                let right = Vec2::new(forward.y, -forward.x);
+                nearby
+                    .filter(|other| (*other - pos).dot(forward) < 0.0)
+                    .min_by(closest)
+                    .map_or(Vec2::ZERO, |chaser| {
+                        right * (chaser - pos).dot(right).clamp(-MAX_SWERVE, MAX_SWERVE)
+                    })
+            }
+        }
+    }
+}
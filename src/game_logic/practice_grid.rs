@@ -0,0 +1,23 @@
+// src/game_logic/practice_grid.rs
+use crate::game_logic::{CpuDifficulty, MAX_RACERS, Personality};
+use bevy::prelude::*;
+
+// One CPU car on the offline grid
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AiDriver {
+    pub difficulty: CpuDifficulty,
+    pub personality: Personality,
+}
+
+/// The CPU cars for an offline race, picked on the race setup screen.
+/// How many of them actually start comes from `RaceSettings::ai_count`.
+#[derive(Resource, Clone, Debug, Default)]
+pub struct PracticeGrid {
+    pub drivers: [AiDriver; MAX_RACERS - 1],
+}
+
+impl PracticeGrid {
+    pub fn driver(&self, slot: usize) -> AiDriver {
+        self.drivers.get(slot).copied().unwrap_or_default()
+    }
+}
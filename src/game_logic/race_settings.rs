@@ -0,0 +1,234 @@
+// src/game_logic/race_settings.rs
+use crate::game_logic::{CUP_RACES, CpuDifficulty, Personality, Team, rivals};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+// Max cars on the starting grid (players + AI)
+pub const MAX_RACERS: usize = 8;
+pub const MIN_LAPS: u8 = 1;
+pub const MAX_LAPS: u8 = 9;
+// Elimination timer choices in seconds, 0 means "last place is out at the end of each lap"
+pub const ELIMINATION_INTERVALS: [u8; 5] = [0, 15, 30, 45, 60];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RaceMode {
+    #[default]
+    Standard,
+    // last-placed car is knocked out every lap or every few seconds, last car standing wins
+    Elimination,
+}
+
+impl RaceMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RaceMode::Standard => "Standard",
+            RaceMode::Elimination => "Elimination",
+        }
+    }
+}
+
+/// Settings the lobby host picks before a race. The server keeps the
+/// authoritative copy on its `Lobby` and sends it to everyone in the lobby.
+#[derive(Resource, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct RaceSettings {
+    pub laps: u8,
+    pub ai_count: u8,
+    pub difficulty: CpuDifficulty,
+    pub personality: Option<Personality>, // None mixes them up across the grid
+    pub car_collisions: bool,
+    pub boost_pickups: bool,
+    pub mode: RaceMode,
+    pub elimination_secs: u8,
+    pub teams: bool,
+    pub friendly_collisions: bool, // team races only, do cars on the same team bump each other
+    pub cup: bool,                 // chain several races into a cup with points
+}
+
+impl Default for RaceSettings {
+    fn default() -> Self {
+        Self {
+            laps: 2,
+            ai_count: (MAX_RACERS - 1) as u8,
+            difficulty: CpuDifficulty::default(),
+            personality: None,
+            car_collisions: true,
+            boost_pickups: true,
+            mode: RaceMode::Standard,
+            elimination_secs: 0,
+            teams: false,
+            friendly_collisions: false,
+            cup: false,
+        }
+    }
+}
+
+// Rows of the lobby settings panel, in display order
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RaceSetting {
+    Laps,
+    AiCount,
+    Difficulty,
+    Personality,
+    CarCollisions,
+    BoostPickups,
+    Mode,
+    EliminationTimer,
+    Teams,
+    FriendlyCollisions,
+    Cup,
+}
+
+impl RaceSetting {
+    pub const ALL: [RaceSetting; 11] = [
+        RaceSetting::Cup,
+        RaceSetting::Mode,
+        RaceSetting::Teams,
+        RaceSetting::Laps,
+        RaceSetting::EliminationTimer,
+        RaceSetting::AiCount,
+        RaceSetting::Difficulty,
+        RaceSetting::Personality,
+        RaceSetting::CarCollisions,
+        RaceSetting::FriendlyCollisions,
+        RaceSetting::BoostPickups,
+    ];
+}
+
+impl RaceSettings {
+    /// Number of AI cars that actually fit on the grid next to `human_count` players
+    pub fn ai_slots(&self, human_count: usize) -> usize {
+        (self.ai_count as usize).min(MAX_RACERS.saturating_sub(human_count))
+    }
+
+    /// Personality of the CPU in AI slot `slot`, a mixed grid cycles through all of them
+    pub fn ai_personality(&self, slot: usize) -> Personality {
+        self.personality
+            .unwrap_or(Personality::ALL[slot % Personality::ALL.len()])
+    }
+
+    /// Step a setting up (`forward`) or down, wrapping around at the ends
+    pub fn adjust(&mut self, setting: RaceSetting, forward: bool) {
+        match setting {
+            RaceSetting::Laps => {
+                self.laps = if forward {
+                    if self.laps >= MAX_LAPS { MIN_LAPS } else { self.laps + 1 }
+                } else if self.laps <= MIN_LAPS {
+                    MAX_LAPS
+                } else {
+                    self.laps - 1
+                };
+            }
+            RaceSetting::AiCount => {
+                let max_ai = (MAX_RACERS - 1) as u8;
+                self.ai_count = if forward {
+                    if self.ai_count >= max_ai { 0 } else { self.ai_count + 1 }
+                } else if self.ai_count == 0 {
+                    max_ai
+                } else {
+                    self.ai_count - 1
+                };
+            }
+            RaceSetting::Difficulty => {
+                self.difficulty = if forward {
+                    self.difficulty.next()
+                } else {
+                    self.difficulty.prev()
+                };
+            }
+            RaceSetting::Personality => {
+                // Mixed, then each personality in turn
+                let choices: Vec<Option<Personality>> = std::iter::once(None)
+                    .chain(Personality::ALL.map(Some))
+                    .collect();
+                let current = choices
+                    .iter()
+                    .position(|&p| p == self.personality)
+                    .unwrap_or(0);
+                let next = if forward {
+                    (current + 1) % choices.len()
+                } else {
+                    (current + choices.len() - 1) % choices.len()
+                };
+                self.personality = choices[next];
+            }
+            RaceSetting::CarCollisions => self.car_collisions = !self.car_collisions,
+            RaceSetting::BoostPickups => self.boost_pickups = !self.boost_pickups,
+            RaceSetting::Mode => {
+                self.mode = match self.mode {
+                    RaceMode::Standard => RaceMode::Elimination,
+                    RaceMode::Elimination => RaceMode::Standard,
+                };
+            }
+            RaceSetting::EliminationTimer => {
+                let count = ELIMINATION_INTERVALS.len();
+                let current = ELIMINATION_INTERVALS
+                    .iter()
+                    .position(|&secs| secs == self.elimination_secs)
+                    .unwrap_or(0);
+                let next = if forward {
+                    (current + 1) % count
+                } else {
+                    (current + count - 1) % count
+                };
+                self.elimination_secs = ELIMINATION_INTERVALS[next];
+            }
+            RaceSetting::Teams => self.teams = !self.teams,
+            RaceSetting::FriendlyCollisions => {
+                self.friendly_collisions = !self.friendly_collisions
+            }
+            RaceSetting::Cup => self.cup = !self.cup,
+        }
+    }
+
+    /// Whether two cars bump into each other, given the teams they're on
+    pub fn cars_collide(&self, a: Option<Team>, b: Option<Team>) -> bool {
+        self.car_collisions && (!self.teams || self.friendly_collisions || rivals(a, b))
+    }
+
+    /// Laps each car has to do. Elimination races run until one car is left.
+    pub fn laps_to_finish(&self) -> u8 {
+        match self.mode {
+            RaceMode::Standard => self.laps,
+            RaceMode::Elimination => u8::MAX,
+        }
+    }
+
+    /// Clamp values coming off the wire to something the server can run
+    pub fn sanitized(mut self) -> Self {
+        self.laps = self.laps.clamp(MIN_LAPS, MAX_LAPS);
+        self.ai_count = self.ai_count.min((MAX_RACERS - 1) as u8);
+        if !ELIMINATION_INTERVALS.contains(&self.elimination_secs) {
+            self.elimination_secs = 0;
+        }
+        self
+    }
+
+    pub fn label(&self, setting: RaceSetting) -> String {
+        let on_off = |b: bool| if b { "ON" } else { "OFF" };
+        match setting {
+            RaceSetting::Laps => format!("Laps: {}", self.laps),
+            RaceSetting::AiCount => format!("AI Opponents: {}", self.ai_count),
+            RaceSetting::Difficulty => format!("AI Difficulty: {}", self.difficulty.as_str()),
+            RaceSetting::Personality => format!(
+                "AI Personality: {}",
+                self.personality.map_or("Mixed", |p| p.as_str())
+            ),
+            RaceSetting::CarCollisions => {
+                format!("Car Collisions: {}", on_off(self.car_collisions))
+            }
+            RaceSetting::BoostPickups => format!("Boost Pickups: {}", on_off(self.boost_pickups)),
+            RaceSetting::Mode => format!("Mode: {}", self.mode.as_str()),
+            RaceSetting::EliminationTimer => match self.elimination_secs {
+                0 => "Eliminate: Every Lap".to_string(),
+                secs => format!("Eliminate: Every {}s", secs),
+            },
+            RaceSetting::Teams => format!("Teams: {}", on_off(self.teams)),
+            RaceSetting::FriendlyCollisions => {
+                format!("Team Collisions: {}", on_off(self.friendly_collisions))
+            }
+            RaceSetting::Cup if self.cup => format!("Cup: ON ({} races)", CUP_RACES),
+            RaceSetting::Cup => "Cup: OFF".to_string(),
+        }
+    }
+}
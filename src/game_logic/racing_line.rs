@@ -0,0 +1,545 @@
+// src/game_logic/racing_line.rs
+use crate::game_logic::theta_grid::ThetaGrid;
+use crate::game_logic::{
+    BOOST_SPEED_SCALE, BRAKE_RATE, CURVATURE_SPAN, DifficultyProfile, Fnv, GameMap, MIN_LOOKAHEAD,
+    PLAYER_SPEED, PathPoint, TILE_SIZE, TURNING_RATE, ThetaCheckpointList, bounded_detour,
+    cornering_speed, menger_curvature, theta_star_generator,
+};
+use crate::networking::MapChoice;
+use bevy::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Gap between neighbouring points on a finished line
+const POINT_SPACING: f32 = 32.0;
+// Each smoothing pass pulls every point part of the way to its neighbours' midpoint,
+// the line is re-spaced every so often so the points don't bunch up
+const SMOOTHING_PASSES: usize = 400;
+const SMOOTHING_STRENGTH: f32 = 0.5;
+const RESAMPLE_EVERY: usize = 50;
+// Ground checked around each point while smoothing, so the line keeps a car's width off the walls
+const CLEARANCE: [(f32, f32); 5] = [
+    (0.0, 0.0),
+    (24.0, 0.0),
+    (-24.0, 0.0),
+    (0.0, 24.0),
+    (0.0, -24.0),
+];
+// Steps tried when moving a point off slow ground
+const NUDGES: [(f32, f32); 4] = [(8.0, 0.0), (-8.0, 0.0), (0.0, 8.0), (0.0, -8.0)];
+// Bump whenever building lines changes in a way the constants below don't show, so the
+// shipped ones are rebuilt
+const LINE_VERSION: u32 = 1;
+// How much of the line in front of a car its controller gets, enough to brake from flat out
+const PATH_HORIZON: f32 = 1200.0;
+// How far along its stretch of line a car looks for cars and hazards in the way
+const BLOCKED_RANGE: f32 = 400.0;
+// How many points past the last known one to search when working out where a car is
+const PROGRESS_WINDOW: usize = 24;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct RacingPoint {
+    pub x: f32,
+    pub y: f32,
+    pub speed: f32,  // pixels per second a car can carry here and still make the corners after
+    pub ground: f32, // speed modifier of the terrain here, the car's own top speed limits it too
+}
+
+/// The fastest way round a track: a closed loop of points with a target speed at each.
+/// Built ahead of time for every track and shipped under `assets/racing_lines/` (see the
+/// `rebuild_racing_lines` test), the AI follows it instead of pathfinding from gate to gate.
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RacingLine {
+    #[serde(default)]
+    pub key: u64, // what it was built from, see RacingLine::key_for
+    pub points: Vec<RacingPoint>,
+}
+
+/// A racing line being worked out on another thread
+#[derive(Resource, Clone)]
+pub struct LineBuild(Arc<Mutex<Option<RacingLine>>>);
+
+impl LineBuild {
+    pub fn start(map: MapChoice, game_map: &GameMap, grid: &ThetaGrid) -> Self {
+        let done = Arc::new(Mutex::new(None));
+        let slot = Arc::clone(&done);
+        let (game_map, grid) = (game_map.clone(), grid.clone());
+        thread::spawn(move || {
+            let line = RacingLine::build_for(map, &game_map, &grid);
+            *slot.lock().unwrap() = Some(line);
+        });
+        Self(done)
+    }
+
+    /// The line once it's ready, only handed out once
+    pub fn take(&self) -> Option<RacingLine> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl RacingLine {
+    pub fn asset_path(map: MapChoice) -> String {
+        format!("assets/racing_lines/{}.json", map.key())
+    }
+
+    /// Read the track's shipped line, None if it hasn't got one or it was built from an
+    /// older version of the track, its checkpoints or the car's handling
+    pub fn load(map: MapChoice) -> Option<Self> {
+        let path = Self::asset_path(map);
+        let text = fs::read_to_string(&path).ok()?;
+        let line: Self = match serde_json::from_str(&text) {
+            Ok(line) => line,
+            Err(e) => {
+                println!("Failed to read racing line from {}: {}", path, e);
+                return None;
+            }
+        };
+        if line.key != Self::key_for(map) {
+            println!("Racing line in {} is out of date", path);
+            return None;
+        }
+        Some(line)
+    }
+
+    /// A hash of everything a track's line is built from: the track, its AI checkpoints,
+    /// how the line is smoothed and how the cars brake and turn
+    pub fn key_for(map: MapChoice) -> u64 {
+        let mut hash = Fnv::default();
+        hash.write(&LINE_VERSION.to_le_bytes());
+        hash.write(&fs::read(map.path()).unwrap_or_default());
+        let checkpoints = ThetaCheckpointList::new(Vec::new()).load_checkpoint_list_for_choice(map);
+        for gate in &checkpoints.checkpoints {
+            hash.write_f32s(&[gate.point1.0, gate.point1.1, gate.point2.0, gate.point2.1]);
+        }
+        hash.write_f32s(&[
+            POINT_SPACING,
+            SMOOTHING_PASSES as f32,
+            SMOOTHING_STRENGTH,
+            RESAMPLE_EVERY as f32,
+            CURVATURE_SPAN as f32,
+            BRAKE_RATE,
+            TURNING_RATE,
+            PLAYER_SPEED,
+            BOOST_SPEED_SCALE,
+        ]);
+        for (dx, dy) in CLEARANCE.iter().chain(&NUDGES) {
+            hash.write_f32s(&[*dx, *dy]);
+        }
+        hash.finish()
+    }
+
+    /// The track's shipped line. A track without one gets it worked out on another thread
+    /// (nothing is saved), it's raced with Theta* until `LineBuild::take` has it.
+    pub fn load_or_build(
+        map: MapChoice,
+        game_map: &GameMap,
+        grid: &ThetaGrid,
+    ) -> (Self, Option<LineBuild>) {
+        match Self::load(map) {
+            Some(line) => (line, None),
+            None => {
+                println!("No racing line for {:?}, working one out", map);
+                (Self::default(), Some(LineBuild::start(map, game_map, grid)))
+            }
+        }
+    }
+
+    /// Work out the line for one of the tracks, the way it's shipped
+    pub fn build_for(map: MapChoice, game_map: &GameMap, grid: &ThetaGrid) -> Self {
+        let checkpoints = ThetaCheckpointList::new(Vec::new()).load_checkpoint_list_for_choice(map);
+        Self {
+            key: Self::key_for(map),
+            ..Self::build(&checkpoints, game_map, grid)
+        }
+    }
+
+    /// Work out a line through the AI checkpoints: Theta* between the gate middles to get
+    /// round the walls, then smoothed out as far as the terrain allows (a minimum-curvature
+    /// line that cuts the corners without leaving the road, but still goes through every
+    /// gate), then a target speed at each point.
+    pub fn build(checkpoints: &ThetaCheckpointList, game_map: &GameMap, grid: &ThetaGrid) -> Self {
+        let gates: Vec<(Vec2, Vec2)> = checkpoints
+            .checkpoints
+            .iter()
+            .map(|gate| {
+                let (a, b) = gate.world_ends(grid);
+                (Vec2::from(a), Vec2::from(b))
+            })
+            .collect();
+        if gates.len() < 2 {
+            return Self::default();
+        }
+        let middles: Vec<Vec2> = gates.iter().map(|(a, b)| a.midpoint(*b)).collect();
+
+        let mut path = Vec::new();
+        for (i, from) in middles.iter().enumerate() {
+            let to = middles[(i + 1) % middles.len()];
+            let start = grid.world_to_grid(from.x, from.y);
+            let goal = grid.world_to_grid(to.x, to.y);
+            match theta_star_generator(grid, start, goal) {
+                // the next leg starts where this one ends
+                Some(nodes) => path.extend(
+                    nodes[..nodes.len() - 1]
+                        .iter()
+                        .filter_map(|&(x, y)| grid.get_node(x, y))
+                        .map(|node| Vec2::new(node.world_x, node.world_y)),
+                ),
+                None => path.push(*from),
+            }
+        }
+
+        // how fast the ground around a point is, walls count as standing still
+        let ground_at = |p: Vec2| {
+            CLEARANCE
+                .iter()
+                .map(|&(dx, dy)| {
+                    let tile = game_map.get_tile(p.x + dx, p.y + dy, TILE_SIZE as f32);
+                    if tile.passable {
+                        tile.speed_modifier
+                    } else {
+                        0.0
+                    }
+                })
+                .fold(f32::INFINITY, f32::min)
+        };
+
+        // the point closest to each gate is held on it, the lap gates are behind some of them
+        let pin_points = |line: &[Vec2]| -> Vec<usize> {
+            middles
+                .iter()
+                .map(|middle| {
+                    (0..line.len())
+                        .min_by(|&a, &b| {
+                            line[a]
+                                .distance_squared(*middle)
+                                .total_cmp(&line[b].distance_squared(*middle))
+                        })
+                        .unwrap_or(0)
+                })
+                .collect()
+        };
+
+        let mut line = resample(&path, POINT_SPACING);
+        let mut pinned = pin_points(&line);
+        for pass in 0..SMOOTHING_PASSES {
+            let n = line.len();
+            for i in 0..n {
+                let mid = (line[(i + n - 1) % n] + line[(i + 1) % n]) / 2.0;
+                let moved = line[i] + (mid - line[i]) * SMOOTHING_STRENGTH;
+                // never smooth onto slower ground than the point is already on
+                if ground_at(moved) >= ground_at(line[i]) {
+                    line[i] = moved;
+                }
+                // and edge anything on slow ground (Theta* cuts the odd corner) back onto the road
+                let here = ground_at(line[i]);
+                if let Some(better) = NUDGES
+                    .iter()
+                    .map(|&(dx, dy)| line[i] + Vec2::new(dx, dy))
+                    .filter(|&p| ground_at(p) > here)
+                    .max_by(|a, b| ground_at(*a).total_cmp(&ground_at(*b)))
+                {
+                    line[i] = better;
+                }
+            }
+            for (&i, &(a, b)) in pinned.iter().zip(&gates) {
+                line[i] = closest_on_segment(line[i], a, b);
+            }
+            if (pass + 1) % RESAMPLE_EVERY == 0 {
+                line = resample(&line, POINT_SPACING);
+                pinned = pin_points(&line);
+            }
+        }
+
+        let speeds = target_speeds(&line, game_map);
+        Self {
+            key: 0,
+            points: line
+                .iter()
+                .zip(speeds)
+                .map(|(p, speed)| RacingPoint {
+                    x: p.x,
+                    y: p.y,
+                    speed,
+                    ground: game_map.get_tile(p.x, p.y, TILE_SIZE as f32).speed_modifier,
+                })
+                .collect(),
+        }
+    }
+
+    fn point(&self, i: usize) -> Vec2 {
+        let p = self.points[i % self.points.len()];
+        Vec2::new(p.x, p.y)
+    }
+
+    /// Point `i` on the line and the angle of the line there
+    pub fn heading_at(&self, i: usize) -> (Vec2, f32) {
+        let here = self.point(i);
+        (here, (self.point(i + 1) - here).to_angle())
+    }
+
+    /// The line from point `from` on, until it's gone `distance` or round the whole loop
+    pub fn points_ahead(&self, from: usize, distance: f32) -> Vec<Vec2> {
+        let mut points = Vec::new();
+        let mut travelled = 0.0;
+        for i in from..from + self.points.len() {
+            points.push(self.point(i));
+            travelled += self.point(i).distance(self.point(i + 1));
+            if travelled > distance {
+                break;
+            }
+        }
+        points
+    }
+
+    // Closest point to `pos`, searching just ahead of `last` once the car has found the line
+    fn closest_point(&self, pos: Vec2, last: Option<usize>) -> usize {
+        let n = self.points.len();
+        let candidates: Box<dyn Iterator<Item = usize>> = match last {
+            Some(last) => Box::new((last..=last + PROGRESS_WINDOW).map(move |i| i % n)),
+            None => Box::new(0..n),
+        };
+        candidates
+            .min_by(|&a, &b| {
+                self.point(a)
+                    .distance_squared(pos)
+                    .total_cmp(&self.point(b).distance_squared(pos))
+            })
+            .unwrap_or(0)
+    }
+
+    /// The stretch of line in front of the car for its controller to follow. Lower
+    /// difficulties wander further off it, and the whole stretch is moved over by the
+    /// car's personality offset. `top_speed` is how fast the car can go right now in
+    /// pixels per second (boosted or not, for its difficulty), before terrain. Anything
+    /// marked on `grid` just in front is driven round.
+    pub fn path_ahead(
+        &self,
+        pos: Vec2,
+        top_speed: f32,
+        nav: &mut ThetaCheckpointList,
+        profile: &DifficultyProfile,
+        grid: &ThetaGrid,
+    ) -> Vec<PathPoint> {
+        if self.points.is_empty() {
+            return Vec::new();
+        }
+        let n = self.points.len();
+        let index = self.closest_point(pos, nav.line_index);
+        nav.line_index = Some(index);
+
+        // drift slowly from side to side, as far as the driver's difficulty lets it
+        nav.line_wander = (nav.line_wander
+            + nav.rng.random_range(-1.0..=1.0) * profile.line_offset * 0.1)
+            .clamp(-profile.line_offset, profile.line_offset);
+        let offset = Vec2::from(nav.target_offset);
+
+        let mut path = Vec::new();
+        let mut travelled = 0.0;
+        nav.line_target = None;
+        for i in index..index + n {
+            let direction = (self.point(i + 1) - self.point(i)).normalize_or_zero();
+            let side = Vec2::new(-direction.y, direction.x);
+            let point = self.point(i) + side * nav.line_wander;
+            // the personalities react to where on the line the car is heading
+            if nav.line_target.is_none() && travelled >= MIN_LOOKAHEAD {
+                nav.line_target = Some((point.x, point.y));
+            }
+            // corners are taken the same speed however fast the car can go on the straights
+            let line_point = self.points[i % n];
+            path.push(PathPoint {
+                pos: point + offset,
+                max_speed: (line_point.speed * profile.speed_scale)
+                    .min(line_point.ground * top_speed),
+            });
+            travelled += self.point(i).distance(self.point(i + 1));
+            if travelled > PATH_HORIZON {
+                break;
+            }
+        }
+        self.go_round_obstacles(pos, index, &mut path, nav, grid);
+        nav.line_path = path.iter().map(|point| (point.pos.x, point.pos.y)).collect();
+        path
+    }
+
+    // If a car or hazard is on the stretch of `path` just in front, swap the points up to
+    // the first clear one past it for a detour (see `bounded_detour`). A car that can't find
+    // a way round stays on the line until whatever's in the way has moved, or it has.
+    fn go_round_obstacles(
+        &self,
+        pos: Vec2,
+        index: usize,
+        path: &mut Vec<PathPoint>,
+        nav: &mut ThetaCheckpointList,
+        grid: &ThetaGrid,
+    ) {
+        let here = grid.world_to_grid(pos.x, pos.y);
+        let cell = |point: &PathPoint| grid.world_to_grid(point.pos.x, point.pos.y);
+        let (mut from, mut last, mut travelled) = (here, pos, 0.0);
+        let blocked = path.iter().position(|point| {
+            travelled += last.distance(point.pos);
+            last = point.pos;
+            let to = cell(point);
+            let blocked = travelled < BLOCKED_RANGE && grid.line_blocked(from, to, here);
+            from = to;
+            blocked
+        });
+        let Some(blocked) = blocked else {
+            nav.line_detour_failed_at = None;
+            return;
+        };
+        let at = (index + blocked) % self.points.len();
+        if nav.line_detour_failed_at == Some(at) {
+            return;
+        }
+
+        let line_cells: Vec<(usize, usize)> = path.iter().map(cell).collect();
+        let Some((rejoin, cells)) = bounded_detour(grid, here, &line_cells, blocked) else {
+            nav.line_detour_failed_at = Some(at);
+            return;
+        };
+        // no faster round it than the line it replaces, both ends are where they already were
+        let max_speed = path[..rejoin]
+            .iter()
+            .map(|point| point.max_speed)
+            .fold(f32::MAX, f32::min);
+        let detour: Vec<PathPoint> = cells[1..cells.len() - 1]
+            .iter()
+            .filter_map(|&(x, y)| grid.get_node(x, y))
+            .map(|node| PathPoint {
+                pos: Vec2::new(node.world_x, node.world_y),
+                max_speed,
+            })
+            .collect();
+        path.splice(..rejoin, detour);
+        nav.line_detour_failed_at = None;
+    }
+}
+
+// Evenly spaced points along a closed path
+fn resample(path: &[Vec2], spacing: f32) -> Vec<Vec2> {
+    let n = path.len();
+    if n < 2 {
+        return path.to_vec();
+    }
+    let mut points = vec![path[0]];
+    let mut carry = 0.0; // distance since the last point placed
+    for i in 0..n {
+        let (a, b) = (path[i], path[(i + 1) % n]);
+        let length = a.distance(b);
+        let mut along = spacing - carry;
+        while along < length {
+            points.push(a.lerp(b, along / length));
+            along += spacing;
+        }
+        carry = (carry + length) % spacing;
+    }
+    // the loop closes back on the first point
+    if points.len() > 1 && points[points.len() - 1].distance(points[0]) < spacing / 2.0 {
+        points.pop();
+    }
+    points
+}
+
+// Fastest each point can be taken in pixels per second: limited by how tight the corner is
+// for the car's turning rate, then lowered wherever the car couldn't brake in time for a
+// slower point further on. Never more than a boosted car's top speed, the terrain and
+// the car's own top speed are left to path_ahead.
+fn target_speeds(line: &[Vec2], game_map: &GameMap) -> Vec<f32> {
+    let n = line.len();
+    let flat_out = PLAYER_SPEED * BOOST_SPEED_SCALE;
+    let mut speeds: Vec<f32> = (0..n)
+        .map(|i| {
+            let tile = game_map.get_tile(line[i].x, line[i].y, TILE_SIZE as f32);
+            let before = line[(i + n - CURVATURE_SPAN) % n];
+            let after = line[(i + CURVATURE_SPAN) % n];
+            let curvature = menger_curvature(before, line[i], after);
+            cornering_speed(curvature, TURNING_RATE * tile.turn_modifier).min(flat_out)
+        })
+        .collect();
+
+    // go round twice to cover the wrap
+    for step in (0..2 * n).rev() {
+        let i = step % n;
+        let next = (i + 1) % n;
+        let gap = line[i].distance(line[next]);
+        let reachable = (speeds[next].powi(2) + 2.0 * BRAKE_RATE * gap).sqrt();
+        speeds[i] = speeds[i].min(reachable);
+    }
+    speeds
+}
+
+fn closest_on_segment(p: Vec2, a: Vec2, b: Vec2) -> Vec2 {
+    let ab = b - a;
+    let t = ((p - a).dot(ab) / ab.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+    a + ab * t
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic::{CpuDifficulty, OBSTACLE_COST, load_map_from_file};
+
+    // The grid each track is raced on, the same sizes the client and server use
+    fn grid_for(map: MapChoice, game_map: &GameMap) -> ThetaGrid {
+        let (w, h) = match map {
+            MapChoice::Small => (100, 100),
+            MapChoice::Big => (125, 125),
+        };
+        ThetaGrid::create_theta_grid_with_size(game_map, TILE_SIZE as f32, w, h)
+    }
+
+    // Run with `cargo test rebuild_racing_lines -- --ignored` after changing a track,
+    // its checkpoints or how lines are built, and commit the new files
+    #[test]
+    #[ignore]
+    fn rebuild_racing_lines() {
+        for map in [MapChoice::Small, MapChoice::Big] {
+            let game_map = load_map_from_file(map.path());
+            let line = RacingLine::build_for(map, &game_map, &grid_for(map, &game_map));
+            let text = serde_json::to_string(&line).unwrap();
+            fs::create_dir_all("assets/racing_lines").unwrap();
+            fs::write(RacingLine::asset_path(map), text).unwrap();
+        }
+    }
+
+    #[test]
+    fn drives_round_a_hazard_on_the_line() {
+        let map = MapChoice::Big;
+        let game_map = load_map_from_file(map.path());
+        let mut grid = grid_for(map, &game_map);
+        let line = RacingLine::load(map).unwrap();
+        let profile = CpuDifficulty::Hard.profile();
+        let start = line.point(0);
+        let on_hazard = |grid: &ThetaGrid, path: &[PathPoint]| {
+            path.iter().any(|point| {
+                let (x, y) = grid.world_to_grid(point.pos.x, point.pos.y);
+                grid.dynamic_cost(grid.index(x, y)) > 0.0
+            })
+        };
+
+        // something dropped on the line a little way ahead
+        let hazard = line.point(8);
+        grid.add_dynamic_cost((hazard.x, hazard.y), 24.0, OBSTACLE_COST);
+        let mut nav = ThetaCheckpointList::new(Vec::new());
+        let clear_grid = grid_for(map, &game_map);
+        let straight_through = line.path_ahead(start, PLAYER_SPEED, &mut nav, &profile, &clear_grid);
+        assert!(on_hazard(&grid, &straight_through));
+
+        let mut nav = ThetaCheckpointList::new(Vec::new());
+        let path = line.path_ahead(start, PLAYER_SPEED, &mut nav, &profile, &grid);
+        assert!(!on_hazard(&grid, &path), "drove over it");
+        assert_eq!(nav.line_detour_failed_at, None);
+    }
+
+    #[test]
+    fn every_track_ships_an_up_to_date_line() {
+        for map in [MapChoice::Small, MapChoice::Big] {
+            let line = RacingLine::load(map).expect("racing line missing or out of date");
+            assert!(line.points.len() > 10, "no racing line for {:?}", map);
+            assert!(line.points.iter().all(|p| p.speed > 0.0 && p.ground > 0.0));
+        }
+    }
+}
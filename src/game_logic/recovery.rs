@@ -0,0 +1,258 @@
+// src/game_logic/recovery.rs
+use crate::game_logic::{AiControls, RacingLine, ThetaCheckpointList, ThetaGrid};
+use bevy::prelude::*;
+
+// Slower than this (pixels per second) for SLOW_SECS and the car counts as stuck
+const STUCK_SPEED: f32 = 20.0;
+const SLOW_SECS: f32 = 1.5;
+// It has to get this many pixels closer to where it's heading every NO_PROGRESS_SECS
+const PROGRESS_DISTANCE: f32 = 64.0;
+const NO_PROGRESS_SECS: f32 = 4.0;
+// Running into a wall this many separate times without making progress counts as stuck too
+const WALL_HIT_LIMIT: u32 = 3;
+// How long a recovery backs up for
+const REVERSE_SECS: f32 = 1.0;
+// Recoveries in a row that don't get it going again before it asks to be put back on the track
+const MAX_RECOVERIES: u32 = 3;
+
+/// What an AI car should do this frame to get out of wherever it's stuck
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Recovery {
+    Driving,               // getting somewhere, drive as normal
+    Reversing(AiControls), // backing out, drive with these instead
+    Replan,                // just finished backing out, plan again from here then drive as normal
+    Respawn,               // backing out hasn't worked, put it back on the track
+}
+
+/// Watches an AI car for signs it's stuck (crawling, not getting any closer to where
+/// it's heading, or running into walls over and over), and backs it out once its
+/// behaviour tree says to recover
+#[derive(Component, Default)]
+pub struct AiRecovery {
+    stuck: bool, // noticed it's stuck, waiting for the tree to pick Recover
+    anchor: Option<(Vec2, Vec2)>, // where the car was and where it was heading when it last made progress
+    since_progress: f32,
+    slow_for: f32,
+    wall_hits: u32,
+    touching_wall: bool,
+    reversing_for: f32, // time left backing up, 0 when driving normally
+    reverse_steer: f32,
+    recoveries: u32, // recoveries since it last made progress
+}
+
+impl AiRecovery {
+    /// Check on the car once a frame, before it drives. `target` is where it's heading
+    /// (its racing line or path waypoint), `last_steer` how it was steering last frame
+    /// and `recover` whether its behaviour tree has it recovering.
+    pub fn update(
+        &mut self,
+        pos: Vec2,
+        speed: f32,
+        target: Option<Vec2>,
+        last_steer: f32,
+        recover: bool,
+        delta_secs: f32,
+    ) -> Recovery {
+        if self.reversing_for > 0.0 {
+            self.reversing_for -= delta_secs;
+            if self.reversing_for > 0.0 {
+                return Recovery::Reversing(AiControls {
+                    steer: self.reverse_steer,
+                    brake: 1.0,
+                    ..default()
+                });
+            }
+            self.restart_watch();
+            return Recovery::Replan;
+        }
+
+        let Some(target) = target else {
+            return Recovery::Driving;
+        };
+        let (anchor_pos, anchor_target) = *self.anchor.get_or_insert((pos, target));
+        if anchor_pos.distance(anchor_target) - pos.distance(anchor_target) >= PROGRESS_DISTANCE {
+            self.restart_watch();
+            self.recoveries = 0;
+            return Recovery::Driving;
+        }
+
+        self.since_progress += delta_secs;
+        self.slow_for = if speed < STUCK_SPEED {
+            self.slow_for + delta_secs
+        } else {
+            0.0
+        };
+        self.stuck = self.stuck
+            || self.slow_for >= SLOW_SECS
+            || self.since_progress >= NO_PROGRESS_SECS
+            || self.wall_hits >= WALL_HIT_LIMIT;
+        if !self.stuck || !recover {
+            return Recovery::Driving;
+        }
+
+        if self.recoveries >= MAX_RECOVERIES {
+            self.restart_watch();
+            self.recoveries = 0;
+            return Recovery::Respawn;
+        }
+
+        // back out on the opposite lock to the one that got it here, going straight in
+        // it swaps sides each attempt
+        self.recoveries += 1;
+        self.reversing_for = REVERSE_SECS;
+        self.reverse_steer = if last_steer.abs() > 0.1 {
+            -last_steer.signum()
+        } else if self.recoveries.is_multiple_of(2) {
+            1.0
+        } else {
+            -1.0
+        };
+        Recovery::Reversing(AiControls {
+            steer: self.reverse_steer,
+            brake: 1.0,
+            ..default()
+        })
+    }
+
+    /// Whether the car is stuck, or still being backed out of where it was
+    pub fn stuck(&self) -> bool {
+        self.stuck || self.reversing_for > 0.0
+    }
+
+    /// Tell it whether the car was stopped by a wall this frame
+    pub fn note_wall_contact(&mut self, hit: bool) {
+        if hit && !self.touching_wall {
+            self.wall_hits += 1;
+        }
+        self.touching_wall = hit;
+    }
+
+    // Start watching for progress again from wherever the car is next frame
+    fn restart_watch(&mut self) {
+        self.stuck = false;
+        self.anchor = None;
+        self.since_progress = 0.0;
+        self.slow_for = 0.0;
+        self.wall_hits = 0;
+    }
+}
+
+impl ThetaCheckpointList {
+    /// Drop the car's path and its place on the racing line, so both are found again
+    /// from where it is now
+    pub fn replan(&mut self) {
+        self.cached_path.clear();
+        self.path_index = 0;
+        self.path_request = None;
+        self.path_search = None;
+        self.path_failed = false;
+        self.detour_failed_at = None;
+        self.line_detour_failed_at = None;
+        self.line_index = None;
+        self.line_path.clear();
+    }
+}
+
+/// Where to put a car back on the track, and the angle to face. On the racing line where
+/// it last was if the track has one, otherwise in the middle of the last gate it went
+/// through, facing the next.
+pub fn respawn_point(
+    checkpoints: &ThetaCheckpointList,
+    grid: &ThetaGrid,
+    racing_line: &RacingLine,
+) -> Option<(Vec2, f32)> {
+    if !racing_line.points.is_empty() {
+        return Some(racing_line.heading_at(checkpoints.line_index.unwrap_or(0)));
+    }
+
+    let n = checkpoints.checkpoints.len();
+    if n == 0 {
+        return None;
+    }
+    let gate_middle = |i: usize| {
+        let (a, b) = checkpoints.checkpoints[i % n].world_ends(grid);
+        Vec2::from(a).midpoint(Vec2::from(b))
+    };
+    let current = checkpoints.current_checkpoint_index;
+    let from = gate_middle(current + n - 1);
+    let to = gate_middle(current);
+    Some((from, (to - from).to_angle()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP: f32 = 1.0 / 60.0;
+
+    // Step a car that isn't going anywhere until it's told to do something other than drive,
+    // its tree has it recovering as soon as it's stuck
+    fn until_told(recovery: &mut AiRecovery, pos: Vec2, target: Vec2) -> Recovery {
+        let mut elapsed = 0.0;
+        loop {
+            elapsed += STEP;
+            let told = recovery.update(pos, 0.0, Some(target), 0.5, recovery.stuck(), STEP);
+            if told != Recovery::Driving {
+                return told;
+            }
+            assert!(elapsed < 10.0, "never noticed the car was stuck");
+        }
+    }
+
+    #[test]
+    fn pinned_car_backs_out_then_respawns() {
+        let mut recovery = AiRecovery::default();
+        let (pos, target) = (Vec2::ZERO, Vec2::new(500.0, 0.0));
+
+        for _ in 0..MAX_RECOVERIES {
+            let mut told = until_told(&mut recovery, pos, target);
+            // opposite lock to the left turn it was making
+            assert!(matches!(
+                told,
+                Recovery::Reversing(controls) if controls.steer < 0.0 && controls.brake > 0.0
+            ));
+            while matches!(told, Recovery::Reversing(_)) {
+                told = recovery.update(pos, 0.0, Some(target), 0.5, true, STEP);
+            }
+            assert_eq!(told, Recovery::Replan);
+        }
+        assert_eq!(until_told(&mut recovery, pos, target), Recovery::Respawn);
+    }
+
+    #[test]
+    fn wall_hits_count_as_stuck_and_progress_clears_them() {
+        let mut recovery = AiRecovery::default();
+        let target = Vec2::new(500.0, 0.0);
+        for _ in 0..WALL_HIT_LIMIT {
+            recovery.note_wall_contact(true);
+            recovery.note_wall_contact(false);
+        }
+        let told = recovery.update(Vec2::ZERO, 100.0, Some(target), 0.0, true, STEP);
+        assert!(matches!(told, Recovery::Reversing(_)));
+
+        // a car that's getting somewhere is left alone
+        let mut recovery = AiRecovery::default();
+        let mut pos = Vec2::ZERO;
+        for _ in 0..(NO_PROGRESS_SECS * 2.0 / STEP) as usize {
+            pos.x += 100.0 * STEP;
+            let told = recovery.update(pos, 100.0, Some(target + pos), 0.0, true, STEP);
+            assert_eq!(told, Recovery::Driving);
+        }
+        assert!(!recovery.stuck());
+    }
+
+    #[test]
+    fn stuck_car_waits_for_its_tree_to_recover() {
+        let mut recovery = AiRecovery::default();
+        let (pos, target) = (Vec2::ZERO, Vec2::new(500.0, 0.0));
+        for _ in 0..(SLOW_SECS * 2.0 / STEP) as usize {
+            let told = recovery.update(pos, 0.0, Some(target), 0.0, false, STEP);
+            assert_eq!(told, Recovery::Driving);
+        }
+        assert!(recovery.stuck(), "the tree should hear it's stuck");
+
+        let told = recovery.update(pos, 0.0, Some(target), 0.0, true, STEP);
+        assert!(matches!(told, Recovery::Reversing(_)));
+        assert!(recovery.stuck());
+    }
+}
@@ -0,0 +1,29 @@
+// src/game_logic/stable_hash.rs
+
+/// FNV-1a, the same on every platform and Rust version unlike std's hasher, for
+/// anything saved or shipped that has to be checked against what it was made from
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv {
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn write_f32s(&mut self, values: &[f32]) {
+        for value in values {
+            self.write(&value.to_le_bytes());
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
@@ -0,0 +1,104 @@
+// src/game_logic/teams.rs
+use crate::game_logic::RaceResultEntry;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+// Points for P1, P2, ... in a team race, anything further back (or a DNF) scores nothing
+pub const TEAM_POINTS: [u32; 8] = [10, 8, 6, 5, 4, 3, 2, 1];
+
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Team {
+    Red,
+    Blue,
+}
+
+impl Team {
+    /// Teams alternate down the grid so both sides get the same mix of front and back slots
+    pub fn for_slot(slot: usize) -> Self {
+        if slot.is_multiple_of(2) { Team::Red } else { Team::Blue }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Team::Red => "Red",
+            Team::Blue => "Blue",
+        }
+    }
+
+    // multiplied into the car sprite, light enough that the skin still shows through
+    pub fn tint(&self) -> Color {
+        match self {
+            Team::Red => Color::srgb(1.0, 0.55, 0.55),
+            Team::Blue => Color::srgb(0.55, 0.7, 1.0),
+        }
+    }
+
+    // darker version for text on the white menus
+    pub fn text_color(&self) -> Color {
+        match self {
+            Team::Red => Color::srgb_u8(190, 30, 30),
+            Team::Blue => Color::srgb_u8(30, 70, 190),
+        }
+    }
+}
+
+/// Cars that race against each other: everyone outside of a team race,
+/// otherwise only cars on the other team. These are also who the
+/// disruptive CPUs gang up on.
+pub fn rivals(a: Option<Team>, b: Option<Team>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a != b,
+        _ => true,
+    }
+}
+
+// Which team every car in the current race is on, empty outside team races
+#[derive(Resource, Default, Clone, Debug)]
+pub struct TeamAssignments(pub HashMap<u32, Team>);
+
+impl TeamAssignments {
+    pub fn team_of(&self, id: u32) -> Option<Team> {
+        self.0.get(&id).copied()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TeamScores {
+    pub red: u32,
+    pub blue: u32,
+}
+
+impl TeamScores {
+    /// Add up each team's points from the final standings
+    pub fn from_results(results: &[RaceResultEntry], teams: &HashMap<u32, Team>) -> Self {
+        let mut scores = Self::default();
+        for entry in results {
+            let Some(team) = teams.get(&entry.id) else {
+                continue;
+            };
+            let points = if entry.dnf {
+                0
+            } else {
+                TEAM_POINTS
+                    .get((entry.position as usize).saturating_sub(1))
+                    .copied()
+                    .unwrap_or(0)
+            };
+            match team {
+                Team::Red => scores.red += points,
+                Team::Blue => scores.blue += points,
+            }
+        }
+        scores
+    }
+
+    /// None on a draw
+    pub fn winner(&self) -> Option<Team> {
+        match self.red.cmp(&self.blue) {
+            std::cmp::Ordering::Greater => Some(Team::Red),
+            std::cmp::Ordering::Less => Some(Team::Blue),
+            std::cmp::Ordering::Equal => None,
+        }
+    }
+}
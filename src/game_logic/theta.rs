@@ -1,20 +1,12 @@
 use crate::game_logic::theta_grid::ThetaGrid;
 use crate::networking::MapChoice;
 use bevy::prelude::Component;
-use rand::Rng;
-use std::collections::{BinaryHeap, HashMap, HashSet};
-use std::cmp::Ordering;
-use crate::game_logic::TILE_SIZE;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use crate::game_logic::{DifficultyProfile, PathSearch, TILE_SIZE, theta_star_bounded};
 
-#[derive(Default)]
-pub enum ThetaCommand {
-    #[default]
-    Stop,
-    Forward,
-    Reverse,
-    TurnLeft,
-    TurnRight,
-}
+// Most nodes a detour round a car or hazard may expand before the car gives up on it
+pub const DETOUR_EXPANSIONS: usize = 400;
 
 #[derive(Clone)]
 pub struct ThetaCheckpoint {
@@ -26,6 +18,11 @@ impl ThetaCheckpoint {
     pub fn new(point1: (f32, f32), point2: (f32, f32)) -> Self {
         Self { point1, point2 }
     }
+
+    // both ends of the gate in world coordinates
+    pub fn world_ends(&self, grid: &ThetaGrid) -> ((f32, f32), (f32, f32)) {
+        (tile_to_world(grid, self.point1), tile_to_world(grid, self.point2))
+    }
 }
 
 #[derive(Component)]
@@ -35,6 +32,17 @@ pub struct ThetaCheckpointList {
     pub cached_path: Vec<(usize, usize)>,
     pub path_index: usize,
     pub target_world_pos: Option<(f32, f32)>,
+    pub target_offset: (f32, f32), // added to the point being steered at, set from the car's personality
+    pub line_index: Option<usize>,  // closest racing line point last frame, None until the car finds the line
+    pub line_wander: f32,           // how far to the side of the racing line the car is driving
+    pub line_target: Option<(f32, f32)>, // point on the racing line it last steered at
+    pub line_path: Vec<(f32, f32)>, // stretch of the racing line it's following, detours and all
+    pub path_request: Option<((usize, usize), (usize, usize))>, // start and goal of a path it's waiting to search for
+    pub path_search: Option<PathSearch>, // search running off the main thread, see path_planning
+    pub path_failed: bool,               // the last search for this checkpoint found no way through
+    pub detour_failed_at: Option<usize>, // path index it last couldn't find a way round something at
+    pub line_detour_failed_at: Option<usize>, // racing line point it last couldn't get round something at
+    pub rng: StdRng, // where it aims across gates and wanders about the line, seeded from the OS unless a test says otherwise
 }
 
 impl ThetaCheckpointList {
@@ -45,12 +53,38 @@ impl ThetaCheckpointList {
             cached_path: Vec::new(),
             path_index: 0,
             target_world_pos: None,
+            target_offset: (0.0, 0.0),
+            line_index: None,
+            line_wander: 0.0,
+            line_target: None,
+            line_path: Vec::new(),
+            path_request: None,
+            path_search: None,
+            path_failed: false,
+            detour_failed_at: None,
+            line_detour_failed_at: None,
+            rng: StdRng::from_os_rng(),
         }
     }
 
+    /// The point on the racing line or path the car is heading for right now, before any offset
+    pub fn current_waypoint(&self, grid: &ThetaGrid) -> Option<(f32, f32)> {
+        self.line_target.or_else(|| {
+            self.cached_path
+                .get(self.path_index)
+                .and_then(|&(x, y)| grid.get_node(x, y))
+                .map(|node| (node.world_x, node.world_y))
+                .or(self.target_world_pos)
+        })
+    }
+
     pub fn advance_checkpoint(&mut self) {
         self.current_checkpoint_index =
             (self.current_checkpoint_index + 1) % self.checkpoints.len();
+        // anything still being searched for was for the old checkpoint, dropping it cancels it
+        self.path_request = None;
+        self.path_search = None;
+        self.path_failed = false;
     }
 
     /// Convenience wrapper: load checkpoints based on a MapChoice instead of a numeric ID.
@@ -69,6 +103,8 @@ impl ThetaCheckpointList {
             checkpoints.push(ThetaCheckpoint::new((91.0, 10.0), (94.0, 10.0)));
             checkpoints.push(ThetaCheckpoint::new((85.0, 9.0), (85.0, 5.0)));
             checkpoints.push(ThetaCheckpoint::new((59.0, 5.0), (59.0, 8.0)));
+            // on the second lap gate, cutting the corner slips past the end of it
+            checkpoints.push(ThetaCheckpoint::new((56.0, 5.5), (56.0, 8.5)));
             checkpoints.push(ThetaCheckpoint::new((54.0, 11.0), (57.0, 11.0)));
             checkpoints.push(ThetaCheckpoint::new((54.0, 20.0), (52.0, 18.0)));
             checkpoints.push(ThetaCheckpoint::new((49.0, 30.0), (52.0, 30.0)));
@@ -90,6 +126,8 @@ impl ThetaCheckpointList {
             checkpoints.push(ThetaCheckpoint::new((60.0, 91.0), (60.0, 94.0)));
             checkpoints.push(ThetaCheckpoint::new((89.0, 91.0), (89.0, 94.0)));
             checkpoints.push(ThetaCheckpoint::new((91.0, 89.0), (94.0, 89.0)));
+            // on the last lap gate, keeps the AI on the road through it instead of across the grass
+            checkpoints.push(ThetaCheckpoint::new((80.5, 51.5), (84.5, 51.5)));
             checkpoints.push(ThetaCheckpoint::new((91.0, 34.0), (93.0, 44.0)));
         } else if (map_num == 2) {
             checkpoints.push(ThetaCheckpoint::new((86.0, 71.5), (86.0, 74.5)));
@@ -142,6 +180,8 @@ impl ThetaCheckpointList {
             checkpoints.push(ThetaCheckpoint::new((109.5, 89.0), (112.5, 89.0)));
             checkpoints.push(ThetaCheckpoint::new((102.0, 81.5), (102.0, 84.5)));
             checkpoints.push(ThetaCheckpoint::new((87.0, 80.5), (87.0, 83.5)));
+            // just past the finish line, cutting straight to the first gate misses it
+            checkpoints.push(ThetaCheckpoint::new((81.5, 79.5), (84.5, 79.5)));
         } else {
             panic!("Invalid map num: {}", map_num);
         }
@@ -149,277 +189,36 @@ impl ThetaCheckpointList {
     }
 }
 
-pub fn get_next_point(list: &ThetaCheckpointList, grid: &ThetaGrid) -> (f32, f32) {
-    let mut rng = rand::thread_rng();
-
+// A target somewhere on the current checkpoint's gate. A spread of 1 may aim anywhere
+// across it, smaller values keep closer to the middle.
+pub fn get_next_point(list: &mut ThetaCheckpointList, grid: &ThetaGrid, spread: f32) -> (f32, f32) {
     let curr_checkpoint: ThetaCheckpoint = list.checkpoints[list.current_checkpoint_index].clone();
 
-    let rand_x_tile: f32 = if curr_checkpoint.point1.0 < curr_checkpoint.point2.0 {
-        rng.gen_range(curr_checkpoint.point1.0..=curr_checkpoint.point2.0)
-    } else {
-        rng.gen_range(curr_checkpoint.point2.0..=curr_checkpoint.point1.0)
-    };
-
-    let rand_y_tile: f32 = if curr_checkpoint.point1.1 < curr_checkpoint.point2.1 {
-        rng.gen_range(curr_checkpoint.point1.1..=curr_checkpoint.point2.1)
-    } else {
-        rng.gen_range(curr_checkpoint.point2.1..=curr_checkpoint.point1.1)
-    };
-
-    // Convert tile coordinates to world coordinates
-    let world_x = (rand_x_tile * TILE_SIZE as f32) - (grid.width as f32 * TILE_SIZE as f32 / 2.0) + (TILE_SIZE as f32 / 2.0);
-    let world_y = -((rand_y_tile * TILE_SIZE as f32) - (grid.height as f32 * TILE_SIZE as f32 / 2.0) + (TILE_SIZE as f32 / 2.0));
-
-
-    return (world_x, world_y);
-}
-
-// Helper function to calculate steering command toward a target position
-pub fn calculate_steering_command(
-    start_pos: (f32, f32),
-    target_pos: (f32, f32),
-    current_angle: f32,
-) -> ThetaCommand {
-    let dx = target_pos.0 - start_pos.0;
-    let dy = target_pos.1 - start_pos.1;
-
-    // Negate dy because tile Y-axis is flipped (increasing Y goes down in world space)
-    let target_angle = dy.atan2(dx);
-
-    // Normalize current angle
-    let pi = std::f32::consts::PI;
-    let mut current_normalized = current_angle % (2.0 * pi);
-    if current_normalized > pi {
-        current_normalized -= 2.0 * pi;
-    } else if current_normalized < -pi {
-        current_normalized += 2.0 * pi;
-    }
-
-    let mut angle_diff = target_angle - current_normalized;
-
-    if angle_diff > std::f32::consts::PI {
-        angle_diff -= 2.0 * std::f32::consts::PI;
-    } else if angle_diff < -std::f32::consts::PI {
-        angle_diff += 2.0 * std::f32::consts::PI;
-    }
-
-    // Give it some wiggle room so it doesn't oscillate (Greyson's idea)
-    let angle_threshold = 0.1; // radians (~5.7 degrees)
-    let reverse_threshold = pi * 0.85; // ~153 degrees - only reverse if target is almost directly behind
-
-    if angle_diff.abs() > reverse_threshold {
-        ThetaCommand::Reverse
-    } else if angle_diff.abs() < angle_threshold {
-        ThetaCommand::Forward
-    } else if angle_diff > 0.0 {
-        ThetaCommand::TurnLeft
-    } else {
-        ThetaCommand::TurnRight
-    }
-}
-
-pub fn track_to_position(start_pos: (f32, f32), current_angle: f32, checkpoints: &mut ThetaCheckpointList, grid: &ThetaGrid) -> ThetaCommand {
-    if checkpoints.checkpoints.is_empty() {
-        return ThetaCommand::Stop;
-    }
-
-    //Grab the current checkpoint from the checkpoint list
-    let end_pos = get_next_point(&checkpoints, &grid);
-
-    //Calc that distance
-    let dx = end_pos.0 - start_pos.0;
-    let dy = end_pos.1 - start_pos.1;
-    let distance = (dx * dx + dy * dy).sqrt();
-
-    let goal_threshold = 5.0; // pixels
-    if distance < goal_threshold {
-        println!("ADVANCE");
-        checkpoints.advance_checkpoint();
-    }
+    let t = 0.5 + list.rng.random_range(-0.5..=0.5) * spread.clamp(0.0, 1.0);
+    let rand_x_tile = curr_checkpoint.point1.0 + (curr_checkpoint.point2.0 - curr_checkpoint.point1.0) * t;
+    let rand_y_tile = curr_checkpoint.point1.1 + (curr_checkpoint.point2.1 - curr_checkpoint.point1.1) * t;
 
-    calculate_steering_command(start_pos, end_pos, current_angle)
+    tile_to_world(grid, (rand_x_tile, rand_y_tile))
 }
 
-// Node for priority queue in Theta*
-#[derive(Clone, Copy, PartialEq)]
-struct Node {
-    pos: (usize, usize),
-    f_score: f32,
+// Convert (fractional) tile coordinates to world coordinates
+pub fn tile_to_world(grid: &ThetaGrid, tile: (f32, f32)) -> (f32, f32) {
+    let world_x = (tile.0 * TILE_SIZE as f32) - (grid.width as f32 * TILE_SIZE as f32 / 2.0) + (TILE_SIZE as f32 / 2.0);
+    let world_y = -((tile.1 * TILE_SIZE as f32) - (grid.height as f32 * TILE_SIZE as f32 / 2.0) + (TILE_SIZE as f32 / 2.0));
+    (world_x, world_y)
 }
 
-impl Eq for Node {}
-
-impl PartialOrd for Node {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        other.f_score.partial_cmp(&self.f_score)
-    }
-}
-
-impl Ord for Node {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap_or(Ordering::Equal)
-    }
-}
-
-// Euclidean distance
-fn heuristic(pos: (usize, usize), goal: (usize, usize)) -> f32 {
-    let dx = (pos.0 as f32 - goal.0 as f32).abs();
-    let dy = (pos.1 as f32 - goal.1 as f32).abs();
-    (dx * dx + dy * dy).sqrt()
-}
-
-// Compute the cost between two grid positions considering terrain
-fn movement_cost(grid: &ThetaGrid, from: (usize, usize), to: (usize, usize)) -> f32 {
-    if let Some(node) = grid.get_node(to.0, to.1) {
-        let dx = (to.0 as f32 - from.0 as f32).abs();
-        let dy = (to.1 as f32 - from.1 as f32).abs();
-        let distance = (dx * dx + dy * dy).sqrt();
-        distance * node.cost
-    } else {
-        f32::INFINITY
-    }
-}
-
-// Theta* pathfinding algorithm
-pub fn theta_star_generator(
-    grid: &ThetaGrid,
-    start: (usize, usize),
-    goal: (usize, usize),
-) -> Option<Vec<(usize, usize)>> {
-    let mut open = BinaryHeap::new();
-    let mut closed = HashSet::new();
-    let mut g_score: HashMap<(usize, usize), f32> = HashMap::new();
-    let mut parent: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
-
-    // Initialize start node
-    g_score.insert(start, 0.0);
-    parent.insert(start, start);
-    open.push(Node {
-        pos: start,
-        f_score: heuristic(start, goal),
-    });
-
-    while let Some(current_node) = open.pop() {
-        let current = current_node.pos;
-
-        // Goal reached
-        if current == goal {
-            return Some(reconstruct_path(&parent, current));
-        }
-
-        // Skip if already processed
-        if closed.contains(&current) {
-            continue;
-        }
-        closed.insert(current);
-
-        // Process neighbors
-        let neighbors = grid.get_neighbors(current.0, current.1);
-        for neighbor_node in neighbors {
-            let neighbor = (neighbor_node.x, neighbor_node.y);
-
-            if closed.contains(&neighbor) {
-                continue;
-            }
-
-            // Initialize neighbor if not seen before
-            g_score.entry(neighbor).or_insert(f32::INFINITY);
-
-            // Update vertex (Theta* logic)
-            update_vertex(grid, &mut g_score, &mut parent, &mut open, current, neighbor, goal);
-        }
-    }
-
-    None // No path found
-}
-
-// Update vertex with Theta* logic
-fn update_vertex(
-    grid: &ThetaGrid,
-    g_score: &mut HashMap<(usize, usize), f32>,
-    parent: &mut HashMap<(usize, usize), (usize, usize)>,
-    open: &mut BinaryHeap<Node>,
-    current: (usize, usize),
-    neighbor: (usize, usize),
-    goal: (usize, usize),
-) {
-    let g_old = *g_score.get(&neighbor).unwrap_or(&f32::INFINITY);
-
-    // Compute cost using Theta* logic
-    compute_cost(grid, g_score, parent, current, neighbor);
-
-    let g_new = *g_score.get(&neighbor).unwrap_or(&f32::INFINITY);
-
-    // If we found a better path, add to open set
-    if g_new < g_old {
-        let f_score = g_new + heuristic(neighbor, goal);
-        open.push(Node {
-            pos: neighbor,
-            f_score,
-        });
-    }
-}
-
-// Compute cost with line-of-sight optimization
-fn compute_cost(
-    grid: &ThetaGrid,
-    g_score: &mut HashMap<(usize, usize), f32>,
-    parent: &mut HashMap<(usize, usize), (usize, usize)>,
-    current: (usize, usize),
-    neighbor: (usize, usize),
-) {
-    let current_parent = *parent.get(&current).unwrap_or(&current);
-
-    // Path 2: Try to connect neighbor directly to current's parent (any-angle path)
-    if grid.line_of_sight((current_parent.0 as f32, current_parent.1 as f32),
-                          (neighbor.0 as f32, neighbor.1 as f32)) {
-        let g_parent = *g_score.get(&current_parent).unwrap_or(&f32::INFINITY);
-        let cost = movement_cost(grid, current_parent, neighbor);
-        let new_g = g_parent + cost;
-
-        if new_g < *g_score.get(&neighbor).unwrap_or(&f32::INFINITY) {
-            parent.insert(neighbor, current_parent);
-            g_score.insert(neighbor, new_g);
-        }
-    } else {
-        // Path 1: Connect neighbor to current (grid-aligned path)
-        let g_current = *g_score.get(&current).unwrap_or(&f32::INFINITY);
-        let cost = movement_cost(grid, current, neighbor);
-        let new_g = g_current + cost;
-
-        if new_g < *g_score.get(&neighbor).unwrap_or(&f32::INFINITY) {
-            parent.insert(neighbor, current);
-            g_score.insert(neighbor, new_g);
-        }
-    }
-}
-
-// Reconstruct the path from parent pointers
-fn reconstruct_path(
-    parent: &HashMap<(usize, usize), (usize, usize)>,
-    mut current: (usize, usize),
-) -> Vec<(usize, usize)> {
-    let mut path = vec![current];
-    while let Some(&p) = parent.get(&current) {
-        if p == current {
-            break; // Reached start
-        }
-        current = p;
-        path.push(current);
-    }
-    path.reverse();
-    path
-}
-
-// Wrapper function for Theta*
-pub fn theta_star(
+/// Where to head for next along the Theta* path, moved over for the car's personality.
+/// Keeps the path and checkpoint up to date as the car goes, None right after moving
+/// on to the next checkpoint (or if there aren't any).
+pub fn theta_star_target(
     start_pos: (f32, f32),
-    current_angle: f32,
     checkpoints: &mut ThetaCheckpointList,
     grid: &ThetaGrid,
-) -> ThetaCommand {
+    profile: &DifficultyProfile,
+) -> Option<(f32, f32)> {
     if checkpoints.checkpoints.is_empty() {
-        return ThetaCommand::Stop;
+        return None;
     }
 
     // Get or use cached target position for current checkpoint
@@ -427,28 +226,22 @@ pub fn theta_star(
         cached_target
     } else {
         // Generate new random point for this checkpoint
-        let new_target = get_next_point(checkpoints, grid);
+        let new_target = get_next_point(checkpoints, grid, profile.aim_spread);
         checkpoints.target_world_pos = Some(new_target);
         new_target
     };
 
-    // Check if we need to recompute the path
-    let needs_recompute = checkpoints.cached_path.is_empty();
-
-    if needs_recompute {
-        // Convert world positions to grid coordinates
-        let start_grid = grid.world_to_grid(start_pos.0, start_pos.1);
-        let goal_grid = grid.world_to_grid(target_pos.0, target_pos.1);
-
-        println!("Computing path from grid {:?} to grid {:?} (world: {:?} to {:?})",
-                 start_grid, goal_grid, start_pos, target_pos);
+    // Without a path, ask for one and steer straight at the target until it arrives.
+    // The search runs off the main thread, see path_planning.
+    if checkpoints.cached_path.is_empty() {
+        if checkpoints.path_search.is_none() {
+            let start_grid = grid.world_to_grid(start_pos.0, start_pos.1);
+            let goal_grid = grid.world_to_grid(target_pos.0, target_pos.1);
+            checkpoints.path_request = Some((start_grid, goal_grid));
+        }
 
-        // Run Theta* pathfinding
-        if let Some(path) = theta_star_generator(grid, start_grid, goal_grid) {
-            checkpoints.cached_path = path;
-            checkpoints.path_index = 0;
-        } else {
-            // No path found, fall back to direct pursuit and check for checkpoint advance
+        // No path found, check for checkpoint advance
+        if checkpoints.path_failed {
             let dx = target_pos.0 - start_pos.0;
             let dy = target_pos.1 - start_pos.1;
             let distance = (dx * dx + dy * dy).sqrt();
@@ -456,11 +249,10 @@ pub fn theta_star(
 
             if distance < goal_threshold {
                 checkpoints.advance_checkpoint();
-                checkpoints.cached_path.clear();
                 checkpoints.target_world_pos = None;
             }
-            return steer_towards(start_pos, current_angle, target_pos, checkpoints);
         }
+        return Some(offset_by(target_pos, checkpoints.target_offset));
     }
 
     // Follow the cached path
@@ -469,9 +261,11 @@ pub fn theta_star(
         checkpoints.advance_checkpoint();
         checkpoints.cached_path.clear();
         checkpoints.target_world_pos = None;
-        return ThetaCommand::Forward;
+        return None;
     }
 
+    avoid_obstacles(start_pos, checkpoints, grid);
+
     // Get the next waypoint in the path
     let next_grid_pos = checkpoints.cached_path[checkpoints.path_index];
     if let Some(node) = grid.get_node(next_grid_pos.0, next_grid_pos.1) {
@@ -491,25 +285,61 @@ pub fn theta_star(
                 checkpoints.advance_checkpoint();
                 checkpoints.cached_path.clear();
                 checkpoints.target_world_pos = None;
-                return ThetaCommand::Forward;
+                return None;
             }
         }
 
-        // Steer towards current waypoint
-        steer_towards(start_pos, current_angle, waypoint, checkpoints)
+        // Steer towards current waypoint, moved over for the car's personality
+        Some(offset_by(waypoint, checkpoints.target_offset))
     } else {
         // Invalid waypoint, recompute
         checkpoints.cached_path.clear();
-        steer_towards(start_pos, current_angle, target_pos, checkpoints)
+        Some(offset_by(target_pos, checkpoints.target_offset))
     }
 }
 
-// Helper function to steer towards a target position
-fn steer_towards(
-    start_pos: (f32, f32),
-    current_angle: f32,
-    target_pos: (f32, f32),
-    _checkpoints: &ThetaCheckpointList,
-) -> ThetaCommand {
-    calculate_steering_command(start_pos, target_pos, current_angle)
+// If a car or hazard is in the way of the next waypoint, splice in a detour from here to the
+// first waypoint past it. A car that can't find a way round keeps its path until the next
+// waypoint.
+fn avoid_obstacles(start_pos: (f32, f32), checkpoints: &mut ThetaCheckpointList, grid: &ThetaGrid) {
+    let index = checkpoints.path_index;
+    if checkpoints.detour_failed_at == Some(index) {
+        return;
+    }
+    let here = grid.world_to_grid(start_pos.0, start_pos.1);
+    let path = &checkpoints.cached_path;
+    if !grid.line_blocked(here, path[index], here) {
+        return;
+    }
+
+    match bounded_detour(grid, here, path, index) {
+        Some((rejoin, mut detour)) => {
+            detour.extend_from_slice(&path[rejoin + 1..]);
+            checkpoints.cached_path = detour;
+            checkpoints.path_index = 0;
+            checkpoints.detour_failed_at = None;
+        }
+        None => checkpoints.detour_failed_at = Some(index),
+    }
+}
+
+/// A way from `here` round whatever's in the way at `path[blocked]`, rejoining the path at
+/// the first clear cell past it (or its end), as that index and the cells to it. The detour
+/// has to be found inside the frame so the search is cut short, None if it ran out first.
+pub fn bounded_detour(
+    grid: &ThetaGrid,
+    here: (usize, usize),
+    path: &[(usize, usize)],
+    blocked: usize,
+) -> Option<(usize, Vec<(usize, usize)>)> {
+    let clear = |&(x, y): &(usize, usize)| grid.dynamic_cost(grid.index(x, y)) == 0.0;
+    let rejoin = (blocked + 1..path.len())
+        .find(|&i| clear(&path[i]))
+        .unwrap_or(path.len() - 1);
+    let detour = theta_star_bounded(grid, here, path[rejoin], DETOUR_EXPANSIONS)?;
+    Some((rejoin, detour))
+}
+
+fn offset_by(pos: (f32, f32), offset: (f32, f32)) -> (f32, f32) {
+    (pos.0 + offset.0, pos.1 + offset.1)
 }
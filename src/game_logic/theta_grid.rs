@@ -1,6 +1,7 @@
 use crate::game_logic::map::GameMap;
 use crate::game_logic::terrain::TerrainTile;
 use bevy::prelude::Resource;
+use std::sync::Arc;
 
 /// Grid node for Theta* pathfinding
 #[derive(Debug, Clone)]
@@ -11,6 +12,7 @@ pub struct GridNode {
     pub world_y: f32,
     pub passable: bool,
     pub cost: f32,
+    pub speed: f32, // terrain's speed modifier, 0 for walls
 }
 
 #[derive(Resource, Clone)]
@@ -18,9 +20,28 @@ pub struct ThetaGrid {
     pub width: usize,
     pub height: usize,
     pub tile_size: f32,
-    nodes: Vec<Vec<GridNode>>,
+    // row by row, node (x, y) is at y * width + x. Shared so clones handed to path searches are cheap
+    nodes: Arc<Vec<GridNode>>,
+    // extra cost of driving through each node from cars and hazards sitting on it, remarked every frame
+    dynamic_costs: Arc<Vec<f32>>,
+    any_dynamic_costs: bool,
 }
 
+// Added to a node's cost for each car or hazard on it, enough that paths go round rather than through
+pub const OBSTACLE_COST: f32 = 50.0;
+
+// Offsets to the eight neighbours, in the order searches expand them
+const NEIGHBOR_OFFSETS: [(i32, i32); 8] = [
+    (-1, -1),
+    (0, -1),
+    (1, -1),
+    (-1, 0),
+    (1, 0),
+    (-1, 1),
+    (0, 1),
+    (1, 1),
+];
+
 impl ThetaGrid {
     pub fn create_theta_grid(game_map: &GameMap, tile_size: f32) -> Self {
         let height = game_map.terrain_layer.len();
@@ -43,10 +64,9 @@ impl ThetaGrid {
             1
         };
 
-        let mut nodes = Vec::with_capacity(grid_height);
+        let mut nodes = Vec::with_capacity(grid_width * grid_height);
 
         for y in 0..grid_height {
-            let mut row = Vec::with_capacity(grid_width);
             for x in 0..grid_width {
                 // Clamp to map dimensions to avoid OOB if requested grid is larger/smaller
                 let tx = x.min(terrain_width - 1);
@@ -61,23 +81,25 @@ impl ThetaGrid {
                 // Calculate movement cost from terrain modifiers
                 let cost = Self::calculate_node_cost(&terrain);
 
-                row.push(GridNode {
+                nodes.push(GridNode {
                     x,
                     y,
                     world_x,
                     world_y,
                     passable: terrain.passable,
                     cost,
+                    speed: if terrain.passable { terrain.speed_modifier } else { 0.0 },
                 });
             }
-            nodes.push(row);
         }
 
         ThetaGrid {
             width: grid_width,
             height: grid_height,
             tile_size,
-            nodes,
+            nodes: Arc::new(nodes),
+            dynamic_costs: Arc::new(vec![0.0; grid_width * grid_height]),
+            any_dynamic_costs: false,
         }
     }
 
@@ -101,12 +123,72 @@ impl ThetaGrid {
 
     pub fn get_node(&self, x: usize, y: usize) -> Option<&GridNode> {
         if x < self.width && y < self.height {
-            Some(&self.nodes[y][x])
+            Some(&self.nodes[self.index(x, y)])
         } else {
             None
         }
     }
 
+    /// Where node (x, y) sits in the flat node list, for searches that keep their own
+    /// per-node arrays. Only meaningful for coordinates inside the grid.
+    pub fn index(&self, x: usize, y: usize) -> usize {
+        y * self.width + x
+    }
+
+    pub fn node_at(&self, index: usize) -> &GridNode {
+        &self.nodes[index]
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Forget every car and hazard marked so far, ready for this frame's
+    pub fn clear_dynamic_costs(&mut self) {
+        if self.any_dynamic_costs {
+            Arc::make_mut(&mut self.dynamic_costs).fill(0.0);
+            self.any_dynamic_costs = false;
+        }
+    }
+
+    /// Mark the nodes under something `radius` pixels either side of `world` as costing
+    /// `cost` more to drive through
+    pub fn add_dynamic_cost(&mut self, world: (f32, f32), radius: f32, cost: f32) {
+        // only just touching a node's edge doesn't count as being on it
+        let reach = (radius - 1.0).max(0.0);
+        let (min_x, min_y) = self.world_to_grid(world.0 - reach, world.1 + reach);
+        let (max_x, max_y) = self.world_to_grid(world.0 + reach, world.1 - reach);
+        let width = self.width;
+        self.any_dynamic_costs = true;
+        let costs = Arc::make_mut(&mut self.dynamic_costs);
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                costs[y * width + x] += cost;
+            }
+        }
+    }
+
+    pub fn dynamic_cost(&self, index: usize) -> f32 {
+        self.dynamic_costs[index]
+    }
+
+    /// Whether a car or hazard is marked anywhere on the straight line between two nodes.
+    /// Nodes next to `own` (the cell the asking car is in) don't count, that's the car itself.
+    pub fn line_blocked(&self, from: (usize, usize), to: (usize, usize), own: (usize, usize)) -> bool {
+        self.any_dynamic_costs
+            && !self.trace_line(
+                (from.0 as f32, from.1 as f32),
+                (to.0 as f32, to.1 as f32),
+                |x, y| nodes_touch(own, (x, y)) || self.dynamic_costs[self.index(x, y)] == 0.0,
+            )
+    }
+
+    /// How fast a car can go on the ground at a world position, 0 for walls
+    pub fn ground_speed(&self, world: (f32, f32)) -> f32 {
+        let (x, y) = self.world_to_grid(world.0, world.1);
+        self.nodes[self.index(x, y)].speed
+    }
+
     pub fn world_to_grid(&self, world_x: f32, world_y: f32) -> (usize, usize) {
         let map_x = world_x + (self.width as f32 * self.tile_size / 2.0);
         let map_y = -world_y + (self.height as f32 * self.tile_size / 2.0);
@@ -120,34 +202,35 @@ impl ThetaGrid {
         )
     }
 
-    pub fn get_neighbors(&self, x: usize, y: usize) -> Vec<&GridNode> {
-        let mut neighbors = Vec::with_capacity(8);
-
-        for dy in -1..=1 {
-            for dx in -1..=1 {
-                if dx == 0 && dy == 0 {
-                    continue; // Skip the center node
-                }
-
-                let nx = x as i32 + dx;
-                let ny = y as i32 + dy;
-
-                if nx >= 0 && ny >= 0 {
-                    if let Some(node) = self.get_node(nx as usize, ny as usize) {
-                        if node.passable {
-                            neighbors.push(node);
-                        }
-                    }
-                }
+    /// The passable nodes around (x, y), without allocating
+    pub fn neighbors(&self, x: usize, y: usize) -> impl Iterator<Item = &GridNode> + '_ {
+        NEIGHBOR_OFFSETS.iter().filter_map(move |&(dx, dy)| {
+            let nx = x as i32 + dx;
+            let ny = y as i32 + dy;
+            if nx < 0 || ny < 0 {
+                return None;
             }
-        }
-
-        neighbors
+            self.get_node(nx as usize, ny as usize)
+                .filter(|node| node.passable)
+        })
     }
 
     // Based on Wikipedia pseudocode https://en.wikipedia.org/wiki/Theta* (All Greyson's code. I just ripped it from Map.rs)
-    pub fn line_of_sight(&self, point1: (f32, f32), point2: (f32, f32)) -> bool
-    {
+    pub fn line_of_sight(&self, point1: (f32, f32), point2: (f32, f32)) -> bool {
+        // If we find a wall or go out of bounds, no LOS
+        self.trace_line(point1, point2, |x, y| {
+            self.get_node(x, y).is_some_and(|node| node.passable)
+        })
+    }
+
+    // Walk the tiles on a line between two points, stopping early if `clear` says one isn't.
+    // True if every tile was clear.
+    fn trace_line(
+        &self,
+        point1: (f32, f32),
+        point2: (f32, f32),
+        mut clear: impl FnMut(usize, usize) -> bool,
+    ) -> bool {
         let mut x0 = point1.0 as usize;
         let mut y0 = point1.1 as usize;
         let x1 = point2.0 as usize;
@@ -160,14 +243,7 @@ impl ThetaGrid {
         let mut err = dx - dy;
 
         loop {
-            // Check current tile using nodes instead of terrain_layer
-            if let Some(node) = self.get_node(x0, y0) {
-                // If we found a wall, no LOS
-                if !node.passable {
-                    return false;
-                }
-            } else {
-                // Out of bounds
+            if x0 >= self.width || y0 >= self.height || !clear(x0, y0) {
                 return false;
             }
 
@@ -189,3 +265,15 @@ impl ThetaGrid {
         true
     }
 }
+
+/// Whether two nodes are the same or touching
+pub fn nodes_touch(a: (usize, usize), b: (usize, usize)) -> bool {
+    a.0.abs_diff(b.0) <= 1 && a.1.abs_diff(b.1) <= 1
+}
+
+/// map2 on the grid the client and server race it on, for tests
+#[cfg(test)]
+pub(crate) fn map2_grid() -> ThetaGrid {
+    let game_map = crate::game_logic::load_map_from_file("assets/map2.txt");
+    ThetaGrid::create_theta_grid_with_size(&game_map, crate::game_logic::TILE_SIZE as f32, 125, 125)
+}
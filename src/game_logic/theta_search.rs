@@ -0,0 +1,603 @@
+// src/game_logic/theta_search.rs
+use crate::game_logic::theta_grid::{ThetaGrid, nodes_touch};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::{BuildHasherDefault, Hasher};
+
+const NO_PARENT: usize = usize::MAX;
+
+// The line of sight cache is keyed by pairs of node indices, which don't need a
+// DoS-resistant hash. Multiplying by a large odd constant spreads them well enough.
+#[derive(Default)]
+struct IndexHasher(u64);
+
+impl Hasher for IndexHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.write_u64(byte as u64);
+        }
+    }
+
+    fn write_usize(&mut self, value: usize) {
+        self.write_u64(value as u64);
+    }
+
+    fn write_u64(&mut self, value: u64) {
+        self.0 = (self.0.rotate_left(5) ^ value).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+}
+
+// Node for priority queue in Theta*
+#[derive(Clone, Copy, PartialEq)]
+struct Node {
+    index: usize,
+    f_score: f32,
+}
+
+impl Eq for Node {}
+
+impl PartialOrd for Node {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Reversed so the heap pops the lowest f score first
+impl Ord for Node {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .f_score
+            .partial_cmp(&self.f_score)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Scratch space for Theta* searches, kept between searches so nothing is allocated
+/// once it has grown to the grid's size. Per-node state is indexed by the grid's flat
+/// node index and only counts when stamped with the current search's generation, so
+/// starting a new search doesn't have to clear anything.
+#[derive(Default)]
+pub struct ThetaSearch {
+    generation: u32,
+    start: (usize, usize), // cars and hazards marked right next to this are the searching car itself
+    seen: Vec<u32>,   // generation that last gave the node a g score and parent
+    closed: Vec<u32>, // generation that last expanded the node
+    g_score: Vec<f32>,
+    parent: Vec<usize>,
+    open: BinaryHeap<Node>,
+    // line of sight between two nodes, only the ground is checked so it holds for the whole search
+    line_of_sight: HashMap<(usize, usize), bool, BuildHasherDefault<IndexHasher>>,
+}
+
+thread_local! {
+    // one set of buffers per thread, so searches on different threads never wait on each other
+    static SEARCH: RefCell<ThetaSearch> = RefCell::new(ThetaSearch::default());
+}
+
+// Theta* pathfinding algorithm, using this thread's search buffers
+pub fn theta_star_generator(
+    grid: &ThetaGrid,
+    start: (usize, usize),
+    goal: (usize, usize),
+) -> Option<Vec<(usize, usize)>> {
+    SEARCH.with(|search| search.borrow_mut().find_path(grid, start, goal))
+}
+
+// Theta* that gives up after expanding `max_expansions` nodes, for short detours that
+// have to be found inside the frame
+pub fn theta_star_bounded(
+    grid: &ThetaGrid,
+    start: (usize, usize),
+    goal: (usize, usize),
+    max_expansions: usize,
+) -> Option<Vec<(usize, usize)>> {
+    SEARCH.with(|search| search.borrow_mut().find_path_bounded(grid, start, goal, max_expansions))
+}
+
+impl ThetaSearch {
+    /// Any-angle path from `start` to `goal` in grid coordinates, both ends included.
+    /// Goes round cars and hazards marked on the grid, other than the searching car
+    /// itself. None if the goal can't be reached.
+    pub fn find_path(
+        &mut self,
+        grid: &ThetaGrid,
+        start: (usize, usize),
+        goal: (usize, usize),
+    ) -> Option<Vec<(usize, usize)>> {
+        self.find_path_bounded(grid, start, goal, usize::MAX)
+    }
+
+    /// Like `find_path`, but None as well if the goal isn't found after expanding
+    /// `max_expansions` nodes
+    pub fn find_path_bounded(
+        &mut self,
+        grid: &ThetaGrid,
+        start: (usize, usize),
+        goal: (usize, usize),
+        max_expansions: usize,
+    ) -> Option<Vec<(usize, usize)>> {
+        if grid.get_node(start.0, start.1).is_none() || grid.get_node(goal.0, goal.1).is_none() {
+            return None;
+        }
+        self.begin(grid.node_count());
+        self.start = start;
+        let mut expansions = 0;
+
+        let start = grid.index(start.0, start.1);
+        let goal = grid.index(goal.0, goal.1);
+
+        // Initialize start node
+        self.visit(start, 0.0, start);
+        self.open.push(Node {
+            index: start,
+            f_score: heuristic(grid, start, goal),
+        });
+
+        while let Some(current_node) = self.open.pop() {
+            let current = current_node.index;
+
+            // Goal reached
+            if current == goal {
+                return Some(self.reconstruct_path(grid, current));
+            }
+
+            // Skip if already processed
+            if self.closed[current] == self.generation {
+                continue;
+            }
+            self.closed[current] = self.generation;
+            expansions += 1;
+            if expansions > max_expansions {
+                return None;
+            }
+
+            // Process neighbors
+            let (x, y) = (grid.node_at(current).x, grid.node_at(current).y);
+            for neighbor_node in grid.neighbors(x, y) {
+                let neighbor = grid.index(neighbor_node.x, neighbor_node.y);
+                if self.closed[neighbor] == self.generation {
+                    continue;
+                }
+                self.update_vertex(grid, current, neighbor, goal);
+            }
+        }
+
+        None // No path found
+    }
+
+    // Start a new search, growing the buffers if the grid is bigger than the last one
+    fn begin(&mut self, node_count: usize) {
+        if self.seen.len() < node_count {
+            self.seen.resize(node_count, 0);
+            self.closed.resize(node_count, 0);
+            self.g_score.resize(node_count, f32::INFINITY);
+            self.parent.resize(node_count, NO_PARENT);
+        }
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            // stamps from 2^32 searches ago would look current again, start them over
+            self.seen.fill(0);
+            self.closed.fill(0);
+            self.generation = 1;
+        }
+        self.open.clear();
+        self.line_of_sight.clear();
+    }
+
+    fn visit(&mut self, index: usize, g_score: f32, parent: usize) {
+        self.seen[index] = self.generation;
+        self.g_score[index] = g_score;
+        self.parent[index] = parent;
+    }
+
+    fn g(&self, index: usize) -> f32 {
+        if self.seen[index] == self.generation {
+            self.g_score[index]
+        } else {
+            f32::INFINITY
+        }
+    }
+
+    fn parent_of(&self, index: usize) -> usize {
+        if self.seen[index] == self.generation {
+            self.parent[index]
+        } else {
+            NO_PARENT
+        }
+    }
+
+    // Update vertex with Theta* logic
+    fn update_vertex(&mut self, grid: &ThetaGrid, current: usize, neighbor: usize, goal: usize) {
+        let g_old = self.g(neighbor);
+
+        // Path 2: Try to connect neighbor directly to current's parent (any-angle path),
+        // Path 1: otherwise connect neighbor to current (grid-aligned path)
+        let current_parent = match self.parent_of(current) {
+            NO_PARENT => current,
+            parent => parent,
+        };
+        let from = if self.has_line_of_sight(grid, current_parent, neighbor) {
+            current_parent
+        } else {
+            current
+        };
+        let g_new = self.g(from) + self.movement_cost(grid, from, neighbor);
+
+        // If we found a better path, add to open set
+        if g_new < g_old {
+            self.visit(neighbor, g_new, from);
+            self.open.push(Node {
+                index: neighbor,
+                f_score: g_new + heuristic(grid, neighbor, goal),
+            });
+        }
+    }
+
+    fn has_line_of_sight(&mut self, grid: &ThetaGrid, from: usize, to: usize) -> bool {
+        let start = self.start;
+        *self.line_of_sight.entry((from, to)).or_insert_with(|| {
+            let (a, b) = (grid.node_at(from), grid.node_at(to));
+            let (a, b) = ((a.x, a.y), (b.x, b.y));
+            // shortcuts don't cut through other cars either
+            grid.line_of_sight((a.0 as f32, a.1 as f32), (b.0 as f32, b.1 as f32))
+                && !grid.line_blocked(a, b, start)
+        })
+    }
+
+    // Compute the cost between two grid positions considering terrain and anything on it
+    fn movement_cost(&self, grid: &ThetaGrid, from: usize, to: usize) -> f32 {
+        let (a, b) = (grid.node_at(from), grid.node_at(to));
+        let dx = (b.x as f32 - a.x as f32).abs();
+        let dy = (b.y as f32 - a.y as f32).abs();
+        let distance = (dx * dx + dy * dy).sqrt();
+        let dynamic = if nodes_touch(self.start, (b.x, b.y)) {
+            0.0
+        } else {
+            grid.dynamic_cost(to)
+        };
+        distance * (b.cost + dynamic)
+    }
+
+    // Reconstruct the path from parent pointers
+    fn reconstruct_path(&self, grid: &ThetaGrid, mut current: usize) -> Vec<(usize, usize)> {
+        let mut path = Vec::new();
+        loop {
+            let node = grid.node_at(current);
+            path.push((node.x, node.y));
+            let parent = self.parent_of(current);
+            if parent == current || parent == NO_PARENT {
+                break; // Reached start
+            }
+            current = parent;
+        }
+        path.reverse();
+        path
+    }
+}
+
+// Euclidean distance
+fn heuristic(grid: &ThetaGrid, from: usize, goal: usize) -> f32 {
+    let (a, b) = (grid.node_at(from), grid.node_at(goal));
+    let dx = (a.x as f32 - b.x as f32).abs();
+    let dy = (a.y as f32 - b.y as f32).abs();
+    (dx * dx + dy * dy).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic::theta_grid::map2_grid;
+    use crate::game_logic::{OBSTACLE_COST, ThetaCheckpointList};
+    use std::time::Instant;
+
+    // From the middle of each of map2's AI gates to the middle of the next one, and to one
+    // a few further on, so both short hops and long searches round corners are covered
+    fn map2_searches(grid: &ThetaGrid) -> Vec<((usize, usize), (usize, usize))> {
+        let gates = ThetaCheckpointList::new(Vec::new()).load_checkpoint_list(2).checkpoints;
+        let middles: Vec<(usize, usize)> = gates
+            .iter()
+            .map(|gate| {
+                let (a, b) = gate.world_ends(grid);
+                grid.world_to_grid((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0)
+            })
+            .collect();
+        (0..middles.len())
+            .flat_map(|i| {
+                [
+                    (middles[i], middles[(i + 1) % middles.len()]),
+                    (middles[i], middles[(i + 5) % middles.len()]),
+                ]
+            })
+            .collect()
+    }
+
+    // Theta* exactly as it was in theta.rs before the search moved onto flat buffers,
+    // kept to check the new one against and to time it by
+    #[rustfmt::skip]
+    #[allow(clippy::non_canonical_partial_ord_impl)]
+    mod original {
+        use crate::game_logic::theta_grid::{GridNode, ThetaGrid};
+        use std::cmp::Ordering;
+        use std::collections::{BinaryHeap, HashMap, HashSet};
+
+        // the grid's neighbour lookup from back then, which collected them into a Vec
+        trait GetNeighbors {
+            fn get_neighbors(&self, x: usize, y: usize) -> Vec<&GridNode>;
+        }
+
+        impl GetNeighbors for ThetaGrid {
+            fn get_neighbors(&self, x: usize, y: usize) -> Vec<&GridNode> {
+                self.neighbors(x, y).collect()
+            }
+        }
+
+
+        // Node for priority queue in Theta*
+        #[derive(Clone, Copy, PartialEq)]
+        struct Node {
+            pos: (usize, usize),
+            f_score: f32,
+        }
+
+        impl Eq for Node {}
+
+        impl PartialOrd for Node {
+            fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+                other.f_score.partial_cmp(&self.f_score)
+            }
+        }
+
+        impl Ord for Node {
+            fn cmp(&self, other: &Self) -> Ordering {
+                self.partial_cmp(other).unwrap_or(Ordering::Equal)
+            }
+        }
+
+        // Euclidean distance
+        fn heuristic(pos: (usize, usize), goal: (usize, usize)) -> f32 {
+            let dx = (pos.0 as f32 - goal.0 as f32).abs();
+            let dy = (pos.1 as f32 - goal.1 as f32).abs();
+            (dx * dx + dy * dy).sqrt()
+        }
+
+        // Compute the cost between two grid positions considering terrain
+        fn movement_cost(grid: &ThetaGrid, from: (usize, usize), to: (usize, usize)) -> f32 {
+            if let Some(node) = grid.get_node(to.0, to.1) {
+                let dx = (to.0 as f32 - from.0 as f32).abs();
+                let dy = (to.1 as f32 - from.1 as f32).abs();
+                let distance = (dx * dx + dy * dy).sqrt();
+                distance * node.cost
+            } else {
+                f32::INFINITY
+            }
+        }
+
+        // Theta* pathfinding algorithm
+        pub fn theta_star_generator(
+            grid: &ThetaGrid,
+            start: (usize, usize),
+            goal: (usize, usize),
+        ) -> Option<Vec<(usize, usize)>> {
+            let mut open = BinaryHeap::new();
+            let mut closed = HashSet::new();
+            let mut g_score: HashMap<(usize, usize), f32> = HashMap::new();
+            let mut parent: HashMap<(usize, usize), (usize, usize)> = HashMap::new();
+
+            // Initialize start node
+            g_score.insert(start, 0.0);
+            parent.insert(start, start);
+            open.push(Node {
+                pos: start,
+                f_score: heuristic(start, goal),
+            });
+
+            while let Some(current_node) = open.pop() {
+                let current = current_node.pos;
+
+                // Goal reached
+                if current == goal {
+                    return Some(reconstruct_path(&parent, current));
+                }
+
+                // Skip if already processed
+                if closed.contains(&current) {
+                    continue;
+                }
+                closed.insert(current);
+
+                // Process neighbors
+                let neighbors = grid.get_neighbors(current.0, current.1);
+                for neighbor_node in neighbors {
+                    let neighbor = (neighbor_node.x, neighbor_node.y);
+
+                    if closed.contains(&neighbor) {
+                        continue;
+                    }
+
+                    // Initialize neighbor if not seen before
+                    g_score.entry(neighbor).or_insert(f32::INFINITY);
+
+                    // Update vertex (Theta* logic)
+                    update_vertex(grid, &mut g_score, &mut parent, &mut open, current, neighbor, goal);
+                }
+            }
+
+            None // No path found
+        }
+
+        // Update vertex with Theta* logic
+        fn update_vertex(
+            grid: &ThetaGrid,
+            g_score: &mut HashMap<(usize, usize), f32>,
+            parent: &mut HashMap<(usize, usize), (usize, usize)>,
+            open: &mut BinaryHeap<Node>,
+            current: (usize, usize),
+            neighbor: (usize, usize),
+            goal: (usize, usize),
+        ) {
+            let g_old = *g_score.get(&neighbor).unwrap_or(&f32::INFINITY);
+
+            // Compute cost using Theta* logic
+            compute_cost(grid, g_score, parent, current, neighbor);
+
+            let g_new = *g_score.get(&neighbor).unwrap_or(&f32::INFINITY);
+
+            // If we found a better path, add to open set
+            if g_new < g_old {
+                let f_score = g_new + heuristic(neighbor, goal);
+                open.push(Node {
+                    pos: neighbor,
+                    f_score,
+                });
+            }
+        }
+
+        // Compute cost with line-of-sight optimization
+        fn compute_cost(
+            grid: &ThetaGrid,
+            g_score: &mut HashMap<(usize, usize), f32>,
+            parent: &mut HashMap<(usize, usize), (usize, usize)>,
+            current: (usize, usize),
+            neighbor: (usize, usize),
+        ) {
+            let current_parent = *parent.get(&current).unwrap_or(&current);
+
+            // Path 2: Try to connect neighbor directly to current's parent (any-angle path)
+            if grid.line_of_sight((current_parent.0 as f32, current_parent.1 as f32),
+                                  (neighbor.0 as f32, neighbor.1 as f32)) {
+                let g_parent = *g_score.get(&current_parent).unwrap_or(&f32::INFINITY);
+                let cost = movement_cost(grid, current_parent, neighbor);
+                let new_g = g_parent + cost;
+
+                if new_g < *g_score.get(&neighbor).unwrap_or(&f32::INFINITY) {
+                    parent.insert(neighbor, current_parent);
+                    g_score.insert(neighbor, new_g);
+                }
+            } else {
+                // Path 1: Connect neighbor to current (grid-aligned path)
+                let g_current = *g_score.get(&current).unwrap_or(&f32::INFINITY);
+                let cost = movement_cost(grid, current, neighbor);
+                let new_g = g_current + cost;
+
+                if new_g < *g_score.get(&neighbor).unwrap_or(&f32::INFINITY) {
+                    parent.insert(neighbor, current);
+                    g_score.insert(neighbor, new_g);
+                }
+            }
+        }
+
+        // Reconstruct the path from parent pointers
+        fn reconstruct_path(
+            parent: &HashMap<(usize, usize), (usize, usize)>,
+            mut current: (usize, usize),
+        ) -> Vec<(usize, usize)> {
+            let mut path = vec![current];
+            while let Some(&p) = parent.get(&current) {
+                if p == current {
+                    break; // Reached start
+                }
+                current = p;
+                path.push(current);
+            }
+            path.reverse();
+            path
+        }
+    }
+
+    #[test]
+    fn same_paths_as_the_original_search() {
+        let grid = map2_grid();
+        let mut search = ThetaSearch::default();
+        // twice over, so the second pass runs on buffers left over from earlier searches
+        for _ in 0..2 {
+            for (start, goal) in map2_searches(&grid) {
+                assert_eq!(
+                    search.find_path(&grid, start, goal),
+                    original::theta_star_generator(&grid, start, goal),
+                    "paths differ from {:?} to {:?}",
+                    start,
+                    goal
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn paths_go_round_other_cars_but_not_the_searching_one() {
+        let mut grid = map2_grid();
+        let (start, goal) = map2_searches(&grid)[1];
+        let clear_path = theta_star_generator(&grid, start, goal).unwrap();
+
+        // a car parked halfway along the path's longest straight, and the searching car itself
+        let longest = clear_path
+            .windows(2)
+            .max_by_key(|pair| pair[0].0.abs_diff(pair[1].0) + pair[0].1.abs_diff(pair[1].1))
+            .unwrap();
+        let world = |(x, y): (usize, usize)| {
+            let node = grid.get_node(x, y).unwrap();
+            (node.world_x, node.world_y)
+        };
+        let (a, b) = (world(longest[0]), world(longest[1]));
+        let blocker = ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0);
+        let own = world(start);
+        grid.add_dynamic_cost(blocker, 32.0, OBSTACLE_COST);
+        grid.add_dynamic_cost(own, 32.0, OBSTACLE_COST);
+        assert!(grid.line_blocked(longest[0], longest[1], start));
+
+        let path = theta_star_generator(&grid, start, goal).unwrap();
+        assert_eq!(path.first(), Some(&start));
+        assert_eq!(path.last(), Some(&goal));
+        for pair in path.windows(2) {
+            assert!(
+                !grid.line_blocked(pair[0], pair[1], start),
+                "{:?} to {:?} drives through the parked car",
+                pair[0],
+                pair[1]
+            );
+        }
+
+        grid.clear_dynamic_costs();
+        assert_eq!(theta_star_generator(&grid, start, goal), Some(clear_path));
+    }
+
+    // cargo test --release bench_map2 -- --ignored --nocapture
+    // Last measured over map2's 102 searches: the original 1.30ms a search, flat buffers
+    // 0.26ms, about 5x faster
+    #[test]
+    #[ignore]
+    fn bench_map2_searches() {
+        const ROUNDS: usize = 20;
+        let grid = map2_grid();
+        let searches = map2_searches(&grid);
+
+        let timer = Instant::now();
+        for _ in 0..ROUNDS {
+            for &(start, goal) in &searches {
+                std::hint::black_box(original::theta_star_generator(&grid, start, goal));
+            }
+        }
+        let before = timer.elapsed();
+
+        let timer = Instant::now();
+        for _ in 0..ROUNDS {
+            for &(start, goal) in &searches {
+                std::hint::black_box(theta_star_generator(&grid, start, goal));
+            }
+        }
+        let after = timer.elapsed();
+
+        let per_search = |total: std::time::Duration| total / (ROUNDS * searches.len()) as u32;
+        println!(
+            "{} searches on map2: original {:?} per search, flat buffers {:?} per search, {:.1}x faster",
+            searches.len(),
+            per_search(before),
+            per_search(after),
+            before.as_secs_f64() / after.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,281 @@
+// src/game_logic/traffic.rs
+use crate::game_logic::CAR_SIZE;
+use crate::game_logic::theta_grid::ThetaGrid;
+use bevy::prelude::*;
+
+// Cars closer than this get nudged apart, about a car and a half
+const SEPARATION_RADIUS: f32 = 96.0;
+const SEPARATION_PUSH: f32 = 64.0;
+// How far up the road a CPU looks for a car to get past
+const OVERTAKE_LOOKAHEAD: f32 = 300.0;
+// Sitting this close behind a car, and no further off its line than this, it's in the car's tow
+const SLIPSTREAM_RANGE: f32 = 250.0;
+const SLIPSTREAM_WIDTH: f32 = CAR_SIZE as f32 / 2.0;
+// Top speed while in another car's tow
+const SLIPSTREAM_SCALE: f32 = 1.1;
+// A CPU stays in the tow this long before pulling out, or until it's about to run into the back of it
+const SLIPSTREAM_SECS: f32 = 1.0;
+const PULL_OUT_DISTANCE: f32 = 120.0;
+// Gap kept to the side of the car being passed, and how long a pass may take before it tucks back in
+const PASS_GAP: f32 = CAR_SIZE as f32 * 1.25;
+const PASS_SECS: f32 = 4.0;
+// Points checked between the line and where an offset moves the aim to
+const EDGE_SAMPLES: usize = 4;
+
+/// Another car on track, as an AI car sees it
+#[derive(Clone, Copy, Debug)]
+pub struct TrafficCar {
+    pub pos: Vec2,
+    pub velocity: Vec2,
+    pub rival: bool, // racing against it rather than on its team, see teams::rivals
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Overtake {
+    #[default]
+    Clear, // nobody slower in the way
+    Following(f32),    // in behind a slower car, for this long
+    Passing(f32, f32), // going by on this side (+1 left, -1 right), for this long
+}
+
+/// Keeps an AI car off the cars round it and gets it past slower ones: it tucks in
+/// behind to slipstream, then pulls out to the inside of the next bend (or the outside
+/// if there's no room) and goes by
+#[derive(Component, Default)]
+pub struct AiTraffic {
+    overtake: Overtake,
+    in_tow: bool,
+}
+
+impl AiTraffic {
+    /// What the car's top speed is scaled by for the tow it was in when it last steered
+    pub fn top_speed_scale(&self) -> f32 {
+        if self.in_tow { SLIPSTREAM_SCALE } else { 1.0 }
+    }
+
+    /// How far to move the point the car steers at (on top of anything its personality
+    /// wants), heading from `pos` towards `waypoint`. Offsets never take the aim onto
+    /// slower ground than the line it's moved off.
+    pub fn steer(
+        &mut self,
+        pos: Vec2,
+        velocity: Vec2,
+        waypoint: Vec2,
+        traffic: &[TrafficCar],
+        grid: &ThetaGrid,
+        delta_secs: f32,
+    ) -> Vec2 {
+        let direction = (waypoint - pos).normalize_or_zero();
+        let side = direction.perp();
+        let along = |car: &TrafficCar| (car.pos - pos).dot(direction);
+        let across = |car: &TrafficCar| (car.pos - pos).dot(side);
+
+        // boid-style separation, only sideways so it never holds the car back
+        let push: f32 = traffic
+            .iter()
+            .filter(|car| car.pos.distance(pos) < SEPARATION_RADIUS)
+            .map(|car| -across(car).signum() * (1.0 - car.pos.distance(pos) / SEPARATION_RADIUS))
+            .sum();
+        let separation = side * push.clamp(-1.0, 1.0) * SEPARATION_PUSH;
+
+        // closest car up the road that's roughly on this car's line
+        let ahead = traffic
+            .iter()
+            .filter(|car| {
+                along(car) > 0.0 && along(car) < OVERTAKE_LOOKAHEAD && across(car).abs() < PASS_GAP
+            })
+            .min_by(|a, b| along(a).total_cmp(&along(b)));
+        self.in_tow = ahead.is_some_and(|car| {
+            along(car) < SLIPSTREAM_RANGE && across(car).abs() < SLIPSTREAM_WIDTH
+        });
+        let slower = ahead.filter(|car| car.velocity.dot(direction) < velocity.dot(direction));
+
+        let lane = match (self.overtake, slower) {
+            // keep going round the car being passed until it's behind, or the pass runs too long
+            (Overtake::Passing(s, secs), _) if secs < PASS_SECS => {
+                let passing = traffic
+                    .iter()
+                    .filter(|car| {
+                        along(car) > -(CAR_SIZE as f32)
+                            && along(car) < OVERTAKE_LOOKAHEAD
+                            && across(car).abs() < PASS_GAP * 2.0
+                    })
+                    .min_by(|a, b| along(a).abs().total_cmp(&along(b).abs()));
+                match passing {
+                    Some(car) => {
+                        self.overtake = Overtake::Passing(s, secs + delta_secs);
+                        Some(side * (across(car) + s * PASS_GAP))
+                    }
+                    None => {
+                        self.overtake = Overtake::Clear;
+                        None
+                    }
+                }
+            }
+            (_, None) => {
+                self.overtake = Overtake::Clear;
+                None
+            }
+            (state, Some(car)) => {
+                let tucked_for = match state {
+                    Overtake::Following(secs) => secs + delta_secs,
+                    _ => 0.0,
+                };
+                self.overtake = Overtake::Following(tucked_for);
+                let behind = side * across(car);
+                if tucked_for < SLIPSTREAM_SECS && along(car) > PULL_OUT_DISTANCE {
+                    Some(behind)
+                } else {
+                    // inside of the bend first, the way the line to the waypoint turns off the car's
+                    let inside = if velocity.perp_dot(direction) < 0.0 {
+                        -1.0
+                    } else {
+                        1.0
+                    };
+                    let pass = [inside, -inside].into_iter().find_map(|s| {
+                        let lane = side * (across(car) + s * PASS_GAP);
+                        (keep_on_track(waypoint, lane, grid) == lane).then_some((s, lane))
+                    });
+                    match pass {
+                        Some((s, lane)) => {
+                            self.overtake = Overtake::Passing(s, 0.0);
+                            Some(lane)
+                        }
+                        // no room either side, stay in the tow and wait
+                        None => Some(behind),
+                    }
+                }
+            }
+        };
+
+        keep_on_track(waypoint, lane.unwrap_or(Vec2::ZERO) + separation, grid)
+    }
+}
+
+/// Shorten `offset` until moving the aim from `from` by it doesn't cross onto slower
+/// ground than `from` is on (off the road, or into a wall), zero if it always does
+pub fn keep_on_track(from: Vec2, offset: Vec2, grid: &ThetaGrid) -> Vec2 {
+    let limit = grid.ground_speed((from.x, from.y));
+    let clear = |offset: Vec2| {
+        (1..=EDGE_SAMPLES).all(|i| {
+            let p = from + offset * (i as f32 / EDGE_SAMPLES as f32);
+            grid.ground_speed((p.x, p.y)) >= limit
+        })
+    };
+    let mut offset = offset;
+    for _ in 0..EDGE_SAMPLES {
+        if clear(offset) {
+            return offset;
+        }
+        offset *= 0.5;
+    }
+    Vec2::ZERO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::game_logic::theta_grid::map2_grid;
+    use crate::game_logic::{
+        AiControls, ControlledCar, CpuDifficulty, Orientation, PLAYER_SPEED, ROAD, RacingLine,
+        TILES, Velocity, apply_ai_controls,
+    };
+    use crate::networking::MapChoice;
+
+    const STEP: f32 = 1.0 / 60.0;
+
+    #[test]
+    fn tucks_in_then_passes_without_leaving_the_road() {
+        let grid = map2_grid();
+        let line = RacingLine::load(MapChoice::Big).unwrap();
+
+        // first stretch of the line that runs straight for a while
+        let straight = (0..line.points.len())
+            .map(|i| line.points_ahead(i, 400.0))
+            .find(|ahead| {
+                let direction = (ahead[ahead.len() - 1] - ahead[0]).normalize();
+                ahead
+                    .iter()
+                    .all(|p| (*p - ahead[0]).perp_dot(direction).abs() < 8.0)
+            })
+            .unwrap();
+        let (pos, waypoint) = (straight[0], straight[straight.len() - 1]);
+        let direction = (waypoint - pos).normalize();
+        let velocity = direction * 300.0;
+        let slower = TrafficCar {
+            pos: pos + direction * 200.0,
+            velocity: direction * 150.0,
+            rival: true,
+        };
+
+        let mut traffic = AiTraffic::default();
+        let first = traffic.steer(pos, velocity, waypoint, &[slower], &grid, STEP);
+        assert!(matches!(traffic.overtake, Overtake::Following(_)));
+        assert!(first.length() < 1.0, "should tuck in right behind");
+
+        // sat in its tow long enough, it pulls out to whichever side has room
+        let mut steer = first;
+        for _ in 0..(2.0 * SLIPSTREAM_SECS / STEP) as usize {
+            steer = traffic.steer(pos, velocity, waypoint, &[slower], &grid, STEP);
+        }
+        assert!(matches!(traffic.overtake, Overtake::Passing(..)));
+        assert!(steer.length() >= PASS_GAP - 1.0);
+        assert_eq!(keep_on_track(waypoint, steer, &grid), steer);
+
+        // a car going faster is left alone
+        let faster = TrafficCar {
+            velocity: direction * 400.0,
+            ..slower
+        };
+        let mut traffic = AiTraffic::default();
+        let steer = traffic.steer(pos, velocity, waypoint, &[faster], &grid, STEP);
+        assert_eq!(traffic.overtake, Overtake::Clear);
+        assert!(steer.length() < 1.0);
+    }
+
+    #[test]
+    fn tucked_in_behind_it_closes_the_gap() {
+        let grid = map2_grid();
+        let tile = &TILES[ROAD as usize];
+        let profile = CpuDifficulty::Hard.profile();
+        let direction = Vec2::Y;
+        let leader_speed = PLAYER_SPEED * tile.speed_modifier;
+
+        // both flat out down the road, one 200 pixels behind the other, for a second
+        let gap_after = |towed: bool| {
+            let mut leader = Vec2::new(0.0, 200.0);
+            let mut pos = Vec2::ZERO;
+            let mut velocity = Velocity::from(direction * leader_speed);
+            let mut orientation = Orientation::new(direction.to_angle());
+            let mut was_drifting = false;
+            let mut traffic = AiTraffic::default();
+            for _ in 0..(SLIPSTREAM_SECS / STEP) as usize {
+                let ahead = TrafficCar {
+                    pos: leader,
+                    velocity: direction * leader_speed,
+                    rival: true,
+                };
+                let cars = if towed { vec![ahead] } else { Vec::new() };
+                traffic.steer(pos, *velocity, pos + direction * 400.0, &cars, &grid, STEP);
+
+                let controls = AiControls {
+                    throttle: 1.0,
+                    ..default()
+                };
+                let car = ControlledCar {
+                    velocity: &mut velocity,
+                    orientation: &mut orientation,
+                    was_drifting: &mut was_drifting,
+                };
+                let top_speed = PLAYER_SPEED * traffic.top_speed_scale();
+                apply_ai_controls(&controls, car, tile, &profile, top_speed, STEP);
+                pos += *velocity * STEP;
+                leader += direction * leader_speed * STEP;
+            }
+            leader.y - pos.y
+        };
+
+        assert!((gap_after(false) - 200.0).abs() < 1.0, "no tow, no gain");
+        assert!(gap_after(true) < 180.0, "gap only down to {}", gap_after(true));
+    }
+}
@@ -1,6 +1,9 @@
 use crate::GameState;
+use crate::game_logic::{RaceSetting, RaceSettings};
 use crate::networking::MapChoice;
+use crate::networking_plugin::NetworkClient;
 use crate::title_screen::{JoinButton, LobbyListContainer, LobbyRow};
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 
 #[derive(Component)]
@@ -22,11 +25,22 @@ pub struct LobbyCodeText;
 #[derive(Component)]
 pub struct LobbyMapLabel;
 
+#[derive(Component)]
+pub struct LobbySettingText {
+    pub row: usize,
+}
+
+#[derive(Component)]
+pub struct LobbySettingsHint;
+
 #[derive(Resource)]
 pub struct LobbyState {
     pub connected_players: Vec<String>,
     pub name: String,
     pub map: MapChoice,
+    pub is_host: bool,
+    pub settings: RaceSettings,
+    pub selected_setting: usize, // row of the settings panel the host is editing
 }
 
 impl Default for LobbyState {
@@ -35,6 +49,30 @@ impl Default for LobbyState {
             connected_players: Vec::new(),
             name: String::new(),
             map: MapChoice::Small,
+            is_host: false,
+            settings: RaceSettings::default(),
+            selected_setting: 0,
+        }
+    }
+}
+
+impl LobbyState {
+    // under the settings panel, saying who gets to change them
+    fn settings_hint(&self) -> &'static str {
+        if self.is_host {
+            "Up/Down to pick, Left/Right to change"
+        } else {
+            "Only the host can change settings"
+        }
+    }
+
+    // text for one row of the settings panel, with a cursor on the host's selected row
+    fn setting_label(&self, row: usize) -> String {
+        let label = self.settings.label(RaceSetting::ALL[row]);
+        if self.is_host && row == self.selected_setting {
+            format!("> {} <", label)
+        } else {
+            label
         }
     }
 }
@@ -99,6 +137,38 @@ pub fn setup_lobby(
         LobbyScreenEntity,
         LobbyMapLabel,
     ));
+    // Race settings panel, everyone sees it but only the host can change it
+    for row in 0..RaceSetting::ALL.len() {
+        commands.spawn((
+            Text2d::new(lobby_state.setting_label(row)),
+            TextColor(Color::BLACK),
+            Transform {
+                translation: Vec3::new(450., 150. - row as f32 * 40., 1.),
+                ..default()
+            },
+            TextFont {
+                font_size: 24.0,
+                ..default()
+            },
+            LobbyScreenEntity,
+            LobbySettingText { row },
+        ));
+    }
+    commands.spawn((
+        Text2d::new(lobby_state.settings_hint()),
+        TextColor(Color::srgb_u8(120, 120, 120)),
+        Transform {
+            translation: Vec3::new(450., 150. - RaceSetting::ALL.len() as f32 * 40., 1.),
+            ..default()
+        },
+        TextFont {
+            font_size: 18.0,
+            ..default()
+        },
+        LobbyScreenEntity,
+        LobbySettingsHint,
+    ));
+
     commands.spawn((
         Sprite::from_image(asset_server.load("title_screen/backArrow.png")),
         Transform {
@@ -197,19 +267,44 @@ pub fn setup_lobby(
     }
 }
 
+// The lobby screen's text, one query per part so each can be rewritten on its own
+type NameTextOnly = (
+    Without<LobbyMapLabel>,
+    Without<LobbySettingText>,
+    Without<LobbySettingsHint>,
+);
+type MapLabelOnly = (
+    With<LobbyMapLabel>,
+    Without<PlayerNameText>,
+    Without<LobbySettingText>,
+    Without<LobbySettingsHint>,
+);
+type SettingTextOnly = (
+    Without<PlayerNameText>,
+    Without<LobbyMapLabel>,
+    Without<LobbySettingsHint>,
+);
+type SettingsHintOnly = (
+    With<LobbySettingsHint>,
+    Without<PlayerNameText>,
+    Without<LobbyMapLabel>,
+    Without<LobbySettingText>,
+);
+
+#[derive(SystemParam)]
+pub struct LobbyTexts<'w, 's> {
+    names: Query<'w, 's, (&'static mut Text2d, &'static PlayerNameText), NameTextOnly>,
+    map_label: Query<'w, 's, &'static mut Text2d, MapLabelOnly>,
+    settings: Query<'w, 's, (&'static mut Text2d, &'static LobbySettingText), SettingTextOnly>,
+    settings_hint: Query<'w, 's, &'static mut Text2d, SettingsHintOnly>,
+}
+
 // System to update lobby UI when LobbyState changes
 pub fn update_lobby_display(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     lobby_state: Res<LobbyState>,
-    mut name_text_query: Query<(&mut Text2d, &PlayerNameText), Without<LobbyMapLabel>>,
-    mut map_label_query: Query<
-        &mut Text2d,
-        (
-            With<LobbyMapLabel>,
-            Without<PlayerNameText>,
-        ),
-    >,
+    mut texts: LobbyTexts,
     slot_query: Query<(Entity, &PlayerSlot), With<LobbyScreenEntity>>,
 ) {
     // Only run when LobbyState changes
@@ -225,7 +320,7 @@ pub fn update_lobby_display(
     ];
 
     // Update existing player name texts
-    for (mut text, player_name_text) in name_text_query.iter_mut() {
+    for (mut text, player_name_text) in texts.names.iter_mut() {
         if let Some(player_name) = lobby_state
             .connected_players
             .get(player_name_text.slot_index)
@@ -234,10 +329,19 @@ pub fn update_lobby_display(
         }
     }
 
-    if let Ok(mut text) = map_label_query.get_single_mut() {
+    if let Ok(mut text) = texts.map_label.single_mut() {
         text.0 = format!("Map: {}", lobby_state.map.label());
     }
 
+    for (mut text, setting_text) in texts.settings.iter_mut() {
+        text.0 = lobby_state.setting_label(setting_text.row);
+    }
+
+    // the host can change hands, and we only find out we're it once the server says so
+    if let Ok(mut text) = texts.settings_hint.single_mut() {
+        text.0 = lobby_state.settings_hint().to_string();
+    }
+
     // Count how many slots currently exist
     let existing_slots = slot_query
         .iter()
@@ -295,6 +399,45 @@ pub fn update_lobby_display(
     }
 }
 
+// Host-only controls for the race settings panel
+pub fn check_for_lobby_settings_input(
+    input: Res<ButtonInput<KeyCode>>,
+    mut lobby_state: ResMut<LobbyState>,
+    mut network_client: ResMut<NetworkClient>,
+) {
+    if !lobby_state.is_host {
+        return;
+    }
+
+    let rows = RaceSetting::ALL.len();
+    if input.just_pressed(KeyCode::ArrowUp) {
+        lobby_state.selected_setting = (lobby_state.selected_setting + rows - 1) % rows;
+        return;
+    } else if input.just_pressed(KeyCode::ArrowDown) {
+        lobby_state.selected_setting = (lobby_state.selected_setting + 1) % rows;
+        return;
+    }
+
+    let forward = if input.just_pressed(KeyCode::ArrowRight) {
+        true
+    } else if input.just_pressed(KeyCode::ArrowLeft) {
+        false
+    } else {
+        return;
+    };
+
+    // Update locally right away, the server echoes the settings back to the whole lobby
+    let setting = RaceSetting::ALL[lobby_state.selected_setting];
+    lobby_state.settings.adjust(setting, forward);
+
+    if let Some(client) = network_client.client.as_mut() {
+        let name = lobby_state.name.clone();
+        if let Err(e) = client.update_lobby_settings(name, lobby_state.settings) {
+            println!("Failed to send lobby settings: {}", e);
+        }
+    }
+}
+
 pub fn populate_lobby_list(
     mut commands: Commands,
     list: Res<LobbyList>,
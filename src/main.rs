@@ -1,3 +1,4 @@
+mod ai_overlay;
 mod camera;
 mod car;
 mod car_skins;
@@ -11,33 +12,63 @@ mod lobby;
 mod multiplayer;
 mod networking;
 mod networking_plugin;
+mod race_phase;
+mod race_setup;
+mod records;
 mod speed;
+mod time_trial;
 mod title_screen;
+mod user_data;
 mod victory_screen;
 
+use ai_overlay::{
+    AiDebugOverlay, clear_ai_debug, collect_ai_debug, draw_ai_debug, toggle_ai_debug,
+    update_ai_debug_labels,
+};
 use speed::{
-    SpeedBoost, SpeedPowerup, collect_powerups, remove_boost_ui, spawn_boost_ui,
-    spawn_speed_powerups, update_speed_boost,
+    ServerPickups, SpeedBoost, SpeedPowerup, boost_pickups_enabled, collect_powerups,
+    remove_boost_ui, spawn_boost_ui, spawn_speed_powerups, sync_server_pickups, update_speed_boost,
+    use_ai_powerups,
 };
 
 use crate::game_logic::{AIControlled, Orientation, TILE_SIZE, ThetaCheckpointList, Velocity, MapLevelData, theta};
 use bevy::render::camera::{Projection, ScalingMode};
 use bevy::{color::palettes::basic::*, input_focus::InputFocus, prelude::*, window::PresentMode};
-use camera::{WIN_H, WIN_W, move_camera, reset_camera_for_credits};
-use car::{Background, ai_car_fsm, move_ai_cars, move_player_car, spawn_cars};
+use camera::{
+    PlayerCamera, WIN_H, WIN_W, cleanup_split_screen, fit_split_screen, move_camera,
+    reset_camera_for_credits, setup_split_screen,
+};
+use car::{
+    Background, ai_car_fsm, mark_dynamic_obstacles, move_ai_cars, move_player_car, plan_ai_paths,
+    spawn_cars,
+};
 use credits::{check_for_credits_input, setup_credits, show_credits};
 use game_logic::{
-    CpuDifficulty, GameMap, LapCounter, load_map_from_file, spawn_lap_triggers, spawn_map,
-    update_laps,
+    AiDebugCars, CpuDifficulty, GameMap, LapCompleted, LapCounter, LocalPlayers, PowerupTree, BehaviourTrees, PracticeGrid, RaceResults,
+    LineBuild, RaceSettings, RacingLine, TeamAssignments, award_cup_points, load_map_from_file, reset_race_standings,
+    spawn_lap_triggers, spawn_map, update_laps,
+};
+use lobby::{
+    LobbyList, LobbyListDirty, LobbyState, check_for_lobby_settings_input, populate_lobby_list,
+    update_lobby_display,
 };
-use lobby::{LobbyList, LobbyListDirty, LobbyState, populate_lobby_list, update_lobby_display};
 use networking_plugin::NetworkingPlugin;
 use networking::SelectedMap;
+use race_phase::{
+    RacePhase, despawn_race_banner, inputs_unlocked, spawn_race_banner, start_local_countdown,
+    update_race_banner, update_race_phase, update_standings_text,
+};
 use title_screen::{
     ServerAddress, check_for_lobby_input, check_for_title_input, pause, setup_title_screen,
     sync_server_address,start_music,
 };
-use victory_screen::setup_victory_screen;
+use race_setup::{RaceSetupView, check_for_race_setup_input, sync_practice_difficulty};
+use records::{RecordsView, check_for_records_input, save_race_records};
+use time_trial::{
+    cleanup_time_trial, record_ghost, setup_time_trial, time_trial_laps, update_ghost_car,
+    update_lap_timer,
+};
+use victory_screen::{check_for_victory_input, cleanup_victory_screen, setup_victory_screen};
 
 //use theta::log_checkpoint_system;
 //use game_logic::map::draw_checkpoint_lines;
@@ -53,8 +84,11 @@ pub enum GameState {
     Settings,
     Playing,
     PlayingDemo,
+    TimeTrial,
+    RaceSetup,
     Victory,
     Credits,
+    Records,
 }
 
 #[derive(Resource)]
@@ -82,6 +116,13 @@ fn main() {
         .init_resource::<networking::SelectedMap>()
         .init_resource::<title_screen::IpTypingMode>()
         .insert_resource(CpuDifficulty::default())
+        .init_resource::<RaceSettings>()
+        .init_resource::<RaceResults>()
+        .init_resource::<TeamAssignments>()
+        .init_resource::<LocalPlayers>()
+        .init_resource::<PracticeGrid>()
+        .init_resource::<RaceSetupView>()
+        .init_resource::<RecordsView>()
         .insert_resource(ClearColor(Color::WHITE))
         .insert_resource(ServerAddress {
             address: "167.172.23.173".to_string(),
@@ -90,8 +131,16 @@ fn main() {
         .init_resource::<client_prediction::InputSequence>()
         .init_resource::<client_prediction::InputBuffer>()
         .init_resource::<MapLevelData>()
+        .init_resource::<RacingLine>()
+        .insert_resource(PowerupTree::load())
+        .insert_resource(BehaviourTrees::load())
+        .init_resource::<AiDebugOverlay>()
+        .init_resource::<AiDebugCars>()
+        .init_resource::<ServerPickups>()
         .insert_resource(Time::<Fixed>::from_hz(60.0)) // 60 Hz fixed update (60fps for input/physics)
         .init_state::<GameState>()
+        .add_sub_state::<RacePhase>()
+        .add_event::<LapCompleted>()
         .add_systems(
             OnEnter(GameState::Playing),
             (
@@ -103,7 +152,34 @@ fn main() {
             )
                 .chain(),
         )
-        .add_systems(OnEnter(GameState::PlayingDemo), load_map2) // THETA* DEMO (but could support our second map)
+        .add_systems(OnEnter(GameState::PlayingDemo), load_selected_map) // map picked on the race setup screen
+        .add_systems(
+            OnEnter(GameState::PlayingDemo),
+            (reset_race_standings, start_local_countdown),
+        )
+        // Time trial: just the player, the clock and a ghost
+        .add_systems(
+            OnEnter(GameState::TimeTrial),
+            (
+                load_selected_map,
+                car_setup,
+                spawn_map,
+                spawn_lap_triggers,
+                reset_race_standings,
+                start_local_countdown,
+                setup_time_trial,
+            )
+                .chain(),
+        )
+        .add_systems(OnExit(GameState::TimeTrial), (despawn_race_banner, cleanup_time_trial))
+        .add_systems(OnEnter(RacePhase::Grid), spawn_race_banner)
+        .add_systems(OnEnter(RacePhase::Results), save_race_records)
+        .add_systems(
+            OnEnter(RacePhase::Results),
+            award_cup_points.run_if(in_state(GameState::PlayingDemo)),
+        )
+        .add_systems(OnExit(GameState::Playing), despawn_race_banner)
+        .add_systems(OnExit(GameState::PlayingDemo), despawn_race_banner)
         //.insert_resource(load_map_from_file("assets/big-map.txt")) // to get a Res handle on GameMap
         .insert_resource(load_map_from_file("assets/big-map.txt")) // to get a Res handle on GameMap
         .init_resource::<LobbyState>()
@@ -114,17 +190,27 @@ fn main() {
         .add_systems(OnEnter(GameState::Title), setup_title_screen)
         .add_systems(
             OnEnter(GameState::PlayingDemo),
-            (initialize_theta_grid, car_setup, spawn_map, spawn_lap_triggers).chain().after(load_map2),
+            (initialize_theta_grid, car_setup, spawn_map, spawn_lap_triggers)
+                .chain()
+                .after(load_selected_map),
         )
         .add_systems(
             OnEnter(GameState::PlayingDemo),
-            (ai_car_setup).after(car_setup),
+            (ai_car_setup, setup_split_screen).after(car_setup),
         )
+        .add_systems(OnExit(GameState::PlayingDemo), cleanup_split_screen)
+        .add_systems(Update, finish_racing_line.run_if(resource_exists::<LineBuild>))
         // .add_systems(Startup, intro::setup_intro)
         // .add_systems(Update, intro::check_for_intro_input)
         .add_systems(Update, sync_server_address)
         .add_systems(Update, check_for_title_input)
         .add_systems(Update, check_for_lobby_input)
+        .add_systems(Update, check_for_records_input)
+        .add_systems(Update, check_for_race_setup_input)
+        .add_systems(
+            Update,
+            sync_practice_difficulty.run_if(resource_changed::<CpuDifficulty>),
+        )
         .add_systems(Update, check_for_credits_input)
         .add_systems(Startup, start_music)
         .add_systems(
@@ -135,18 +221,42 @@ fn main() {
             Update,
             (
                 update_lobby_display.run_if(in_state(GameState::Lobby)),
+                check_for_lobby_settings_input.run_if(in_state(GameState::Lobby)),
                 //move_car.run_if(in_state(GameState::Playing)),
                 // Server now controls player physics, client just renders server position
                 // Client only controls game state in GameState::PlayingDemo
-                move_player_car.run_if(in_state(GameState::PlayingDemo)),
+                move_player_car.run_if(
+                    in_state(GameState::PlayingDemo)
+                        .or(in_state(GameState::TimeTrial))
+                        .and(in_state(RacePhase::Racing))
+                        .and(inputs_unlocked),
+                ),
                 //move_camera.after(move_car).run_if(in_state(GameState::Playing)),
-                move_camera
-                    .run_if(in_state(GameState::Playing).or(in_state(GameState::PlayingDemo))),
-                move_ai_cars
-                    .run_if(in_state(GameState::Playing).or(in_state(GameState::PlayingDemo))),
-                ai_car_fsm.run_if(in_state(GameState::PlayingDemo)),
-                update_laps
-                    .run_if(in_state(GameState::Playing).or(in_state(GameState::PlayingDemo))),
+                fit_split_screen.run_if(in_state(GameState::PlayingDemo)),
+                move_camera.run_if(
+                    in_state(GameState::Playing)
+                        .or(in_state(GameState::PlayingDemo))
+                        .or(in_state(GameState::TimeTrial)),
+                ),
+                (mark_dynamic_obstacles, plan_ai_paths, move_ai_cars).chain().run_if(
+                    in_state(RacePhase::Racing)
+                        .or(in_state(RacePhase::Finished))
+                        .or(in_state(RacePhase::Results)),
+                ),
+                ai_car_fsm.after(plan_ai_paths).before(move_ai_cars).run_if(
+                    in_state(GameState::PlayingDemo)
+                        .and(in_state(RacePhase::Racing).or(in_state(RacePhase::Finished))),
+                ),
+                // online races are judged by the server, see race_progress_system
+                update_laps.run_if(
+                    in_state(GameState::PlayingDemo)
+                        .or(in_state(GameState::TimeTrial))
+                        .and(in_state(RacePhase::Racing).or(in_state(RacePhase::Finished))),
+                ),
+                (update_race_phase, update_race_banner)
+                    .chain()
+                    .run_if(state_exists::<RacePhase>),
+                update_standings_text.run_if(in_state(GameState::Playing)),
                 interpolation::interpolate_networked_cars.run_if(in_state(GameState::Playing)),
                 populate_lobby_list.run_if(in_state(GameState::Joining)),
             ),
@@ -155,12 +265,31 @@ fn main() {
             FixedUpdate,
             (
                 // Client-side prediction and reconciliation run at fixed 30 Hz
-                client_prediction::send_keyboard_input.run_if(in_state(GameState::Playing)),
+                client_prediction::send_keyboard_input
+                    .run_if(in_state(GameState::Playing).and(inputs_unlocked)),
                 multiplayer::get_car_positions.run_if(in_state(GameState::Playing)),
+                multiplayer::apply_team_colors.run_if(in_state(GameState::Playing)),
             )
                 .chain(),
         )
+        .add_systems(
+            Update,
+            (
+                time_trial_laps,
+                record_ghost.run_if(in_state(RacePhase::Racing)),
+                update_ghost_car,
+                update_lap_timer,
+            )
+                .chain()
+                .after(update_laps)
+                .run_if(in_state(GameState::TimeTrial)),
+        )
         .add_systems(OnEnter(GameState::Victory), setup_victory_screen)
+        .add_systems(OnExit(GameState::Victory), cleanup_victory_screen)
+        .add_systems(
+            Update,
+            check_for_victory_input.run_if(in_state(GameState::Victory)),
+        )
         .add_systems(
             OnEnter(GameState::Credits),
             (reset_camera_for_credits, setup_credits),
@@ -174,14 +303,33 @@ fn main() {
         .add_systems(
             Update,
             (
-                spawn_speed_powerups,
+                // online the server puts the pickups down, so everyone gets the same ones
+                spawn_speed_powerups
+                    .run_if(boost_pickups_enabled.and(in_state(GameState::PlayingDemo))),
+                sync_server_pickups.run_if(in_state(GameState::Playing)),
                 collect_powerups,
+                use_ai_powerups,
                 update_speed_boost,
                 spawn_boost_ui,
                 remove_boost_ui,
             )
                 .run_if(in_state(GameState::PlayingDemo).or(in_state(GameState::Playing))),
         )
+        // AI debug overlay, F3/F4/F5 toggle its layers
+        .add_systems(Update, toggle_ai_debug)
+        .add_systems(
+            Update,
+            (
+                collect_ai_debug.run_if(in_state(GameState::PlayingDemo)),
+                draw_ai_debug,
+                update_ai_debug_labels,
+            )
+                .chain()
+                .after(move_ai_cars)
+                .run_if(in_state(GameState::PlayingDemo).or(in_state(GameState::Playing))),
+        )
+        .add_systems(OnExit(GameState::Playing), clear_ai_debug)
+        .add_systems(OnExit(GameState::PlayingDemo), clear_ai_debug)
         //.add_systems(Update, log_checkpoint_system) //REMOVE THIS
         //.add_systems(Update, draw_checkpoint_lines) // AND THIS
         .run();
@@ -195,10 +343,11 @@ fn camera_setup(mut commands: Commands) {
     projection.scaling_mode = ScalingMode::WindowSize;
     projection.scale = 1.0;
 
-    // spawn with the custom projection
+    // spawn with the custom projection, split-screen adds a second camera next to this one
     commands
         .spawn(Camera2d::default())
-        .insert(Projection::Orthographic(projection));
+        .insert(Projection::Orthographic(projection))
+        .insert((PlayerCamera(0), IsDefaultUiCamera));
 }
 
 fn car_setup(
@@ -208,10 +357,13 @@ fn car_setup(
     state: Res<State<GameState>>,
     map_data: Res<MapLevelData>,
     skin_selection: Res<car_skins::CarSkinSelection>,
+    race_settings: Res<RaceSettings>,
+    local_players: Res<LocalPlayers>,
+    practice_grid: Res<PracticeGrid>,
 ) {
     // spawn_cars now detects the game mode and spawns accordingly
     // - Playing (multiplayer): Only player car
-    // - PlayingDemo: Player car + AI car
+    // - PlayingDemo: Player car(s) + the CPU grid from the race setup screen
     spawn_cars(
         commands,
         asset_server,
@@ -219,6 +371,9 @@ fn car_setup(
         state,
         map_data,
         skin_selection,
+        race_settings,
+        local_players,
+        practice_grid,
     );
 }
 fn ai_car_setup(
@@ -235,75 +390,10 @@ fn load_selected_map(mut commands: Commands, selected_map: Res<SelectedMap>) {
     let map_path = selected_map.choice.path();
     commands.insert_resource(load_map_from_file(map_path));
 
-    // Define data for Map 1 (Default/Big Map)
-    let map1_data = MapLevelData {
-        start_position: Vec3::new(2752.0, 960.0, 5.0),
-        finish_line_pos: Vec3::new(2752., 960., 5.),
-        checkpoints: vec![
-            (Vec3::new(2752., 1500., 10.), 0.0),
-            (Vec3::new(2700., 2700., 10.), std::f32::consts::PI / 4.0),
-            (Vec3::new(425., 2725., 10.), std::f32::consts::PI / -4.0),
-            (Vec3::new(-1600., 400., 10.), std::f32::consts::PI / -4.0),
-            (Vec3::new(-2044., -1493., 10.), 0.0),
-            (Vec3::new(-1979., -2750., 10.), std::f32::consts::PI / 2.0),
-            (Vec3::new(1515., -2750., 10.), std::f32::consts::PI / 2.0),
-            (Vec3::new(2100., -150., 10.), 0.0),
-        ],
-    };
-
-    // Define data for Map 2
-    let map2_data = MapLevelData {
-        start_position: Vec3::new(1300.0, -1131.0, 5.0), 
-        finish_line_pos: Vec3::new(1300.0, -1131.0, 5.0), 
-        checkpoints: vec![
-            (Vec3::new(1386., 974., 10.), 0.0),
-            (Vec3::new(3175., 1949., 10.), std::f32::consts::PI / 4.0),
-            (Vec3::new(-1891., 2167., 10.), std::f32::consts::PI / -4.0),
-            (Vec3::new(-471., 2146., 10.), std::f32::consts::PI / -4.0),
-            (Vec3::new(862., 1907., 10.), 0.0),
-            (Vec3::new(-1834., 30., 10.), std::f32::consts::PI / 2.0),
-            (Vec3::new(-2841., 2059., 10.), 0.0),
-            (Vec3::new(-3738., 1465., 10.), 0.0),
-            (Vec3::new(-91., -2441., 10.), 0.0),
-            (Vec3::new(3117., -2376., 10.), 0.0),
-        ],
-    };
-
     // Determine which data to inject
-    if map_path.contains("map2") {
-        commands.insert_resource(map2_data);
-        commands.insert_resource(CurrentMapNumber(2));
-    } else {
-        commands.insert_resource(map1_data);
-        commands.insert_resource(CurrentMapNumber(1));
-    }
-}
-
-// Map 2 Loader for PlayingDemo state
-fn load_map2(mut commands: Commands) {
-    // load grid
-    commands.insert_resource(load_map_from_file("assets/map2.txt"));
-
-    // define triggers and positions
-    let map2_data = MapLevelData {
-        start_position: Vec3::new(1300.0, -1131.0, 5.0), 
-        finish_line_pos: Vec3::new(1300.0, -1131.0, 5.0), 
-        checkpoints: vec![
-            (Vec3::new(1386., 974., 10.), 0.0),
-            (Vec3::new(3175., 1949., 10.), std::f32::consts::PI / 4.0),
-            (Vec3::new(-1891., 2167., 10.), std::f32::consts::PI / -4.0),
-            (Vec3::new(-471., 2146., 10.), std::f32::consts::PI / -4.0),
-            (Vec3::new(862., 1907., 10.), 0.0),
-            (Vec3::new(-1834., 30., 10.), std::f32::consts::PI / 2.0),
-            (Vec3::new(-2841., 2059., 10.), 0.0),
-            (Vec3::new(-3738., 1465., 10.), 0.0),
-            (Vec3::new(-91., -2441., 10.), 0.0),
-            (Vec3::new(3117., -2376., 10.), 0.0),
-        ],
-    };
-
-    commands.insert_resource(map2_data);
-    commands.insert_resource(CurrentMapNumber(2));
+    let map_num = if map_path.contains("map2") { 2 } else { 1 };
+    commands.insert_resource(MapLevelData::for_map(map_num));
+    commands.insert_resource(CurrentMapNumber(map_num));
 }
 
 // Initialize ThetaGrid from GameMap for pathfinding
@@ -311,6 +401,7 @@ fn initialize_theta_grid(
     mut commands: Commands,
     game_map: Res<GameMap>,
     map_number: Res<CurrentMapNumber>,
+    selected_map: Res<SelectedMap>,
 ) {
     use game_logic::theta_grid::ThetaGrid;
     let (w, h) = match map_number.0 {
@@ -319,5 +410,25 @@ fn initialize_theta_grid(
     };
     let theta_grid =
         ThetaGrid::create_theta_grid_with_size(&game_map, TILE_SIZE as f32, w, h);
+    // the AI follows the track's racing line, shipped with the track
+    let (racing_line, build) =
+        RacingLine::load_or_build(selected_map.choice, &game_map, &theta_grid);
+    commands.insert_resource(racing_line);
+    match build {
+        Some(build) => commands.insert_resource(build),
+        None => commands.remove_resource::<LineBuild>(),
+    }
     commands.insert_resource(theta_grid);
 }
+
+// Swap in a racing line that was being worked out in the background once it's ready
+fn finish_racing_line(
+    mut commands: Commands,
+    build: Res<LineBuild>,
+    mut racing_line: ResMut<RacingLine>,
+) {
+    if let Some(line) = build.take() {
+        *racing_line = line;
+        commands.remove_resource::<LineBuild>();
+    }
+}
@@ -1,8 +1,8 @@
 use crate::car_skins::{AI_SKIN, CarSkinSelection};
 use crate::client_prediction::PredictionBuffer;
 use crate::game_logic::{
-    CAR_SIZE, CLIENT_TIMESTEP, Car, GameMap, LapCounter, Orientation, PlayerControlled, TILE_SIZE,
-    Velocity, apply_physics, handle_collision,
+    CAR_SIZE, CLIENT_TIMESTEP, Car, Eliminated, Finished, GameMap, LapCounter, Orientation, PlayerControlled,
+    RaceSettings, TILE_SIZE, Team, Velocity, apply_physics, handle_collision,
 };
 use crate::interpolation::{InterpolationBuffer, InterpolationDelay};
 use crate::networking_plugin::{NetworkClient, PlayerPositions};
@@ -14,6 +14,15 @@ pub struct NetworkPlayer {
     pub player_id: u32,
 }
 
+/// The other players' cars still in the race, the ones our car can bump into. Prediction and
+/// reconciliation both collide with these, so they replay the same as the server.
+pub type CollidableNetworkCar = (
+    With<NetworkPlayer>,
+    Without<PlayerControlled>,
+    Without<Finished>,
+    Without<Eliminated>,
+);
+
 pub fn get_car_positions(
     network_client: Res<NetworkClient>,
     mut network_cars: Query<(&NetworkPlayer, &mut InterpolationBuffer)>,
@@ -23,10 +32,11 @@ pub fn get_car_positions(
             &mut Velocity,
             &mut Orientation,
             &mut PredictionBuffer,
+            Option<&Team>,
         ),
         (With<PlayerControlled>, Without<NetworkPlayer>),
     >,
-    other_cars: Query<(&Transform, &Velocity), (With<NetworkPlayer>, Without<PlayerControlled>)>,
+    other_cars: Query<(&Transform, &Velocity, Option<&Team>), CollidableNetworkCar>,
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     mut texture_atlases: ResMut<Assets<TextureAtlasLayout>>,
@@ -35,6 +45,7 @@ pub fn get_car_positions(
     game_map: Res<GameMap>,
     mut interp_delay: ResMut<InterpolationDelay>,
     skin_selection: Res<CarSkinSelection>,
+    race_settings: Res<RaceSettings>,
 ) {
     if network_client.client.is_none() {
         return;
@@ -46,7 +57,7 @@ pub fn get_car_positions(
     for (id, player_pos) in &player_positions.positions {
         // Reconcile our own player with server state
         if Some(*id) == my_id {
-            if let Ok((mut transform, mut velocity, mut orientation, mut buffer)) =
+            if let Ok((mut transform, mut velocity, mut orientation, mut buffer, my_team)) =
                 player_car.single_mut()
             {
                 // Step 1: Use the server sequence number to get the inputs after it
@@ -93,7 +104,10 @@ pub fn get_car_positions(
                         let new_position = replay_pos.extend(transform.translation.z);
                         let other_cars_iter = other_cars
                             .iter()
-                            .map(|(t, v)| (t.translation.truncate(), v.velocity));
+                            .filter(|(_, _, other_team)| {
+                                race_settings.cars_collide(my_team.copied(), other_team.copied())
+                            })
+                            .map(|(t, v, _)| (t.translation.truncate(), v.velocity));
                         let should_update = handle_collision(
                             new_position,
                             prev_pos,
@@ -188,3 +202,31 @@ fn buffer_networked_car(
         LapCounter::default(),
     ));
 }
+
+// Tint each car by its team once it shows up in a server snapshot
+pub fn apply_team_colors(
+    mut commands: Commands,
+    network_client: Res<NetworkClient>,
+    player_positions: Res<PlayerPositions>,
+    mut cars: Query<
+        (Entity, Option<&NetworkPlayer>, Has<PlayerControlled>, &mut Sprite),
+        (With<Car>, Without<Team>),
+    >,
+) {
+    for (entity, network_player, is_player, mut sprite) in cars.iter_mut() {
+        let id = if is_player {
+            network_client.player_id
+        } else {
+            network_player.map(|p| p.player_id)
+        };
+        let Some(team) = id
+            .and_then(|id| player_positions.positions.get(&id))
+            .and_then(|pos| pos.team)
+        else {
+            continue;
+        };
+
+        sprite.color = team.tint().with_alpha(sprite.color.alpha());
+        commands.entity(entity).insert(team);
+    }
+}
@@ -1,5 +1,7 @@
+use crate::game_logic::{AiDebugCar, CupStandings, Pickup, RaceResultEntry, RaceSettings, Team};
 use bevy::{prelude::Resource, tasks::IoTaskPool};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::mpsc::Sender;
 use std::time::{Duration, Instant};
@@ -46,6 +48,14 @@ impl MapChoice {
         }
     }
 
+    /// Short name used for per-map save files
+    pub fn key(self) -> &'static str {
+        match self {
+            MapChoice::Small => "small",
+            MapChoice::Big => "big",
+        }
+    }
+
     pub fn path(self) -> &'static str {
         match self {
             MapChoice::Small => "assets/big-map.txt",
@@ -89,6 +99,11 @@ pub enum MessageType {
         name: String,
     },
 
+    UpdateLobbySettings {
+        name: String,
+        settings: RaceSettings,
+    },
+
     CarPosition {
         x: f32,
         y: f32,
@@ -113,6 +128,16 @@ pub enum MessageType {
         inputs: Vec<InputData>,
     },
 
+    // turn the AI debug stream on or off
+    WatchAi {
+        enabled: bool,
+    },
+
+    // we drove over one of the server's pickups
+    CollectPickup {
+        id: u32,
+    },
+
     Ping,
 }
 
@@ -130,10 +155,48 @@ pub enum ServerMessage {
     ActiveLobbies { lobbies: Vec<LobbyInfo> },
 
     #[serde(rename = "game_started")]
-    GameStarted { lobby: String, time: u64, map: MapChoice },
+    GameStarted {
+        lobby: String,
+        // both in server clock milliseconds
+        server_time: u64,
+        go_time: u64,
+        map: MapChoice,
+        #[serde(default)]
+        settings: RaceSettings,
+        #[serde(default)]
+        teams: HashMap<u32, Team>,
+    },
+
+    #[serde(rename = "car_finished")]
+    CarFinished { id: u32, position: u8, time: f32 },
 
+    #[serde(rename = "car_eliminated")]
+    CarEliminated { id: u32, position: u8, time: f32 },
+
+    #[serde(rename = "race_standings")]
+    RaceStandings {
+        order: Vec<u32>,
+        next_elimination: Option<f32>,
+    },
+
+    #[serde(rename = "race_results")]
+    RaceResults { results: Vec<RaceResultEntry> },
+
+    // cup points after each race and the playlist, sent again when the next race starts
+    #[serde(rename = "cup_standings")]
+    CupStandings { standings: CupStandings },
+
+    // what the lobby's CPU cars are doing, only sent while the AI debug overlay is open
+    #[serde(rename = "ai_debug")]
+    AiDebug { cars: Vec<AiDebugCar> },
+
+    // every pickup on the track, sent whenever one's put down or collected
+    #[serde(rename = "pickups")]
+    Pickups { pickups: Vec<Pickup> },
+
+    // server clock milliseconds when it answered the ping
     #[serde(rename = "pong")]
-    Pong,
+    Pong { server_time: u64 },
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -148,6 +211,12 @@ pub struct LobbyInfo {
 pub struct LobbyStateMessage {
     pub lobby: String,
     pub players: Vec<u32>,
+    #[serde(default)]
+    pub host: u32,
+    #[serde(default)]
+    pub settings: RaceSettings,
+    #[serde(default)]
+    pub teams: HashMap<u32, Team>,
 }
 
 // Position message for car positions
@@ -161,6 +230,8 @@ pub struct PlayerPositionData {
     pub angle: f32,
     #[serde(default)]
     pub last_processed_sequence: u64,
+    #[serde(default)]
+    pub team: Option<Team>,
     // Array of position snapshots (one per processed input)
     #[serde(default)]
     pub snapshots: Vec<PositionSnapshot>,
@@ -234,6 +305,11 @@ impl Client {
         self.send(MessageType::StartLobby { name })
     }
 
+    /// Host only: push new race settings for the lobby to the server.
+    pub fn update_lobby_settings(&mut self, name: String, settings: RaceSettings) -> io::Result<()> {
+        self.send(MessageType::UpdateLobbySettings { name, settings })
+    }
+
     pub fn send_player_input(
         &mut self,
         sequence: u64,
@@ -261,6 +337,16 @@ impl Client {
         self.send(MessageType::PlayerInputBuffer { inputs })
     }
 
+    /// Ask the server to start or stop sending what its AI cars are doing
+    pub fn watch_ai(&mut self, enabled: bool) -> io::Result<()> {
+        self.send(MessageType::WatchAi { enabled })
+    }
+
+    /// Tell the server one of its pickups is ours, so it's taken off everyone's track
+    pub fn collect_pickup(&mut self, id: u32) -> io::Result<()> {
+        self.send(MessageType::CollectPickup { id })
+    }
+
     pub fn send_ping(&mut self) -> io::Result<()> {
         self.send(MessageType::Ping)
     }
@@ -1,17 +1,24 @@
 use crate::GameState;
+use crate::game_logic::{
+    AiDebugCars, Car, CupStandings, Eliminated, Finished, PlayerControlled, RaceResults, TeamAssignments,
+    format_race_time, racer_name,
+};
 use crate::lobby::{LobbyInfo, LobbyList, LobbyListDirty, LobbyState, setup_lobby};
+use crate::multiplayer::NetworkPlayer;
 use crate::networking::{MapChoice, SelectedMap};
+use crate::race_phase::{ELIMINATED_ALPHA, LiveStandings, RaceCountdown, RacePhase};
 use crate::networking::{
     Client, IncomingMessage, PlayerPositionData, ServerMessage, spawn_listener_thread,
 };
+use crate::speed::ServerPickups;
 use crate::title_screen::destroy_screen;
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use bevy::time::common_conditions::on_timer;
 use std::collections::HashMap;
 use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 // Resource to hold the client connection
 #[derive(Resource)]
@@ -35,6 +42,7 @@ impl Default for NetworkClient {
 pub struct Latency {
     now: Arc<Mutex<Instant>>,
     average_latency: Arc<Mutex<f32>>,
+    clock_offset: Arc<Mutex<f64>>, // average ms the server clock is ahead of ours
     count: Arc<Mutex<u32>>,
 }
 
@@ -43,11 +51,37 @@ impl Default for Latency {
         Self {
             now: Arc::new(Mutex::new(Instant::now())),
             average_latency: Arc::new(Mutex::new(0.0)),
+            clock_offset: Arc::new(Mutex::new(0.0)),
             count: Arc::new(Mutex::new(0)),
         }
     }
 }
 
+impl Latency {
+    // What the server clock reads right now, once a ping has come back to measure it by
+    fn server_now_ms(&self) -> Option<f64> {
+        if *self.count.lock().unwrap() == 0 {
+            return None;
+        }
+        Some(local_clock_ms() + *self.clock_offset.lock().unwrap())
+    }
+}
+
+// Milliseconds on our clock, on the same scale as the server's
+fn local_clock_ms() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64() * 1000.0)
+        .unwrap_or(0.0)
+}
+
+// What the server streams in during a race besides the cars themselves
+#[derive(SystemParam)]
+struct RaceFeeds<'w> {
+    ai_debug_cars: ResMut<'w, AiDebugCars>,
+    server_pickups: ResMut<'w, ServerPickups>,
+}
+
 // Resource to hold the message receiver
 #[derive(Resource)]
 pub struct MessageReceiver {
@@ -94,6 +128,7 @@ fn process_network_messages(
     mut network_client: ResMut<NetworkClient>,
     mut lobby_state: ResMut<LobbyState>,
     mut next_state: ResMut<NextState<GameState>>,
+    mut next_phase: ResMut<NextState<RacePhase>>,
     mut commands: Commands,
     lobby_query: Query<Entity, With<crate::lobby::LobbyScreenEntity>>,
     mut player_positions: ResMut<PlayerPositions>,
@@ -102,6 +137,11 @@ fn process_network_messages(
     mut dirty: ResMut<LobbyListDirty>,
     latency: Res<Latency>,
     mut selected_map: ResMut<SelectedMap>,
+    mut feeds: RaceFeeds,
+    mut cars: Query<
+        (Entity, Option<&NetworkPlayer>, Has<PlayerControlled>, &mut Sprite),
+        With<Car>,
+    >,
 ) {
     // Lock the receiver to access it
     let rx = receiver.receiver.lock().unwrap();
@@ -141,24 +181,119 @@ fn process_network_messages(
                         }
                         dirty.0 = true;
                     }
-                    ServerMessage::GameStarted { lobby, time, map } => {
+                    ServerMessage::GameStarted {
+                        lobby,
+                        server_time,
+                        go_time,
+                        map,
+                        settings,
+                        teams,
+                    } => {
                         println!("Game started for lobby: {} on {}", lobby, map.label());
                         selected_map.choice = map;
+                        // a cup's standings follow this, anything else clears the last cup
+                        if !settings.cup {
+                            commands.remove_resource::<CupStandings>();
+                        }
+                        commands.insert_resource(settings);
+                        commands.insert_resource(TeamAssignments(teams));
 
                         // Destroy lobby screen entities
                         for entity in lobby_query.iter() {
                             commands.entity(entity).despawn();
                         }
 
-                        let average_latency = latency.average_latency.lock().unwrap();
-
-                        // Sleep to mimick the synchronized start - account for latency delays
-                        thread::sleep(Duration::from_millis(time - *average_latency as u64));
-
-                        // Transition to Playing state
+                        // GO is at the same moment on every client however late this arrived,
+                        // before any ping has come back assume it took half a round trip
+                        let until_go_ms = match latency.server_now_ms() {
+                            Some(server_now) => go_time as f64 - server_now,
+                            None => {
+                                let average_latency = *latency.average_latency.lock().unwrap();
+                                go_time.saturating_sub(server_time) as f64 - average_latency as f64
+                            }
+                        };
+                        commands.insert_resource(RaceCountdown::new((until_go_ms / 1000.0) as f32));
+                        commands.insert_resource(LiveStandings::default());
+                        *feeds.server_pickups = ServerPickups::default();
+
+                        // Transition to Playing state, the countdown runs from the Grid phase
                         next_state.set(GameState::Playing);
                     }
-                    ServerMessage::Pong => {
+                    ServerMessage::CarFinished { id, position, time } => {
+                        println!(
+                            "{} finished P{} in {}",
+                            racer_name(id, network_client.player_id),
+                            position,
+                            format_race_time(time)
+                        );
+
+                        // Finished cars stop taking input and just spectate
+                        let is_you = Some(id) == network_client.player_id;
+                        if let Some(entity) = find_car(&cars, id, is_you) {
+                            commands.entity(entity).insert(Finished);
+                        }
+                        if is_you {
+                            next_phase.set(RacePhase::Finished);
+                        }
+                    }
+                    ServerMessage::CarEliminated { id, position, time } => {
+                        println!(
+                            "{} eliminated in P{} after {}",
+                            racer_name(id, network_client.player_id),
+                            position,
+                            format_race_time(time)
+                        );
+
+                        // Knocked out cars stay on track as a ghost, their driver spectates
+                        let is_you = Some(id) == network_client.player_id;
+                        if let Some(entity) = find_car(&cars, id, is_you) {
+                            commands.entity(entity).insert(Eliminated);
+                            if let Ok((_, _, _, mut sprite)) = cars.get_mut(entity) {
+                                sprite.color = sprite.color.with_alpha(ELIMINATED_ALPHA);
+                            }
+                        }
+                        if is_you {
+                            next_phase.set(RacePhase::Finished);
+                        }
+                    }
+                    ServerMessage::RaceStandings {
+                        order,
+                        next_elimination,
+                    } => {
+                        commands.insert_resource(LiveStandings {
+                            order,
+                            next_elimination,
+                        });
+                    }
+                    ServerMessage::RaceResults { results } => {
+                        println!("Race over:");
+                        for entry in &results {
+                            println!(
+                                "  P{} {} {}",
+                                entry.position,
+                                racer_name(entry.id, network_client.player_id),
+                                entry.time.map(format_race_time).unwrap_or("DNF".to_string())
+                            );
+                        }
+                        commands.insert_resource(RaceResults { entries: results });
+                        next_phase.set(RacePhase::Results);
+                    }
+                    ServerMessage::CupStandings { standings } => {
+                        println!(
+                            "Cup after race {} of {}",
+                            standings.race + 1,
+                            standings.tracks.len()
+                        );
+                        commands.insert_resource(standings);
+                    }
+                    // the server splits the cars over several messages
+                    ServerMessage::AiDebug { cars } => {
+                        feeds.ai_debug_cars.merge(cars);
+                    }
+                    ServerMessage::Pickups { pickups } => {
+                        feeds.server_pickups.pickups = pickups;
+                    }
+                    ServerMessage::Pong { server_time } => {
                         let now = Instant::now();
                         let mut time = latency.now.lock().unwrap();
                         let new_latency = now.duration_since(*time).as_millis() / 2;
@@ -169,6 +304,11 @@ fn process_network_messages(
                         let mut average_latency = latency.average_latency.lock().unwrap();
                         *average_latency += (new_latency as f32 - *average_latency) / *count as f32;
 
+                        // the server read its clock about half a round trip ago
+                        let new_offset = server_time as f64 + new_latency as f64 - local_clock_ms();
+                        let mut clock_offset = latency.clock_offset.lock().unwrap();
+                        *clock_offset += (new_offset - *clock_offset) / *count as f64;
+
                         println!("Received Pong");
                     }
                 }
@@ -182,17 +322,22 @@ fn process_network_messages(
 
                 // Update the lobby state resource
                 lobby_state.name = state.lobby.clone();
+                lobby_state.is_host = network_client.player_id == Some(state.host);
+                lobby_state.settings = state.settings;
                 network_client.current_lobby = Some(state.lobby);
 
                 // Convert player IDs to display names
                 lobby_state.connected_players.clear();
                 for (i, player_id) in state.players.iter().enumerate() {
                     let is_you = Some(*player_id) == network_client.player_id;
-                    let name = if is_you {
+                    let mut name = if is_you {
                         format!("Player {} (You)", player_id)
                     } else {
                         format!("Player {}", player_id)
                     };
+                    if let Some(team) = state.teams.get(player_id) {
+                        name = format!("{} - {}", name, team.as_str());
+                    }
                     lobby_state.connected_players.push(name);
                 }
 
@@ -210,6 +355,23 @@ fn process_network_messages(
     }
 }
 
+// Entity of the car with this id, the local car doesn't carry a NetworkPlayer
+fn find_car(
+    cars: &Query<(Entity, Option<&NetworkPlayer>, Has<PlayerControlled>, &mut Sprite), With<Car>>,
+    id: u32,
+    is_you: bool,
+) -> Option<Entity> {
+    cars.iter()
+        .find(|(_, network_player, is_player, _)| {
+            if *is_player {
+                is_you
+            } else {
+                network_player.is_some_and(|p| p.player_id == id)
+            }
+        })
+        .map(|(entity, _, _, _)| entity)
+}
+
 // Helper function to connect to server
 pub fn connect_to_server(
     network_client: &mut NetworkClient,
@@ -0,0 +1,255 @@
+use crate::GameState;
+use crate::game_logic::{DriveInput, Eliminated, LocalPlayer, PlayerControlled};
+use crate::networking_plugin::NetworkClient;
+use bevy::prelude::*;
+
+// Cars sit on the grid for a moment before the lights start counting down
+pub const GRID_SECS: f32 = 1.0;
+pub const COUNTDOWN_SECS: f32 = 3.0;
+// How long the car is held after GO if the throttle was down before it
+pub const FALSE_START_PENALTY_SECS: f32 = 1.0;
+// How long "GO!" stays on screen
+const GO_BANNER_SECS: f32 = 1.0;
+// How long the race over banner shows before the results screen
+const RESULTS_DELAY_SECS: f32 = 2.0;
+// Knocked out cars are drawn this see-through
+pub const ELIMINATED_ALPHA: f32 = 0.3;
+
+// Phases of a single race, only exists while a race is being played
+#[derive(SubStates, Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[source(GameState = GameState::Playing | GameState::PlayingDemo | GameState::TimeTrial)]
+pub enum RacePhase {
+    #[default]
+    Grid,
+    Countdown,
+    Racing,
+    Finished, // local player is done, spectating the rest
+    Results,
+}
+
+// Start clock for the current race. Offline it starts when the race loads,
+// online it is built from the server's scheduled GO time.
+#[derive(Resource)]
+pub struct RaceCountdown {
+    pub until_go: Timer,
+    pub false_start: bool,
+    pub penalty: Timer,
+    pub since_go: f32,
+    pub results_delay: Timer,
+}
+
+impl RaceCountdown {
+    pub fn new(secs_until_go: f32) -> Self {
+        Self {
+            until_go: Timer::from_seconds(secs_until_go.max(0.0), TimerMode::Once),
+            false_start: false,
+            penalty: Timer::from_seconds(FALSE_START_PENALTY_SECS, TimerMode::Once),
+            since_go: 0.0,
+            results_delay: Timer::from_seconds(RESULTS_DELAY_SECS, TimerMode::Once),
+        }
+    }
+
+    /// The car may be driven: past GO and any false start penalty served
+    pub fn inputs_unlocked(&self) -> bool {
+        self.until_go.finished() && (!self.false_start || self.penalty.finished())
+    }
+}
+
+impl Default for RaceCountdown {
+    fn default() -> Self {
+        Self::new(GRID_SECS + COUNTDOWN_SECS)
+    }
+}
+
+// Run condition for anything driven by the local player's controls
+pub fn inputs_unlocked(countdown: Option<Res<RaceCountdown>>) -> bool {
+    countdown.is_some_and(|countdown| countdown.inputs_unlocked())
+}
+
+// Running order sent by the server during online races
+#[derive(Resource, Default, Clone)]
+pub struct LiveStandings {
+    pub order: Vec<u32>,
+    pub next_elimination: Option<f32>, // seconds, elimination races only
+}
+
+#[derive(Component)]
+pub struct RaceBanner;
+
+#[derive(Component)]
+pub struct RaceBannerText;
+
+#[derive(Component)]
+pub struct StandingsText;
+
+// Offline races start their own countdown
+pub fn start_local_countdown(mut commands: Commands) {
+    commands.insert_resource(RaceCountdown::default());
+}
+
+// Tick the start clock and move through Grid -> Countdown -> Racing
+pub fn update_race_phase(
+    time: Res<Time>,
+    input: Res<ButtonInput<KeyCode>>,
+    gamepads: Query<&Gamepad>,
+    local_players: Query<&LocalPlayer>,
+    countdown: Option<ResMut<RaceCountdown>>,
+    phase: Res<State<RacePhase>>,
+    mut next_phase: ResMut<NextState<RacePhase>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let Some(mut countdown) = countdown else {
+        return;
+    };
+
+    match phase.get() {
+        RacePhase::Grid | RacePhase::Countdown => {
+            countdown.until_go.tick(time.delta());
+
+            // Throttle down before the lights go out, split-screen players share the penalty
+            let throttle_down = local_players
+                .iter()
+                .any(|player| DriveInput::read(*player, &input, &gamepads).forward);
+            if throttle_down && !countdown.false_start {
+                countdown.false_start = true;
+                println!("False start! Held for {}s after GO", FALSE_START_PENALTY_SECS);
+            }
+
+            if countdown.until_go.finished() {
+                next_phase.set(RacePhase::Racing);
+            } else if countdown.until_go.remaining_secs() <= COUNTDOWN_SECS
+                && *phase.get() == RacePhase::Grid
+            {
+                next_phase.set(RacePhase::Countdown);
+            }
+        }
+        RacePhase::Racing | RacePhase::Finished => {
+            countdown.since_go += time.delta_secs();
+            if countdown.false_start {
+                countdown.penalty.tick(time.delta());
+            }
+        }
+        RacePhase::Results => {
+            if countdown.results_delay.tick(time.delta()).finished() {
+                next_state.set(GameState::Victory);
+            }
+        }
+    }
+}
+
+pub fn spawn_race_banner(mut commands: Commands) {
+    commands
+        .spawn((
+            Node {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.0),
+                top: Val::Percent(25.0),
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            RaceBanner,
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                Text::new(""),
+                TextFont {
+                    font_size: 96.0,
+                    ..default()
+                },
+                TextColor(Color::WHITE),
+                TextShadow::default(),
+                RaceBannerText,
+            ));
+        });
+
+    // Position and elimination timer (top left corner)
+    commands.spawn((
+        Text::new(""),
+        TextFont {
+            font_size: 32.0,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        TextShadow::default(),
+        Node {
+            position_type: PositionType::Absolute,
+            left: Val::Px(20.0),
+            top: Val::Px(20.0),
+            ..default()
+        },
+        RaceBanner,
+        StandingsText,
+    ));
+}
+
+pub fn update_race_banner(
+    phase: Res<State<RacePhase>>,
+    countdown: Option<Res<RaceCountdown>>,
+    mut banner: Query<&mut Text, With<RaceBannerText>>,
+    eliminated: Query<(), (With<PlayerControlled>, With<Eliminated>)>,
+) {
+    let Some(countdown) = countdown else {
+        return;
+    };
+
+    let label = match phase.get() {
+        RacePhase::Grid => String::new(),
+        RacePhase::Countdown => {
+            format!("{}", countdown.until_go.remaining_secs().ceil() as u32)
+        }
+        RacePhase::Racing if countdown.false_start && !countdown.penalty.finished() => {
+            "FALSE START".to_string()
+        }
+        RacePhase::Racing if countdown.since_go < GO_BANNER_SECS => "GO!".to_string(),
+        RacePhase::Racing => String::new(),
+        RacePhase::Finished if !eliminated.is_empty() => "ELIMINATED".to_string(),
+        RacePhase::Finished => "FINISHED".to_string(),
+        RacePhase::Results => "RACE OVER".to_string(),
+    };
+
+    for mut text in banner.iter_mut() {
+        if text.0 != label {
+            text.0 = label.clone();
+        }
+    }
+}
+
+pub fn update_standings_text(
+    time: Res<Time>,
+    standings: Option<ResMut<LiveStandings>>,
+    network_client: Res<NetworkClient>,
+    mut standings_text: Query<&mut Text, With<StandingsText>>,
+) {
+    let Some(mut standings) = standings else {
+        return;
+    };
+
+    // count down locally between server updates
+    if let Some(next) = standings.next_elimination.as_mut() {
+        *next = (*next - time.delta_secs()).max(0.0);
+    }
+
+    let mut label = match standings
+        .order
+        .iter()
+        .position(|id| Some(*id) == network_client.player_id)
+    {
+        Some(index) => format!("P{}/{}", index + 1, standings.order.len()),
+        None => String::new(),
+    };
+    if let Some(next) = standings.next_elimination {
+        label += &format!("\nNext out in {}s", next.ceil() as u32);
+    }
+
+    for mut text in standings_text.iter_mut() {
+        if text.0 != label {
+            text.0 = label.clone();
+        }
+    }
+}
+
+pub fn despawn_race_banner(mut commands: Commands, banner: Query<Entity, With<RaceBanner>>) {
+    for entity in banner.iter() {
+        commands.entity(entity).despawn();
+    }
+}
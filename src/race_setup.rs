@@ -0,0 +1,254 @@
+use crate::GameState;
+use crate::game_logic::{
+    CpuDifficulty, CupStandings, LocalPlayers, MAX_RACERS, PracticeGrid, RaceSetting, RaceSettings,
+};
+use crate::networking::{MapChoice, SelectedMap};
+use crate::title_screen::{
+    IpTypingMode, MainScreenEntity, ServerAddress, destroy_screen, setup_title_screen,
+};
+use bevy::prelude::*;
+
+// Offline race setup, between the title screen and an offline race
+
+#[derive(Component)]
+pub struct RaceSetupScreenEntity;
+
+// Rows above the per-CPU rows
+const MAP_ROW: usize = 0;
+const PLAYERS_ROW: usize = 1;
+const LAPS_ROW: usize = 2;
+const AI_COUNT_ROW: usize = 3;
+const CUP_ROW: usize = 4;
+const FIRST_CPU_ROW: usize = 5;
+
+#[derive(Resource, Default, Clone, Copy)]
+pub struct RaceSetupView {
+    pub selected: usize,
+}
+
+// Picking a CPU difficulty in the settings menu resets every CPU on the grid to it
+pub fn sync_practice_difficulty(
+    cpu_difficulty: Res<CpuDifficulty>,
+    mut practice_grid: ResMut<PracticeGrid>,
+) {
+    for driver in practice_grid.drivers.iter_mut() {
+        driver.difficulty = *cpu_difficulty;
+    }
+}
+
+pub fn check_for_race_setup_input(
+    input: Res<ButtonInput<KeyCode>>,
+    current_state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    server_address: Res<ServerAddress>,
+    typing_mode: Res<IpTypingMode>,
+    mut view: ResMut<RaceSetupView>,
+    mut selected_map: ResMut<SelectedMap>,
+    mut race_settings: ResMut<RaceSettings>,
+    mut practice_grid: ResMut<PracticeGrid>,
+    mut local_players: ResMut<LocalPlayers>,
+    main_screen_query: Query<Entity, With<MainScreenEntity>>,
+    setup_screen_query: Query<Entity, With<RaceSetupScreenEntity>>,
+) {
+    match *current_state.get() {
+        GameState::Title => {
+            if typing_mode.enabled {
+                return;
+            }
+            // 4 races the CPU alone, 6 brings a second player along for split-screen
+            let players = if input.just_pressed(KeyCode::Digit4) {
+                1
+            } else if input.just_pressed(KeyCode::Digit6) {
+                2
+            } else {
+                return;
+            };
+            local_players.count = players;
+            view.selected = 0;
+            next_state.set(GameState::RaceSetup);
+            destroy_screen(&mut commands, &main_screen_query);
+            setup_race_setup_screen(
+                &mut commands,
+                selected_map.choice,
+                &race_settings,
+                &practice_grid,
+                &local_players,
+                *view,
+            );
+        }
+        GameState::RaceSetup => {
+            if input.just_pressed(KeyCode::Escape) {
+                next_state.set(GameState::Title);
+                destroy_screen(&mut commands, &setup_screen_query);
+                setup_title_screen(commands, asset_server, server_address);
+                return;
+            }
+            if input.any_just_pressed([KeyCode::Enter, KeyCode::Digit1]) {
+                // a cup starts on the picked map and runs until the podium
+                if race_settings.cup {
+                    commands.insert_resource(CupStandings::new(selected_map.choice));
+                } else {
+                    commands.remove_resource::<CupStandings>();
+                }
+                next_state.set(GameState::PlayingDemo);
+                destroy_screen(&mut commands, &setup_screen_query);
+                return;
+            }
+
+            let rows = FIRST_CPU_ROW + race_settings.ai_slots(local_players.count as usize);
+            let mut changed = false;
+            if input.any_just_pressed([KeyCode::ArrowUp, KeyCode::KeyW]) {
+                view.selected = (view.selected + rows - 1) % rows;
+                changed = true;
+            } else if input.any_just_pressed([KeyCode::ArrowDown, KeyCode::KeyS]) {
+                view.selected = (view.selected + 1) % rows;
+                changed = true;
+            }
+
+            let forward = if input.any_just_pressed([KeyCode::ArrowRight, KeyCode::KeyD]) {
+                Some(true)
+            } else if input.any_just_pressed([KeyCode::ArrowLeft, KeyCode::KeyA]) {
+                Some(false)
+            } else {
+                None
+            };
+
+            if let Some(forward) = forward {
+                match view.selected {
+                    MAP_ROW => {
+                        selected_map.choice = match selected_map.choice {
+                            MapChoice::Small => MapChoice::Big,
+                            MapChoice::Big => MapChoice::Small,
+                        };
+                    }
+                    PLAYERS_ROW => {
+                        local_players.count = if local_players.split_screen() { 1 } else { 2 };
+                    }
+                    LAPS_ROW => race_settings.adjust(RaceSetting::Laps, forward),
+                    AI_COUNT_ROW => race_settings.adjust(RaceSetting::AiCount, forward),
+                    CUP_ROW => race_settings.adjust(RaceSetting::Cup, forward),
+                    row => {
+                        let driver = &mut practice_grid.drivers[row - FIRST_CPU_ROW];
+                        driver.difficulty = if forward {
+                            driver.difficulty.next()
+                        } else {
+                            driver.difficulty.prev()
+                        };
+                    }
+                }
+                changed = true;
+            }
+
+            // P cycles the personality of the selected CPU
+            if input.just_pressed(KeyCode::KeyP) && view.selected >= FIRST_CPU_ROW {
+                let driver = &mut practice_grid.drivers[view.selected - FIRST_CPU_ROW];
+                driver.personality = driver.personality.next();
+                changed = true;
+            }
+
+            if changed {
+                // fewer CPUs (or a second player) can leave the cursor past the last row
+                let rows = FIRST_CPU_ROW + race_settings.ai_slots(local_players.count as usize);
+                view.selected = view.selected.min(rows - 1);
+                destroy_screen(&mut commands, &setup_screen_query);
+                setup_race_setup_screen(
+                    &mut commands,
+                    selected_map.choice,
+                    &race_settings,
+                    &practice_grid,
+                    &local_players,
+                    *view,
+                );
+            }
+        }
+        _ => {}
+    }
+}
+
+fn setup_race_setup_screen(
+    commands: &mut Commands,
+    map: MapChoice,
+    race_settings: &RaceSettings,
+    practice_grid: &PracticeGrid,
+    local_players: &LocalPlayers,
+    view: RaceSetupView,
+) {
+    commands.spawn((
+        Text2d::new("Race Setup"),
+        TextColor(Color::BLACK),
+        Transform {
+            translation: Vec3::new(0., 300., 1.),
+            ..default()
+        },
+        TextFont {
+            font_size: 50.0,
+            ..default()
+        },
+        RaceSetupScreenEntity,
+    ));
+
+    let humans = local_players.count as usize;
+    let ai_count = race_settings.ai_slots(humans);
+    let mut labels = vec![
+        format!("Map: {}", map.label()),
+        format!(
+            "Players: {}",
+            if local_players.split_screen() {
+                "2 (Split Screen)"
+            } else {
+                "1"
+            }
+        ),
+        race_settings.label(RaceSetting::Laps),
+        format!("AI Opponents: {} / {}", ai_count, MAX_RACERS - humans),
+        race_settings.label(RaceSetting::Cup),
+    ];
+    for (i, driver) in practice_grid.drivers.iter().take(ai_count).enumerate() {
+        labels.push(format!(
+            "CPU {}: {}  {}",
+            i + 1,
+            driver.difficulty.as_str(),
+            driver.personality.as_str()
+        ));
+    }
+
+    for (row, label) in labels.into_iter().enumerate() {
+        let (text, color) = if row == view.selected {
+            (format!("> {} <", label), Color::BLACK)
+        } else {
+            (label, Color::srgb_u8(60, 60, 60))
+        };
+        commands.spawn((
+            Text2d::new(text),
+            TextColor(color),
+            Transform {
+                translation: Vec3::new(0., 220. - row as f32 * 40., 1.),
+                ..default()
+            },
+            TextFont {
+                font_size: 28.0,
+                ..default()
+            },
+            RaceSetupScreenEntity,
+        ));
+    }
+
+    // Hint text
+    commands.spawn((
+        Text2d::new(
+            "W/S to pick, A/D to change, P for CPU personality, ENTER to race, ESC to go back",
+        ),
+        TextColor(Color::srgb_u8(120, 120, 120)),
+        Transform {
+            translation: Vec3::new(0., -300., 1.),
+            ..default()
+        },
+        TextFont {
+            font_size: 22.0,
+            ..default()
+        },
+        RaceSetupScreenEntity,
+    ));
+}
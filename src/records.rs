@@ -0,0 +1,389 @@
+use crate::car_skins::CarSkinSelection;
+use crate::drift_settings::DriftSettings;
+use crate::game_logic::{RaceMode, RaceResults, RaceSettings, RaceStandings, format_race_time};
+use crate::networking::MapChoice;
+use crate::networking_plugin::NetworkClient;
+use crate::title_screen::{IpTypingMode, MainScreenEntity, ServerAddress, destroy_screen, setup_title_screen};
+use crate::user_data::user_data_path;
+use crate::{CurrentMapNumber, GameState};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// How many entries each table keeps
+pub const TOP_N: usize = 10;
+
+const RECORDS_FILE: &str = "records.json";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RecordEntry {
+    pub time: f32,
+    pub player: String,
+    pub skin: String,
+    pub date: String,
+    pub easy_drift: bool,
+    // race records only compare races of the same length
+    #[serde(default)]
+    pub laps: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MapRecords {
+    pub best_laps: Vec<RecordEntry>,
+    pub best_races: Vec<RecordEntry>,
+}
+
+// Everything in records.json, keyed by map
+#[derive(Resource, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RecordsStore {
+    pub maps: HashMap<String, MapRecords>,
+}
+
+impl RecordsStore {
+    pub fn load() -> Self {
+        let path = user_data_path(RECORDS_FILE);
+        let Ok(text) = fs::read_to_string(&path) else {
+            return Self::default();
+        };
+        match serde_json::from_str(&text) {
+            Ok(store) => store,
+            Err(e) => {
+                println!("Failed to read records from {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self) {
+        let path = user_data_path(RECORDS_FILE);
+        let text = serde_json::to_string_pretty(self).unwrap();
+        if let Err(e) = fs::write(&path, text) {
+            println!("Failed to save records to {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn map(&self, map: MapChoice) -> Option<&MapRecords> {
+        self.maps.get(map.key())
+    }
+
+    /// Add a lap, returns its rank (0 = best) if it made the table
+    pub fn add_lap(&mut self, map: MapChoice, entry: RecordEntry) -> Option<usize> {
+        let records = self.maps.entry(map.key().to_string()).or_default();
+        insert_ranked(&mut records.best_laps, entry, |_| true)
+    }
+
+    /// Add a race time, ranked against races with the same lap count
+    pub fn add_race(&mut self, map: MapChoice, entry: RecordEntry) -> Option<usize> {
+        let records = self.maps.entry(map.key().to_string()).or_default();
+        let laps = entry.laps;
+        insert_ranked(&mut records.best_races, entry, |other| other.laps == laps)
+    }
+}
+
+// Insert into a table sorted by time, keeping only the top N of entries matching `same_table`
+fn insert_ranked(
+    table: &mut Vec<RecordEntry>,
+    entry: RecordEntry,
+    same_table: impl Fn(&RecordEntry) -> bool,
+) -> Option<usize> {
+    let rank = table
+        .iter()
+        .filter(|other| same_table(other))
+        .take_while(|other| other.time <= entry.time)
+        .count();
+    if rank >= TOP_N {
+        return None;
+    }
+
+    let index = table
+        .iter()
+        .position(|other| other.time > entry.time)
+        .unwrap_or(table.len());
+    table.insert(index, entry);
+
+    // drop whatever got pushed off the bottom of this table
+    let mut kept = 0;
+    table.retain(|other| {
+        if !same_table(other) {
+            return true;
+        }
+        kept += 1;
+        kept <= TOP_N
+    });
+
+    Some(rank)
+}
+
+// Records set by the race that just ended, highlighted on the results screen
+#[derive(Resource, Default, Clone)]
+pub struct NewRecords {
+    pub race_rank: Option<usize>,
+    pub laps: Vec<(f32, usize)>, // lap time, rank
+}
+
+// Name saved next to records, there are no accounts so use the OS user
+pub fn player_name() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .unwrap_or("Player".to_string())
+}
+
+// Today's date as YYYY-MM-DD (UTC)
+pub fn today() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = (secs / 86_400) as i64;
+
+    // days since 1970-01-01 to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Once the race is over, file the local player's laps and race time
+pub fn save_race_records(
+    mut commands: Commands,
+    state: Res<State<GameState>>,
+    map_number: Res<CurrentMapNumber>,
+    results: Res<RaceResults>,
+    standings: Option<Res<RaceStandings>>,
+    network_client: Res<NetworkClient>,
+    race_settings: Res<RaceSettings>,
+    skin_selection: Res<CarSkinSelection>,
+    drift_settings: Res<DriftSettings>,
+) {
+    let map = if map_number.0 == 2 {
+        MapChoice::Big
+    } else {
+        MapChoice::Small
+    };
+
+    // Online the server only reports race times, offline we also know each lap
+    let (local_id, lap_times) = if *state.get() == GameState::Playing {
+        (network_client.player_id, Vec::new())
+    } else {
+        (
+            Some(0),
+            standings
+                .map(|standings| standings.player_lap_times.clone())
+                .unwrap_or_default(),
+        )
+    };
+
+    let entry = |time: f32, laps: Option<u8>| RecordEntry {
+        time,
+        player: player_name(),
+        skin: skin_selection.current_skin().to_string(),
+        date: today(),
+        easy_drift: drift_settings.easy_mode,
+        laps,
+    };
+
+    let mut store = RecordsStore::load();
+    let mut new_records = NewRecords::default();
+
+    for lap_time in lap_times {
+        if let Some(rank) = store.add_lap(map, entry(lap_time, None)) {
+            println!("New lap record #{}: {}", rank + 1, format_race_time(lap_time));
+            new_records.laps.push((lap_time, rank));
+        }
+    }
+
+    // elimination races don't have a comparable finishing time
+    let race_time = results
+        .entries
+        .iter()
+        .find(|e| Some(e.id) == local_id && !e.dnf && !e.eliminated)
+        .and_then(|e| e.time)
+        .filter(|_| race_settings.mode == RaceMode::Standard);
+    if let Some(race_time) = race_time {
+        new_records.race_rank = store.add_race(map, entry(race_time, Some(race_settings.laps)));
+        if let Some(rank) = new_records.race_rank {
+            println!("New race record #{}: {}", rank + 1, format_race_time(race_time));
+        }
+    }
+
+    store.save();
+    commands.insert_resource(new_records);
+}
+
+// Records browser
+
+#[derive(Component)]
+pub struct RecordsScreenEntity;
+
+#[derive(Resource, Clone, Copy)]
+pub struct RecordsView {
+    pub map: MapChoice,
+    pub show_races: bool,
+}
+
+impl Default for RecordsView {
+    fn default() -> Self {
+        Self {
+            map: MapChoice::Small,
+            show_races: false,
+        }
+    }
+}
+
+pub fn check_for_records_input(
+    input: Res<ButtonInput<KeyCode>>,
+    current_state: Res<State<GameState>>,
+    mut next_state: ResMut<NextState<GameState>>,
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    server_address: Res<ServerAddress>,
+    typing_mode: Res<IpTypingMode>,
+    mut view: ResMut<RecordsView>,
+    main_screen_query: Query<Entity, With<MainScreenEntity>>,
+    records_screen_query: Query<Entity, With<RecordsScreenEntity>>,
+) {
+    match *current_state.get() {
+        GameState::Title => {
+            if !typing_mode.enabled && input.just_pressed(KeyCode::KeyR) {
+                next_state.set(GameState::Records);
+                destroy_screen(&mut commands, &main_screen_query);
+                setup_records_screen(&mut commands, &RecordsStore::load(), *view);
+            }
+        }
+        GameState::Records => {
+            if input.just_pressed(KeyCode::Escape) {
+                next_state.set(GameState::Title);
+                destroy_screen(&mut commands, &records_screen_query);
+                setup_title_screen(commands, asset_server, server_address);
+                return;
+            }
+
+            let mut changed = false;
+            if input.any_just_pressed([
+                KeyCode::KeyA,
+                KeyCode::ArrowLeft,
+                KeyCode::KeyD,
+                KeyCode::ArrowRight,
+            ]) {
+                view.map = match view.map {
+                    MapChoice::Small => MapChoice::Big,
+                    MapChoice::Big => MapChoice::Small,
+                };
+                changed = true;
+            }
+            if input.any_just_pressed([
+                KeyCode::KeyW,
+                KeyCode::ArrowUp,
+                KeyCode::KeyS,
+                KeyCode::ArrowDown,
+            ]) {
+                view.show_races = !view.show_races;
+                changed = true;
+            }
+
+            if changed {
+                destroy_screen(&mut commands, &records_screen_query);
+                setup_records_screen(&mut commands, &RecordsStore::load(), *view);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn setup_records_screen(commands: &mut Commands, store: &RecordsStore, view: RecordsView) {
+    let table_name = if view.show_races { "Best Races" } else { "Best Laps" };
+    commands.spawn((
+        Text2d::new(format!("{} - {}", table_name, view.map.label())),
+        TextColor(Color::BLACK),
+        Transform {
+            translation: Vec3::new(0., 280., 1.),
+            ..default()
+        },
+        TextFont {
+            font_size: 50.0,
+            ..default()
+        },
+        RecordsScreenEntity,
+    ));
+
+    let entries = store
+        .map(view.map)
+        .map(|records| {
+            if view.show_races {
+                records.best_races.clone()
+            } else {
+                records.best_laps.clone()
+            }
+        })
+        .unwrap_or_default();
+
+    if entries.is_empty() {
+        commands.spawn((
+            Text2d::new("No records yet"),
+            TextColor(Color::srgb_u8(120, 120, 120)),
+            Transform {
+                translation: Vec3::new(0., 150., 1.),
+                ..default()
+            },
+            TextFont {
+                font_size: 32.0,
+                ..default()
+            },
+            RecordsScreenEntity,
+        ));
+    }
+
+    for (row, entry) in entries.iter().enumerate() {
+        let laps = entry
+            .laps
+            .map(|laps| format!("  {} laps", laps))
+            .unwrap_or_default();
+        let easy = if entry.easy_drift { "  (easy drift)" } else { "" };
+        commands.spawn((
+            Text2d::new(format!(
+                "{:>2}. {}  {}  {}  {}{}{}",
+                row + 1,
+                format_race_time(entry.time),
+                entry.player,
+                entry.skin.trim_end_matches(".png"),
+                entry.date,
+                laps,
+                easy
+            )),
+            TextColor(Color::BLACK),
+            Transform {
+                translation: Vec3::new(0., 210. - row as f32 * 40., 1.),
+                ..default()
+            },
+            TextFont {
+                font_size: 28.0,
+                ..default()
+            },
+            RecordsScreenEntity,
+        ));
+    }
+
+    // Hint text
+    commands.spawn((
+        Text2d::new("A/D to change map, W/S to switch laps/races, ESC to go back"),
+        TextColor(Color::srgb_u8(120, 120, 120)),
+        Transform {
+            translation: Vec3::new(0., -300., 1.),
+            ..default()
+        },
+        TextFont {
+            font_size: 24.0,
+            ..default()
+        },
+        RecordsScreenEntity,
+    ));
+}
@@ -1,9 +1,17 @@
 use serde_json::json;
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use crate::game_logic::{
+    AI_ID_START, AiDebugCar, CupStandings, MapLevelData, Pickup, RaceResultEntry, RaceSettings, START_ORIENTATION,
+    Team,
+};
 use crate::types::*;
 use crate::networking::MapChoice;
+use crate::race_phase::{COUNTDOWN_SECS, GRID_SECS};
+use crate::utils::server_clock_ms;
 
 /// Broadcast the current lobby state to all players in the lobby
 pub fn broadcast_lobby_state(
@@ -11,15 +19,18 @@ pub fn broadcast_lobby_state(
     lobbies: &LobbyList,
     lobby_index: usize,
 ) {
-    let guard = lobbies.lock().unwrap();
+    let mut guard = lobbies.lock().unwrap();
 
-    let lobby = if let Some(i) = guard.get(lobby_index) {
+    let lobby = if let Some(i) = guard.get_mut(lobby_index) {
         i
     } else {
         println!("Lobby does not exist");
         return;
     };
 
+    // Teams follow the player list and settings, so refresh them with every update
+    lobby.assign_teams();
+
     // Snapshot the player IDs
     let players: Vec<u32> = {
         let lobby_guard = lobby.players.lock().unwrap();
@@ -29,7 +40,10 @@ pub fn broadcast_lobby_state(
     // Build one payload that everyone in this lobby gets
     let payload = json!({
         "lobby": lobby.name.clone(),
-        "players": players
+        "players": players,
+        "host": lobby.host,
+        "settings": lobby.settings,
+        "teams": lobby.teams
     })
     .to_string()
         + "\n";
@@ -79,12 +93,19 @@ pub fn broadcast_game_start(
     players: &[u32],
     lobby_name: &str,
     map: MapChoice,
+    settings: &RaceSettings,
+    teams: &HashMap<u32, Team>,
+    server_time: u64,
+    go_time: u64,
 ) {
     let payload = json!({
         "type": "game_started",
         "lobby": lobby_name,
-        "time": 1000,
-        "map": map
+        "server_time": server_time,
+        "go_time": go_time,
+        "map": map,
+        "settings": settings,
+        "teams": teams
     })
     .to_string()
         + "\n";
@@ -98,6 +119,234 @@ pub fn broadcast_game_start(
     }
 }
 
+/// Tell everyone in a lobby that a car crossed the line for the last time
+pub fn broadcast_car_finished(
+    connected_clients: &ConnectedClients,
+    players: &[u32],
+    entry: &RaceResultEntry,
+) {
+    let payload = json!({
+        "type": "car_finished",
+        "id": entry.id,
+        "position": entry.position,
+        "time": entry.time.unwrap_or_default()
+    });
+    send_to_players(connected_clients, players, &payload);
+}
+
+/// Tell everyone in a lobby that a car was knocked out of an elimination race
+pub fn broadcast_car_eliminated(
+    connected_clients: &ConnectedClients,
+    players: &[u32],
+    entry: &RaceResultEntry,
+) {
+    let payload = json!({
+        "type": "car_eliminated",
+        "id": entry.id,
+        "position": entry.position,
+        "time": entry.time.unwrap_or_default()
+    });
+    send_to_players(connected_clients, players, &payload);
+}
+
+/// Current running order, plus the time until the next elimination if there is one
+pub fn broadcast_race_standings(
+    connected_clients: &ConnectedClients,
+    players: &[u32],
+    order: &[u32],
+    next_elimination: Option<f32>,
+) {
+    let payload = json!({
+        "type": "race_standings",
+        "order": order,
+        "next_elimination": next_elimination
+    });
+    send_to_players(connected_clients, players, &payload);
+}
+
+/// What every CPU car in the lobby is up to, for players with the AI debug overlay open.
+/// Split over as many messages as it takes to keep each one inside a single packet.
+pub fn broadcast_ai_debug(connected_clients: &ConnectedClients, players: &[u32], cars: &[AiDebugCar]) {
+    for batch in ai_debug_batches(cars) {
+        let payload = json!({
+            "type": "ai_debug",
+            "cars": batch
+        });
+        send_to_players(connected_clients, players, &payload);
+    }
+}
+
+// Split the cars into groups small enough to each go out in one message of at most
+// MAX_AI_DEBUG_BYTES, trimming the far end of any path that won't fit even on its own
+fn ai_debug_batches(cars: &[AiDebugCar]) -> Vec<Vec<AiDebugCar>> {
+    const MAX_AI_DEBUG_BYTES: usize = 1200;
+    // the rest of the message around the cars, `{"cars":[],"type":"ai_debug"}`
+    const ENVELOPE_BYTES: usize = 29;
+    let size = |car: &AiDebugCar| serde_json::to_string(car).map_or(0, |json| json.len());
+
+    let mut batches: Vec<Vec<AiDebugCar>> = Vec::new();
+    let mut batch_bytes = 0;
+    for car in cars {
+        let mut car = car.clone();
+        while ENVELOPE_BYTES + size(&car) > MAX_AI_DEBUG_BYTES && car.path.pop().is_some() {}
+        // one more comma between this car and the last
+        let bytes = size(&car) + 1;
+        match batches.last_mut() {
+            Some(batch) if batch_bytes + bytes <= MAX_AI_DEBUG_BYTES => {
+                batch.push(car);
+                batch_bytes += bytes;
+            }
+            _ => {
+                batches.push(vec![car]);
+                batch_bytes = ENVELOPE_BYTES + bytes - 1;
+            }
+        }
+    }
+    batches
+}
+
+/// Every pickup on the lobby's track, sent whenever the list changes
+pub fn broadcast_pickups(connected_clients: &ConnectedClients, players: &[u32], pickups: &[Pickup]) {
+    let payload = json!({
+        "type": "pickups",
+        "pickups": pickups
+    });
+    send_to_players(connected_clients, players, &payload);
+}
+
+/// Broadcast the final standings once the race is over
+pub fn broadcast_race_results(
+    connected_clients: &ConnectedClients,
+    players: &[u32],
+    results: &[RaceResultEntry],
+) {
+    let payload = json!({
+        "type": "race_results",
+        "results": results
+    });
+    send_to_players(connected_clients, players, &payload);
+}
+
+/// Cup points so far, sent after every race of a cup and again when the next one starts
+pub fn broadcast_cup_standings(
+    connected_clients: &ConnectedClients,
+    players: &[u32],
+    standings: &CupStandings,
+) {
+    let payload = json!({
+        "type": "cup_standings",
+        "standings": standings
+    });
+    send_to_players(connected_clients, players, &payload);
+}
+
+/// Put every car in the lobby on the grid of its current map and tell the players
+/// the race is on. Runs for the first race and for each following race of a cup.
+pub fn start_race(
+    lobby: &mut Lobby,
+    connected_clients: &ConnectedClients,
+    cmd_sender: &Sender<ServerCommand>,
+) {
+    lobby.assign_teams();
+
+    // Schedule GO after the grid and countdown, the race clock starts from there
+    let start_delay = Duration::from_secs_f32(GRID_SECS + COUNTDOWN_SECS);
+    let server_time = server_clock_ms();
+    let go_time = server_time + start_delay.as_millis() as u64;
+    lobby.race = RaceProgress {
+        started_at: Some(Instant::now() + start_delay),
+        ..Default::default()
+    };
+
+    let players: Vec<u32> = lobby.players.lock().unwrap().clone();
+    let start_positions = MapLevelData::for_choice(lobby.map_choice).start_grid;
+
+    // Initialize all players to fixed grid spawn positions
+    {
+        let mut states = lobby.states.lock().unwrap();
+        states.clear();
+        for (idx, player_id) in players.iter().enumerate() {
+            if let Some((spawn_x, spawn_y)) = start_positions.get(idx) {
+                states.insert(
+                    *player_id,
+                    PlayerState {
+                        x: *spawn_x,
+                        y: *spawn_y,
+                        velocity: bevy::math::Vec2::ZERO,
+                        angle: START_ORIENTATION,
+                        inputs: PlayerInput::default(),
+                        last_processed_sequence: 0,
+                        boost_remaining: 0.0,
+                        was_drifting: false,
+                        input_queue: Vec::new(),
+                    },
+                );
+            }
+        }
+    }
+
+    broadcast_game_start(
+        connected_clients,
+        &players,
+        &lobby.name,
+        lobby.map_choice,
+        &lobby.settings,
+        &lobby.teams,
+        server_time,
+        go_time,
+    );
+    if let Some(cup) = &lobby.cup {
+        broadcast_cup_standings(connected_clients, &players, cup);
+    }
+
+    // Clear out the cars of the last race, then spawn one for each player
+    let _ = cmd_sender.send(ServerCommand::DespawnLobby {
+        lobby_name: lobby.name.clone(),
+    });
+    for (idx, player_id) in players.iter().enumerate() {
+        if let Some((spawn_x, spawn_y)) = start_positions.get(idx) {
+            let _ = cmd_sender.send(ServerCommand::SpawnPlayer {
+                player_id: *player_id,
+                lobby_name: lobby.name.clone(),
+                x: *spawn_x,
+                y: *spawn_y,
+            });
+        }
+    }
+
+    // Spawn as many AI cars as the host asked for, as long as they fit on the grid
+    let num_players = players.len();
+    let num_ai = lobby
+        .settings
+        .ai_slots(num_players)
+        .min(start_positions.len().saturating_sub(num_players));
+    for i in 0..num_ai {
+        let ai_id = AI_ID_START + i as u32;
+        let slot_index = num_players + i;
+        if let Some((spawn_x, spawn_y)) = start_positions.get(slot_index) {
+            let _ = cmd_sender.send(ServerCommand::SpawnAI {
+                ai_id,
+                lobby_name: lobby.name.clone(),
+                x: *spawn_x,
+                y: *spawn_y,
+                angle: START_ORIENTATION,
+                difficulty: lobby.settings.difficulty,
+                personality: lobby.settings.ai_personality(i),
+            });
+        }
+    }
+}
+
+fn send_to_players(connected_clients: &ConnectedClients, players: &[u32], payload: &serde_json::Value) {
+    let payload = payload.to_string() + "\n";
+    let addrs = connected_clients.addrs.lock().unwrap();
+    for pid in players {
+        if let Some(addr) = addrs.get(pid) {
+            let _ = connected_clients.socket.send_to(payload.as_bytes(), addr);
+        }
+    }
+}
+
 /// Clean up when a client disconnects
 pub fn disconnect_cleanup(
     id: u32,
@@ -118,6 +367,9 @@ pub fn disconnect_cleanup(
     if let Ok(mut last_seen) = connected.last_seen.lock() {
         last_seen.remove(&id);
     }
+    if let Ok(mut ai_watchers) = connected.ai_watchers.lock() {
+        ai_watchers.remove(&id);
+    }
     if let Some(addr) = addr {
         if let Ok(mut addr_to_id) = connected.addr_to_id.lock() {
             addr_to_id.remove(&addr);
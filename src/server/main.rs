@@ -21,6 +21,8 @@ mod multiplayer;
 mod networking;
 #[path = "../networking_plugin.rs"]
 mod networking_plugin;
+#[path = "../race_phase.rs"]
+mod race_phase;
 #[path = "../speed.rs"]
 mod speed;
 #[path = "../title_screen.rs"]
@@ -40,7 +42,7 @@ use std::net::UdpSocket;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use game_logic::{SERVER_TIMESTEP};
+use game_logic::{BehaviourTrees, PowerupTree, SERVER_TIMESTEP};
 use lobby_management::*;
 use net::*;
 use simulation::*;
@@ -85,6 +87,7 @@ fn main() {
         addrs: Arc::clone(&connected_clients.addrs),
         addr_to_id: Arc::clone(&connected_clients.addr_to_id),
         last_seen: Arc::clone(&connected_clients.last_seen),
+        ai_watchers: Arc::clone(&connected_clients.ai_watchers),
         socket: Arc::clone(&socket),
     };
     let lobbies_clone = Arc::clone(&lobbies);
@@ -107,6 +110,8 @@ fn main() {
         .insert_resource(connected_clients)
         .insert_resource(Lobbies { list: lobbies })
         .insert_resource(PlayerEntities::default())
+        .insert_resource(BehaviourTrees::load())
+        .insert_resource(PowerupTree::load())
         .insert_resource(ServerCommandReceiver {
             receiver: cmd_receiver,
         })
@@ -117,8 +122,16 @@ fn main() {
                 process_server_commands_system,
                 sync_input_from_lobbies_system,
                 physics_simulation_system,
+                racing_line_build_system,
+                ai_obstacle_system,
+                ai_path_planning_system,
+                ai_behaviour_system,
+                ai_powerup_system,
                 ai_movement_system,
+                race_progress_system,
+                cup_intermission_system,
                 broadcast_state_system,
+                broadcast_ai_debug_system,
                 timeout_cleanup_system,
             )
                 .chain(),
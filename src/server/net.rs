@@ -4,12 +4,10 @@ use std::io;
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
-use crate::game_logic::{START_ORIENTATION, START_POSITIONS, START_POSITIONS_MAP2, TILE_SIZE};
-use crate::networking::MapChoice;
+use crate::game_logic::CupStandings;
 use crate::lobby_management::*;
 use crate::types::*;
-use crate::game_logic::{GameMap, load_map_from_file};
-use crate::game_logic::theta_grid::ThetaGrid;
+use crate::utils::server_clock_ms;
 
 /// Spawn the UDP listener task that handles incoming client messages
 pub fn server_listener(
@@ -147,25 +145,7 @@ fn handle_client_message(
             new_lobby.host = id;
             new_lobby.players.lock().unwrap().push(id);
             // add game_map to new_lobby's data based on selection
-            let game_map = load_map_from_file(map.path());
-            println!(
-                "Server loaded map ({:?}): {}x{}",
-                map,
-                game_map.width,
-                game_map.height
-            );
-            new_lobby.map_choice = map;
-            new_lobby.map = game_map;
-            let grid_size = match map {
-                MapChoice::Small => (100, 100),
-                MapChoice::Big => (125, 125),
-            };
-            new_lobby.theta_grid = ThetaGrid::create_theta_grid_with_size(
-                &new_lobby.map,
-                TILE_SIZE as f32,
-                grid_size.0,
-                grid_size.1,
-            );
+            new_lobby.load_map(map);
 
             guard.push(new_lobby);
 
@@ -327,73 +307,14 @@ fn handle_client_message(
                 }
 
                 lobby.started = true;
-
-                let players: Vec<u32> = lobby.players.lock().unwrap().clone();
-
-                // Initialize all players to fixed grid spawn positions
-                {
-                    let start_positions = match lobby.map_choice {
-                        MapChoice::Small => &START_POSITIONS,
-                        MapChoice::Big => &START_POSITIONS_MAP2,
-                    };
-                    let mut states = lobby.states.lock().unwrap();
-                    for (idx, player_id) in players.iter().enumerate() {
-                        if let Some((spawn_x, spawn_y)) = start_positions.get(idx) {
-                            states.insert(
-                                *player_id,
-                                PlayerState {
-                                    x: *spawn_x,
-                                    y: *spawn_y,
-                                    velocity: bevy::math::Vec2::ZERO,
-                                    angle: START_ORIENTATION,
-                                    inputs: PlayerInput::default(),
-                                    last_processed_sequence: 0,
-                                    boost_remaining: 0.0,
-                                    was_drifting: false,
-                                    input_queue: Vec::new(),
-                                },
-                            );
-                        }
-                    }
-                }
-
-                let map_choice = lobby.map_choice;
-                drop(guard);
-                broadcast_game_start(connected_clients, &players, &name, map_choice);
-
-                // Spawn commands for each player
-                let sender = cmd_sender.lock().unwrap();
-                let start_positions = match map_choice {
-                    MapChoice::Small => &START_POSITIONS,
-                    MapChoice::Big => &START_POSITIONS_MAP2,
+                // a cup runs its whole playlist from here, starting on the lobby's map
+                lobby.cup = if lobby.settings.cup {
+                    Some(CupStandings::new(lobby.map_choice))
+                } else {
+                    None
                 };
-                for (idx, player_id) in players.iter().enumerate() {
-                    if let Some((spawn_x, spawn_y)) = start_positions.get(idx) {
-                        let _ = sender.send(ServerCommand::SpawnPlayer {
-                            player_id: *player_id,
-                            lobby_name: name.clone(),
-                            x: *spawn_x,
-                            y: *spawn_y,
-                        });
-                    }
-                }
-
-                // Spawn AI cars to fill empty slots (up to 4 total)
-                let num_players = players.len();
-                let num_ai = start_positions.len().saturating_sub(num_players);
-                for i in 0..num_ai {
-                    let ai_id = 1000 + i as u32;
-                    let slot_index = num_players + i;
-                    if let Some((spawn_x, spawn_y)) = start_positions.get(slot_index) {
-                        let _ = sender.send(ServerCommand::SpawnAI {
-                            ai_id,
-                            lobby_name: name.clone(),
-                            x: *spawn_x,
-                            y: *spawn_y,
-                            angle: START_ORIENTATION,
-                        });
-                    }
-                }
+                start_race(lobby, connected_clients, &cmd_sender.lock().unwrap());
+                drop(guard);
 
                 let _ = send_to_client(
                     id,
@@ -417,6 +338,56 @@ fn handle_client_message(
             Ok(())
         }
 
+        MessageType::UpdateLobbySettings { name, settings } => {
+            let mut guard = lobbies.lock().unwrap();
+            let lobby_index_opt = guard.iter().position(|l| l.name == name);
+
+            if let Some(lobby_index) = lobby_index_opt {
+                let lobby = &mut guard[lobby_index];
+
+                if lobby.host != id {
+                    let _ = send_to_client(
+                        id,
+                        connected_clients,
+                        &json!({
+                            "type": "error",
+                            "message": "Only the host can change the race settings"
+                        }),
+                    );
+                    return Ok(());
+                }
+
+                if lobby.started {
+                    let _ = send_to_client(
+                        id,
+                        connected_clients,
+                        &json!({
+                            "type": "error",
+                            "message": "Lobby has already started"
+                        }),
+                    );
+                    return Ok(());
+                }
+
+                lobby.settings = settings.sanitized();
+                drop(guard);
+
+                // Everyone in the lobby sees the new settings
+                broadcast_lobby_state(connected_clients, lobbies, lobby_index);
+            } else {
+                let _ = send_to_client(
+                    id,
+                    connected_clients,
+                    &json!({
+                        "type": "error",
+                        "message": format!("Lobby '{}' does not exist", name)
+                    }),
+                );
+            }
+
+            Ok(())
+        }
+
         MessageType::PlayerInput {
             sequence,
             forward,
@@ -444,13 +415,39 @@ fn handle_client_message(
             handle_player_input_buffer(id, inputs, connected_clients, lobbies)
         }
 
+        MessageType::WatchAi { enabled } => {
+            let mut watchers = connected_clients.ai_watchers.lock().unwrap();
+            if enabled {
+                watchers.insert(id);
+            } else {
+                watchers.remove(&id);
+            }
+            Ok(())
+        }
+
+        MessageType::CollectPickup { id: pickup_id } => {
+            // the player's car has already boosted, just take the can off everyone's track
+            let mut guard = lobbies.lock().unwrap();
+            if let Some(lobby) = guard
+                .iter_mut()
+                .find(|lobby| lobby.players.lock().unwrap().contains(&id))
+                && let Some(index) = lobby.pickups.iter().position(|p| p.id == pickup_id)
+            {
+                lobby.pickups.swap_remove(index);
+                let players: Vec<u32> = lobby.players.lock().unwrap().clone();
+                broadcast_pickups(connected_clients, &players, &lobby.pickups);
+            }
+            Ok(())
+        }
+
         MessageType::Ping => {
-            // Send Pong response to client
+            // Send Pong response to client, with our clock so it can line its own up with it
             let _ = send_to_client(
                 id,
                 connected_clients,
                 &json!({
                     "type": "pong",
+                    "server_time": server_clock_ms(),
                 }),
             );
             Ok(())
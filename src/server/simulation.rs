@@ -1,15 +1,26 @@
 use bevy::prelude::*;
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
 
 use crate::game_logic::{
-    AIControlled, CAR_SIZE, DRIFT_RELEASE_BOOST, GameMap, Orientation, PLAYER_SPEED,
-    SERVER_TIMESTEP, START_ORIENTATION, TILE_SIZE, Velocity, handle_collision,
+    AIControlled, AiCar, AiDebugCar, AiPowerups, AiRecovery, AiReflexes, AiTrack, AiTraffic, BehaviourTrees, Blackboard, CAR_SIZE, CUP_INTERMISSION_SECS, CpuDifficulty, DNF_TIMEOUT_SECS, DRIFT_RELEASE_BOOST, Eliminated,
+    Finished, LapCounter, LineBuild, OBSTACLE_COST, LapEvent, MapLevelData, Orientation, PATH_SEARCHES_PER_TICK, PLAYER_SPEED, Personality, RaceMode,
+    PICKUP_COUNT, PICKUP_DISTANCE, Pickup, PowerupHolder, PowerupSituation, PowerupTree,
+    RaceResultEntry, RaceSettings, SERVER_TIMESTEP, START_ORIENTATION, TILE_SIZE, Team,
+    TeamScores, TrafficCar, Velocity, build_race_results, drive_ai_car, format_race_time, handle_collision, last_place, order_by_progress, place_ai_car,
+    car_in_front, pickup_spots, plan_paths, rivals, straight_ahead, use_ai_powerup,
     physics::{PhysicsInput, apply_physics},
-    theta::{ThetaCheckpointList, theta_star, ThetaCommand},
+    theta::ThetaCheckpointList,
+    theta_grid::ThetaGrid,
 };
+use crate::car_state::CarState;
+use crate::speed::SpeedBoost;
 use crate::networking::MapChoice;
-use crate::lobby_management::timeout_cleanup;
+use crate::lobby_management::{
+    broadcast_ai_debug, broadcast_car_eliminated, broadcast_car_finished, broadcast_cup_standings, broadcast_pickups,
+    broadcast_race_results, broadcast_race_standings, start_race, timeout_cleanup,
+};
 use crate::types::*;
 
 
@@ -24,27 +35,33 @@ pub fn physics_simulation_system(
         &mut Orientation,
         &mut PlayerInputComponent,
         &LobbyMember,
+        Option<&Team>,
+        Has<Finished>,
+        Has<Eliminated>,
     )>,
     lobbies: Res<Lobbies>,
 ) {
 
-    // Check which lobbies have started
-    let started_lobbies: Vec<String> = {
+    // Check which lobbies have started, their settings (collisions, teams) and whether GO has passed
+    let started_lobbies: HashMap<String, (RaceSettings, bool)> = {
         let guard = lobbies.list.lock().unwrap();
         guard
             .iter()
             .filter(|l| l.started)
-            .map(|l| l.name.clone())
+            .map(|l| (l.name.clone(), (l.settings, l.race.has_gone())))
             .collect()
     };
 
     // Snapshot positions/velocities for collision checks without aliasing the query
-    let player_snapshots: Vec<(u32, String, Vec2, Vec2)> = query
+    // Finished cars are out of the race, so nobody collides with them
+    let player_snapshots: Vec<(u32, String, Option<Team>, Vec2, Vec2)> = query
         .iter()
-        .map(|(player_id, pos, vel, _, _, lobby_member)| {
+        .filter(|(_, _, _, _, _, _, _, finished, eliminated)| !finished && !eliminated)
+        .map(|(player_id, pos, vel, _, _, lobby_member, team, _, _)| {
             (
                 player_id.0,
                 lobby_member.lobby_name.clone(),
+                team.copied(),
                 Vec2::new(pos.x, pos.y),
                 vel.velocity,
             )
@@ -52,13 +69,23 @@ pub fn physics_simulation_system(
         .collect();
 
     // Process each player
-    for (player_id, mut pos, mut vel, mut orient, mut input_component, lobby_member) in
-        query.iter_mut()
+    for (
+        player_id,
+        mut pos,
+        mut vel,
+        mut orient,
+        mut input_component,
+        lobby_member,
+        team,
+        finished,
+        eliminated,
+    ) in query.iter_mut()
     {
         // Only simulate physics for players in started lobbies
-        if !started_lobbies.contains(&lobby_member.lobby_name) {
+        let Some(&(settings, racing)) = started_lobbies.get(&lobby_member.lobby_name) else {
             continue;
-        }
+        };
+        let team = team.copied();
 
         // Find the lobby to access input queue
         let guard = lobbies.list.lock().unwrap();
@@ -75,6 +102,19 @@ pub fn physics_simulation_system(
                 let inputs_to_process: Vec<InputData> =
                     player_state.input_queue.drain(..).collect();
 
+                // Cars on the grid and finished or eliminated players sit still,
+                // just acknowledge their inputs
+                if finished || eliminated || !racing {
+                    if let Some(last) = inputs_to_process.last() {
+                        player_state.last_processed_sequence = last.sequence;
+                        input_component.last_processed_sequence = last.sequence;
+                    }
+                    vel.velocity = Vec2::ZERO;
+                    player_state.velocity = Vec2::ZERO;
+                    player_state.inputs = PlayerInput::default();
+                    continue;
+                }
+
                 for input_data in inputs_to_process {
                     // Refresh boost timer when client reports a pickup
                     if input_data.boost && player_state.boost_remaining <= 0.0 {
@@ -138,10 +178,12 @@ pub fn physics_simulation_system(
                     // Resolve collisions against walls/other cars (same lobby only)
                     let other_cars_iter = player_snapshots
                         .iter()
-                        .filter(|(other_id, lobby_name, _, _)| {
-                            *other_id != player_id.0 && *lobby_name == lobby_member.lobby_name
+                        .filter(|(other_id, lobby_name, other_team, _, _)| {
+                            *other_id != player_id.0
+                                && *lobby_name == lobby_member.lobby_name
+                                && settings.cars_collide(team, *other_team)
                         })
-                        .map(|(_, _, other_pos, other_vel)| (*other_pos, *other_vel));
+                        .map(|(_, _, _, other_pos, other_vel)| (*other_pos, *other_vel));
                     let should_update = handle_collision(
                         position_vec.extend(0.0),
                         prev_pos,
@@ -238,7 +280,8 @@ pub fn broadcast_state_system(
                         "vx": vel.x,
                         "vy": vel.y,
                         "angle": orient.angle,
-                        "last_processed_sequence": input.last_processed_sequence
+                        "last_processed_sequence": input.last_processed_sequence,
+                        "team": lobby.team_of(*id)
                     })
                 })
                 .collect();
@@ -264,6 +307,64 @@ pub fn broadcast_state_system(
     }
 }
 
+/// System to send the AI debug overlay what each lobby's CPU cars are doing, only to the
+/// players in it who have the overlay open
+pub fn broadcast_ai_debug_system(
+    ai_cars: Query<
+        (&PlayerId, &Position, &CarState, &ThetaCheckpointList, &LobbyMember),
+        With<AIControlled>,
+    >,
+    connected_clients: Res<ConnectedClients>,
+    lobbies: Res<Lobbies>,
+) {
+    // ten times a second is plenty to watch them by
+    const AI_DEBUG_INTERVAL: Duration = Duration::from_millis(100);
+
+    let watchers = connected_clients.ai_watchers.lock().unwrap().clone();
+    if watchers.is_empty() {
+        return;
+    }
+
+    let now = Instant::now();
+    let mut guard = lobbies.list.lock().unwrap();
+    for lobby in guard.iter_mut().filter(|l| l.started) {
+        if lobby
+            .race
+            .last_ai_debug
+            .is_some_and(|last| now.duration_since(last) < AI_DEBUG_INTERVAL)
+        {
+            continue;
+        }
+        let watching: Vec<u32> = lobby
+            .players
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .filter(|id| watchers.contains(id))
+            .collect();
+        if watching.is_empty() {
+            continue;
+        }
+
+        let cars: Vec<AiDebugCar> = ai_cars
+            .iter()
+            .filter(|(.., member)| member.lobby_name == lobby.name)
+            .map(|(id, pos, car_state, checkpoints, _)| {
+                AiDebugCar::new(
+                    id.0,
+                    Vec2::new(pos.x, pos.y),
+                    car_state.behaviour(),
+                    checkpoints,
+                    &lobby.theta_grid,
+                )
+            })
+            .collect();
+        lobby.race.last_ai_debug = Some(now);
+        broadcast_ai_debug(&connected_clients, &watching, &cars);
+    }
+}
+
 /// System to update player input components from the lobby states
 pub fn sync_input_from_lobbies_system(
     mut query: Query<(&PlayerId, &mut PlayerInputComponent, &LobbyMember)>,
@@ -294,6 +395,7 @@ pub fn process_server_commands_system(
     receiver: Res<ServerCommandReceiver>,
     lobbies: Res<Lobbies>,
     mut player_entities: ResMut<PlayerEntities>,
+    lobby_cars: Query<(Entity, &PlayerId, &LobbyMember)>,
 ) {
     // Process all pending commands
     let recv = receiver.receiver.lock().unwrap();
@@ -307,18 +409,22 @@ pub fn process_server_commands_system(
             } => {
                 println!("Spawning player {} in lobby {}", player_id, lobby_name);
 
-                let entity = commands
-                    .spawn((
-                        PlayerId(player_id),
-                        Position { x, y },
-                        Velocity::new(),
-                        Orientation::new(START_ORIENTATION),
-                        PlayerInputComponent::default(),
-                        LobbyMember { lobby_name },
-                    ))
-                    .id();
-
-                player_entities.map.insert(player_id, entity);
+                let laps = lobby_laps(&lobbies, &lobby_name);
+                let team = lobby_team(&lobbies, &lobby_name, player_id);
+                let mut entity = commands.spawn((
+                    PlayerId(player_id),
+                    Position { x, y },
+                    Velocity::new(),
+                    Orientation::new(START_ORIENTATION),
+                    PlayerInputComponent::default(),
+                    LobbyMember { lobby_name },
+                    LapCounter::new(laps),
+                ));
+                if let Some(team) = team {
+                    entity.insert(team);
+                }
+
+                player_entities.map.insert(player_id, entity.id());
             }
             ServerCommand::SpawnAI {
                 ai_id,
@@ -326,8 +432,16 @@ pub fn process_server_commands_system(
                 x,
                 y,
                 angle,
+                difficulty,
+                personality,
             } => {
-                println!("Spawning AI {} in lobby {}", ai_id, lobby_name);
+                println!(
+                    "Spawning AI {} ({}, {}) in lobby {}",
+                    ai_id,
+                    difficulty.as_str(),
+                    personality.as_str(),
+                    lobby_name
+                );
 
                 // Load checkpoints based on the lobby's selected map
                 let map_choice = {
@@ -340,21 +454,34 @@ pub fn process_server_commands_system(
                 };
                 let mut checkpoint_list = ThetaCheckpointList::new(Vec::new());
                 checkpoint_list = checkpoint_list.load_checkpoint_list_for_choice(map_choice);
+                let laps = lobby_laps(&lobbies, &lobby_name);
+                let team = lobby_team(&lobbies, &lobby_name, ai_id);
 
-                let entity = commands
-                    .spawn((
-                        PlayerId(ai_id),
-                        Position { x, y },
-                        Velocity::new(),
-                        Orientation::new(angle),
-                        PlayerInputComponent::default(),
-                        LobbyMember { lobby_name },
-                        AIControlled,
-                        checkpoint_list,
-                    ))
-                    .id();
-
-                player_entities.map.insert(ai_id, entity);
+                let mut entity = commands.spawn((
+                    PlayerId(ai_id),
+                    Position { x, y },
+                    Velocity::new(),
+                    Orientation::new(angle),
+                    PlayerInputComponent::default(),
+                    LobbyMember { lobby_name },
+                    AIControlled,
+                    difficulty,
+                    personality,
+                    checkpoint_list,
+                    CarState::new(),
+                    (
+                        AiReflexes::default(),
+                        AiRecovery::default(),
+                        AiTraffic::default(),
+                        AiPowerups::default(),
+                    ),
+                    LapCounter::new(laps),
+                ));
+                if let Some(team) = team {
+                    entity.insert(team);
+                }
+
+                player_entities.map.insert(ai_id, entity.id());
             }
             ServerCommand::DespawnPlayer { player_id } => {
                 if let Some(entity) = player_entities.map.remove(&player_id) {
@@ -362,10 +489,282 @@ pub fn process_server_commands_system(
                     commands.entity(entity).despawn();
                 }
             }
+            ServerCommand::DespawnLobby { lobby_name } => {
+                println!("Clearing cars in lobby {}", lobby_name);
+                for (entity, player_id, lobby_member) in lobby_cars.iter() {
+                    if lobby_member.lobby_name != lobby_name {
+                        continue;
+                    }
+                    if player_entities.map.get(&player_id.0) == Some(&entity) {
+                        player_entities.map.remove(&player_id.0);
+                    }
+                    commands.entity(entity).despawn();
+                }
+            }
         }
     }
 }
 
+// Laps each car needs to finish in this lobby
+fn lobby_laps(lobbies: &Lobbies, lobby_name: &str) -> u8 {
+    let guard = lobbies.list.lock().unwrap();
+    guard
+        .iter()
+        .find(|l| l.name == lobby_name)
+        .map(|l| l.settings.laps_to_finish())
+        .unwrap_or_default()
+}
+
+// Team a car races for in this lobby, if it's a team race
+fn lobby_team(lobbies: &Lobbies, lobby_name: &str, id: u32) -> Option<Team> {
+    let guard = lobbies.list.lock().unwrap();
+    guard
+        .iter()
+        .find(|l| l.name == lobby_name)
+        .and_then(|l| l.team_of(id))
+}
+
+/// System to track laps on the server, announce finishers and end the race
+/// once everyone is home or the DNF timer runs out. In elimination races it
+/// also knocks out the last-placed car on schedule until one is left.
+pub fn race_progress_system(
+    mut commands: Commands,
+    mut cars: Query<
+        (Entity, &PlayerId, &Position, &mut LapCounter, &LobbyMember, Has<Eliminated>),
+    >,
+    lobbies: Res<Lobbies>,
+    connected_clients: Res<ConnectedClients>,
+) {
+    const STANDINGS_INTERVAL: Duration = Duration::from_secs(1);
+
+    let mut guard = lobbies.list.lock().unwrap();
+
+    for lobby in guard.iter_mut() {
+        if !lobby.started || !lobby.race.has_gone() || lobby.race.results_sent {
+            continue;
+        }
+        let Some(started_at) = lobby.race.started_at else {
+            continue;
+        };
+
+        let (checkpoints, finish) = MapLevelData::for_choice(lobby.map_choice).gates();
+        let players: Vec<u32> = lobby.players.lock().unwrap().clone();
+        let now = Instant::now();
+        let race_time = now.duration_since(started_at).as_secs_f32();
+        let elimination = lobby.settings.mode == RaceMode::Elimination;
+
+        let mut any_cars = false;
+        let mut all_finished = true;
+        let mut unfinished = Vec::new();
+        // cars still on track: (id, progress, distance to next gate) and their entity
+        let mut running = Vec::new();
+        let mut running_entities = HashMap::new();
+        for (entity, player_id, pos, mut lap_counter, lobby_member, eliminated) in cars.iter_mut() {
+            if lobby_member.lobby_name != lobby.name {
+                continue;
+            }
+            any_cars = true;
+            if eliminated {
+                continue;
+            }
+
+            let car_pos = Vec2::new(pos.x, pos.y);
+            if lap_counter.advance(car_pos, &checkpoints, &finish) == LapEvent::Finished {
+                let entry = RaceResultEntry {
+                    id: player_id.0,
+                    position: lobby.race.finish_order.len() as u8 + 1,
+                    time: Some(race_time),
+                    dnf: false,
+                    eliminated: false,
+                };
+                println!(
+                    "Lobby {}: car {} finished P{} in {}",
+                    lobby.name,
+                    entry.id,
+                    entry.position,
+                    format_race_time(race_time)
+                );
+                broadcast_car_finished(&connected_clients, &players, &entry);
+                lobby.race.finish_order.push(entry);
+                commands.entity(entity).insert(Finished);
+
+                // first finisher starts the countdown for everyone else
+                if lobby.race.dnf_deadline.is_none() {
+                    lobby.race.dnf_deadline =
+                        Some(now + Duration::from_secs_f32(DNF_TIMEOUT_SECS));
+                }
+            }
+
+            if !lap_counter.has_finished {
+                all_finished = false;
+                unfinished.push((player_id.0, lap_counter.progress()));
+                running.push((
+                    player_id.0,
+                    lap_counter.progress(),
+                    lap_counter.distance_to_next(car_pos, &checkpoints, &finish),
+                ));
+                running_entities.insert(player_id.0, entity);
+            }
+        }
+        order_by_progress(&mut running);
+
+        if elimination && any_cars {
+            // Is it time to knock someone out?
+            let leader_lap = running.first().map(|(_, (lap, _), _)| *lap).unwrap_or(0);
+            let due = match lobby.settings.elimination_secs {
+                0 => leader_lap > lobby.race.leader_lap,
+                secs => {
+                    let next = *lobby
+                        .race
+                        .next_elimination
+                        .get_or_insert(started_at + Duration::from_secs(secs as u64));
+                    now >= next
+                }
+            };
+            lobby.race.leader_lap = leader_lap;
+
+            if due && running.len() > 1 {
+                if lobby.settings.elimination_secs > 0 {
+                    lobby.race.next_elimination =
+                        Some(now + Duration::from_secs(lobby.settings.elimination_secs as u64));
+                }
+
+                let (last_id, _, _) = running.pop().unwrap();
+                let entry = RaceResultEntry {
+                    id: last_id,
+                    position: running.len() as u8 + 1,
+                    time: Some(race_time),
+                    dnf: false,
+                    eliminated: true,
+                };
+                println!(
+                    "Lobby {}: car {} eliminated in P{}",
+                    lobby.name, entry.id, entry.position
+                );
+                broadcast_car_eliminated(&connected_clients, &players, &entry);
+                lobby.race.eliminated.push(entry);
+                if let Some(entity) = running_entities.get(&last_id) {
+                    commands.entity(*entity).insert(Eliminated);
+                }
+            }
+
+            // Last car standing wins
+            if running.len() <= 1 {
+                let mut results: Vec<RaceResultEntry> = running
+                    .iter()
+                    .map(|(id, _, _)| RaceResultEntry {
+                        id: *id,
+                        position: 1,
+                        time: Some(race_time),
+                        dnf: false,
+                        eliminated: false,
+                    })
+                    .collect();
+                results.extend(lobby.race.eliminated.iter().rev().cloned());
+                println!("Lobby {}: elimination race over, sending results", lobby.name);
+                finish_race(lobby, &connected_clients, &players, &results);
+                continue;
+            }
+        }
+
+        // Running order for the clients' HUD, once a second is plenty
+        if lobby
+            .race
+            .last_standings
+            .is_none_or(|last| now.duration_since(last) >= STANDINGS_INTERVAL)
+        {
+            lobby.race.last_standings = Some(now);
+            let order: Vec<u32> = running.iter().map(|(id, _, _)| *id).collect();
+            let next_elimination = if elimination {
+                lobby
+                    .race
+                    .next_elimination
+                    .map(|next| next.saturating_duration_since(now).as_secs_f32())
+            } else {
+                None
+            };
+            broadcast_race_standings(&connected_clients, &players, &order, next_elimination);
+        }
+
+        let dnf_expired = lobby.race.dnf_deadline.is_some_and(|deadline| now >= deadline);
+        // cars may not have spawned yet on the first tick after start
+        if (any_cars && all_finished) || dnf_expired {
+            let results = build_race_results(&lobby.race.finish_order, unfinished);
+            println!("Lobby {}: race over, sending results", lobby.name);
+            finish_race(lobby, &connected_clients, &players, &results);
+        }
+    }
+}
+
+// Send the results, and in a cup score them and line up the next race
+fn finish_race(
+    lobby: &mut Lobby,
+    connected_clients: &ConnectedClients,
+    players: &[u32],
+    results: &[RaceResultEntry],
+) {
+    log_team_scores(lobby, results);
+    broadcast_race_results(connected_clients, players, results);
+    lobby.race.results_sent = true;
+
+    let Some(cup) = lobby.cup.as_mut() else {
+        return;
+    };
+    cup.award(results);
+    println!(
+        "Lobby {}: cup after race {} of {}: {:?}",
+        lobby.name,
+        cup.race + 1,
+        cup.tracks.len(),
+        cup.table()
+    );
+    broadcast_cup_standings(connected_clients, players, cup);
+    if !cup.is_last_race() {
+        lobby.race.next_race_at =
+            Some(Instant::now() + Duration::from_secs_f32(CUP_INTERMISSION_SECS));
+    }
+}
+
+/// Start the next race of a cup once the intermission standings have been up long enough
+pub fn cup_intermission_system(
+    lobbies: Res<Lobbies>,
+    connected_clients: Res<ConnectedClients>,
+    cmd_sender: Res<ServerCommandSender>,
+) {
+    let now = Instant::now();
+    let mut guard = lobbies.list.lock().unwrap();
+    for lobby in guard.iter_mut() {
+        if lobby.race.next_race_at.is_none_or(|at| now < at) {
+            continue;
+        }
+        let Some(track) = lobby.cup.as_mut().and_then(|cup| cup.advance()) else {
+            lobby.race.next_race_at = None;
+            continue;
+        };
+        println!("Lobby {}: next cup race on {}", lobby.name, track.label());
+        lobby.load_map(track);
+        start_race(lobby, &connected_clients, &cmd_sender.sender.lock().unwrap());
+    }
+}
+
+// Clients add up the team points themselves from the results, this is just for the server log
+fn log_team_scores(lobby: &Lobby, results: &[RaceResultEntry]) {
+    if lobby.teams.is_empty() {
+        return;
+    }
+    let scores = TeamScores::from_results(results, &lobby.teams);
+    println!(
+        "Lobby {}: Red {} - {} Blue, {}",
+        lobby.name,
+        scores.red,
+        scores.blue,
+        scores
+            .winner()
+            .map(|team| format!("{} team wins", team.as_str()))
+            .unwrap_or("draw".to_string())
+    );
+}
+
 /// System to check for timed out clients and disconnect them
 pub fn timeout_cleanup_system(
     connected_clients: Res<ConnectedClients>,
@@ -381,154 +780,456 @@ pub fn timeout_cleanup_system(
     );
 }
 
-/// System to move AI cars using Theta* pathfinding
-pub fn ai_movement_system(
+/// System to mark every car in a started lobby on that lobby's pathfinding grid,
+/// so its AI plans round them
+pub fn ai_obstacle_system(
     lobbies: Res<Lobbies>,
+    cars: Query<(&Position, &LobbyMember), Without<Eliminated>>,
+) {
+    let mut guard = lobbies.list.lock().unwrap();
+    for lobby in guard.iter_mut().filter(|l| l.started) {
+        lobby.theta_grid.clear_dynamic_costs();
+        for (pos, member) in &cars {
+            if member.lobby_name == lobby.name {
+                lobby
+                    .theta_grid
+                    .add_dynamic_cost((pos.x, pos.y), CAR_SIZE as f32 / 2.0, OBSTACLE_COST);
+            }
+        }
+    }
+}
+
+/// System to run the AI cars' Theta* searches off the simulation thread, each against
+/// its own lobby's grid, starting at most PATH_SEARCHES_PER_TICK a tick
+pub fn ai_path_planning_system(
+    lobbies: Res<Lobbies>,
+    mut ai_cars: Query<(&mut ThetaCheckpointList, &LobbyMember), With<AIControlled>>,
+) {
+    let grids: HashMap<String, ThetaGrid> = {
+        let guard = lobbies.list.lock().unwrap();
+        guard
+            .iter()
+            .filter(|l| l.started)
+            .map(|l| (l.name.clone(), l.theta_grid.clone()))
+            .collect()
+    };
+
+    plan_paths(
+        ai_cars.iter_mut().filter_map(|(checkpoints, member)| {
+            grids
+                .get(&member.lobby_name)
+                .map(|grid| (checkpoints.into_inner(), grid))
+        }),
+        PATH_SEARCHES_PER_TICK,
+    );
+}
+
+/// System to hand each lobby the racing line it was missing, once it's been worked out
+pub fn racing_line_build_system(lobbies: Res<Lobbies>) {
+    let mut guard = lobbies.list.lock().unwrap();
+    for lobby in guard.iter_mut() {
+        if let Some(line) = lobby.line_build.as_ref().and_then(LineBuild::take) {
+            lobby.racing_line = line;
+            lobby.line_build = None;
+        }
+    }
+}
+
+// Where a car is and where it's going at the start of a tick, for the AI cars in its
+// lobby to react to
+struct OnTrack {
+    entity: Entity,
+    lobby: String,
+    team: Option<Team>,
+    pos: Vec2,
+    velocity: Vec2,
+}
+
+impl OnTrack {
+    fn new(
+        entity: Entity,
+        pos: &Position,
+        velocity: &Velocity,
+        member: &LobbyMember,
+        team: Option<&Team>,
+    ) -> Self {
+        Self {
+            entity,
+            lobby: member.lobby_name.clone(),
+            team: team.copied(),
+            pos: Vec2::new(pos.x, pos.y),
+            velocity: **velocity,
+        }
+    }
+}
+
+// The other cars in an AI car's lobby, as it sees them, telling rivals from teammates
+fn traffic_for(
+    on_track: &[OnTrack],
+    entity: Entity,
+    member: &LobbyMember,
+    team: Option<Team>,
+) -> Vec<TrafficCar> {
+    on_track
+        .iter()
+        .filter(|car| car.entity != entity && car.lobby == member.lobby_name)
+        .map(|car| TrafficCar {
+            pos: car.pos,
+            velocity: car.velocity,
+            rival: rivals(team, car.team),
+        })
+        .collect()
+}
+
+/// System to run each AI car's behaviour tree, the same ones offline CPUs use,
+/// against the other cars in its lobby. ai_movement_system does what it decides.
+pub fn ai_behaviour_system(
+    lobbies: Res<Lobbies>,
+    trees: Res<BehaviourTrees>,
     mut ai_cars: Query<
         (
-            &mut Position,
-            &mut Velocity,
-            &mut Orientation,
-            &mut ThetaCheckpointList,
+            Entity,
+            &mut CarState,
+            &Position,
+            &Velocity,
+            &Orientation,
+            &AiRecovery,
+            &AiPowerups,
+            Has<SpeedBoost>,
             &LobbyMember,
+            &Personality,
+            Option<&Team>,
         ),
-        With<AIControlled>,
+        (With<AIControlled>, Without<Eliminated>),
+    >,
+    other_cars: Query<
+        (Entity, &Position, &Velocity, &LobbyMember, Option<&Team>),
+        (Without<AIControlled>, Without<Finished>, Without<Eliminated>),
     >,
-    other_cars: Query<(&Position, &Velocity), Without<AIControlled>>,
 ) {
-    // Check which lobbies have started
-    let started_lobbies: Vec<String> = {
+    // Only lobbies whose lights are out
+    let racing: HashSet<String> = {
         let guard = lobbies.list.lock().unwrap();
         guard
             .iter()
-            .filter(|l| l.started)
+            .filter(|l| l.started && l.race.has_gone())
             .map(|l| l.name.clone())
             .collect()
     };
 
-    // AI physics constants (same as player)
-    const ACCEL_RATE: f32 = 400.0;
-    const TURNING_RATE: f32 = 3.0;
-    const PLAYER_SPEED: f32 = 300.0;
-
-    let deltat = SERVER_TIMESTEP;
-    let accel = ACCEL_RATE * deltat;
+    // Where every car is at the start of the tick, for the trees to look at
+    let on_track: Vec<OnTrack> = ai_cars
+        .iter()
+        .map(|(entity, _, pos, velocity, .., member, _, team)| {
+            OnTrack::new(entity, pos, velocity, member, team)
+        })
+        .chain(
+            other_cars
+                .iter()
+                .map(|(entity, pos, velocity, member, team)| {
+                    OnTrack::new(entity, pos, velocity, member, team)
+                }),
+        )
+        .collect();
 
-    for (mut pos, mut velocity, mut orientation, mut theta_checkpoint_list, lobby_member) in
-        ai_cars.iter_mut()
+    for (
+        entity,
+        mut car_state,
+        pos,
+        _,
+        orientation,
+        recovery,
+        powerups,
+        boosted,
+        lobby_member,
+        personality,
+        team,
+    ) in ai_cars.iter_mut()
     {
-        // Only simulate AI in started lobbies
-        if !started_lobbies.contains(&lobby_member.lobby_name) {
+        if !racing.contains(&lobby_member.lobby_name) {
             continue;
         }
 
-        // Find the lobby to access input queue (same thing as above basically)
-        let (game_map, theta_grid) = {
-            let guard = lobbies.list.lock().unwrap();
-            let lobby_opt = guard.iter().find(|l| l.name == lobby_member.lobby_name);
-            
-            if let Some(lobby) = lobby_opt {
-                (lobby.map.clone(), lobby.theta_grid.clone())
-            } else {
-                let default_map = GameMap::default();
-                let default_grid =
-                    crate::game_logic::theta_grid::ThetaGrid::create_theta_grid(&default_map, TILE_SIZE as f32);
-                (default_map, default_grid)
-            }
+        let ai_pos = Vec2::new(pos.x, pos.y);
+        let traffic = traffic_for(&on_track, entity, lobby_member, team.copied());
+        let blackboard = Blackboard {
+            stuck: recovery.stuck(),
+            holding_item: powerups.held,
+            boosted,
+            ..Blackboard::sense(ai_pos, orientation.forward_vector(), &traffic)
         };
 
-        // Get the current tile
-        let tile = game_map.get_tile(pos.x, pos.y, TILE_SIZE as f32);
-
-        // Get terrain modifiers
-        let fric_mod = tile.friction_modifier;
-        let speed_mod = tile.speed_modifier;
-        let turn_mod = tile.turn_modifier;
-        let decel_mod = tile.decel_modifier;
-
-        // Get command from Theta* pathfinding
-        let command = theta_star(
-            (pos.x, pos.y),
-            orientation.angle,
-            &mut theta_checkpoint_list,
-            &theta_grid,
+        car_state.update(
+            Duration::from_secs_f32(SERVER_TIMESTEP),
+            trees.for_personality(*personality),
+            &blackboard,
         );
+    }
+}
 
-        // COPIED FROM src/car.rs
-        // Execute the command
-        match command {
-            ThetaCommand::TurnLeft => {
-                orientation.angle += TURNING_RATE * deltat * turn_mod;
+// What ai_powerup_system looks at of each CPU, and of every car still racing
+type PowerupCpu<'a> = (
+    Entity,
+    &'a PlayerId,
+    &'a Position,
+    &'a Orientation,
+    &'a ThetaCheckpointList,
+    &'a CpuDifficulty,
+    &'a CarState,
+    &'a LobbyMember,
+    &'a mut AiPowerups,
+    Option<&'a mut SpeedBoost>,
+);
+type RacingCpu = (With<AIControlled>, Without<Finished>, Without<Eliminated>);
+type RacingCar<'a> = (&'a PlayerId, &'a Position, &'a LapCounter, &'a LobbyMember);
+
+/// System to run each racing lobby's pickups and give the CPUs the same power-ups as
+/// offline: keeps the track stocked if the host left pickups on and tells the players what's
+/// on it, lets CPUs grab them, asks their power-up trees when to fire what they're holding
+/// and runs their boosts out
+pub fn ai_powerup_system(
+    mut commands: Commands,
+    lobbies: Res<Lobbies>,
+    connected_clients: Res<ConnectedClients>,
+    tree: Res<PowerupTree>,
+    mut ai_cars: Query<PowerupCpu, RacingCpu>,
+    racers: Query<RacingCar, (Without<Finished>, Without<Eliminated>)>,
+) {
+    // sent whenever it changes, and now and then anyway in case a message went missing
+    const PICKUPS_INTERVAL: Duration = Duration::from_secs(1);
+
+    let deltat = SERVER_TIMESTEP;
+    let now = Instant::now();
+    let mut guard = lobbies.list.lock().unwrap();
+
+    for lobby in guard.iter_mut() {
+        if !lobby.started || !lobby.race.has_gone() {
+            lobby.pickups.clear();
+            continue;
+        }
+        let mut pickups_changed = false;
+        if lobby.settings.boost_pickups && lobby.pickups.len() < PICKUP_COUNT {
+            for spot in pickup_spots(&lobby.map, PICKUP_COUNT - lobby.pickups.len()) {
+                lobby.pickups.push(Pickup {
+                    id: lobby.next_pickup_id,
+                    pos: (spot.x, spot.y),
+                });
+                lobby.next_pickup_id += 1;
             }
-            ThetaCommand::TurnRight => {
-                orientation.angle -= TURNING_RATE * deltat * turn_mod;
+            pickups_changed = true;
+        }
+
+        let (gates, finish) = MapLevelData::for_choice(lobby.map_choice).gates();
+        let in_lobby = |member: &LobbyMember| member.lobby_name == lobby.name;
+        let last_id = last_place(
+            racers
+                .iter()
+                .filter(|(_, _, _, member)| in_lobby(member))
+                .map(|(id, pos, lap_counter, _)| {
+                    let pos = Vec2::new(pos.x, pos.y);
+                    (id.0, lap_counter.progress(), lap_counter.distance_to_next(pos, &gates, &finish))
+                })
+                .collect(),
+        );
+
+        for (
+            entity,
+            id,
+            pos,
+            orientation,
+            checkpoints,
+            difficulty,
+            car_state,
+            member,
+            mut powerups,
+            boost,
+        ) in ai_cars.iter_mut()
+        {
+            if !in_lobby(member) {
+                continue;
             }
-            ThetaCommand::Forward => {
-                let forward = orientation.forward_vector() * accel;
-                **velocity += forward;
-                **velocity = velocity.clamp_length_max(PLAYER_SPEED * speed_mod);
+            if let Some(mut boost) = boost {
+                boost.timer.tick(Duration::from_secs_f32(deltat));
+                if boost.timer.finished() {
+                    commands.entity(entity).remove::<SpeedBoost>();
+                }
+                continue;
             }
-            ThetaCommand::Reverse => {
-                let backward = -orientation.forward_vector() * (accel / 2.0);
-                **velocity += backward;
-                **velocity = velocity.clamp_length_max(PLAYER_SPEED * (speed_mod / 2.0));
+
+            // CPUs hang on to what they pick up until their decision tree says to fire it
+            let here = Vec2::new(pos.x, pos.y);
+            if !powerups.held
+                && let Some(index) = lobby
+                    .pickups
+                    .iter()
+                    .position(|pickup| Vec2::from(pickup.pos).distance(here) < PICKUP_DISTANCE)
+            {
+                lobby.pickups.swap_remove(index);
+                pickups_changed = true;
+                powerups.pick_up();
+                println!("Lobby {}: CPU {} picked up a powerup", lobby.name, id.0);
             }
-            ThetaCommand::Stop => {
-                if velocity.length() > 0.0 {
-                    let backward = -orientation.forward_vector() * (accel / 2.0);
-                    **velocity += backward;
-                    **velocity = velocity.clamp_length_max(PLAYER_SPEED * (speed_mod / 2.0));
-                } else {
-                    **velocity = Vec2::ZERO;
+
+            let holder = PowerupHolder {
+                id: id.0,
+                difficulty: *difficulty,
+                behaviour: car_state.behaviour(),
+                boosted: false,
+                powerups: &mut powerups,
+            };
+            use_ai_powerup(&mut commands, entity, holder, &tree, deltat, || {
+                let forward = orientation.forward_vector();
+                let traffic: Vec<Vec2> = racers
+                    .iter()
+                    .filter(|(other, _, _, member)| other.0 != id.0 && in_lobby(member))
+                    .map(|(_, p, ..)| Vec2::new(p.x, p.y))
+                    .collect();
+                PowerupSituation {
+                    straight_ahead: straight_ahead(
+                        here,
+                        forward,
+                        checkpoints,
+                        &lobby.theta_grid,
+                        &lobby.racing_line,
+                    ),
+                    car_in_front: car_in_front(here, forward, &traffic),
+                    last_place: last_id == Some(id.0),
                 }
-            }
+            });
         }
 
-        // Apply friction when not accelerating forward or reversing
-        if !matches!(command, ThetaCommand::Forward | ThetaCommand::Reverse) {
-            let decel_rate = decel_mod * fric_mod * deltat;
-            let curr_speed = velocity.length();
-            if curr_speed > 0.0 {
-                let new_speed = (curr_speed - decel_rate).max(0.0);
-                if new_speed > 0.0 {
-                    **velocity = velocity.normalize() * new_speed;
-                } else {
-                    **velocity = Vec2::ZERO;
-                }
-            }
+        if pickups_changed
+            || lobby
+                .race
+                .last_pickups
+                .is_none_or(|last| now.duration_since(last) >= PICKUPS_INTERVAL)
+        {
+            lobby.race.last_pickups = Some(now);
+            let players: Vec<u32> = lobby.players.lock().unwrap().clone();
+            broadcast_pickups(&connected_clients, &players, &lobby.pickups);
         }
+    }
+}
+
+/// System to move AI cars, driven by the same code as offline CPUs
+pub fn ai_movement_system(
+    lobbies: Res<Lobbies>,
+    mut ai_cars: Query<
+        (
+            Entity,
+            &mut Position,
+            &mut Velocity,
+            &mut Orientation,
+            &mut ThetaCheckpointList,
+            &mut AiReflexes,
+            &mut AiRecovery,
+            &mut AiTraffic,
+            &LobbyMember,
+            &CpuDifficulty,
+            &CarState,
+            &AiPowerups,
+            Has<SpeedBoost>,
+            Option<&Team>,
+        ),
+        (With<AIControlled>, Without<Eliminated>),
+    >,
+    other_cars: Query<
+        (Entity, &Position, &Velocity, &LobbyMember, Option<&Team>),
+        (Without<AIControlled>, Without<Finished>, Without<Eliminated>),
+    >,
+) {
+    let deltat = SERVER_TIMESTEP;
 
-        // Update position
-        let change_x = velocity.x * deltat;
-        let change_y = velocity.y * deltat;
+    // Where every car is and where it's going at the start of the tick, for the AI to react to
+    let on_track: Vec<OnTrack> = ai_cars
+        .iter()
+        .map(|(entity, pos, velocity, _, _, _, _, _, member, .., team)| {
+            OnTrack::new(entity, pos, velocity, member, team)
+        })
+        .chain(
+            other_cars
+                .iter()
+                .map(|(entity, pos, velocity, member, team)| {
+                    OnTrack::new(entity, pos, velocity, member, team)
+                }),
+        )
+        .collect();
 
-        let new_x = (pos.x + change_x).clamp(
-            -game_map.width / 2.0 + (CAR_SIZE as f32) / 2.0,
-            game_map.width / 2.0 - (CAR_SIZE as f32) / 2.0,
-        );
-        let new_y = (pos.y + change_y).clamp(
-            -game_map.height / 2.0 + (CAR_SIZE as f32) / 2.0,
-            game_map.height / 2.0 - (CAR_SIZE as f32) / 2.0,
-        );
+    // The lobbies' tracks are only borrowed while the cars on them drive
+    let guard = lobbies.list.lock().unwrap();
 
-        // Handle collision detection
-        let new_pos_vec = Vec2::new(new_x, new_y);
-        let current_pos = Vec2::new(pos.x, pos.y);
-        let other_cars_iter = other_cars
+    for (
+        entity,
+        mut pos,
+        mut velocity,
+        mut orientation,
+        mut theta_checkpoint_list,
+        mut reflexes,
+        mut recovery,
+        mut ai_traffic,
+        lobby_member,
+        difficulty,
+        car_state,
+        powerups,
+        boosted,
+        team,
+    ) in ai_cars.iter_mut()
+    {
+        // Only simulate AI in started lobbies once the lights are out
+        let Some(lobby) = guard
             .iter()
-            .map(|(p, v)| (Vec2::new(p.x, p.y), v.velocity));
+            .find(|l| l.name == lobby_member.lobby_name && l.started && l.race.has_gone())
+        else {
+            continue;
+        };
+
+        let in_lobby = |member: &LobbyMember| member.lobby_name == lobby_member.lobby_name;
+        let traffic = traffic_for(&on_track, entity, lobby_member, team.copied());
+
+        // only drivers that know to use them, with their hands free, go after pickups
+        let wanted_pickups: Vec<Vec2> = if !powerups.held && difficulty.profile().uses_boosts {
+            lobby.pickups.iter().map(|pickup| Vec2::from(pickup.pos)).collect()
+        } else {
+            Vec::new()
+        };
+
+        let here = Vec2::new(pos.x, pos.y);
+        let car = AiCar {
+            velocity: &mut velocity,
+            orientation: &mut orientation,
+            checkpoints: &mut theta_checkpoint_list,
+            reflexes: &mut reflexes,
+            recovery: &mut recovery,
+            ai_traffic: &mut ai_traffic,
+            difficulty: *difficulty,
+            behaviour: car_state.behaviour(),
+            boosted,
+        };
+        let track = AiTrack {
+            game_map: &lobby.map,
+            theta_grid: &lobby.theta_grid,
+            racing_line: &lobby.racing_line,
+            traffic: &traffic,
+            pickups: &wanted_pickups,
+        };
+        let target = drive_ai_car(car, here, &track, deltat);
 
-        let should_update = handle_collision(
-            new_pos_vec.extend(0.0),
-            current_pos,
-            &mut velocity.velocity,
-            &game_map,
+        // Keep it on the map and out of the walls and the players' cars it can hit
+        let other_cars_iter = other_cars
+            .iter()
+            .filter(|(_, _, _, member, other_team)| {
+                in_lobby(member) && lobby.settings.cars_collide(team.copied(), other_team.copied())
+            })
+            .map(|(_, p, v, ..)| (Vec2::new(p.x, p.y), v.velocity));
+        let placed = place_ai_car(
+            here,
+            target,
+            &mut velocity,
+            &mut recovery,
+            &lobby.map,
             other_cars_iter,
         );
-
-        if should_update {
-            pos.x = new_x;
-            pos.y = new_y;
-        }
+        pos.x = placed.x;
+        pos.y = placed.y;
     }
 }
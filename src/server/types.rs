@@ -1,10 +1,13 @@
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::net::{SocketAddr, UdpSocket};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
-use crate::game_logic::{GameMap, TILE_SIZE};
+use crate::game_logic::{
+    AI_ID_START, CpuDifficulty, CupStandings, GameMap, LineBuild, Personality, Pickup, RaceResultEntry, RaceSettings,
+    RacingLine, TILE_SIZE, Team, load_map_from_file,
+};
 use crate::game_logic::theta_grid::ThetaGrid;
 use crate::networking::MapChoice;
 
@@ -45,8 +48,10 @@ pub enum GameState {
     Settings,
     Playing,
     PlayingDemo,
+    TimeTrial,
     Victory,
     Credits,
+    Records,
 }
 
 // Message types from clients
@@ -67,6 +72,10 @@ pub enum MessageType {
     StartLobby {
         name: String,
     },
+    UpdateLobbySettings {
+        name: String,
+        settings: RaceSettings,
+    },
     PlayerInput {
         sequence: u64,
         forward: bool,
@@ -80,6 +89,14 @@ pub enum MessageType {
     PlayerInputBuffer {
         inputs: Vec<InputData>,
     },
+    // turn the AI debug stream on or off for this client
+    WatchAi {
+        enabled: bool,
+    },
+    // the player drove over one of the lobby's pickups
+    CollectPickup {
+        id: u32,
+    },
     Ping,
 }
 
@@ -90,6 +107,7 @@ pub struct ConnectedClients {
     pub addrs: Arc<Mutex<HashMap<u32, SocketAddr>>>,
     pub addr_to_id: Arc<Mutex<HashMap<SocketAddr, u32>>>,
     pub last_seen: Arc<Mutex<HashMap<u32, Instant>>>,
+    pub ai_watchers: Arc<Mutex<HashSet<u32>>>, // clients with the AI debug overlay open
     pub socket: Arc<UdpSocket>,
 }
 
@@ -100,6 +118,7 @@ impl ConnectedClients {
             addrs: Arc::new(Mutex::new(HashMap::new())),
             addr_to_id: Arc::new(Mutex::new(HashMap::new())),
             last_seen: Arc::new(Mutex::new(HashMap::new())),
+            ai_watchers: Arc::new(Mutex::new(HashSet::new())),
             socket,
         }
     }
@@ -146,6 +165,24 @@ pub struct PlayerState {
     pub input_queue: Vec<InputData>,
 }
 
+// Finish tracking for a lobby's current race
+#[derive(Clone, Debug, Default)]
+pub struct RaceProgress {
+    pub started_at: Option<Instant>, // the scheduled GO, cars are held until then
+    pub finish_order: Vec<RaceResultEntry>,
+    pub dnf_deadline: Option<Instant>,
+    pub results_sent: bool,
+    // elimination mode
+    pub eliminated: Vec<RaceResultEntry>, // in the order they were knocked out
+    pub leader_lap: u8,
+    pub next_elimination: Option<Instant>,
+    pub last_standings: Option<Instant>,
+    pub last_ai_debug: Option<Instant>,
+    pub last_pickups: Option<Instant>,
+    // cups only, when the intermission is over and the next race starts
+    pub next_race_at: Option<Instant>,
+}
+
 // Lobby structure
 #[derive(Clone)]
 pub struct Lobby {
@@ -157,6 +194,67 @@ pub struct Lobby {
     pub map_choice: MapChoice,
     pub map: GameMap,
     pub theta_grid: ThetaGrid,
+    pub racing_line: RacingLine,
+    pub line_build: Option<LineBuild>, // the track had no racing line, one's being worked out
+    pub pickups: Vec<Pickup>, // boost pickups on the track, for players and CPUs alike
+    pub next_pickup_id: u32,
+    pub settings: RaceSettings,
+    pub race: RaceProgress,
+    pub teams: HashMap<u32, Team>, // empty unless the host turned teams on
+    pub cup: Option<CupStandings>, // set from the start of a cup until the lobby closes
+}
+
+impl RaceProgress {
+    pub fn has_gone(&self) -> bool {
+        self.started_at.is_some_and(|go| Instant::now() >= go)
+    }
+}
+
+impl Lobby {
+    /// Split the grid into teams, players in join order then the AI cars that
+    /// will fill the remaining slots. Runs again whenever the lobby changes.
+    pub fn assign_teams(&mut self) {
+        self.teams.clear();
+        if !self.settings.teams {
+            return;
+        }
+
+        let players = self.players.lock().unwrap().clone();
+        let ai_ids = (0..self.settings.ai_slots(players.len())).map(|i| AI_ID_START + i as u32);
+        for (slot, id) in players.into_iter().chain(ai_ids).enumerate() {
+            self.teams.insert(id, Team::for_slot(slot));
+        }
+    }
+
+    pub fn team_of(&self, id: u32) -> Option<Team> {
+        self.teams.get(&id).copied()
+    }
+
+    /// Load a track with its pathfinding grid and racing line, cups change track between races
+    pub fn load_map(&mut self, map: MapChoice) {
+        let game_map = load_map_from_file(map.path());
+        println!(
+            "Server loaded map ({:?}): {}x{}",
+            map,
+            game_map.width,
+            game_map.height
+        );
+        let grid_size = match map {
+            MapChoice::Small => (100, 100),
+            MapChoice::Big => (125, 125),
+        };
+        self.theta_grid = ThetaGrid::create_theta_grid_with_size(
+            &game_map,
+            TILE_SIZE as f32,
+            grid_size.0,
+            grid_size.1,
+        );
+        (self.racing_line, self.line_build) =
+            RacingLine::load_or_build(map, &game_map, &self.theta_grid);
+        self.pickups.clear();
+        self.map_choice = map;
+        self.map = game_map;
+    }
 }
 
 impl Default for Lobby {
@@ -172,6 +270,14 @@ impl Default for Lobby {
             map_choice: MapChoice::Small,
             map,
             theta_grid,
+            racing_line: RacingLine::default(),
+            line_build: None,
+            pickups: Vec::new(),
+            next_pickup_id: 0,
+            settings: RaceSettings::default(),
+            race: RaceProgress::default(),
+            teams: HashMap::new(),
+            cup: None,
         }
     }
 }
@@ -249,10 +355,16 @@ pub enum ServerCommand {
         x: f32,
         y: f32,
         angle: f32,
+        difficulty: CpuDifficulty,
+        personality: Personality,
     },
     DespawnPlayer {
         player_id: u32,
     },
+    // every car in a lobby, before the next race of a cup
+    DespawnLobby {
+        lobby_name: String,
+    },
 }
 
 // Resource for receiving commands in Bevy systems
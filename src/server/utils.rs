@@ -1,4 +1,5 @@
 use std::net::UdpSocket;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Get the local IP address for display purposes
 pub fn get_local_ip() -> Result<String, Box<dyn std::error::Error>> {
@@ -7,3 +8,11 @@ pub fn get_local_ip() -> Result<String, Box<dyn std::error::Error>> {
     let local_addr = socket.local_addr()?;
     Ok(local_addr.ip().to_string())
 }
+
+/// Milliseconds on the server clock, used to schedule race starts
+pub fn server_clock_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -1,8 +1,14 @@
+use crate::car_state::CarState;
+use crate::game_logic::theta_grid::ThetaGrid;
 use crate::game_logic::{
-    AIControlled, Car, GameMap, Orientation, PlayerControlled, TILE_SIZE, Velocity,
+    AIControlled, AiPowerups, BOOST_SECS, Car, Checkpoint, CpuDifficulty, Finished, FinishLine,
+    GameMap, Gate, LapCounter, Orientation, PICKUP_COUNT, PICKUP_DISTANCE, Pickup,
+    PlayerControlled, PowerupHolder, PowerupSituation, PowerupTree, RaceSettings, RacerId,
+    RacingLine, ThetaCheckpointList, car_in_front, last_place, pickup_spots, straight_ahead,
+    use_ai_powerup,
 };
+use crate::networking_plugin::NetworkClient;
 use bevy::prelude::*;
-use rand::{Rng, rngs::ThreadRng};
 
 // Component for the powerup
 #[derive(Component)]
@@ -16,6 +22,22 @@ pub struct SpeedBoost {
 #[derive(Component)]
 pub struct ShowBoostBox;
 
+// Online, which of the server's pickups this one is
+#[derive(Component)]
+pub struct ServerPickup(pub u32);
+
+/// The pickups the server last said are on the track, online only
+#[derive(Resource, Default)]
+pub struct ServerPickups {
+    pub pickups: Vec<Pickup>,
+    newest_spawned: Option<u32>, // ids only go up, anything older we've already seen
+}
+
+// Run condition: pickups can be switched off in the lobby settings
+pub fn boost_pickups_enabled(settings: Res<RaceSettings>) -> bool {
+    settings.boost_pickups
+}
+
 // System to spawn powerups on road tiles
 pub fn spawn_speed_powerups(
     mut commands: Commands,
@@ -24,10 +46,9 @@ pub fn spawn_speed_powerups(
     powerups: Query<Entity, With<SpeedPowerup>>,
 ) {
     let current_count = powerups.iter().count();
-    let target_count = 10;
 
-    if current_count < target_count {
-        let to_spawn = target_count - current_count;
+    if current_count < PICKUP_COUNT {
+        let to_spawn = PICKUP_COUNT - current_count;
         spawn_powerups(&mut commands, &asset_server, &game_map, to_spawn);
     }
 }
@@ -38,76 +59,209 @@ fn spawn_powerups(
     game_map: &Res<GameMap>,
     count: usize,
 ) {
-    let mut rng: ThreadRng = rand::rng();
-
-    // Collect all road tile positions
-    let mut road_tiles = Vec::new();
+    // Spawn powerups at random road positions
+    for world_pos in pickup_spots(game_map, count) {
+        commands.spawn((
+            Sprite::from_image(asset_server.load("GasCanPowerUp.png")),
+            Transform::from_xyz(world_pos.x, world_pos.y, 15.0),
+            SpeedPowerup,
+        ));
+    }
+}
 
-    // Iterate through gameMap to find road tiles
-    for row in game_map.terrain_layer.iter() {
-        for tile in row.iter() {
-            if tile.tile_id <= 15 {
-                road_tiles.push((tile.x_coordinate, tile.y_coordinate));
-            }
+// Online, put down the server's new pickups and take away the ones someone else collected.
+// One we collected ourselves stays gone even if the server hasn't heard yet.
+pub fn sync_server_pickups(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    server_pickups: Option<ResMut<ServerPickups>>,
+    powerups: Query<(Entity, &ServerPickup)>,
+) {
+    let Some(mut server_pickups) = server_pickups else {
+        return;
+    };
+    if !server_pickups.is_changed() {
+        return;
+    }
+    for (entity, pickup) in powerups.iter() {
+        if !server_pickups.pickups.iter().any(|p| p.id == pickup.0) {
+            commands.entity(entity).despawn();
         }
     }
-
-    // Spawn powerups at random road positions
-    for _ in 0..count {
-        if let Some(&(x, y)) = road_tiles.get(rng.random_range(0..road_tiles.len())) {
-            // Convert tile coordinates to world coordinates
-            let world_pos = game_map.tile_to_world(x as f32, y as f32, TILE_SIZE as f32);
-            commands.spawn((
-                Sprite::from_image(asset_server.load("GasCanPowerUp.png")),
-                Transform::from_xyz(world_pos.x, world_pos.y, 15.0),
-                SpeedPowerup,
-            ));
+    let newest_spawned = server_pickups.newest_spawned;
+    for pickup in &server_pickups.pickups {
+        if newest_spawned.is_some_and(|newest| pickup.id <= newest) {
+            continue;
         }
+        commands.spawn((
+            Sprite::from_image(asset_server.load("GasCanPowerUp.png")),
+            Transform::from_xyz(pickup.pos.0, pickup.pos.1, 15.0),
+            SpeedPowerup,
+            ServerPickup(pickup.id),
+        ));
     }
+    let newest = server_pickups.pickups.iter().map(|p| p.id).max();
+    server_pickups.newest_spawned = newest_spawned.max(newest);
 }
 
 // System to handle powerup collection
 pub fn collect_powerups(
     mut commands: Commands,
+    mut network_client: ResMut<NetworkClient>,
     player_query: Query<(Entity, &Transform), With<PlayerControlled>>,
-    powerup_query: Query<(Entity, &Transform), With<SpeedPowerup>>,
+    powerup_query: Query<(Entity, &Transform, Option<&ServerPickup>), With<SpeedPowerup>>,
     asset_server: Res<AssetServer>,
     boost_query: Query<&SpeedBoost, With<PlayerControlled>>,
+    mut ai_query: Query<(&Transform, &mut AiPowerups, &RacerId), With<AIControlled>>,
 ) {
-    const PICKUP_DISTANCE: f32 = 64.0;
+    // Two local players can reach the same can on the same frame, only one gets it
+    let mut collected = Vec::new();
 
-    if let Ok((player_entity, player_transform)) = player_query.single() {
+    for (player_entity, player_transform) in player_query.iter() {
         if boost_query.get(player_entity).is_ok() {
             // Player already has a boost, don't pick up more
-            return;
+            continue;
         }
 
         let player_pos = player_transform.translation.truncate();
 
-        for (powerup_entity, powerup_transform) in powerup_query.iter() {
+        for (powerup_entity, powerup_transform, server_pickup) in powerup_query.iter() {
+            if collected.contains(&powerup_entity) {
+                continue;
+            }
             let powerup_pos = powerup_transform.translation.truncate();
             let distance = player_pos.distance(powerup_pos);
 
             if distance < PICKUP_DISTANCE {
                 // Despawn the powerup
                 commands.entity(powerup_entity).despawn();
+                collected.push(powerup_entity);
 
                 // Add speed boost component to player
                 commands.entity(player_entity).insert(SpeedBoost {
-                    timer: Timer::from_seconds(5.0, TimerMode::Once), // 5 second boost
+                    timer: Timer::from_seconds(BOOST_SECS, TimerMode::Once),
                 });
 
+                // online everyone else's copy of it has to go too
+                if let (Some(pickup), Some(client)) = (server_pickup, &mut network_client.client)
+                    && let Err(e) = client.collect_pickup(pickup.0)
+                {
+                    println!("Failed to tell the server about a pickup: {}", e);
+                }
+
                 println!("Powerup collected! Speed boost activated!");
+                break;
             }
         }
     }
+
+    // CPUs hang on to what they pick up until their decision tree says to fire it
+    for (ai_transform, mut powerups, racer_id) in ai_query.iter_mut() {
+        if powerups.held {
+            continue;
+        }
+        let ai_pos = ai_transform.translation.truncate();
+        let reached = powerup_query.iter().find(|(powerup_entity, powerup_transform, _)| {
+            !collected.contains(powerup_entity)
+                && ai_pos.distance(powerup_transform.translation.truncate()) < PICKUP_DISTANCE
+        });
+        if let Some((powerup_entity, ..)) = reached {
+            commands.entity(powerup_entity).despawn();
+            collected.push(powerup_entity);
+            powerups.pick_up();
+            info!("CPU {} picked up a powerup", racer_id.0);
+        }
+    }
+}
+
+// CPUs holding a powerup ask their decision tree every so often whether to fire it
+pub fn use_ai_powerups(
+    mut commands: Commands,
+    time: Res<Time>,
+    tree: Res<PowerupTree>,
+    racing_line: Res<RacingLine>,
+    theta_grid: Res<ThetaGrid>,
+    mut ai_cars: Query<
+        (
+            Entity,
+            &Transform,
+            &Orientation,
+            &ThetaCheckpointList,
+            &CpuDifficulty,
+            &RacerId,
+            &mut AiPowerups,
+            &CarState,
+            Has<SpeedBoost>,
+        ),
+        With<AIControlled>,
+    >,
+    racers: Query<(&Transform, &LapCounter, &RacerId), (With<Car>, Without<Finished>)>,
+    query_checkpoints: Query<(&Gate, &Checkpoint)>,
+    query_finish: Query<&Gate, With<FinishLine>>,
+) {
+    let mut checkpoint_data: Vec<(Gate, usize)> = query_checkpoints
+        .iter()
+        .map(|(g, c)| (*g, c.index))
+        .collect();
+    checkpoint_data.sort_by_key(|(_, i)| *i);
+    let checkpoint_gates: Vec<Gate> = checkpoint_data.into_iter().map(|(g, _)| g).collect();
+    let last_id = query_finish.single().ok().and_then(|finish_gate| {
+        last_place(
+            racers
+                .iter()
+                .map(|(transform, lap_counter, racer_id)| {
+                    let pos = transform.translation.truncate();
+                    (
+                        racer_id.0,
+                        lap_counter.progress(),
+                        lap_counter.distance_to_next(pos, &checkpoint_gates, finish_gate),
+                    )
+                })
+                .collect(),
+        )
+    });
+
+    for (
+        entity,
+        transform,
+        orientation,
+        checkpoints,
+        difficulty,
+        racer_id,
+        mut powerups,
+        car_state,
+        boosted,
+    ) in ai_cars.iter_mut()
+    {
+        let holder = PowerupHolder {
+            id: racer_id.0,
+            difficulty: *difficulty,
+            behaviour: car_state.behaviour(),
+            boosted,
+            powerups: &mut powerups,
+        };
+        use_ai_powerup(&mut commands, entity, holder, &tree, time.delta_secs(), || {
+            let pos = transform.translation.truncate();
+            let forward = orientation.forward_vector();
+            let traffic: Vec<Vec2> = racers
+                .iter()
+                .filter(|(_, _, other)| other.0 != racer_id.0)
+                .map(|(t, ..)| t.translation.truncate())
+                .collect();
+            PowerupSituation {
+                straight_ahead: straight_ahead(pos, forward, checkpoints, &theta_grid, &racing_line),
+                car_in_front: car_in_front(pos, forward, &traffic),
+                last_place: last_id == Some(racer_id.0),
+            }
+        });
+    }
 }
 
-// System to handle boost expiration
+// System to handle boost expiration, for players and CPUs alike
 pub fn update_speed_boost(
     mut commands: Commands,
     time: Res<Time>,
-    mut query: Query<(Entity, &mut SpeedBoost, &mut Sprite), With<PlayerControlled>>,
+    mut query: Query<(Entity, &mut SpeedBoost, &mut Sprite), With<Car>>,
 ) {
     for (entity, mut boost, mut sprite) in query.iter_mut() {
         boost.timer.tick(time.delta());
@@ -130,7 +284,7 @@ pub fn spawn_boost_ui(
 ) {
     // spawn if no power up ui exists
     if existing_ui.is_empty() {
-        if player_query.single().is_ok() {
+        if !player_query.is_empty() {
             commands
                 .spawn((
                     Node {
@@ -165,11 +319,11 @@ pub fn spawn_boost_ui(
 
 pub fn remove_boost_ui(
     mut commands: Commands,
-    player_query: Query<Entity, (With<PlayerControlled>, Without<SpeedBoost>)>,
+    player_query: Query<Entity, (With<PlayerControlled>, With<SpeedBoost>)>,
     ui_query: Query<Entity, With<ShowBoostBox>>,
 ) {
-    // If player doesn't have boost anymore, remove the UI
-    if player_query.single().is_ok() {
+    // If no local player has a boost anymore, remove the UI
+    if player_query.is_empty() {
         for ui_entity in ui_query.iter() {
             commands.entity(ui_entity).despawn();
         }
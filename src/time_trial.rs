@@ -0,0 +1,298 @@
+use crate::car_skins::CarSkinSelection;
+use crate::game_logic::{
+    CAR_SIZE, LapCompleted, LapCounter, Orientation, PlayerControlled, RaceStandings,
+    format_race_time,
+};
+use crate::networking::{MapChoice, SelectedMap};
+use crate::user_data::{content_hash, user_data_path};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+// How see-through the ghost car is
+const GHOST_ALPHA: f32 = 0.4;
+
+// One recorded tick of the car, time is seconds since the lap started
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct GhostFrame {
+    pub t: f32,
+    pub x: f32,
+    pub y: f32,
+    pub angle: f32,
+}
+
+// A saved best lap. The map hash ties it to the exact map file it was driven on,
+// so ghosts can be passed around between players.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GhostFile {
+    pub map: MapChoice,
+    pub map_hash: String,
+    pub lap_time: f32,
+    pub skin: String,
+    pub frames: Vec<GhostFrame>,
+}
+
+impl GhostFile {
+    pub fn path(map: MapChoice) -> PathBuf {
+        user_data_path(&format!("ghosts/{}.json", map.key()))
+    }
+
+    /// Load the ghost saved for this map, if there is one and it was made on this exact map
+    pub fn load(map: MapChoice, map_hash: &str) -> Option<Self> {
+        let path = Self::path(map);
+        let text = fs::read_to_string(&path).ok()?;
+        let ghost: GhostFile = match serde_json::from_str(&text) {
+            Ok(ghost) => ghost,
+            Err(e) => {
+                println!("Ignoring ghost {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        if ghost.map != map || ghost.map_hash != map_hash {
+            println!(
+                "Ignoring ghost {}: recorded on a different version of the map",
+                path.display()
+            );
+            return None;
+        }
+        if ghost.frames.is_empty() {
+            return None;
+        }
+        Some(ghost)
+    }
+
+    pub fn save(&self) {
+        let path = Self::path(self.map);
+        let text = serde_json::to_string(self).unwrap();
+        if let Err(e) = fs::write(&path, text) {
+            println!("Failed to save ghost to {}: {}", path.display(), e);
+        }
+    }
+
+    /// Where the ghost was `t` seconds into its lap
+    pub fn sample(&self, t: f32) -> GhostFrame {
+        let next = self.frames.partition_point(|f| f.t < t);
+        if next == 0 {
+            return self.frames[0];
+        }
+        if next >= self.frames.len() {
+            return *self.frames.last().unwrap();
+        }
+
+        let a = self.frames[next - 1];
+        let b = self.frames[next];
+        let span = b.t - a.t;
+        let s = if span > 0.0 { (t - a.t) / span } else { 1.0 };
+        GhostFrame {
+            t,
+            x: a.x + (b.x - a.x) * s,
+            y: a.y + (b.y - a.y) * s,
+            angle: a.angle + (b.angle - a.angle) * s,
+        }
+    }
+}
+
+// State of the current time trial session
+#[derive(Resource)]
+pub struct TimeTrialRun {
+    pub map: MapChoice,
+    pub map_hash: String,
+    pub lap_start: f32, // race time when the current lap began
+    pub lap_times: Vec<f32>,
+    pub recording: Vec<GhostFrame>,
+    pub ghost: Option<GhostFile>,
+}
+
+impl TimeTrialRun {
+    pub fn best_lap(&self) -> Option<f32> {
+        self.ghost.as_ref().map(|ghost| ghost.lap_time)
+    }
+}
+
+#[derive(Component)]
+pub struct GhostCar;
+
+#[derive(Component)]
+pub struct LapTimerText;
+
+pub fn setup_time_trial(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlasLayout>>,
+    selected_map: Res<SelectedMap>,
+) {
+    let map = selected_map.choice;
+    let map_hash = content_hash(map.path()).unwrap_or_default();
+    let ghost = GhostFile::load(map, &map_hash);
+
+    if let Some(ghost) = &ghost {
+        println!("Racing ghost: {}", format_race_time(ghost.lap_time));
+        spawn_ghost_car(&mut commands, &asset_server, &mut texture_atlases, ghost);
+    }
+
+    commands.insert_resource(TimeTrialRun {
+        map,
+        map_hash,
+        lap_start: 0.0,
+        lap_times: Vec::new(),
+        recording: Vec::new(),
+        ghost,
+    });
+
+    // Lap timer (top left corner)
+    commands.spawn((
+        Text::new(""),
+        TextFont {
+            font_size: 32.0,
+            ..default()
+        },
+        TextColor(Color::WHITE),
+        Node {
+            position_type: PositionType::Absolute,
+            left: Val::Px(20.0),
+            top: Val::Px(20.0),
+            ..default()
+        },
+        LapTimerText,
+    ));
+}
+
+fn spawn_ghost_car(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    texture_atlases: &mut Assets<TextureAtlasLayout>,
+    ghost: &GhostFile,
+) {
+    let car_layout = TextureAtlasLayout::from_grid(UVec2::splat(CAR_SIZE), 2, 2, None, None);
+    let mut sprite = Sprite::from_atlas_image(
+        asset_server.load(ghost.skin.clone()),
+        TextureAtlas {
+            layout: texture_atlases.add(car_layout),
+            index: 0,
+        },
+    );
+    sprite.color = Color::srgba(1.0, 1.0, 1.0, GHOST_ALPHA);
+
+    // No Car component, so nothing collides with it
+    let start = ghost.frames[0];
+    commands.spawn((
+        sprite,
+        Transform::from_xyz(start.x, start.y, 9.).with_rotation(Quat::from_rotation_z(start.angle)),
+        GhostCar,
+    ));
+}
+
+// Record the player's car every tick of the current lap
+pub fn record_ghost(
+    mut run: ResMut<TimeTrialRun>,
+    standings: Res<RaceStandings>,
+    player_car: Single<(&Transform, &Orientation), With<PlayerControlled>>,
+) {
+    let (transform, orientation) = player_car.into_inner();
+    let t = standings.elapsed - run.lap_start;
+    run.recording.push(GhostFrame {
+        t,
+        x: transform.translation.x,
+        y: transform.translation.y,
+        angle: orientation.angle,
+    });
+}
+
+// Close out each lap, keeping it as the new ghost if it beat the old one
+pub fn time_trial_laps(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlasLayout>>,
+    mut run: ResMut<TimeTrialRun>,
+    mut lap_events: EventReader<LapCompleted>,
+    skin_selection: Res<CarSkinSelection>,
+    ghost_car: Query<Entity, With<GhostCar>>,
+) {
+    for event in lap_events.read() {
+        let lap_time = event.race_time - run.lap_start;
+        run.lap_start = event.race_time;
+        run.lap_times.push(lap_time);
+        let frames = std::mem::take(&mut run.recording);
+
+        println!("Lap {}: {}", event.lap, format_race_time(lap_time));
+
+        if run.best_lap().is_some_and(|best| best <= lap_time) {
+            continue;
+        }
+
+        println!("New best lap!");
+        let ghost = GhostFile {
+            map: run.map,
+            map_hash: run.map_hash.clone(),
+            lap_time,
+            skin: skin_selection.current_skin().to_string(),
+            frames,
+        };
+        ghost.save();
+
+        // Race the new best from the next lap on
+        for entity in ghost_car.iter() {
+            commands.entity(entity).despawn();
+        }
+        if !ghost.frames.is_empty() {
+            spawn_ghost_car(&mut commands, &asset_server, &mut texture_atlases, &ghost);
+        }
+        run.ghost = Some(ghost);
+    }
+}
+
+pub fn update_ghost_car(
+    run: Res<TimeTrialRun>,
+    standings: Res<RaceStandings>,
+    mut ghost_car: Query<&mut Transform, With<GhostCar>>,
+) {
+    let Some(ghost) = &run.ghost else {
+        return;
+    };
+    if ghost.frames.is_empty() {
+        return;
+    }
+
+    let frame = ghost.sample(standings.elapsed - run.lap_start);
+    for mut transform in ghost_car.iter_mut() {
+        transform.translation.x = frame.x;
+        transform.translation.y = frame.y;
+        transform.rotation = Quat::from_rotation_z(frame.angle);
+    }
+}
+
+pub fn update_lap_timer(
+    run: Res<TimeTrialRun>,
+    standings: Res<RaceStandings>,
+    player_laps: Single<&LapCounter, With<PlayerControlled>>,
+    mut timer_text: Query<&mut Text, With<LapTimerText>>,
+) {
+    let lap = (player_laps.current_lap + 1).min(player_laps.total_laps);
+    let best = run
+        .best_lap()
+        .map(format_race_time)
+        .unwrap_or("--".to_string());
+    let label = format!(
+        "Lap {}/{}  {}\nBest {}",
+        lap,
+        player_laps.total_laps,
+        format_race_time(standings.elapsed - run.lap_start),
+        best
+    );
+
+    for mut text in timer_text.iter_mut() {
+        text.0 = label.clone();
+    }
+}
+
+pub fn cleanup_time_trial(
+    mut commands: Commands,
+    entities: Query<Entity, Or<(With<GhostCar>, With<LapTimerText>)>>,
+) {
+    for entity in entities.iter() {
+        commands.entity(entity).despawn();
+    }
+    commands.remove_resource::<TimeTrialRun>();
+}
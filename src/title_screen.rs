@@ -118,6 +118,7 @@ pub struct TitleUiQueries<'w, 's> {
             Without<LobbyNameInput>,
         ),
     >,
+    pub typing_mode: ResMut<'w, IpTypingMode>,
 }
 
 pub fn check_for_title_input(
@@ -140,26 +141,21 @@ pub fn check_for_title_input(
 ) {
     match *current_state.get() {
         GameState::Title => {
-            // Use a local variable to track typing mode that persists across frames
-            use bevy::prelude::Local;
-            static mut TYPING_MODE: bool = false;
-
+            // Typing mode lives in a resource so other title screen systems can respect it
             // Toggle typing mode with Tab
             if input.just_pressed(KeyCode::Tab) {
-                unsafe {
-                    TYPING_MODE = !TYPING_MODE;
-                    println!(
-                        "IP typing mode: {}",
-                        if TYPING_MODE {
-                            "ON"
-                        } else {
-                            "OFF (use 1/2/3/4)"
-                        }
-                    );
-                }
+                ui_queries.typing_mode.enabled = !ui_queries.typing_mode.enabled;
+                println!(
+                    "IP typing mode: {}",
+                    if ui_queries.typing_mode.enabled {
+                        "ON"
+                    } else {
+                        "OFF (use 1/2/3/4)"
+                    }
+                );
             }
 
-            let is_typing_ip = unsafe { TYPING_MODE };
+            let is_typing_ip = ui_queries.typing_mode.enabled;
 
             // Only handle text input when in typing mode
             if is_typing_ip {
@@ -249,9 +245,10 @@ pub fn check_for_title_input(
                     drift_settings.clone(),
                 );
             }
-            // Theta* DEMO
-            else if !is_typing_ip && input.just_pressed(KeyCode::Digit4) {
-                next_state.set(GameState::PlayingDemo);
+            // 4 and 6 open the offline race setup, see race_setup.rs
+            else if !is_typing_ip && input.just_pressed(KeyCode::Digit5) {
+                // Time trial on the currently selected map
+                next_state.set(GameState::TimeTrial);
                 destroy_screen(&mut commands, &main_screen_query);
             }
         }
@@ -442,18 +439,18 @@ pub fn check_for_lobby_input(
                 next_state.set(GameState::Lobby);
                 destroy_screen(&mut commands, &create_query);
 
-                lobby_state.connected_players.clear();
-                if let Some(player_id) = &mut network_client.player_id {
-                    lobby_state
-                        .connected_players
-                        .push(format!("Player {} (You)", player_id));
-                } else {
-                    lobby_state
-                        .connected_players
-                        .push("Connecting...".to_string());
-                }
-                lobby_state.name = lobby_name;
-                lobby_state.map = selected_map.choice;
+                // nothing carries over from the last lobby, whoever creates one hosts it
+                let first_player = match network_client.player_id {
+                    Some(player_id) => format!("Player {} (You)", player_id),
+                    None => "Connecting...".to_string(),
+                };
+                *lobby_state = LobbyState {
+                    connected_players: vec![first_player],
+                    name: lobby_name,
+                    map: selected_map.choice,
+                    is_host: true,
+                    ..default()
+                };
 
                 setup_lobby(&mut commands, asset_server.clone(), &lobby_state);
             }
@@ -492,12 +489,13 @@ pub fn check_for_lobby_input(
                     // Transition to lobby screen
                     next_state.set(GameState::Lobby);
                     destroy_screen(&mut commands, &join_query);
-                    lobby_state.connected_players.clear();
-                    lobby_state
-                        .connected_players
-                        .push("Connecting...".to_string());
-                    lobby_state.name = join_btn.lobby_name.clone();
-                    lobby_state.map = join_btn.map;
+                    // the server says who's in it and how it's set up once we're in
+                    *lobby_state = LobbyState {
+                        connected_players: vec!["Connecting...".to_string()],
+                        name: join_btn.lobby_name.clone(),
+                        map: join_btn.map,
+                        ..default()
+                    };
                     selected_map.choice = join_btn.map;
                     setup_lobby(&mut commands, asset_server.clone(), &lobby_state);
                 }
@@ -557,6 +555,36 @@ pub fn setup_title_screen(
         MainScreenEntity,
     ));
 
+    // Records browser
+    commands.spawn((
+        Text2d::new("R: Records"),
+        TextColor(Color::BLACK),
+        Transform {
+            translation: Vec3::new(-540., 140., 1.),
+            ..default()
+        },
+        TextFont {
+            font_size: 25.0,
+            ..default()
+        },
+        MainScreenEntity,
+    ));
+
+    // Local two player race against the CPU
+    commands.spawn((
+        Text2d::new("6: Split Screen"),
+        TextColor(Color::BLACK),
+        Transform {
+            translation: Vec3::new(-540., 100., 1.),
+            ..default()
+        },
+        TextFont {
+            font_size: 25.0,
+            ..default()
+        },
+        MainScreenEntity,
+    ));
+
     commands.spawn((
         Sprite::from_image(asset_server.load("title_screen/slantedButton.png")),
         Transform {
@@ -694,7 +722,7 @@ pub fn setup_title_screen(
         ServerIpInput,
     ));
 
-    // Theta* DEMO (Remove later)
+    // Offline race against the CPU, 4 opens the race setup screen
     commands.spawn((
         Sprite::from_image(asset_server.load("single-player.png")),
         Transform {
@@ -711,6 +739,42 @@ pub fn setup_title_screen(
         },
         MainScreenEntity,
     ));
+
+    // Time trial
+    commands.spawn((
+        Text2d::new("TIME\nTRIAL"),
+        TextColor(Color::BLACK),
+        Transform {
+            translation: Vec3::new(-450., -200., 1.),
+            ..default()
+        },
+        TextFont {
+            font_size: 40.0,
+            ..default()
+        },
+        MainScreenEntity,
+    ));
+    commands.spawn((
+        Sprite::from_image(asset_server.load("title_screen/keys/keyBasic.png")),
+        Transform {
+            translation: Vec3::new(-450., -300., 1.),
+            ..default()
+        },
+        MainScreenEntity,
+    ));
+    commands.spawn((
+        Text2d::new("5"),
+        TextColor(Color::BLACK),
+        Transform {
+            translation: Vec3::new(-450., -300., 2.),
+            ..default()
+        },
+        TextFont {
+            font_size: 40.0,
+            ..default()
+        },
+        MainScreenEntity,
+    ));
 }
 
 fn setup_create_lobby(
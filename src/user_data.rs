@@ -0,0 +1,38 @@
+use crate::game_logic::Fnv;
+use std::fs;
+use std::path::PathBuf;
+
+/// Folder for everything the game saves locally (ghosts, records, ...).
+/// Follows the usual per-platform spot, falling back to the working directory.
+pub fn user_data_dir() -> PathBuf {
+    let base = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        std::env::var_os("HOME").map(|home| PathBuf::from(home).join("Library/Application Support"))
+    } else {
+        std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+    };
+
+    base.unwrap_or_else(|| PathBuf::from(".")).join("rust_racers")
+}
+
+/// Path to a file inside the user data dir, creating any missing folders on the way.
+pub fn user_data_path(relative: &str) -> PathBuf {
+    let path = user_data_dir().join(relative);
+    if let Some(parent) = path.parent() {
+        if let Err(e) = fs::create_dir_all(parent) {
+            println!("Failed to create {}: {}", parent.display(), e);
+        }
+    }
+    path
+}
+
+/// Stable hash of a file's contents (FNV-1a), so saved data can be checked
+/// against the exact map it was made on, on any machine.
+pub fn content_hash(path: &str) -> Option<String> {
+    let mut hash = Fnv::default();
+    hash.write(&fs::read(path).ok()?);
+    Some(format!("{:016x}", hash.finish()))
+}
@@ -1,11 +1,28 @@
+use crate::GameState;
+use crate::game_logic::{
+    Car, Checkpoint, CupStandings, FinishLine, MapTile, RaceResults, TeamAssignments, TeamScores,
+    format_race_time, racer_name,
+};
+use crate::networking::SelectedMap;
+use crate::networking_plugin::NetworkClient;
+use crate::records::NewRecords;
+use crate::speed::{ShowBoostBox, SpeedPowerup};
 use crate::title_screen::TitleScreenAudio;
 use bevy::prelude::*;
 
+#[derive(Component)]
+pub struct VictoryScreenEntity;
+
 pub fn setup_victory_screen(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
     mut camera_query: Query<&mut Transform, With<Camera>>,
     title_audio_query: Query<Entity, With<TitleScreenAudio>>,
+    results: Res<RaceResults>,
+    network_client: Res<NetworkClient>,
+    new_records: Option<Res<NewRecords>>,
+    teams: Res<TeamAssignments>,
+    cup: Option<Res<CupStandings>>,
 ) {
     if let Ok(mut camera) = camera_query.get_single_mut() {
         camera.translation = Vec3::ZERO;
@@ -22,7 +39,237 @@ pub fn setup_victory_screen(
             translation: Vec3::new(0., 0., 100.),
             ..default()
         },
+        VictoryScreenEntity,
+    ));
+
+    // Team races are decided on points, show the scoreline above the standings
+    if !teams.0.is_empty() {
+        let scores = TeamScores::from_results(&results.entries, &teams.0);
+        let (headline, color) = match scores.winner() {
+            Some(team) => (format!("{} team wins!", team.as_str()), team.text_color()),
+            None => ("Draw!".to_string(), Color::BLACK),
+        };
+        commands.spawn((
+            Text2d::new(format!(
+                "{}  Red {} - {} Blue",
+                headline, scores.red, scores.blue
+            )),
+            TextColor(color),
+            Transform {
+                translation: Vec3::new(0., 260., 101.),
+                ..default()
+            },
+            TextFont {
+                font_size: 36.0,
+                ..default()
+            },
+            VictoryScreenEntity,
+        ));
+    }
+
+    let new_records = new_records.map(|r| r.clone()).unwrap_or_default();
+    let record_color = Color::srgb_u8(212, 160, 23);
+
+    // Final standings, offline the local player is always racer 0
+    let local_id = network_client.player_id.or(Some(0));
+    for (row, entry) in results.entries.iter().enumerate() {
+        let mut time = match entry.time {
+            Some(time) if entry.eliminated => format!("OUT {}", format_race_time(time)),
+            Some(time) if !entry.dnf => format_race_time(time),
+            _ => "DNF".to_string(),
+        };
+        let mut color = teams
+            .team_of(entry.id)
+            .map(|team| team.text_color())
+            .unwrap_or(Color::BLACK);
+        if Some(entry.id) == local_id {
+            if let Some(rank) = new_records.race_rank {
+                time = format!("{}  NEW RECORD #{}", time, rank + 1);
+                color = record_color;
+            }
+        }
+        commands.spawn((
+            Text2d::new(format!(
+                "P{}  {}  {}",
+                entry.position,
+                racer_name(entry.id, local_id),
+                time
+            )),
+            TextColor(color),
+            Transform {
+                translation: Vec3::new(0., 200. - row as f32 * 40., 101.),
+                ..default()
+            },
+            TextFont {
+                font_size: 32.0,
+                ..default()
+            },
+            VictoryScreenEntity,
+        ));
+    }
+
+    // New lap records under the standings
+    let first_row = results.entries.len();
+    for (i, (lap_time, rank)) in new_records.laps.iter().enumerate() {
+        commands.spawn((
+            Text2d::new(format!(
+                "Lap record #{}: {}",
+                rank + 1,
+                format_race_time(*lap_time)
+            )),
+            TextColor(record_color),
+            Transform {
+                translation: Vec3::new(0., 200. - (first_row + i) as f32 * 40. - 20., 101.),
+                ..default()
+            },
+            TextFont {
+                font_size: 28.0,
+                ..default()
+            },
+            VictoryScreenEntity,
+        ));
+    }
+
+    if let Some(cup) = cup {
+        spawn_cup_table(
+            &mut commands,
+            &cup,
+            local_id,
+            network_client.client.is_some(),
+        );
+    }
+
+    commands.spawn((
+        AudioPlayer::new(asset_server.load("victory-screen/67.mp3")),
+        VictoryScreenEntity,
+    ));
+}
+
+// Cup points so far on the right of the standings, after the last race it's the podium
+fn spawn_cup_table(
+    commands: &mut Commands,
+    cup: &CupStandings,
+    local_id: Option<u32>,
+    online: bool,
+) {
+    let podium = [
+        Color::srgb_u8(212, 160, 23),
+        Color::srgb_u8(140, 140, 140),
+        Color::srgb_u8(176, 100, 40),
+    ];
+    let table = cup.table();
+
+    let heading = if cup.is_last_race() {
+        match table.first() {
+            Some((id, _)) => format!("{} wins the cup!", racer_name(*id, local_id)),
+            None => "Cup over".to_string(),
+        }
+    } else {
+        format!("Cup: race {} of {}", cup.race + 1, cup.tracks.len())
+    };
+    commands.spawn((
+        Text2d::new(heading),
+        TextColor(Color::BLACK),
+        Transform {
+            translation: Vec3::new(420., 260., 101.),
+            ..default()
+        },
+        TextFont {
+            font_size: 32.0,
+            ..default()
+        },
+        VictoryScreenEntity,
     ));
 
-    commands.spawn(AudioPlayer::new(asset_server.load("victory-screen/67.mp3")));
+    for (row, (id, points)) in table.iter().enumerate() {
+        let color = match podium.get(row) {
+            Some(color) if cup.is_last_race() => *color,
+            _ => Color::BLACK,
+        };
+        commands.spawn((
+            Text2d::new(format!(
+                "{}. {}  {} pts",
+                row + 1,
+                racer_name(*id, local_id),
+                points
+            )),
+            TextColor(color),
+            Transform {
+                translation: Vec3::new(420., 200. - row as f32 * 36., 101.),
+                ..default()
+            },
+            TextFont {
+                font_size: if cup.is_last_race() && row < podium.len() {
+                    32.0
+                } else {
+                    26.0
+                },
+                ..default()
+            },
+            VictoryScreenEntity,
+        ));
+    }
+
+    if let Some(next) = cup.next_track() {
+        // online the server starts the next race by itself after the intermission
+        let hint = if online {
+            format!("Next up: {}, starting shortly", next.label())
+        } else {
+            format!("Next up: {}, ENTER to race", next.label())
+        };
+        commands.spawn((
+            Text2d::new(hint),
+            TextColor(Color::srgb_u8(120, 120, 120)),
+            Transform {
+                translation: Vec3::new(420., 200. - table.len() as f32 * 36. - 20., 101.),
+                ..default()
+            },
+            TextFont {
+                font_size: 22.0,
+                ..default()
+            },
+            VictoryScreenEntity,
+        ));
+    }
+}
+
+// Offline cups move on to the next track from the results screen
+pub fn check_for_victory_input(
+    input: Res<ButtonInput<KeyCode>>,
+    network_client: Res<NetworkClient>,
+    cup: Option<ResMut<CupStandings>>,
+    mut selected_map: ResMut<SelectedMap>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    let Some(mut cup) = cup else {
+        return;
+    };
+    if network_client.client.is_some() || !input.just_pressed(KeyCode::Enter) {
+        return;
+    }
+    if let Some(track) = cup.advance() {
+        selected_map.choice = track;
+        next_state.set(GameState::PlayingDemo);
+    }
+}
+
+// Clear the finished race and the results away before the next one loads
+pub fn cleanup_victory_screen(
+    mut commands: Commands,
+    query: Query<
+        Entity,
+        Or<(
+            With<VictoryScreenEntity>,
+            With<Car>,
+            With<MapTile>,
+            With<FinishLine>,
+            With<Checkpoint>,
+            With<SpeedPowerup>,
+            With<ShowBoostBox>,
+        )>,
+    >,
+) {
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
 }